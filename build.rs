@@ -1,4 +1,20 @@
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
 fn main() {
+    // Put `memory.x` in the output directory and make sure it's on the linker search path.
+    // A custom memory layout is used (instead of embassy-stm32's `memory-x` feature) so that
+    // the end of flash can be reserved for persistent data.
+    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    File::create(out.join("memory.x"))
+        .unwrap()
+        .write_all(include_bytes!("memory.x"))
+        .unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+
     // Set default values if environment variables are not set
     let usb_vid = std::env::var("USB_VID").unwrap_or_else(|_| "0x1209".to_string());
     let usb_pid = std::env::var("USB_PID").unwrap_or_else(|_| "0x0002".to_string());
@@ -0,0 +1,7 @@
+MEMORY
+{
+  /* STM32G431CB: 128K flash, 32K SRAM (SRAM1 + SRAM2 + CCM SRAM alias).
+   * The last 16K of flash are reserved for persistent data, see src/flash_layout.rs. */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 112K
+  RAM   : ORIGIN = 0x20000000, LENGTH = 32K
+}
@@ -0,0 +1,298 @@
+//! Coulomb-counting gauge: integrates the BQ76920 current to track the remaining capacity,
+//! learns the real battery capacity, and counts cycles and the energy charged and discharged.
+//!
+//! The state is saved to the config store from time to time (`GaugeCheckpoint`) and restored
+//! after a reset. The restored charge is checked against the open-circuit voltage estimate; if
+//! they disagree too much only the capacity, cycle count and energy totals are kept.
+
+use crate::battery_profile::{self, NOMINAL_CAPACITY_MAH};
+use crate::config::ConfigRecord;
+
+/// Minimum time between two writes (s), to spare the flash
+const MIN_CHECKPOINT_INTERVAL_S: u32 = 60;
+
+/// Regular write interval while the state changes (s)
+const CHECKPOINT_INTERVAL_S: u32 = 15 * 60;
+
+/// Write soon once the remaining charge has moved by this percentage of the full capacity
+const CHECKPOINT_SOC_STEP_PCT: u32 = 5;
+
+/// Charge current that abandons a capacity learning cycle (mA); less is treated as zero noise
+const LEARN_ABORT_CHARGE_MA: i32 = 50;
+
+/// The restored charge is not trusted when it differs from the open-circuit voltage estimate
+/// by more than this (%)
+const RESTORE_TOLERANCE_PCT: u8 = 30;
+
+/// Gauge state, published with the BQ76920 measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct GaugeStatus {
+    /// The gauge has been initialised from the saved state or the open-circuit voltage
+    pub valid: bool,
+    pub soc_pct: u8,
+    pub remaining_mah: u16,
+    /// Learned real capacity (mAh)
+    pub full_capacity_mah: u16,
+    pub cycle_count: u16,
+    /// Total energy charged into the battery (mWh)
+    pub charged_mwh: u32,
+    /// Total energy discharged from the battery (mWh)
+    pub discharged_mwh: u32,
+}
+
+impl GaugeStatus {
+    /// Remaining capacity from the gauge, or the open-circuit voltage estimate before it is
+    /// initialised
+    pub fn soc_or_ocv(&self, cell_voltages_mv: &[i32]) -> u8 {
+        if self.valid {
+            self.soc_pct
+        } else {
+            battery_profile::soc_from_cell_voltages(cell_voltages_mv)
+        }
+    }
+}
+
+/// Gauge state saved to the config store
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct GaugeCheckpoint {
+    pub remaining_mah: u16,
+    pub full_capacity_mah: u16,
+    pub cycle_count: u16,
+    /// Discharge not yet adding up to a full cycle (mAh)
+    pub cycle_progress_mah: u16,
+    pub charged_mwh: u32,
+    pub discharged_mwh: u32,
+}
+
+impl ConfigRecord for GaugeCheckpoint {
+    // Runtime state uses keys from 0x80 up, apart from the config parameters
+    const KEY: u8 = 0x80;
+    const LEN: usize = 16;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.remaining_mah.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.full_capacity_mah.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.cycle_count.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.cycle_progress_mah.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.charged_mwh.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.discharged_mwh.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            remaining_mah: u16::from_le_bytes([buf[0], buf[1]]),
+            full_capacity_mah: u16::from_le_bytes([buf[2], buf[3]]),
+            cycle_count: u16::from_le_bytes([buf[4], buf[5]]),
+            cycle_progress_mah: u16::from_le_bytes([buf[6], buf[7]]),
+            charged_mwh: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            discharged_mwh: u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct BatteryGauge {
+    valid: bool,
+    remaining_uah: i64,
+    full_capacity_mah: u32,
+    cycle_count: u16,
+    /// Discharge not yet adding up to a full cycle (µAh)
+    cycle_progress_uah: u64,
+    charged_uwh: u64,
+    discharged_uwh: u64,
+    /// Discharge since the last full charge, which gives the capacity once the pack is empty.
+    /// Charging in between discards it
+    learn_discharged_uah: Option<u64>,
+    last_checkpoint: Option<GaugeCheckpoint>,
+    since_checkpoint_s: u32,
+}
+
+impl Default for BatteryGauge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BatteryGauge {
+    pub const fn new() -> Self {
+        Self {
+            valid: false,
+            remaining_uah: 0,
+            full_capacity_mah: NOMINAL_CAPACITY_MAH,
+            cycle_count: 0,
+            cycle_progress_uah: 0,
+            charged_uwh: 0,
+            discharged_uwh: 0,
+            learn_discharged_uah: None,
+            last_checkpoint: None,
+            since_checkpoint_s: 0,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    /// Initialises from the saved state. If the remaining charge is too far from the
+    /// open-circuit voltage estimate `ocv_soc_pct`, the estimate is used instead and `false` is
+    /// returned; a state with an implausible capacity is discarded entirely.
+    pub fn restore(&mut self, checkpoint: &GaugeCheckpoint, ocv_soc_pct: u8) -> bool {
+        let capacity = checkpoint.full_capacity_mah as u32;
+        let capacity_range =
+            battery_profile::MIN_LEARNED_CAPACITY_MAH..=battery_profile::MAX_LEARNED_CAPACITY_MAH;
+        if !capacity_range.contains(&capacity) {
+            defmt::warn!("Gauge: discarding implausible checkpoint {:?}", checkpoint);
+            self.init_from_ocv(ocv_soc_pct);
+            return false;
+        }
+
+        self.full_capacity_mah = capacity;
+        self.cycle_count = checkpoint.cycle_count;
+        self.cycle_progress_uah = checkpoint.cycle_progress_mah as u64 * 1000;
+        self.charged_uwh = checkpoint.charged_mwh as u64 * 1000;
+        self.discharged_uwh = checkpoint.discharged_mwh as u64 * 1000;
+        self.last_checkpoint = Some(*checkpoint);
+
+        let restored_mah = (checkpoint.remaining_mah as u32).min(capacity);
+        let restored_soc = (restored_mah * 100 / capacity) as u8;
+        if restored_soc.abs_diff(ocv_soc_pct) > RESTORE_TOLERANCE_PCT {
+            defmt::warn!(
+                "Gauge: restored SoC {}% disagrees with cell voltages ({}%), using the latter",
+                restored_soc,
+                ocv_soc_pct
+            );
+            self.init_from_ocv(ocv_soc_pct);
+            return false;
+        }
+        self.remaining_uah = restored_mah as i64 * 1000;
+        self.valid = true;
+        true
+    }
+
+    /// Initialises the remaining charge from the open-circuit voltage when there is no saved state
+    pub fn init_from_ocv(&mut self, ocv_soc_pct: u8) {
+        self.remaining_uah = self.full_capacity_mah as i64 * 1000 * ocv_soc_pct as i64 / 100;
+        self.valid = true;
+    }
+
+    /// Accumulates one sample. `current_ma` is positive while charging.
+    pub fn update(&mut self, current_ma: i32, pack_mv: i32, min_cell_mv: i32, elapsed_ms: u32) {
+        if !self.valid {
+            return;
+        }
+        // mA * ms / 3600 = µAh; µAh * mV / 1000 = µWh
+        let delta_uah = current_ma as i64 * elapsed_ms as i64 / 3600;
+        let delta_uwh = (delta_uah * pack_mv.max(0) as i64 / 1000).unsigned_abs();
+        let full_uah = self.full_capacity_mah as i64 * 1000;
+
+        if delta_uah >= 0 {
+            self.charged_uwh += delta_uwh;
+            if current_ma > LEARN_ABORT_CHARGE_MA {
+                self.learn_discharged_uah = None;
+            }
+        } else {
+            let discharged = delta_uah.unsigned_abs();
+            self.discharged_uwh += delta_uwh;
+            self.cycle_progress_uah += discharged;
+            if self.cycle_progress_uah >= full_uah as u64 {
+                self.cycle_progress_uah -= full_uah as u64;
+                self.cycle_count = self.cycle_count.saturating_add(1);
+            }
+            if let Some(learned) = self.learn_discharged_uah.as_mut() {
+                *learned += discharged;
+            }
+        }
+        self.remaining_uah = (self.remaining_uah + delta_uah).clamp(0, full_uah);
+
+        if current_ma >= 0
+            && current_ma < battery_profile::GAUGE_FULL_TAPER_CURRENT_MA
+            && min_cell_mv >= battery_profile::GAUGE_FULL_CELL_MV
+        {
+            // Full: calibrate the remaining charge and start learning the capacity
+            self.remaining_uah = full_uah;
+            self.learn_discharged_uah = Some(0);
+        } else if current_ma < 0 && min_cell_mv <= battery_profile::GAUGE_EMPTY_CELL_MV {
+            // Empty: a complete discharge from full to empty gives the real capacity
+            if let Some(learned_uah) = self.learn_discharged_uah.take() {
+                self.learn_capacity((learned_uah / 1000) as u32);
+            }
+            self.remaining_uah = 0;
+        }
+    }
+
+    fn learn_capacity(&mut self, measured_mah: u32) {
+        let capacity_range =
+            battery_profile::MIN_LEARNED_CAPACITY_MAH..=battery_profile::MAX_LEARNED_CAPACITY_MAH;
+        if !capacity_range.contains(&measured_mah) {
+            defmt::warn!("Gauge: ignoring implausible capacity {} mAh", measured_mah);
+            return;
+        }
+        // Smooth out the error of a single measurement
+        self.full_capacity_mah = (self.full_capacity_mah * 3 + measured_mah) / 4;
+        defmt::info!(
+            "Gauge: measured {} mAh, learned capacity now {} mAh",
+            measured_mah,
+            self.full_capacity_mah
+        );
+    }
+
+    pub fn status(&self) -> GaugeStatus {
+        let remaining_mah = (self.remaining_uah / 1000) as u32;
+        GaugeStatus {
+            valid: self.valid,
+            soc_pct: (remaining_mah * 100 / self.full_capacity_mah).min(100) as u8,
+            remaining_mah: remaining_mah.min(u16::MAX as u32) as u16,
+            full_capacity_mah: self.full_capacity_mah.min(u16::MAX as u32) as u16,
+            cycle_count: self.cycle_count,
+            charged_mwh: (self.charged_uwh / 1000).min(u32::MAX as u64) as u32,
+            discharged_mwh: (self.discharged_uwh / 1000).min(u32::MAX as u64) as u32,
+        }
+    }
+
+    fn checkpoint(&self) -> GaugeCheckpoint {
+        let status = self.status();
+        GaugeCheckpoint {
+            remaining_mah: status.remaining_mah,
+            full_capacity_mah: status.full_capacity_mah,
+            cycle_count: status.cycle_count,
+            cycle_progress_mah: (self.cycle_progress_uah / 1000).min(u16::MAX as u64) as u16,
+            charged_mwh: status.charged_mwh,
+            discharged_mwh: status.discharged_mwh,
+        }
+    }
+
+    /// Called once per second; returns the state to write to the flash, if any.
+    ///
+    /// Writes are at least `MIN_CHECKPOINT_INTERVAL_S` apart. A marked change in the remaining
+    /// charge, or a new cycle count or capacity, is written soon; other changes are written every
+    /// `CHECKPOINT_INTERVAL_S`.
+    pub fn checkpoint_due(&mut self, elapsed_s: u16) -> Option<GaugeCheckpoint> {
+        if !self.valid {
+            return None;
+        }
+        self.since_checkpoint_s = self.since_checkpoint_s.saturating_add(elapsed_s as u32);
+        if self.since_checkpoint_s < MIN_CHECKPOINT_INTERVAL_S {
+            return None;
+        }
+
+        let current = self.checkpoint();
+        let due = match self.last_checkpoint {
+            None => true,
+            Some(last) if last == current => false,
+            Some(last) => {
+                let soc_step_mah = self.full_capacity_mah * CHECKPOINT_SOC_STEP_PCT / 100;
+                last.cycle_count != current.cycle_count
+                    || last.full_capacity_mah != current.full_capacity_mah
+                    || (last.remaining_mah as u32).abs_diff(current.remaining_mah as u32)
+                        >= soc_step_mah
+                    || self.since_checkpoint_s >= CHECKPOINT_INTERVAL_S
+            }
+        };
+        if !due {
+            return None;
+        }
+        self.last_checkpoint = Some(current);
+        self.since_checkpoint_s = 0;
+        Some(current)
+    }
+}
@@ -0,0 +1,132 @@
+//! Battery pack parameters (5S LiFePO4) and state of charge estimation from the open-circuit
+//! voltage.
+
+/// Number of cells in series
+pub const CELL_COUNT: usize = 5;
+
+/// Nominal pack capacity (mAh)
+pub const NOMINAL_CAPACITY_MAH: u32 = 3000;
+
+/// Nominal cell voltage (mV), used to convert charge to energy
+pub const CELL_NOMINAL_MV: u32 = 3200;
+
+/// Remaining capacity below which the battery is low (%, HID RemainingCapacityLimit)
+pub const REMAINING_CAPACITY_LIMIT_PCT: u8 = 20;
+
+/// Remaining capacity below which the host is warned (%, HID WarningCapacityLimit)
+pub const WARNING_CAPACITY_LIMIT_PCT: u8 = 30;
+
+/// Remaining capacity below which shutdown is imminent (%, HID ShutdownImminent)
+pub const SHUTDOWN_IMMINENT_PCT: u8 = 10;
+
+/// The battery needs replacing once the learned full charge capacity falls below this
+/// percentage of the nominal capacity (HID NeedReplacement)
+pub const REPLACEMENT_CAPACITY_PCT: u8 = 80;
+
+/// Lowest remaining capacity during a battery self-test (%); below it the test is aborted and
+/// charging resumes
+pub const SELF_TEST_MIN_SOC_PCT: u8 = 30;
+
+/// Lowest cell voltage during a battery self-test (mV); below it the test is aborted and
+/// charging resumes
+pub const SELF_TEST_MIN_CELL_MV: i32 = 3000;
+
+/// Allowed charge voltage range (per cell, mV); above the maximum the BQ76920 overvoltage
+/// protection trips
+pub const CELL_CHARGE_VOLTAGE_MIN_MV: u16 = 3400;
+pub const CELL_CHARGE_VOLTAGE_MAX_MV: u16 = 3650;
+
+/// Maximum charge current (mA), at most 1C
+pub const MAX_CHARGE_CURRENT_MA: u16 = NOMINAL_CAPACITY_MAH as u16;
+
+/// Smallest charge current that can be set (mA), one BQ25730 step
+pub const MIN_CHARGE_CURRENT_MA: u16 = 64;
+
+/// Allowed input current limit range (mA)
+pub const MIN_INPUT_CURRENT_LIMIT_MA: u16 = 500;
+pub const MAX_INPUT_CURRENT_LIMIT_MA: u16 = 6000;
+
+/// Allowed minimum system voltage range (mV), capped at the nominal pack voltage
+pub const MIN_VSYS_MIN_MV: u16 = 10000;
+pub const MAX_VSYS_MIN_MV: u16 = CELL_COUNT as u16 * CELL_NOMINAL_MV as u16;
+
+/// Allowed BQ76920 overvoltage threshold range (per cell, mV); LiFePO4 ages faster above 3.8 V
+pub const CELL_OV_TRIP_MIN_MV: u16 = 3550;
+pub const CELL_OV_TRIP_MAX_MV: u16 = 3800;
+
+/// Allowed BQ76920 undervoltage threshold range (per cell, mV); LiFePO4 is permanently damaged
+/// below 2.0 V
+pub const CELL_UV_TRIP_MIN_MV: u16 = 2000;
+pub const CELL_UV_TRIP_MAX_MV: u16 = 2800;
+
+/// Allowed BQ76920 discharge overcurrent threshold range (mA)
+pub const MIN_OCD_LIMIT_MA: u16 = 2000;
+pub const MAX_OCD_LIMIT_MA: u16 = 20_000;
+
+/// Allowed BQ76920 discharge short-circuit threshold range (mA), above the overcurrent threshold
+pub const MIN_SCD_LIMIT_MA: u16 = 5000;
+pub const MAX_SCD_LIMIT_MA: u16 = 40_000;
+
+/// Gauge: the pack is full once the charge current tapers below this value while the lowest cell
+/// is above `GAUGE_FULL_CELL_MV` (mA)
+pub const GAUGE_FULL_TAPER_CURRENT_MA: i32 = 100;
+pub const GAUGE_FULL_CELL_MV: i32 = 3450;
+
+/// Gauge: the pack is empty once the lowest cell drops below this voltage while discharging (mV)
+pub const GAUGE_EMPTY_CELL_MV: i32 = 2900;
+
+/// Allowed range for the learned capacity (mAh); results outside it are discarded
+pub const MIN_LEARNED_CAPACITY_MAH: u32 = NOMINAL_CAPACITY_MAH / 2;
+pub const MAX_LEARNED_CAPACITY_MAH: u32 = NOMINAL_CAPACITY_MAH * 6 / 5;
+
+/// LiFePO4 cell open-circuit voltage against remaining capacity (mV, %), in ascending voltage.
+const LIFEPO4_OCV_TABLE: [(i32, u8); 11] = [
+    (2500, 0),
+    (2900, 5),
+    (3100, 10),
+    (3200, 20),
+    (3250, 30),
+    (3280, 40),
+    (3300, 50),
+    (3320, 60),
+    (3330, 70),
+    (3350, 80),
+    (3400, 100),
+];
+
+/// Estimates the remaining capacity by linear interpolation of the cell voltage in the
+/// open-circuit voltage table.
+///
+/// The LiFePO4 discharge plateau is very flat, so the estimate is only useful at rest or at low
+/// current.
+pub fn soc_from_cell_mv(cell_mv: i32) -> u8 {
+    let (first_mv, first_pct) = LIFEPO4_OCV_TABLE[0];
+    if cell_mv <= first_mv {
+        return first_pct;
+    }
+    for window in LIFEPO4_OCV_TABLE.windows(2) {
+        let (lo_mv, lo_pct) = window[0];
+        let (hi_mv, hi_pct) = window[1];
+        if cell_mv <= hi_mv {
+            let span_pct = (hi_pct - lo_pct) as i32;
+            let pct = lo_pct as i32 + (cell_mv - lo_mv) * span_pct / (hi_mv - lo_mv);
+            return pct as u8;
+        }
+    }
+    100
+}
+
+/// Estimates the pack's remaining capacity from its lowest cell, which limits the usable charge.
+pub fn soc_from_cell_voltages(cell_voltages_mv: &[i32]) -> u8 {
+    cell_voltages_mv
+        .iter()
+        .copied()
+        .min()
+        .map_or(0, soc_from_cell_mv)
+}
+
+/// Remaining energy for the given remaining capacity (mWh).
+pub fn remaining_energy_mwh(soc_pct: u8) -> u32 {
+    let full_mwh = NOMINAL_CAPACITY_MAH * CELL_NOMINAL_MV * CELL_COUNT as u32 / 1000;
+    full_mwh * soc_pct as u32 / 100
+}
@@ -0,0 +1,301 @@
+//! Battery self-test: charging is paused and the battery supplies the load for a while to
+//! measure the voltage drop, internal resistance and discharge rate.
+//!
+//! `bq25730_task` advances the state machine once per loop and sets the BQ25730 CHRG_INHIBIT
+//! (charge inhibit) and EN_LEARN (battery supplies the system) bits from [`BatteryTestControl`].
+
+use crate::battery_profile;
+
+/// Length of the rest phase: time for the battery voltage to settle after charging stops (s)
+const REST_PHASE_S: u16 = 5;
+
+/// Time into the load phase before the loaded voltage is sampled (s)
+const LOAD_SETTLE_S: u16 = 2;
+
+/// Below this discharge current the internal resistance cannot be computed reliably (mA)
+const MIN_TEST_CURRENT_MA: i32 = 100;
+
+/// Pack internal resistance above which the test warns (mΩ)
+const INTERNAL_RESISTANCE_WARN_MOHM: u32 = 150;
+
+/// Pack internal resistance above which the test fails (mΩ)
+const INTERNAL_RESISTANCE_FAIL_MOHM: u32 = 300;
+
+/// Self-test parameters from the host
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct BatteryTestParams {
+    /// Maximum length of the load phase (s)
+    pub duration_s: u16,
+    /// Largest remaining capacity drop in the load phase (%); reaching it ends the test early
+    pub max_depth_pct: u8,
+}
+
+/// Self-test command
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub enum BatteryTestCommand {
+    Start(BatteryTestParams),
+    Abort,
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum BatteryTestPhase {
+    #[default]
+    Idle = 0,
+    /// Charging inhibited, waiting for the battery to rest
+    Rest = 1,
+    /// The battery supplies the load
+    Load = 2,
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum TestVerdict {
+    /// No self-test has run yet
+    #[default]
+    None = 0,
+    Pass = 1,
+    Warn = 2,
+    Fail = 3,
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum TestAbortReason {
+    #[default]
+    None = 0,
+    /// Remaining capacity below the safety limit
+    LowSoc = 1,
+    /// A cell voltage below the safety limit
+    LowCellVoltage = 2,
+    /// Cancelled by the host
+    Cancelled = 3,
+    /// No BQ76920 measurements available
+    NoData = 4,
+}
+
+/// Self-test result
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct BatteryTestResult {
+    pub verdict: TestVerdict,
+    pub abort_reason: TestAbortReason,
+    /// Actual length of the load phase (s)
+    pub duration_s: u16,
+    /// Resting voltage (mV)
+    pub rest_voltage_mv: i32,
+    /// Lowest voltage during the load phase (mV)
+    pub min_loaded_voltage_mv: i32,
+    /// Voltage drop under load (mV)
+    pub voltage_sag_mv: i32,
+    /// Pack internal resistance (mΩ), 0 when the discharge current was too low to compute it
+    pub internal_resistance_mohm: u32,
+    /// Average discharge current during the load phase (mA)
+    pub avg_discharge_ma: i32,
+    /// Remaining capacity drop during the load phase (%)
+    pub soc_drop_pct: u8,
+}
+
+/// What the self-test needs from the BQ25730
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct BatteryTestControl {
+    pub inhibit_charging: bool,
+    pub learn_mode: bool,
+}
+
+/// Self-test state, published with the BQ25730 measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct BatteryTestStatus {
+    pub phase: BatteryTestPhase,
+    pub elapsed_s: u16,
+    pub last_result: BatteryTestResult,
+}
+
+/// Battery data from one sample
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct BatterySample {
+    pub pack_voltage_mv: i32,
+    /// Positive while charging, negative while discharging
+    pub current_ma: i32,
+    pub min_cell_mv: i32,
+    pub soc_pct: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct BatteryTest {
+    params: Option<BatteryTestParams>,
+    phase: BatteryTestPhase,
+    phase_elapsed_s: u16,
+    rest_voltage_mv: i32,
+    rest_current_ma: i32,
+    start_soc_pct: u8,
+    loaded_voltage_mv: Option<i32>,
+    loaded_current_ma: i32,
+    min_loaded_voltage_mv: i32,
+    discharge_ma_sum: i64,
+    discharge_samples: u32,
+    last_result: BatteryTestResult,
+}
+
+impl BatteryTest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handle_command(&mut self, command: BatteryTestCommand) {
+        match command {
+            BatteryTestCommand::Start(params) => {
+                if self.phase != BatteryTestPhase::Idle {
+                    defmt::warn!("BatteryTest: test already running, ignoring start request");
+                    return;
+                }
+                defmt::info!("BatteryTest: starting with {:?}", params);
+                *self = Self {
+                    params: Some(params),
+                    phase: BatteryTestPhase::Rest,
+                    last_result: self.last_result,
+                    ..Self::default()
+                };
+            }
+            BatteryTestCommand::Abort => {
+                if self.phase != BatteryTestPhase::Idle {
+                    self.finish(TestAbortReason::Cancelled);
+                }
+            }
+        }
+    }
+
+    /// Advances by `elapsed_s` seconds. `sample` is `None` when no BQ76920 data was available this
+    /// round.
+    pub fn tick(&mut self, elapsed_s: u16, sample: Option<BatterySample>) {
+        let Some(params) = self.params else {
+            return;
+        };
+        let Some(sample) = sample else {
+            self.finish(TestAbortReason::NoData);
+            return;
+        };
+
+        // The safety limits come first: crossing one in any phase aborts and resumes charging
+        if sample.soc_pct < battery_profile::SELF_TEST_MIN_SOC_PCT {
+            self.finish(TestAbortReason::LowSoc);
+            return;
+        }
+        if sample.min_cell_mv < battery_profile::SELF_TEST_MIN_CELL_MV {
+            self.finish(TestAbortReason::LowCellVoltage);
+            return;
+        }
+
+        self.phase_elapsed_s = self.phase_elapsed_s.saturating_add(elapsed_s);
+        match self.phase {
+            BatteryTestPhase::Idle => {}
+            BatteryTestPhase::Rest => {
+                if self.phase_elapsed_s >= REST_PHASE_S {
+                    self.rest_voltage_mv = sample.pack_voltage_mv;
+                    self.rest_current_ma = sample.current_ma;
+                    self.start_soc_pct = sample.soc_pct;
+                    self.min_loaded_voltage_mv = sample.pack_voltage_mv;
+                    self.phase = BatteryTestPhase::Load;
+                    self.phase_elapsed_s = 0;
+                    defmt::info!(
+                        "BatteryTest: rest voltage {}mV, switching load to battery",
+                        self.rest_voltage_mv
+                    );
+                }
+            }
+            BatteryTestPhase::Load => {
+                self.min_loaded_voltage_mv = self.min_loaded_voltage_mv.min(sample.pack_voltage_mv);
+                if self.phase_elapsed_s >= LOAD_SETTLE_S {
+                    if self.loaded_voltage_mv.is_none() {
+                        self.loaded_voltage_mv = Some(sample.pack_voltage_mv);
+                        self.loaded_current_ma = sample.current_ma;
+                    }
+                    self.discharge_ma_sum += (-sample.current_ma) as i64;
+                    self.discharge_samples += 1;
+                }
+                let soc_drop = self.start_soc_pct.saturating_sub(sample.soc_pct);
+                if self.phase_elapsed_s >= params.duration_s || soc_drop >= params.max_depth_pct {
+                    self.finish_with_soc(TestAbortReason::None, sample.soc_pct);
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self, reason: TestAbortReason) {
+        let soc = self.start_soc_pct;
+        self.finish_with_soc(reason, soc);
+    }
+
+    fn finish_with_soc(&mut self, abort_reason: TestAbortReason, end_soc_pct: u8) {
+        let load_duration_s = if self.phase == BatteryTestPhase::Load {
+            self.phase_elapsed_s
+        } else {
+            0
+        };
+        let avg_discharge_ma = if self.discharge_samples > 0 {
+            (self.discharge_ma_sum / self.discharge_samples as i64) as i32
+        } else {
+            0
+        };
+
+        let (voltage_sag_mv, internal_resistance_mohm) = match self.loaded_voltage_mv {
+            Some(loaded_mv) => {
+                let sag_mv = self.rest_voltage_mv - loaded_mv;
+                let delta_ma = self.rest_current_ma - self.loaded_current_ma;
+                let r_mohm = if delta_ma >= MIN_TEST_CURRENT_MA && sag_mv > 0 {
+                    (sag_mv as i64 * 1000 / delta_ma as i64) as u32
+                } else {
+                    0
+                };
+                (sag_mv, r_mohm)
+            }
+            None => (0, 0),
+        };
+
+        let verdict = if abort_reason == TestAbortReason::Cancelled {
+            TestVerdict::None
+        } else if abort_reason != TestAbortReason::None
+            || internal_resistance_mohm > INTERNAL_RESISTANCE_FAIL_MOHM
+        {
+            TestVerdict::Fail
+        } else if internal_resistance_mohm == 0
+            || internal_resistance_mohm > INTERNAL_RESISTANCE_WARN_MOHM
+        {
+            // Internal resistance not measurable (discharge current too low) or high
+            TestVerdict::Warn
+        } else {
+            TestVerdict::Pass
+        };
+
+        self.last_result = BatteryTestResult {
+            verdict,
+            abort_reason,
+            duration_s: load_duration_s,
+            rest_voltage_mv: self.rest_voltage_mv,
+            min_loaded_voltage_mv: self.min_loaded_voltage_mv,
+            voltage_sag_mv,
+            internal_resistance_mohm,
+            avg_discharge_ma,
+            soc_drop_pct: self.start_soc_pct.saturating_sub(end_soc_pct),
+        };
+        defmt::info!("BatteryTest: finished: {:?}", self.last_result);
+
+        self.params = None;
+        self.phase = BatteryTestPhase::Idle;
+        self.phase_elapsed_s = 0;
+    }
+
+    pub fn control(&self) -> BatteryTestControl {
+        BatteryTestControl {
+            inhibit_charging: self.phase != BatteryTestPhase::Idle,
+            learn_mode: self.phase == BatteryTestPhase::Load,
+        }
+    }
+
+    pub fn status(&self) -> BatteryTestStatus {
+        BatteryTestStatus {
+            phase: self.phase,
+            elapsed_s: self.phase_elapsed_s,
+            last_result: self.last_result,
+        }
+    }
+}
@@ -4,11 +4,7 @@ use bq25730_async_rs::data_types::{
     VsysMinSetting,
 }; // Added AdcVsys
 use defmt::*;
-use embassy_time::{Duration, Timer};
-
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
-use embassy_stm32::i2c::I2c;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
 
 use bq769x0_async_rs::registers::{
     SysCtrl2Flags as Bq76920SysCtrl2Flags, SysStatFlags as Bq76920SysStatFlags,
@@ -20,23 +16,165 @@ use bq25730_async_rs::registers::{
 }; // Removed ChargeOption2Flags
 use bq25730_async_rs::{Bq25730, SenseResistorValue};
 
+use crate::battery_test::{BatterySample, BatteryTest};
+use crate::calibration::Bq25730Calibration;
+use crate::config::ChargerConfig;
+use crate::data_types::{Bq76920Measurements, SampleInfo};
+use crate::energy_meter::EnergyMeter;
+use crate::i2c_supervisor::{I2cDeviceId, I2cSupervisor, SharedI2cDevice};
+use crate::power_quality::{PowerQualityConfig, PowerQualityMonitor};
+use crate::sampling::{BQ25730_ADC_ONE_SHOT_TIME, SamplingControl};
 use crate::shared::{
-    Bq25730AlertsPublisher, Bq25730MeasurementsPublisher, Bq76920MeasurementsSubscriber,
+    BatteryTestCommandReceiver, Bq25730AlertsPublisher, Bq25730MeasurementsPublisher,
+    Bq76920MeasurementsSubscriber, SystemConfigSubscriber,
 };
-// Default charging parameters
-const DEFAULT_CHARGE_CURRENT_MA: u16 = 512;
-const DEFAULT_CHARGE_VOLTAGE_MV: u16 = 18000;
+use crate::task_watchdog::{SupervisedTask, TaskWatchdog};
+
+// How long to wait for BQ76920 measurements before running the charge control on stale data.
+const BMS_UPDATE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Applies the VsysMin and input current limit settings. Charge voltage and current are
+// rewritten on every loop iteration and need no separate handling. Returns `false` if any
+// register could not be written.
+async fn apply_input_settings(
+    bq25730: &mut Bq25730<SharedI2cDevice>,
+    charger: &ChargerConfig,
+) -> bool {
+    let mut ok = true;
+    match bq25730
+        .set_vsys_min_setting(VsysMinSetting::from_millivolts(charger.vsys_min_mv))
+        .await
+    {
+        Ok(()) => { /* Log removed */ }
+        Err(e) => {
+            error!("Failed to set BQ25730 VsysMin: {}", e);
+            ok = false;
+        }
+    }
+
+    match bq25730.read_iin_host_setting().await {
+        Ok(mut iin_host) => {
+            iin_host.milliamps = charger.input_current_limit_ma;
+            if let Err(e) = bq25730.set_iin_host_setting(iin_host).await {
+                error!("Failed to set BQ25730 input current limit: {:?}", e);
+                ok = false;
+            }
+        }
+        Err(e) => {
+            error!("Failed to read BQ25730 IIN_HOST: {:?}", e);
+            ok = false;
+        }
+    }
+    ok
+}
+
+// Initializes the charger: writes the driver configuration, starts with 0 mA charge current at
+// the configured charge voltage and enables continuous ADC conversion. Returns `false` if the
+// chip could not be initialized, in which case the caller retries later.
+async fn init_charger(bq25730: &mut Bq25730<SharedI2cDevice>, charger: &ChargerConfig) -> bool {
+    // init() will determine the correct rsns from the chip and update bq25730.rsns
+    if let Err(e) = bq25730.init().await {
+        error!("Failed to initialize BQ25730: {:?}", e);
+        return false;
+    }
+
+    let initial_charge_current = ChargeCurrentSetting {
+        milliamps: 0,
+        rsns_bat: bq25730.config().rsns_bat,
+    };
+    if let Err(e) = bq25730
+        .set_charge_current_setting(initial_charge_current)
+        .await
+    {
+        error!(
+            "Failed to set initial BQ25730 charge current to 0mA: {:?}",
+            e
+        );
+    }
+
+    let target_charge_voltage = ChargeVoltageSetting::from_millivolts(charger.charge_voltage_mv);
+    if let Err(e) = bq25730
+        .set_charge_voltage_setting(target_charge_voltage)
+        .await
+    {
+        error!("Failed to set BQ25730 target charge voltage: {:?}", e);
+    }
+
+    info!("Configuring and enabling BQ25730 ADC for continuous conversion...");
+    start_adc(bq25730, false).await;
+
+    true
+}
+
+// Starts the ADC on all channels, either converting continuously or, with `one_shot`, once.
+// A one-shot conversion clears ADC_START when done, after `BQ25730_ADC_ONE_SHOT_TIME`.
+async fn start_adc(bq25730: &mut Bq25730<SharedI2cDevice>, one_shot: bool) -> bool {
+    let mut msb_flags = bq25730_async_rs::registers::AdcOptionMsbFlags::ADC_START
+        | bq25730_async_rs::registers::AdcOptionMsbFlags::ADC_FULLSCALE;
+    if !one_shot {
+        msb_flags |= bq25730_async_rs::registers::AdcOptionMsbFlags::ADC_CONV;
+    }
+    let adc_option = bq25730_async_rs::data_types::AdcOption {
+        msb_flags,
+        lsb_flags: bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_CMPIN
+            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_VBUS
+            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_PSYS
+            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_IIN
+            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_IDCHG
+            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_ICHG
+            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_VSYS
+            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_VBAT,
+    };
+    match bq25730.set_adc_option(adc_option).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to set BQ25730 ADC options: {:?}", e);
+            false
+        }
+    }
+}
 
 /// Embassy task for managing the BQ25730 charger IC.
+///
+/// Besides gating charging on the BQ76920 state, this task runs the battery self-test
+/// requested via `battery_test_command_receiver`: it inhibits charging (CHRG_INHIBIT) and
+/// lets the battery supply the system (EN_LEARN) while the test is running.
+///
+/// Charge voltage/current, input current limit and VsysMin come from `charger`, loaded
+/// from the configuration store at boot, and are updated live from `system_config_subscriber`,
+/// as is the factory `calibration` applied to the ICHG, IDCHG and IIN ADC readings.
+/// Every ADC reading feeds the input power (VBUS × IIN) to `energy_meter` and the input
+/// power-quality statistics (see `power_quality`), whose thresholds come from `power_quality`
+/// and are updated live as well.
+///
+/// The cycle interval follows the active `sampling` mode. While the UPS is idle or in
+/// low-power operation the ADC is switched from continuous to one-shot conversion, started once
+/// per cycle.
+///
+/// BQ76920 measurements that are invalid or older than `MEASUREMENT_MAX_AGE` are treated as
+/// unsafe: charging is not permitted and a running battery self-test is aborted.
+///
+/// The charger is initialized again whenever `i2c_supervisor` reports a bus recovery or
+/// repeated failures, and retried every cycle until the first initialization succeeds.
+/// Each cycle checks in with `task_watchdog`.
 #[embassy_executor::task]
 pub async fn bq25730_task(
-    i2c_bus: I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, embassy_stm32::mode::Async>>,
+    i2c_bus: SharedI2cDevice,
+    i2c_supervisor: &'static I2cSupervisor,
     address: u8,
     bq25730_alerts_publisher: Bq25730AlertsPublisher<'static>,
     bq25730_measurements_publisher: Bq25730MeasurementsPublisher<'static>,
     mut bq76920_measurements_subscriber: Bq76920MeasurementsSubscriber<'static, 5>,
+    battery_test_command_receiver: BatteryTestCommandReceiver<'static>,
+    mut system_config_subscriber: SystemConfigSubscriber<'static>,
+    mut charger: ChargerConfig,
+    power_quality: PowerQualityConfig,
+    mut calibration: Bq25730Calibration,
+    energy_meter: &'static EnergyMeter,
+    sampling: &'static SamplingControl,
+    task_watchdog: &'static TaskWatchdog,
 ) {
-    info!("BQ25730 task started.");
+    info!("BQ25730 task started with {:?}", charger);
 
     // Initialize with a Config struct
     let mut config = bq25730_async_rs::data_types::Config::new(
@@ -90,66 +228,119 @@ pub async fn bq25730_task(
 
     let mut bq25730 = Bq25730::new(i2c_bus, address, config);
 
-    // init() will determine the correct rsns from the chip and update bq25730.rsns
-    if let Err(e) = bq25730.init().await {
-        error!("Failed to initialize BQ25730: {:?}", e);
-        // Handle initialization error, perhaps by retrying or stopping the task
-        return;
-    }
+    // Re-run after an I2C bus recovery, or until the first initialization succeeds.
+    let mut reinit_generation = 0;
+    let mut initialized = init_charger(&mut bq25730, &charger).await;
 
-    let initial_charge_current = ChargeCurrentSetting {
-        milliamps: 0,
-        rsns_bat: bq25730.config().rsns_bat,
-    };
-    if let Err(e) = bq25730
-        .set_charge_current_setting(initial_charge_current)
+    // Charger settings last written successfully, reported so that configuration
+    // transactions can tell when a change has taken effect.
+    let mut applied_charger = apply_input_settings(&mut bq25730, &charger)
         .await
-    {
-        error!(
-            "Failed to set initial BQ25730 charge current to 0mA: {:?}",
-            e
-        );
-    }
+        .then_some(charger);
 
-    let target_charge_voltage = ChargeVoltageSetting::from_millivolts(DEFAULT_CHARGE_VOLTAGE_MV);
-    if let Err(e) = bq25730
-        .set_charge_voltage_setting(target_charge_voltage)
-        .await
-    {
-        error!("Failed to set BQ25730 target charge voltage: {:?}", e);
-    }
+    let mut battery_test = BatteryTest::new();
+    let mut power_quality = PowerQualityMonitor::new(power_quality);
+    let mut last_test_tick = Instant::now();
+    let mut last_bq76920_measurements = Bq76920Measurements::<5>::default();
+    let mut last_adc_measurements: Option<AdcMeasurements> = None;
+    let mut adc_sample = SampleInfo::default();
+    // `init_charger` leaves the ADC converting continuously
+    let mut adc_one_shot = false;
 
-    info!("Configuring and enabling BQ25730 ADC for continuous conversion...");
-    let adc_option = bq25730_async_rs::data_types::AdcOption {
-        msb_flags: bq25730_async_rs::registers::AdcOptionMsbFlags::ADC_CONV
-            | bq25730_async_rs::registers::AdcOptionMsbFlags::ADC_START
-            | bq25730_async_rs::registers::AdcOptionMsbFlags::ADC_FULLSCALE,
-        lsb_flags: bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_CMPIN
-            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_VBUS
-            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_PSYS
-            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_IIN
-            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_IDCHG
-            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_ICHG
-            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_VSYS
-            | bq25730_async_rs::registers::AdcOptionFlags::EN_ADC_VBAT,
-    };
-    if let Err(e) = bq25730.set_adc_option(adc_option).await {
-        error!("Failed to set BQ25730 ADC options: {:?}", e);
-    }
+    loop {
+        task_watchdog.check_in(SupervisedTask::Bq25730);
 
-    match bq25730
-        .set_vsys_min_setting(VsysMinSetting::from_millivolts(12000))
+        // Keep the charger under control even if bq76920_task stops publishing
+        let bq76920_measurements = match with_timeout(
+            BMS_UPDATE_TIMEOUT,
+            bq76920_measurements_subscriber.next_message_pure(),
+        )
         .await
-    {
-        Ok(()) => { /* Log removed */ }
-        Err(e) => error!("Failed to set BQ25730 VsysMin: {}", e),
-    }
+        {
+            Ok(mut measurements) => {
+                // With faster BQ76920 sampling several readings may be queued; use the newest
+                while let Some(newer) = bq76920_measurements_subscriber.try_next_message_pure() {
+                    measurements = newer;
+                }
+                last_bq76920_measurements = measurements;
+                measurements
+            }
+            Err(_) => {
+                warn!("[BQ25730] No BQ76920 measurements received, using stale data.");
+                last_bq76920_measurements
+            }
+        };
+        let bms_data_fresh = bq76920_measurements.sample.is_fresh(Instant::now());
 
-    loop {
-        let bq76920_measurements = bq76920_measurements_subscriber.next_message_pure().await;
+        while let Some(config) = system_config_subscriber.try_next_message_pure() {
+            calibration = config.calibration.bq25730;
+            power_quality.set_config(config.power_quality);
+            if config.charger != charger {
+                info!(
+                    "[BQ25730] Charger configuration updated: {:?}",
+                    config.charger
+                );
+                charger = config.charger;
+                if apply_input_settings(&mut bq25730, &charger).await {
+                    applied_charger = Some(charger);
+                }
+            }
+        }
+
+        if !initialized || i2c_supervisor.take_reinit(I2cDeviceId::Bq25730, &mut reinit_generation)
+        {
+            info!("[BQ25730] Re-initializing charger...");
+            initialized = init_charger(&mut bq25730, &charger).await;
+            adc_one_shot = false;
+            if initialized && apply_input_settings(&mut bq25730, &charger).await {
+                applied_charger = Some(charger);
+            }
+        }
+
+        // --- Battery self-test ---
+        while let Ok(command) = battery_test_command_receiver.try_receive() {
+            info!("[BQ25730] Battery test command received: {:?}", command);
+            battery_test.handle_command(command);
+        }
+        let elapsed_s = last_test_tick.elapsed().as_secs();
+        last_test_tick += Duration::from_secs(elapsed_s);
+        let core = &bq76920_measurements.core_measurements;
+        battery_test.tick(
+            elapsed_s.min(u16::MAX as u64) as u16,
+            bms_data_fresh.then(|| BatterySample {
+                pack_voltage_mv: core.total_voltage_mv,
+                current_ma: core.current_ma,
+                min_cell_mv: core
+                    .cell_voltages
+                    .voltages
+                    .iter()
+                    .copied()
+                    .min()
+                    .unwrap_or(0),
+                soc_pct: bq76920_measurements
+                    .gauge
+                    .soc_or_ocv(&core.cell_voltages.voltages),
+            }),
+        );
+        let test_control = battery_test.control();
+
+        // In the slow sampling modes the ADC only converts once per cycle, right before it is read
+        let rates = sampling.rates();
+        if initialized && rates.bq25730_adc_one_shot {
+            adc_one_shot = start_adc(&mut bq25730, true).await;
+            Timer::after(BQ25730_ADC_ONE_SHOT_TIME).await;
+        } else if initialized && adc_one_shot {
+            info!("[BQ25730] Resuming continuous ADC conversion");
+            adc_one_shot = !start_adc(&mut bq25730, false).await;
+        }
 
         let bq25730_adc_measurements_option = match bq25730.read_adc_measurements().await {
-            Ok(measurements) => {
+            Ok(mut measurements) => {
+                measurements.ichg.milliamps =
+                    calibration.ichg.apply_u16(measurements.ichg.milliamps);
+                measurements.idchg.milliamps =
+                    calibration.idchg.apply_u16(measurements.idchg.milliamps);
+                measurements.iin.milliamps = calibration.iin.apply_u16(measurements.iin.milliamps);
                 info!(
                     "[BQ25730 ADC] VBUS:{}mV, VSYS:{}mV, VBAT:{}mV, ICHG:{}mA, IIN:{}mA, PSYS:{}mV, CMPIN:{}mV, IDCHG:{}mA",
                     measurements.vbus.0,
@@ -169,6 +360,14 @@ pub async fn bq25730_task(
             }
         };
 
+        let now = Instant::now();
+        adc_sample.record(bq25730_adc_measurements_option.is_some(), now);
+        if let Some(measurements) = &bq25730_adc_measurements_option {
+            energy_meter.record_input(measurements.vbus.0, measurements.iin.milliamps, now);
+            power_quality.update(measurements.vbus.0, measurements.iin.milliamps, now);
+            last_adc_measurements = bq25730_adc_measurements_option;
+        }
+
         let bq25730_charger_status_option = match bq25730.read_charger_status().await {
             Ok(status) => {
                 info!(
@@ -286,7 +485,16 @@ pub async fn bq25730_task(
             Bq76920SysStatFlags::UV | Bq76920SysStatFlags::SCD | Bq76920SysStatFlags::OCD,
         );
 
-        let final_charge_permission = bq76920_charge_fet_enabled && bq76920_safe_to_charge;
+        let final_charge_permission = bms_data_fresh
+            && bq76920_charge_fet_enabled
+            && bq76920_safe_to_charge
+            && !test_control.inhibit_charging;
+        if !bms_data_fresh {
+            warn!("[BQ25730] BQ76920 data invalid or stale, charging not permitted.");
+        }
+        if test_control.inhibit_charging {
+            info!("[BQ25730] Charging inhibited by battery self-test.");
+        }
 
         // Log key register values for ICHG debugging
         match bq25730.read_charge_current_setting().await {
@@ -330,6 +538,17 @@ pub async fn bq25730_task(
                     .lsb_flags
                     .insert(ChargeOption0Flags::IADPT_GAIN);
 
+                // Learn mode makes the battery supply the system even with the adapter present.
+                if test_control.learn_mode {
+                    charge_option_0
+                        .lsb_flags
+                        .insert(ChargeOption0Flags::EN_LEARN);
+                } else {
+                    charge_option_0
+                        .lsb_flags
+                        .remove(ChargeOption0Flags::EN_LEARN);
+                }
+
                 if final_charge_permission {
                     let chrg_inhibit_was_set =
                         original_lsb_as_flags.contains(ChargeOption0Flags::CHRG_INHIBIT);
@@ -374,7 +593,7 @@ pub async fn bq25730_task(
         if final_charge_permission {
             if let Err(e) = bq25730
                 .set_charge_voltage_setting(ChargeVoltageSetting::from_millivolts(
-                    DEFAULT_CHARGE_VOLTAGE_MV,
+                    charger.charge_voltage_mv,
                 ))
                 .await
             {
@@ -382,7 +601,7 @@ pub async fn bq25730_task(
             }
             if let Err(e) = bq25730
                 .set_charge_current_setting(ChargeCurrentSetting {
-                    milliamps: DEFAULT_CHARGE_CURRENT_MA,
+                    milliamps: charger.charge_current_ma,
                     rsns_bat: bq25730.config().rsns_bat,
                 })
                 .await
@@ -394,7 +613,8 @@ pub async fn bq25730_task(
         }
 
         let bq25730_measurements_payload = crate::data_types::Bq25730Measurements {
-            adc_measurements: bq25730_adc_measurements_option.unwrap_or_else(|| {
+            // The last good reading; `sample` tells whether it is current
+            adc_measurements: last_adc_measurements.unwrap_or_else(|| {
                 // Directly use the specific rsns_bat or rsns_ac from bq25730 instance
                 AdcMeasurements {
                     // Use AdcMeasurements directly as it's in scope
@@ -417,9 +637,13 @@ pub async fn bq25730_task(
                     cmpin: AdcCmpin(0),
                 }
             }),
+            battery_test: battery_test.status(),
+            power_quality: power_quality.status(),
+            applied_charger,
+            sample: adc_sample,
         };
         bq25730_measurements_publisher.publish_immediate(bq25730_measurements_payload);
 
-        Timer::after(Duration::from_secs(1)).await;
+        Timer::after(Duration::from_millis(rates.bq25730_ms as u64)).await;
     }
 }
@@ -0,0 +1,114 @@
+//! Decides whether the BQ76920 ALERT pin can be used.
+//!
+//! ALERT is driven high while any SYS_STAT bit is set, until the bit is cleared. With the coulomb
+//! counter converting continuously, CC_READY is set every 250 ms, so a working pin interrupts at
+//! least that often: fault bits are handled right in the interrupt, and the current is sampled on
+//! CC_READY for coulomb counting.
+//!
+//! If the pin is disconnected or stuck, the task falls back to polling once per second:
+//! - stuck low: no CC_READY for longer than `CC_READY_TIMEOUT`;
+//! - stuck high: `SPURIOUS_LIMIT` interrupts in a row read an empty SYS_STAT or fail to read it.
+//!
+//! While polling, ALERT is tried again every `RETRY_INTERVAL`. The decisions only depend on the
+//! times passed in.
+
+use bq769x0_async_rs::registers::SysStatFlags;
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant};
+
+/// CC_READY comes every 250 ms; this leaves some margin
+const CC_READY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Invalid interrupts in a row after which the pin is taken as stuck high
+const SPURIOUS_LIMIT: u8 = 3;
+
+/// Interval between attempts to use ALERT again while polling
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// SYS_STAT bits that need handling right away (everything but CC_READY)
+pub const FAULT_FLAGS: SysStatFlags = SysStatFlags::all().difference(SysStatFlags::CC_READY);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum AlertMode {
+    /// Waiting for ALERT interrupts; the current is sampled on CC_READY
+    Interrupt,
+    /// ALERT is unusable; SYS_STAT is polled every measurement cycle
+    Polling,
+}
+
+#[derive(Debug)]
+pub struct AlertMonitor {
+    mode: AlertMode,
+    /// `Interrupt`: the last CC_READY; `Polling`: when polling started
+    since: Instant,
+    spurious: u8,
+}
+
+impl AlertMonitor {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            mode: AlertMode::Interrupt,
+            since: now,
+            spurious: 0,
+        }
+    }
+
+    pub fn mode(&self) -> AlertMode {
+        self.mode
+    }
+
+    /// The current is sampled on the CC_READY interrupt, so the measurement cycle must not add
+    /// it to the coulomb count again
+    pub fn samples_current(&self) -> bool {
+        self.mode == AlertMode::Interrupt
+    }
+
+    /// Handles the SYS_STAT read for an ALERT interrupt; `None` means the read failed
+    pub fn on_alert(&mut self, status: Option<SysStatFlags>, now: Instant) {
+        if self.mode != AlertMode::Interrupt {
+            return;
+        }
+        match status {
+            Some(flags) if !flags.is_empty() => {
+                self.spurious = 0;
+                if flags.contains(SysStatFlags::CC_READY) {
+                    self.since = now;
+                }
+            }
+            _ => {
+                self.spurious += 1;
+                if self.spurious >= SPURIOUS_LIMIT {
+                    warn!("BQ76920 ALERT: pin held high without status, falling back to polling");
+                    self.fall_back(now);
+                }
+            }
+        }
+    }
+
+    /// Checks that CC_READY arrives in time, and tries ALERT again once the polling period is over
+    pub fn check(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.since);
+        match self.mode {
+            AlertMode::Interrupt if elapsed > CC_READY_TIMEOUT => {
+                warn!(
+                    "BQ76920 ALERT: no CC_READY for {} ms, falling back to polling",
+                    elapsed.as_millis()
+                );
+                self.fall_back(now);
+            }
+            AlertMode::Polling if elapsed >= RETRY_INTERVAL => {
+                info!("BQ76920 ALERT: retrying interrupt mode");
+                self.mode = AlertMode::Interrupt;
+                self.since = now;
+                self.spurious = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn fall_back(&mut self, now: Instant) {
+        self.mode = AlertMode::Polling;
+        self.since = now;
+        self.spurious = 0;
+    }
+}
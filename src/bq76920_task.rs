@@ -1,33 +1,87 @@
-use bq769x0_async_rs::registers::CellBal1Flags;
+use bq769x0_async_rs::registers::{CellBal1Flags, SysCtrl2Flags, SysStatFlags};
 use defmt::*;
-use embassy_time::{Duration, Timer};
+use embassy_futures::select::{Either, select};
+use embassy_stm32::exti::ExtiInput;
+use embassy_time::{Duration, Instant, Timer};
 
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
-use embassy_stm32::i2c::I2c;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 // Removed WaitResult import as it's no longer needed in this task
 
 // use bq769x0_async_rs::registers::*; // Removed unused import
 // use bq769x0_async_rs::units::ElectricalResistance; // Removed as uom is no longer used by the lib
 use bq769x0_async_rs::ProtectionConfig;
+use bq769x0_async_rs::data_types::{OcdDelay, OvDelay, ScdDelay, UvDelay};
 use bq769x0_async_rs::{
     BatteryConfig, Bq769x0, data_types::NtcParameters, errors::Error as BQ769x0Error,
 }; // Import Error, removed RegisterAccess, Added NtcParameters // Added to resolve E0422
 
 // Import necessary data types
+use crate::battery_gauge::{BatteryGauge, GaugeCheckpoint};
+use crate::battery_profile;
+use crate::bq76920_alert::{AlertMode, AlertMonitor, FAULT_FLAGS};
+use crate::calibration::Bq76920Calibration;
+use crate::config::{self, BalancingConfig, CellProtectionConfig};
+use crate::data_types::SampleInfo;
+use crate::energy_meter::EnergyMeter;
+use crate::event_log::{PowerEvent, PowerEventCode};
+use crate::flash_layout::SharedConfigStore;
+use crate::host_watchdog::HostWatchdog;
+use crate::i2c_supervisor::{I2cDeviceId, I2cSupervisor, SharedI2cDevice};
+use crate::load_control::{
+    AutoRestore, LoadAction, LoadCommand, LoadControlStatus, LoadScheduler, RestorePolicy,
+};
+use crate::overload_protection::{
+    OverloadConfig, OverloadEvent, OverloadProtection, OverloadState,
+};
+use crate::protection::{ProtectionApplyResult, ProtectionStatus};
+use crate::sampling::{SamplingControl, SamplingInputs};
 use crate::shared::{
+    Bq25730MeasurementsSubscriber,
     Bq76920AlertsPublisher,
     Bq76920MeasurementsPublisher, // Added Bq76920MeasurementsPublisher
+    Ina226MeasurementsSubscriber,
+    LoadCommandReceiver,
+    PowerEventSender,
+    SystemConfigSubscriber,
 };
+use crate::task_watchdog::{SupervisedTask, TaskWatchdog};
+
+// Lowest cell voltage of a reading, used by the gauge for the empty point.
+fn min_cell_mv(measurements: &bq769x0_async_rs::data_types::Bq76920Measurements<5>) -> i32 {
+    measurements
+        .cell_voltages
+        .voltages
+        .iter()
+        .copied()
+        .min()
+        .unwrap_or(0)
+}
+
+// Applies a load output action requested by the load scheduler via the DSG FET.
+async fn execute_load_action(
+    bq: &mut Bq769x0<SharedI2cDevice, bq769x0_async_rs::Enabled, 5>,
+    action: LoadAction,
+) {
+    match action {
+        LoadAction::DisableOutput => {
+            info!("Load control: disabling BQ76920 Discharge FET (DSG_ON)...");
+            if let Err(e) = bq.disable_discharging().await {
+                error!("Failed to disable BQ76920 Discharge FET: {:?}", e);
+            }
+        }
+        LoadAction::EnableOutput => {
+            info!("Load control: enabling BQ76920 Discharge FET (DSG_ON)...");
+            if let Err(e) = bq.enable_discharging().await {
+                error!("Failed to enable BQ76920 Discharge FET: {:?}", e);
+            }
+        }
+    }
+}
 
 // New helper function for battery balancing logic
 async fn execute_battery_balancing<'a>(
-    bq: &'a mut Bq769x0<
-        I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, embassy_stm32::mode::Async>>,
-        bq769x0_async_rs::Enabled,
-        5,
-    >,
+    bq: &'a mut Bq769x0<SharedI2cDevice, bq769x0_async_rs::Enabled, 5>,
     latest_core_measurements: &'a Option<bq769x0_async_rs::data_types::Bq76920Measurements<5>>,
+    balance_threshold_mv: i32,
 ) {
     if let Some(measurements) = latest_core_measurements {
         let mut balance_flags = CellBal1Flags::empty();
@@ -41,7 +95,6 @@ async fn execute_battery_balancing<'a>(
 
         if cell_count > 0 {
             let average_voltage = total_voltage / cell_count;
-            let balance_threshold_mv = 50; // Example threshold: 50mV
 
             for (i, voltage) in measurements.cell_voltages.voltages.iter().enumerate() {
                 if *voltage > average_voltage + balance_threshold_mv {
@@ -59,22 +112,150 @@ async fn execute_battery_balancing<'a>(
 
             // Write the calculated balance flags to the CELLBAL1 register
             if !balance_flags.is_empty() {
-                info!("Attempting to set BQ76920 cell balance flags: {:#010b}", balance_flags.bits());
+                info!(
+                    "Attempting to set BQ76920 cell balance flags: {:#010b}",
+                    balance_flags.bits()
+                );
                 if let Err(e) = bq.set_cell_balancing(balance_flags.bits() as u16).await {
-                    error!("Failed to set BQ76920 cell balance flags using set_cell_balancing: {:?}", e);
+                    error!(
+                        "Failed to set BQ76920 cell balance flags using set_cell_balancing: {:?}",
+                        e
+                    );
                 } else {
                     info!("BQ76920 cell balance flags set.");
                 }
             } else {
-                 // If no cells need balancing, ensure balance flags are cleared
-                 if let Err(e) = bq.set_cell_balancing(CellBal1Flags::empty().bits() as u16).await {
-                    error!("Failed to clear BQ76920 cell balance flags using set_cell_balancing: {:?}", e);
+                // If no cells need balancing, ensure balance flags are cleared
+                if let Err(e) = bq
+                    .set_cell_balancing(CellBal1Flags::empty().bits() as u16)
+                    .await
+                {
+                    error!(
+                        "Failed to clear BQ76920 cell balance flags using set_cell_balancing: {:?}",
+                        e
+                    );
                 }
             }
         }
     }
 }
 
+// Builds the driver configuration for the given protection thresholds.
+fn battery_config(protection: &CellProtectionConfig, sense_resistor_m_ohm: u32) -> BatteryConfig {
+    BatteryConfig {
+        overvoltage_trip: protection.overvoltage_mv as u32,
+        undervoltage_trip: protection.undervoltage_mv as u32,
+        ov_delay: match protection.ov_delay_s {
+            1 => OvDelay::Delay1s,
+            2 => OvDelay::Delay2s,
+            4 => OvDelay::Delay4s,
+            _ => OvDelay::Delay8s,
+        },
+        uv_delay: match protection.uv_delay_s {
+            1 => UvDelay::Delay1s,
+            4 => UvDelay::Delay4s,
+            8 => UvDelay::Delay8s,
+            _ => UvDelay::Delay16s,
+        },
+        protection_config: ProtectionConfig {
+            ocd_limit: protection.ocd_limit_ma as i32,
+            ocd_delay: match protection.ocd_delay_ms {
+                8 => OcdDelay::Delay8ms,
+                20 => OcdDelay::Delay20ms,
+                40 => OcdDelay::Delay40ms,
+                80 => OcdDelay::Delay80ms,
+                160 => OcdDelay::Delay160ms,
+                320 => OcdDelay::Delay320ms,
+                640 => OcdDelay::Delay640ms,
+                _ => OcdDelay::Delay1280ms,
+            },
+            scd_limit: protection.scd_limit_ma as i32,
+            scd_delay: match protection.scd_delay_us {
+                70 => ScdDelay::Delay70us,
+                100 => ScdDelay::Delay100us,
+                200 => ScdDelay::Delay200us,
+                _ => ScdDelay::Delay400us,
+            },
+            ..BatteryConfig::default().protection_config // Inherit other protection_config fields
+        },
+        rsense: sense_resistor_m_ohm, // Use mOhms directly as per BatteryConfig field
+        ..Default::default()          // Inherit other BatteryConfig fields
+    }
+}
+
+// Writes the protection thresholds and verifies the safety registers by reading them back.
+async fn apply_protection(
+    bq: &mut Bq769x0<SharedI2cDevice, bq769x0_async_rs::Enabled, 5>,
+    protection: &CellProtectionConfig,
+    sense_resistor_m_ohm: u32,
+) -> bool {
+    match bq
+        .try_apply_config(&battery_config(protection, sense_resistor_m_ohm))
+        .await
+    {
+        Ok(_) => true,
+        Err(BQ769x0Error::ConfigVerificationFailed {
+            register,
+            expected,
+            actual,
+        }) => {
+            // This is a CRITICAL error. Configuration did not write correctly.
+            error!("CRITICAL: BQ76920 CONFIGURATION VERIFICATION FAILED!");
+            error!("  Register: {:?}", register);
+            error!("  Expected: {:#04x}", expected);
+            error!("  Actual:   {:#04x}", actual);
+            false
+        }
+        Err(e) => {
+            // Handles other errors from try_apply_config, such as I2C communication errors.
+            error!(
+                "CRITICAL: Failed to apply BQ76920 configuration due to other error: {:?}",
+                e
+            );
+            false
+        }
+    }
+}
+
+// Applies new protection thresholds at runtime. If verification fails, the last known-good
+// thresholds in `active` are written back. `try_apply_config` rewrites SYS_CTRL2, so the FET
+// state seen before the change (`mos_status`) is restored afterwards.
+async fn change_protection(
+    bq: &mut Bq769x0<SharedI2cDevice, bq769x0_async_rs::Enabled, 5>,
+    active: &mut CellProtectionConfig,
+    requested: CellProtectionConfig,
+    sense_resistor_m_ohm: u32,
+    mos_status: Option<SysCtrl2Flags>,
+) -> ProtectionApplyResult {
+    info!("Applying BQ76920 protection thresholds: {:?}", requested);
+    let result = if apply_protection(bq, &requested, sense_resistor_m_ohm).await {
+        *active = requested;
+        ProtectionApplyResult::Applied
+    } else {
+        warn!("Reverting BQ76920 protection thresholds to {:?}", *active);
+        if apply_protection(bq, active, sense_resistor_m_ohm).await {
+            ProtectionApplyResult::Reverted
+        } else {
+            error!("CRITICAL: Failed to restore BQ76920 protection thresholds.");
+            ProtectionApplyResult::RevertFailed
+        }
+    };
+
+    if let Some(mos_status) = mos_status {
+        if mos_status.contains(SysCtrl2Flags::CHG_ON) {
+            if let Err(e) = bq.enable_charging().await {
+                error!("Failed to re-enable BQ76920 Charge FET: {:?}", e);
+            }
+        }
+        if mos_status.contains(SysCtrl2Flags::DSG_ON) {
+            if let Err(e) = bq.enable_discharging().await {
+                error!("Failed to re-enable BQ76920 Discharge FET: {:?}", e);
+            }
+        }
+    }
+    result
+}
+
 /// Embassy task for managing the BQ76920 battery monitor IC.
 ///
 /// This task is responsible for:
@@ -95,37 +276,107 @@ async fn execute_battery_balancing<'a>(
 ///    - Clearing any set status flags in the BQ76920.
 ///    - Publishing the collected alert information (system status) via `bq76920_alerts_publisher`.
 ///    - Publishing the comprehensive measurement data via `bq76920_measurements_publisher`.
+///      If a read fails, the last good readings are published with `sample.valid` cleared.
+///    - Executing scheduled load shutdown/restore actions received via `load_command_receiver`
+///      by switching the DSG FET.
+///    - Supervising host heartbeats and power-cycling the load when the host stops responding.
+///    - Restoring the load after a low-battery (UV) shutdown once input power (BQ25730 VBUS)
+///      returns, according to the configured `RestorePolicy`.
+///    - Protecting the load output from sustained overload: the INA226 load power is accumulated
+///      on the configured I²t or time-over-threshold curve, the host is warned before the DSG
+///      FET is switched off, and the output is retried automatically (see `overload_protection`).
+///    - Reporting host watchdog trips and overload warnings and trips to the event log via
+///      `power_event_sender`.
+///    - Applying protection threshold changes received via `system_config_subscriber`,
+///      reverting to the last known-good thresholds if verification fails.
+///    - Correcting the measured current and cell voltages with the factory calibration.
+///    - Running the coulomb-counting battery gauge and checkpointing its state to `config_store`,
+///      so that SoC, learned capacity, cycle count and energy counters survive a reset.
+///    - Feeding the battery charge and discharge power (current × pack voltage) to the
+///      `energy_meter`.
+///    - Selecting the sampling mode of all device tasks from the AC, battery and fault state
+///      (see `sampling`). The measurement cycle runs at the interval of the active mode.
+/// 5. Between measurement cycles, waiting on the ALERT pin: faults flagged in SYS_STAT are
+///    published and cleared within milliseconds, and the current is sampled on every 250 ms
+///    CC_READY for the gauge. If the pin is stuck, SYS_STAT is only polled once per cycle
+///    (see `bq76920_alert`).
 ///
 /// # Arguments
 ///
 /// * `i2c_bus`: A shared I2C bus device for communication with the BQ76920.
+/// * `i2c_supervisor`: I2C bus supervisor; after a bus recovery the protection thresholds are
+///   rewritten and verified, and the FETs restored.
 /// * `address`: The I2C address of the BQ76920 chip.
+/// * `alert`: EXTI input connected to the BQ76920 ALERT pin.
 /// * `bq76920_alerts_publisher`: Publisher for sending BQ76920 alert data.
 /// * `bq76920_measurements_publisher`: Publisher for sending BQ76920 measurement data.
 ///   The const generic `5` indicates the number of cells, matching the `N` for `Bq769x0`.
+/// * `load_command_receiver`: Receiver for load control commands forwarded by the USB task.
+/// * `bq25730_measurements_subscriber`: Subscriber for BQ25730 measurements, used for VBUS detection.
+/// * `ina226_measurements_subscriber`: Subscriber for INA226 measurements, used for overload
+///   protection of the load output.
+/// * `power_event_sender`: Sender for events recorded in the power event log.
+/// * `system_config_subscriber`: Subscriber for live configuration updates (balancing,
+///   protection thresholds and restore policy) from the USB task.
+/// * `cell_protection`, `balancing`, `restore_policy`, `overload`: Parameters loaded from the
+///   configuration store.
+/// * `calibration`: Factory calibration of the CC current and cell voltages, updated live from
+///   `system_config_subscriber`.
+/// * `config_store`: Configuration store holding the battery gauge checkpoint, if available.
+/// * `energy_meter`: Energy meter fed with the battery charge and discharge power.
+/// * `sampling`: Shared sampling control. The task selects the sampling mode of all device
+///   tasks from the battery state every cycle and follows its own measurement interval.
+/// * `task_watchdog`: Task watchdog the main loop checks in with every cycle.
 #[embassy_executor::task]
 pub async fn bq76920_task(
-    i2c_bus: I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, embassy_stm32::mode::Async>>,
+    i2c_bus: SharedI2cDevice,
+    i2c_supervisor: &'static I2cSupervisor,
     address: u8,
+    mut alert: ExtiInput<'static>,
     sense_resistor_m_ohm: u32, // Added: Sense resistor value in mOhms
     ntc_params: Option<NtcParameters>, // Added: NTC parameters
     bq76920_alerts_publisher: Bq76920AlertsPublisher<'static>,
     bq76920_measurements_publisher: Bq76920MeasurementsPublisher<'static, 5>,
+    load_command_receiver: LoadCommandReceiver<'static>,
+    mut bq25730_measurements_subscriber: Bq25730MeasurementsSubscriber<'static>,
+    mut ina226_measurements_subscriber: Ina226MeasurementsSubscriber<'static>,
+    power_event_sender: PowerEventSender<'static>,
+    mut system_config_subscriber: SystemConfigSubscriber<'static>,
+    cell_protection: CellProtectionConfig,
+    mut balancing: BalancingConfig,
+    restore_policy: RestorePolicy,
+    overload: OverloadConfig,
+    mut calibration: Bq76920Calibration,
+    config_store: Option<&'static SharedConfigStore>,
+    energy_meter: &'static EnergyMeter,
+    sampling: &'static SamplingControl,
+    task_watchdog: &'static TaskWatchdog,
 ) {
     info!("BQ76920 task started.");
 
+    // The gauge is restored once the first cell voltages are available for the plausibility check.
+    let mut gauge = BatteryGauge::new();
+    let mut gauge_checkpoint: Option<GaugeCheckpoint> = match config_store {
+        Some(store) => config::read_record(&mut *store.lock().await).await,
+        None => None,
+    };
+    let mut last_gauge_sample: Option<Instant> = None;
+    // Last successful reading, republished with `sample.valid` cleared while reads fail
+    let mut last_good_core_measurements: Option<
+        bq769x0_async_rs::data_types::Bq76920Measurements<5>,
+    > = None;
+    let mut sample = SampleInfo::default();
+
     // Initialize the BQ769x0 driver instance with CRC enabled and for 5 cells.
     // sense_resistor_m_ohm and ntc_params are now passed as arguments to this task.
-    let mut bq: Bq769x0<
-        I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, embassy_stm32::mode::Async>>,
-        bq769x0_async_rs::Enabled,
-        5,
-    > = Bq769x0::new(i2c_bus, address, sense_resistor_m_ohm, ntc_params);
+    let mut bq: Bq769x0<SharedI2cDevice, bq769x0_async_rs::Enabled, 5> =
+        Bq769x0::new(i2c_bus, address, sense_resistor_m_ohm, ntc_params);
 
     // Variables to store the latest readings from the sub-module, which are now in physical units.
     #[allow(unused_assignments)]
-    let mut latest_core_measurements: Option<bq769x0_async_rs::data_types::Bq76920Measurements<5>> =
-        None;
+    let mut latest_core_measurements: Option<
+        bq769x0_async_rs::data_types::Bq76920Measurements<5>,
+    > = None;
 
     // --- BQ76920 Initialization Sequence ---
 
@@ -133,72 +384,35 @@ pub async fn bq76920_task(
     // is typically handled by external hardware, e.g., by pulling the TS1 pin high.
     // This task assumes the chip is already in NORMAL mode or has been woken up by such means.
 
-    // Define the battery configuration.
-    // Start with default values and then override specific parameters.
-    // Define the battery configuration using struct update syntax.
-    // `sense_resistor_uohms` is defined earlier in the function.
-    let battery_config = BatteryConfig {
-        overvoltage_trip: 3600u32,  // Set to 3.6V
-        undervoltage_trip: 2500u32, // Set to 2.5V
-        protection_config: ProtectionConfig {
-            ocd_limit: 10_000i32,                         // Set to 10A (10_000 mA)
-            ..BatteryConfig::default().protection_config  // Inherit other protection_config fields
-        },
-        rsense: sense_resistor_m_ohm, // Use mOhms directly as per BatteryConfig field
-        ..Default::default()          // Inherit other BatteryConfig fields
-    };
-
     let mut fets_enabled_after_config = false;
+    let mut reinit_generation = 0;
 
     // Attempt to apply the configuration and, critically, verify that key safety registers
     // have been written correctly by reading them back.
-    match bq.try_apply_config(&battery_config).await {
-        Ok(_) => {
-            info!("BQ76920 configuration applied and verified successfully.");
+    if apply_protection(&mut bq, &cell_protection, sense_resistor_m_ohm).await {
+        info!("BQ76920 configuration applied and verified successfully.");
 
-            // If configuration is verified, proceed to enable the Charge and Discharge FETs.
-            // This allows the BQ76920 to control the battery pack's connection to charger/load.
-            info!("Attempting to enable BQ76920 Charge FET (CHG_ON)...");
-            if let Err(e) = bq.enable_charging().await {
-                error!("Failed to enable BQ76920 Charge FET: {:?}", e);
-            } else {
-                info!("BQ76920 Charge FET (CHG_ON) enabled command sent.");
-            }
-
-            info!("Attempting to enable BQ76920 Discharge FET (DSG_ON)...");
-            if let Err(e) = bq.enable_discharging().await {
-                error!("Failed to enable BQ76920 Discharge FET: {:?}", e);
-            } else {
-                info!("BQ76920 Discharge FET (DSG_ON) enabled command sent.");
-            }
-            fets_enabled_after_config = true; // Mark that FETs were attempted to be enabled.
+        // If configuration is verified, proceed to enable the Charge and Discharge FETs.
+        // This allows the BQ76920 to control the battery pack's connection to charger/load.
+        info!("Attempting to enable BQ76920 Charge FET (CHG_ON)...");
+        if let Err(e) = bq.enable_charging().await {
+            error!("Failed to enable BQ76920 Charge FET: {:?}", e);
+        } else {
+            info!("BQ76920 Charge FET (CHG_ON) enabled command sent.");
         }
-        Err(BQ769x0Error::ConfigVerificationFailed {
-            register,
-            expected,
-            actual,
-        }) => {
-            // This is a CRITICAL error. Configuration did not write correctly.
-            // FETs will NOT be enabled to prevent potentially unsafe operation
-            // with incorrect protection settings.
-            error!("CRITICAL: BQ76920 CONFIGURATION VERIFICATION FAILED!");
-            error!("  Register: {:?}", register);
-            error!("  Expected: {:#04x}", expected);
-            error!("  Actual:   {:#04x}", actual);
-            error!(
-                "FETs will NOT be enabled due to this configuration error. System may be unsafe."
-            );
-            // Depending on system requirements, this might warrant a panic or a safe shutdown procedure.
-        }
-        Err(e) => {
-            // Handles other errors from try_apply_config, such as I2C communication errors.
-            // Also a CRITICAL failure scenario.
-            error!(
-                "CRITICAL: Failed to apply BQ76920 configuration due to other error: {:?}",
-                e
-            );
-            error!("FETs will NOT be enabled. System may be unsafe.");
+
+        info!("Attempting to enable BQ76920 Discharge FET (DSG_ON)...");
+        if let Err(e) = bq.enable_discharging().await {
+            error!("Failed to enable BQ76920 Discharge FET: {:?}", e);
+        } else {
+            info!("BQ76920 Discharge FET (DSG_ON) enabled command sent.");
         }
+        fets_enabled_after_config = true; // Mark that FETs were attempted to be enabled.
+    } else {
+        // FETs will NOT be enabled to prevent potentially unsafe operation
+        // with incorrect protection settings.
+        error!("FETs will NOT be enabled due to this configuration error. System may be unsafe.");
+        // Depending on system requirements, this might warrant a panic or a safe shutdown procedure.
     }
 
     if fets_enabled_after_config {
@@ -213,9 +427,82 @@ pub async fn bq76920_task(
     // as NTC parameters and sense resistor are now part of Bq769x0 driver initialization.
 
     // Main loop for continuous data acquisition and publishing.
-    let mut balance_timer_counter: u32 = 0; // Counter for battery balancing frequency
+    // Balancing runs on the first cycle and then every `balancing.interval_s`
+    let mut last_balance: Option<Instant> = None;
+    let mut load_scheduler = LoadScheduler::new();
+    let mut host_watchdog = HostWatchdog::new();
+    let mut auto_restore = AutoRestore::new(restore_policy);
+    let mut overload_protection = OverloadProtection::new(overload);
+    let mut last_load_sample: Option<Instant> = None;
+    let mut last_load_tick = Instant::now();
+    let mut ac_present = false;
+    let mut protection = ProtectionStatus {
+        active: cell_protection,
+        ..Default::default()
+    };
+    let mut alert_monitor = AlertMonitor::new(Instant::now());
+    // Faults handled on ALERT since the last cycle, reported with the next measurements
+    let mut pending_faults = SysStatFlags::empty();
 
     loop {
+        let cycle_start = Instant::now();
+        task_watchdog.check_in(SupervisedTask::Bq76920);
+
+        let mut requested_protection = None;
+        while let Some(config) = system_config_subscriber.try_next_message_pure() {
+            balancing = config.balancing;
+            calibration = config.calibration.bq76920;
+            overload_protection.set_config(config.overload);
+            if config.restore_policy != auto_restore.policy() {
+                auto_restore.set_policy(config.restore_policy);
+            }
+            requested_protection =
+                (config.cell_protection != protection.active).then_some(config.cell_protection);
+        }
+        if let Some(requested) = requested_protection {
+            protection.apply_count = protection.apply_count.wrapping_add(1);
+            protection.last_result = change_protection(
+                &mut bq,
+                &mut protection.active,
+                requested,
+                sense_resistor_m_ohm,
+                latest_core_measurements.as_ref().map(|m| m.mos_status.0),
+            )
+            .await;
+        }
+
+        // After an I2C bus recovery the thresholds are rewritten and verified, since a write may
+        // have been cut short. The FETs come back in their last known state, or are enabled as
+        // at boot if the initial configuration never got through.
+        if i2c_supervisor.take_reinit(I2cDeviceId::Bq76920, &mut reinit_generation) {
+            info!("Re-initializing BQ76920 after I2C bus failures...");
+            let mos_status = fets_enabled_after_config.then(|| {
+                last_good_core_measurements
+                    .as_ref()
+                    .map_or(SysCtrl2Flags::CHG_ON | SysCtrl2Flags::DSG_ON, |m| {
+                        m.mos_status.0
+                    })
+            });
+            let requested = protection.active;
+            let result = change_protection(
+                &mut bq,
+                &mut protection.active,
+                requested,
+                sense_resistor_m_ohm,
+                mos_status,
+            )
+            .await;
+            if result == ProtectionApplyResult::Applied && !fets_enabled_after_config {
+                if let Err(e) = bq.enable_charging().await {
+                    error!("Failed to enable BQ76920 Charge FET: {:?}", e);
+                }
+                if let Err(e) = bq.enable_discharging().await {
+                    error!("Failed to enable BQ76920 Discharge FET: {:?}", e);
+                }
+                fets_enabled_after_config = true;
+            }
+        }
+
         // This task focuses on reading data from the BQ76920 itself.
         // Communication with other chips (like BQ25730 charger) is handled in their respective tasks.
 
@@ -225,8 +512,64 @@ pub async fn bq76920_task(
 
         // Read all measurements from BQ76920. These are now in physical units.
         match bq.read_all_measurements().await {
-            Ok(core_meas) => {
+            Ok(mut core_meas) => {
+                core_meas.current_ma = calibration.current.apply(core_meas.current_ma);
+                for (voltage, cell) in core_meas
+                    .cell_voltages
+                    .voltages
+                    .iter_mut()
+                    .zip(calibration.cells.iter())
+                {
+                    *voltage = cell.apply(*voltage);
+                }
+                // Faults already handled on ALERT are reported with this reading as well
+                core_meas.system_status.0 |= pending_faults;
+                pending_faults = SysStatFlags::empty();
                 latest_core_measurements = Some(core_meas);
+                last_good_core_measurements = Some(core_meas);
+
+                let now = Instant::now();
+                sample.record(true, now);
+                // With a working ALERT pin the current is sampled on every CC_READY instead
+                if !alert_monitor.samples_current() {
+                    energy_meter.record_battery(
+                        core_meas.current_ma,
+                        core_meas.total_voltage_mv,
+                        now,
+                    );
+                }
+                if gauge.is_valid() {
+                    // With a working ALERT pin the gauge is fed on every CC_READY instead
+                    if !alert_monitor.samples_current() {
+                        let elapsed_ms = last_gauge_sample
+                            .map_or(0, |t| (now - t).as_millis().min(u32::MAX as u64) as u32);
+                        gauge.update(
+                            core_meas.current_ma,
+                            core_meas.total_voltage_mv,
+                            min_cell_mv(&core_meas),
+                            elapsed_ms,
+                        );
+                        last_gauge_sample = Some(now);
+                    }
+                } else {
+                    let ocv_soc_pct =
+                        battery_profile::soc_from_cell_voltages(&core_meas.cell_voltages.voltages);
+                    match gauge_checkpoint.take() {
+                        Some(checkpoint) => {
+                            if gauge.restore(&checkpoint, ocv_soc_pct) {
+                                info!("Battery gauge restored: {:?}", gauge.status());
+                            }
+                        }
+                        None => {
+                            info!(
+                                "No battery gauge checkpoint, starting from {}%",
+                                ocv_soc_pct
+                            );
+                            gauge.init_from_ocv(ocv_soc_pct);
+                        }
+                    }
+                    last_gauge_sample = Some(now);
+                }
 
                 // Log all BQ76920 measurements in a single line
                 info!(
@@ -242,9 +585,19 @@ pub async fn bq76920_task(
                 };
                 bq76920_alerts_publisher.publish_immediate(alerts);
 
+                if core_meas.system_status.0.contains(SysStatFlags::UV) {
+                    // The BQ76920 switches the DSG FET off by itself on undervoltage.
+                    auto_restore.note_low_battery_shutdown();
+                }
+
                 // It's important to clear any set status flags after reading them,
                 // so that new events can be detected. Writing '1' to a bit clears it.
-                let flags_to_clear = core_meas.system_status.0.bits();
+                // CC_READY is left to the ALERT handler, which samples the current on it.
+                let mut flags_to_clear = core_meas.system_status.0;
+                if alert_monitor.samples_current() {
+                    flags_to_clear.remove(SysStatFlags::CC_READY);
+                }
+                let flags_to_clear = flags_to_clear.bits();
                 if flags_to_clear != 0 {
                     if let Err(e_clear) = bq.clear_status_flags(flags_to_clear).await {
                         error!("Failed to clear BQ76920 status flags: {:?}", e_clear);
@@ -256,31 +609,228 @@ pub async fn bq76920_task(
             Err(e) => {
                 error!("Failed to read BQ76920 measurements: {:?}", e);
                 latest_core_measurements = None;
+                sample.record(false, Instant::now());
                 // Optionally publish default/error state for alerts if needed
                 let alerts = crate::data_types::Bq76920Alerts::default();
                 bq76920_alerts_publisher.publish_immediate(alerts);
             }
         }
 
+        // --- Load Control (scheduled shutdown / power-cycle / auto restore) ---
+        while let Some(bq25730_meas) = bq25730_measurements_subscriber.try_next_message_pure() {
+            ac_present =
+                bq25730_meas.adc_measurements.vbus.0 >= crate::ups_state::AC_PRESENT_VBUS_MV;
+        }
+        while let Some(ina226_meas) = ina226_measurements_subscriber.try_next_message_pure() {
+            let (true, Some(sampled_at)) =
+                (ina226_meas.sample.valid, ina226_meas.sample.sampled_at)
+            else {
+                continue;
+            };
+            let elapsed_ms = last_load_sample.map_or(0, |t| {
+                sampled_at
+                    .saturating_duration_since(t)
+                    .as_millis()
+                    .min(u32::MAX as u64) as u32
+            });
+            last_load_sample = Some(sampled_at);
+            let event = match overload_protection.update(ina226_meas.power, elapsed_ms) {
+                Some(OverloadEvent::Warning) => PowerEvent::new(
+                    PowerEventCode::OverloadWarning,
+                    (ina226_meas.power / 1000.0).clamp(0.0, u16::MAX as f32) as u16,
+                ),
+                Some(OverloadEvent::Trip) => {
+                    execute_load_action(&mut bq, LoadAction::DisableOutput).await;
+                    PowerEvent::new(
+                        PowerEventCode::OverloadTrip,
+                        overload_protection.status().trip_count,
+                    )
+                }
+                None => continue,
+            };
+            if power_event_sender.try_send(event).is_err() {
+                warn!(
+                    "Power event queue full, overload {:?} not logged",
+                    event.code
+                );
+            }
+        }
+        while let Ok(command) = load_command_receiver.try_receive() {
+            info!("Load control command received: {:?}", command);
+            match command {
+                LoadCommand::Watchdog(watchdog_command) => {
+                    host_watchdog.handle_command(watchdog_command)
+                }
+                LoadCommand::RestoreNow => {
+                    overload_protection.reset();
+                    load_scheduler.handle_command(command)
+                }
+                other => load_scheduler.handle_command(other),
+            }
+        }
+        let elapsed_s = last_load_tick.elapsed().as_secs();
+        if elapsed_s > 0 {
+            last_load_tick += Duration::from_secs(elapsed_s);
+            let elapsed_s = elapsed_s.min(u16::MAX as u64) as u16;
+            if let Some(power_cycle) = host_watchdog.tick(elapsed_s) {
+                load_scheduler.handle_command(power_cycle);
+                let trip_count = host_watchdog.status().trip_count;
+                let event = PowerEvent::new(PowerEventCode::HostWatchdogTrip, trip_count);
+                if power_event_sender.try_send(event).is_err() {
+                    warn!("Power event queue full, host watchdog trip not logged");
+                }
+            }
+            if let (Some(checkpoint), Some(store)) = (gauge.checkpoint_due(elapsed_s), config_store)
+            {
+                if !config::save_record(&mut *store.lock().await, &checkpoint).await {
+                    warn!("Failed to checkpoint battery gauge state");
+                }
+            }
+            let soc_pct = latest_core_measurements
+                .as_ref()
+                .map_or(0, |m| gauge.status().soc_or_ocv(&m.cell_voltages.voltages));
+            let action = overload_protection.tick(elapsed_s).or_else(|| {
+                load_scheduler
+                    .tick(elapsed_s)
+                    .or_else(|| auto_restore.tick(elapsed_s, ac_present, soc_pct))
+            });
+            // The output stays off while an overload trip is pending
+            let action = action.filter(|action| {
+                *action != LoadAction::EnableOutput || !overload_protection.holds_output_off()
+            });
+            if let Some(action) = action {
+                execute_load_action(&mut bq, action).await;
+                if action == LoadAction::EnableOutput {
+                    auto_restore.clear();
+                }
+            }
+        }
+
         // Construct the BQ76920 measurements payload for the main `AllMeasurements` publisher.
-        // If read_all_measurements failed, use default values.
+        // If read_all_measurements failed, republish the last good reading marked as invalid.
         let bq76920_measurements_payload_for_main_pub = crate::data_types::Bq76920Measurements {
-            core_measurements: latest_core_measurements.unwrap_or_default(),
+            core_measurements: last_good_core_measurements.unwrap_or_default(),
+            load_control: LoadControlStatus {
+                low_battery_shutdown: auto_restore.is_latched(),
+                ..load_scheduler.status()
+            },
+            host_watchdog: host_watchdog.status(),
+            overload: overload_protection.status(),
+            protection,
+            gauge: gauge.status(),
+            sample,
         };
 
         // Publish the collected BQ76920 measurements (which are now wrapped in the main project's type).
         bq76920_measurements_publisher.publish_immediate(bq76920_measurements_payload_for_main_pub);
 
+        // Fault and overload states and the battery state select the sampling rates of all tasks
+        let battery_current_ma = last_good_core_measurements.map_or(0, |m| m.current_ma);
+        let soc_pct = last_good_core_measurements
+            .as_ref()
+            .map_or(0, |m| gauge.status().soc_or_ocv(&m.cell_voltages.voltages));
+        let fault = last_good_core_measurements
+            .is_some_and(|m| m.system_status.0.intersects(FAULT_FLAGS))
+            || overload_protection.status().state != OverloadState::Normal;
+        sampling.update(
+            SamplingInputs {
+                ac_present,
+                battery_current_ma,
+                soc_pct,
+                fault,
+            },
+            Instant::now(),
+        );
+
         // --- Battery Balancing Logic (executed approximately once per hour) ---
-        if balance_timer_counter == 0 || balance_timer_counter >= 3600 { // 3600 seconds = 1 hour
-            info!("Executing hourly battery balancing logic.");
-            execute_battery_balancing(&mut bq, &latest_core_measurements).await;
-            balance_timer_counter = 0; // Reset counter after execution
+        let balance_interval = Duration::from_secs(balancing.interval_s as u64);
+        if last_balance.is_none_or(|t| t.elapsed() >= balance_interval) {
+            info!("Executing periodic battery balancing logic.");
+            execute_battery_balancing(
+                &mut bq,
+                &latest_core_measurements,
+                balancing.threshold_mv as i32,
+            )
+            .await;
+            last_balance = Some(Instant::now());
         }
         // --- End Battery Balancing Logic ---
 
-        // Wait for a defined interval before the next cycle of readings.
-        Timer::after(Duration::from_secs(1)).await;
-        balance_timer_counter += 1;
+        // Until the next cycle, serve the ALERT pin. It stays high while any SYS_STAT bit is set.
+        let next_cycle = cycle_start + Duration::from_millis(sampling.rates().bq76920_ms as u64);
+        loop {
+            let now = Instant::now();
+            alert_monitor.check(now);
+            // A pin that keeps re-asserting must not hold off the measurement cycle
+            if now >= next_cycle {
+                break;
+            }
+            if alert_monitor.mode() == AlertMode::Polling {
+                Timer::at(next_cycle).await;
+                break;
+            }
+            if let Either::Second(()) = select(alert.wait_for_high(), Timer::at(next_cycle)).await {
+                break;
+            }
+
+            let status = match bq.read_status().await {
+                Ok(status) => Some(status),
+                Err(e) => {
+                    error!("BQ76920 ALERT: failed to read SYS_STAT: {:?}", e);
+                    None
+                }
+            };
+            alert_monitor.on_alert(status.map(|s| s.0), Instant::now());
+            let Some(status) = status else {
+                continue;
+            };
+            let flags = status.0;
+
+            let faults = flags.intersection(FAULT_FLAGS);
+            if !faults.is_empty() {
+                warn!("BQ76920 ALERT: {:?}", flags);
+                pending_faults |= faults;
+                bq76920_alerts_publisher.publish_immediate(crate::data_types::Bq76920Alerts {
+                    system_status: status,
+                });
+                if faults.contains(SysStatFlags::UV) {
+                    auto_restore.note_low_battery_shutdown();
+                }
+            }
+
+            if flags.contains(SysStatFlags::CC_READY) && alert_monitor.samples_current() {
+                match bq.read_current().await {
+                    Ok(current_ma) => {
+                        let current_ma = calibration.current.apply(current_ma);
+                        let now = Instant::now();
+                        if let Some(last_good) = last_good_core_measurements.as_ref() {
+                            energy_meter.record_battery(
+                                current_ma,
+                                last_good.total_voltage_mv,
+                                now,
+                            );
+                        }
+                        if let (true, Some(last_good)) =
+                            (gauge.is_valid(), last_good_core_measurements.as_ref())
+                        {
+                            let elapsed_ms = last_gauge_sample
+                                .map_or(0, |t| (now - t).as_millis().min(u32::MAX as u64) as u32);
+                            gauge.update(
+                                current_ma,
+                                last_good.total_voltage_mv,
+                                min_cell_mv(last_good),
+                                elapsed_ms,
+                            );
+                            last_gauge_sample = Some(now);
+                        }
+                    }
+                    Err(e) => error!("BQ76920 ALERT: failed to read CC: {:?}", e),
+                }
+            }
+
+            if let Err(e) = bq.clear_status_flags(flags.bits()).await {
+                error!("BQ76920 ALERT: failed to clear status flags: {:?}", e);
+            }
+        }
     }
 }
@@ -0,0 +1,368 @@
+//! Factory calibration: gain and offset corrections for each measurement channel.
+//!
+//! The host collects one or two calibration points over USB against a reference meter, and
+//! `CalibrationSession` derives a new gain and offset from them on top of the current
+//! calibration. The calibration is part of `SystemConfig`, kept in the config store,
+//! and applied by the sampling tasks in the measurement path:
+//! calibrated = raw × (1 + gain_trim / 100000) + offset.
+
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::config::{ConfigRecord, save_record};
+use crate::config_store::ConfigStore;
+
+/// `gain_trim` units that make a gain of 1.0 (10 ppm per unit)
+const GAIN_TRIM_SCALE: i64 = 100_000;
+
+/// Allowed gain trim, ±10%
+pub const MAX_GAIN_TRIM: i16 = 10_000;
+
+/// Allowed offset trim (mV or mA)
+pub const MAX_OFFSET: i16 = 2_000;
+
+/// Minimum distance between the two measured points; closer points give too large a gain
+/// error (mV or mA)
+pub const MIN_POINT_SPAN: i32 = 100;
+
+/// Calibration coefficients of one channel
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub struct ChannelCalibration {
+    /// Gain trim in 10 ppm units
+    pub gain_trim: i16,
+    /// Offset trim (mV or mA)
+    pub offset: i16,
+}
+
+impl ChannelCalibration {
+    pub fn apply(&self, raw: i32) -> i32 {
+        let scaled = raw as i64 * (GAIN_TRIM_SCALE + self.gain_trim as i64) / GAIN_TRIM_SCALE;
+        (scaled + self.offset as i64).clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+
+    pub fn apply_f32(&self, raw: f32) -> f32 {
+        raw * (1.0 + self.gain_trim as f32 / GAIN_TRIM_SCALE as f32) + self.offset as f32
+    }
+
+    /// For channels whose result is a `u16` (BQ25730 ADC current)
+    pub fn apply_u16(&self, raw: u16) -> u16 {
+        self.apply(raw as i32).clamp(0, u16::MAX as i32) as u16
+    }
+
+    fn is_valid(&self) -> bool {
+        self.gain_trim.unsigned_abs() <= MAX_GAIN_TRIM as u16
+            && self.offset.unsigned_abs() <= MAX_OFFSET as u16
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.gain_trim.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.offset.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        Self {
+            gain_trim: i16::from_le_bytes([buf[0], buf[1]]),
+            offset: i16::from_le_bytes([buf[2], buf[3]]),
+        }
+    }
+}
+
+/// Measurement channels that can be calibrated
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum CalibrationChannel {
+    Ina226Voltage = 0x00,
+    Ina226Current = 0x01,
+    /// BQ76920 coulomb counter current
+    Bq76920Current = 0x02,
+    Bq76920Cell1 = 0x03,
+    Bq76920Cell2 = 0x04,
+    Bq76920Cell3 = 0x05,
+    Bq76920Cell4 = 0x06,
+    Bq76920Cell5 = 0x07,
+    Bq25730Ichg = 0x08,
+    Bq25730Idchg = 0x09,
+    Bq25730Iin = 0x0A,
+}
+
+impl CalibrationChannel {
+    pub const ALL: [Self; 11] = [
+        Self::Ina226Voltage,
+        Self::Ina226Current,
+        Self::Bq76920Current,
+        Self::Bq76920Cell1,
+        Self::Bq76920Cell2,
+        Self::Bq76920Cell3,
+        Self::Bq76920Cell4,
+        Self::Bq76920Cell5,
+        Self::Bq25730Ichg,
+        Self::Bq25730Idchg,
+        Self::Bq25730Iin,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+}
+
+/// Calibration of the INA226 load voltage and current
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct Ina226Calibration {
+    pub voltage: ChannelCalibration,
+    pub current: ChannelCalibration,
+}
+
+impl Ina226Calibration {
+    /// Calibrates the raw measurements (mV, mA, mW); the power is recomputed from the calibrated
+    /// voltage and current
+    pub fn apply(&self, voltage_mv: f32, current_ma: f32, power_mw: f32) -> (f32, f32, f32) {
+        if *self == Self::default() {
+            return (voltage_mv, current_ma, power_mw);
+        }
+        let voltage_mv = self.voltage.apply_f32(voltage_mv);
+        let current_ma = self.current.apply_f32(current_ma);
+        let power_mw = voltage_mv * current_ma / 1000.0;
+        (
+            voltage_mv,
+            current_ma,
+            if power_mw < 0.0 { -power_mw } else { power_mw },
+        )
+    }
+}
+
+impl ConfigRecord for Ina226Calibration {
+    const KEY: u8 = 0x07;
+    const LEN: usize = 8;
+
+    fn encode(&self, buf: &mut [u8]) {
+        self.voltage.encode(&mut buf[0..4]);
+        self.current.encode(&mut buf[4..8]);
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            voltage: ChannelCalibration::decode(&buf[0..4]),
+            current: ChannelCalibration::decode(&buf[4..8]),
+        })
+    }
+}
+
+/// Calibration of the BQ76920 coulomb counter current and the cell voltages
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct Bq76920Calibration {
+    pub current: ChannelCalibration,
+    pub cells: [ChannelCalibration; 5],
+}
+
+impl ConfigRecord for Bq76920Calibration {
+    const KEY: u8 = 0x08;
+    const LEN: usize = 24;
+
+    fn encode(&self, buf: &mut [u8]) {
+        self.current.encode(&mut buf[0..4]);
+        for (i, cell) in self.cells.iter().enumerate() {
+            cell.encode(&mut buf[4 + i * 4..8 + i * 4]);
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            current: ChannelCalibration::decode(&buf[0..4]),
+            cells: core::array::from_fn(|i| ChannelCalibration::decode(&buf[4 + i * 4..8 + i * 4])),
+        })
+    }
+}
+
+/// Calibration of the BQ25730 ADC current
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct Bq25730Calibration {
+    pub ichg: ChannelCalibration,
+    pub idchg: ChannelCalibration,
+    pub iin: ChannelCalibration,
+}
+
+impl ConfigRecord for Bq25730Calibration {
+    const KEY: u8 = 0x09;
+    const LEN: usize = 12;
+
+    fn encode(&self, buf: &mut [u8]) {
+        self.ichg.encode(&mut buf[0..4]);
+        self.idchg.encode(&mut buf[4..8]);
+        self.iin.encode(&mut buf[8..12]);
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            ichg: ChannelCalibration::decode(&buf[0..4]),
+            idchg: ChannelCalibration::decode(&buf[4..8]),
+            iin: ChannelCalibration::decode(&buf[8..12]),
+        })
+    }
+}
+
+/// Calibration of every channel
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct CalibrationData {
+    pub ina226: Ina226Calibration,
+    pub bq76920: Bq76920Calibration,
+    pub bq25730: Bq25730Calibration,
+}
+
+impl CalibrationData {
+    pub fn channel(&self, channel: CalibrationChannel) -> ChannelCalibration {
+        let mut data = *self;
+        *data.channel_mut(channel)
+    }
+
+    pub fn channel_mut(&mut self, channel: CalibrationChannel) -> &mut ChannelCalibration {
+        use CalibrationChannel::*;
+        match channel {
+            Ina226Voltage => &mut self.ina226.voltage,
+            Ina226Current => &mut self.ina226.current,
+            Bq76920Current => &mut self.bq76920.current,
+            Bq76920Cell1 => &mut self.bq76920.cells[0],
+            Bq76920Cell2 => &mut self.bq76920.cells[1],
+            Bq76920Cell3 => &mut self.bq76920.cells[2],
+            Bq76920Cell4 => &mut self.bq76920.cells[3],
+            Bq76920Cell5 => &mut self.bq76920.cells[4],
+            Bq25730Ichg => &mut self.bq25730.ichg,
+            Bq25730Idchg => &mut self.bq25730.idchg,
+            Bq25730Iin => &mut self.bq25730.iin,
+        }
+    }
+
+    /// All coefficients are within their allowed range
+    pub fn is_valid(&self) -> bool {
+        CalibrationChannel::ALL
+            .iter()
+            .all(|&channel| self.channel(channel).is_valid())
+    }
+
+    /// Writes the group holding `channel` to the config store; returns `false` on failure
+    pub async fn persist<F: NorFlash>(
+        &self,
+        store: &mut ConfigStore<F>,
+        channel: CalibrationChannel,
+    ) -> bool {
+        use CalibrationChannel::*;
+        match channel {
+            Ina226Voltage | Ina226Current => save_record(store, &self.ina226).await,
+            Bq76920Current | Bq76920Cell1 | Bq76920Cell2 | Bq76920Cell3 | Bq76920Cell4
+            | Bq76920Cell5 => save_record(store, &self.bq76920).await,
+            Bq25730Ichg | Bq25730Idchg | Bq25730Iin => save_record(store, &self.bq25730).await,
+        }
+    }
+}
+
+/// Why the calibration coefficients could not be derived
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum CalibrationError {
+    /// No calibration point has been collected for the channel
+    NoPoints,
+    /// The two measured points are too close together
+    PointsTooClose,
+    /// The coefficients are out of range, usually a wrong reference value or wiring
+    OutOfRange,
+}
+
+/// One calibration point: the value measured with the current calibration and the reference
+/// meter reading
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+struct CalibrationPoint {
+    measured: i32,
+    reference: i32,
+}
+
+/// Calibration points collected during one calibration, held by `usb_task`
+#[derive(Debug, Default)]
+pub struct CalibrationSession {
+    channel: Option<CalibrationChannel>,
+    points: [CalibrationPoint; 2],
+    count: u8,
+}
+
+impl CalibrationSession {
+    /// Number of points collected for `channel`
+    pub fn point_count(&self, channel: CalibrationChannel) -> u8 {
+        if self.channel == Some(channel) {
+            self.count
+        } else {
+            0
+        }
+    }
+
+    /// Records a calibration point. Switching channel drops the earlier points; with two points
+    /// already, the older one is replaced.
+    pub fn capture(&mut self, channel: CalibrationChannel, measured: i32, reference: i32) -> u8 {
+        if self.channel != Some(channel) {
+            self.channel = Some(channel);
+            self.count = 0;
+        }
+        if self.count == 2 {
+            self.points[0] = self.points[1];
+            self.count = 1;
+        }
+        self.points[self.count as usize] = CalibrationPoint {
+            measured,
+            reference,
+        };
+        self.count += 1;
+        self.count
+    }
+
+    pub fn clear(&mut self) {
+        self.channel = None;
+        self.count = 0;
+    }
+
+    /// Derives new coefficients on top of `current`, the calibration the points were taken with.
+    ///
+    /// One point only corrects the offset; two points correct both gain and offset.
+    pub fn solve(
+        &self,
+        channel: CalibrationChannel,
+        current: ChannelCalibration,
+    ) -> Result<ChannelCalibration, CalibrationError> {
+        let (first, second) = match self.point_count(channel) {
+            0 => return Err(CalibrationError::NoPoints),
+            1 => (self.points[0], None),
+            _ => (self.points[0], Some(self.points[1])),
+        };
+
+        // Fit another linear correction reference = measured × k + b with k = num / den and
+        // combine it with the current coefficients: gain' = gain × k, offset' = offset × k + b
+        let (num, den) = match second {
+            Some(second) => {
+                let span = second.measured as i64 - first.measured as i64;
+                if span.unsigned_abs() < MIN_POINT_SPAN as u64 {
+                    return Err(CalibrationError::PointsTooClose);
+                }
+                (second.reference as i64 - first.reference as i64, span)
+            }
+            None => (1, 1),
+        };
+        let b_times_den = first.reference as i64 * den - first.measured as i64 * num;
+        let gain_trim =
+            div_round((GAIN_TRIM_SCALE + current.gain_trim as i64) * num, den) - GAIN_TRIM_SCALE;
+        let offset = div_round(current.offset as i64 * num + b_times_den, den);
+
+        if gain_trim.unsigned_abs() > MAX_GAIN_TRIM as u64
+            || offset.unsigned_abs() > MAX_OFFSET as u64
+        {
+            return Err(CalibrationError::OutOfRange);
+        }
+        Ok(ChannelCalibration {
+            gain_trim: gain_trim as i16,
+            offset: offset as i16,
+        })
+    }
+}
+
+/// Integer division rounding to nearest
+fn div_round(num: i64, den: i64) -> i64 {
+    let (num, den) = if den < 0 { (-num, -den) } else { (num, den) };
+    if num >= 0 {
+        (num + den / 2) / den
+    } else {
+        (num - den / 2) / den
+    }
+}
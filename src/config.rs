@@ -0,0 +1,995 @@
+//! Persistent runtime parameters.
+//!
+//! Each parameter group is one key in the config store, its value a fixed-length little-endian
+//! encoding. New fields may only be appended, together with a bump of `CONFIG_SCHEMA_VERSION`
+//! and a conversion from the older version in `migrate`.
+//! Groups that fail to load (missing key, wrong length, failed migration) use the built-in
+//! defaults.
+
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+
+use crate::battery_profile;
+use crate::calibration::CalibrationData;
+use crate::config_store::{ConfigStore, MAX_VALUE_LEN};
+use crate::load_control::{RestoreMode, RestorePolicy};
+use crate::load_monitor::{
+    AVERAGING_COUNTS, CONVERSION_TIMES_US, MAX_OVER_CURRENT_LIMIT_MA, MAX_OVER_POWER_LIMIT_W,
+};
+use crate::overload_protection::{self, OverloadConfig, RATED_POWER_W};
+use crate::power_quality::PowerQualityConfig;
+use crate::protection::{OCD_DELAYS_MS, OV_DELAYS_S, SCD_DELAYS_US, UV_DELAYS_S};
+use crate::ups_status::AC_PRESENT_VBUS_MV;
+
+/// Config schema version of this firmware
+pub const CONFIG_SCHEMA_VERSION: u16 = 2;
+
+/// A persistent group of parameters
+pub trait ConfigRecord: Sized {
+    /// Key in the config store
+    const KEY: u8;
+    /// Encoded length
+    const LEN: usize;
+
+    fn encode(&self, buf: &mut [u8]);
+    fn decode(buf: &[u8]) -> Option<Self>;
+}
+
+/// BQ25730 charge parameters
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct ChargerConfig {
+    pub charge_voltage_mv: u16,
+    pub charge_current_ma: u16,
+    /// Input current limit (IIN_HOST)
+    pub input_current_limit_ma: u16,
+    pub vsys_min_mv: u16,
+}
+
+impl Default for ChargerConfig {
+    fn default() -> Self {
+        Self {
+            charge_voltage_mv: 18000,
+            charge_current_ma: 512,
+            input_current_limit_ma: 3200,
+            vsys_min_mv: 12000,
+        }
+    }
+}
+
+impl ConfigRecord for ChargerConfig {
+    const KEY: u8 = 0x01;
+    const LEN: usize = 8;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.charge_voltage_mv.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.charge_current_ma.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.input_current_limit_ma.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.vsys_min_mv.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            charge_voltage_mv: u16_at(buf, 0),
+            charge_current_ma: u16_at(buf, 2),
+            input_current_limit_ma: u16_at(buf, 4),
+            vsys_min_mv: u16_at(buf, 6),
+        })
+    }
+}
+
+/// BQ76920 passive balancing parameters
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct BalancingConfig {
+    /// How far a cell must be above the average to start balancing (mV)
+    pub threshold_mv: u16,
+    /// Interval between balancing decisions (s)
+    pub interval_s: u16,
+}
+
+impl Default for BalancingConfig {
+    fn default() -> Self {
+        Self {
+            threshold_mv: 50,
+            interval_s: 3600,
+        }
+    }
+}
+
+impl ConfigRecord for BalancingConfig {
+    const KEY: u8 = 0x02;
+    const LEN: usize = 4;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.threshold_mv.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.interval_s.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            threshold_mv: u16_at(buf, 0),
+            interval_s: u16_at(buf, 2),
+        })
+    }
+}
+
+/// BQ76920 hardware protection thresholds
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct CellProtectionConfig {
+    /// Cell overvoltage threshold (mV)
+    pub overvoltage_mv: u16,
+    /// Cell undervoltage threshold (mV)
+    pub undervoltage_mv: u16,
+    /// Discharge overcurrent threshold (mA)
+    pub ocd_limit_ma: u16,
+    /// Discharge short-circuit threshold (mA)
+    pub scd_limit_ma: u16,
+    /// Short-circuit delay (µs), one of `protection::SCD_DELAYS_US`
+    pub scd_delay_us: u16,
+    /// Overcurrent delay (ms), one of `protection::OCD_DELAYS_MS`
+    pub ocd_delay_ms: u16,
+    /// Overvoltage delay (s), one of `protection::OV_DELAYS_S`
+    pub ov_delay_s: u8,
+    /// Undervoltage delay (s), one of `protection::UV_DELAYS_S`
+    pub uv_delay_s: u8,
+}
+
+impl Default for CellProtectionConfig {
+    fn default() -> Self {
+        Self {
+            overvoltage_mv: 3600,
+            undervoltage_mv: 2500,
+            ocd_limit_ma: 10_000,
+            scd_limit_ma: 20_000,
+            scd_delay_us: 200,
+            ocd_delay_ms: 8,
+            ov_delay_s: 1,
+            uv_delay_s: 4,
+        }
+    }
+}
+
+impl CellProtectionConfig {
+    /// Checks that the thresholds are within what the cell chemistry and the BQ76920 allow
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        use battery_profile::*;
+        let in_range = (CELL_OV_TRIP_MIN_MV..=CELL_OV_TRIP_MAX_MV).contains(&self.overvoltage_mv)
+            && (CELL_UV_TRIP_MIN_MV..=CELL_UV_TRIP_MAX_MV).contains(&self.undervoltage_mv)
+            && (MIN_OCD_LIMIT_MA..=MAX_OCD_LIMIT_MA).contains(&self.ocd_limit_ma)
+            && (MIN_SCD_LIMIT_MA..=MAX_SCD_LIMIT_MA).contains(&self.scd_limit_ma)
+            && SCD_DELAYS_US.contains(&self.scd_delay_us)
+            && OCD_DELAYS_MS.contains(&self.ocd_delay_ms)
+            && OV_DELAYS_S.contains(&self.ov_delay_s)
+            && UV_DELAYS_S.contains(&self.uv_delay_s);
+        if !in_range {
+            return Err(ConfigError::OutOfRange);
+        }
+        if self.scd_limit_ma <= self.ocd_limit_ma {
+            return Err(ConfigError::Inconsistent);
+        }
+        Ok(())
+    }
+}
+
+impl ConfigRecord for CellProtectionConfig {
+    const KEY: u8 = 0x03;
+    const LEN: usize = 14;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.overvoltage_mv.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.undervoltage_mv.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.ocd_limit_ma.to_le_bytes());
+        // schema v2
+        buf[6..8].copy_from_slice(&self.scd_limit_ma.to_le_bytes());
+        buf[8..10].copy_from_slice(&self.scd_delay_us.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.ocd_delay_ms.to_le_bytes());
+        buf[12] = self.ov_delay_s;
+        buf[13] = self.uv_delay_s;
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            overvoltage_mv: u16_at(buf, 0),
+            undervoltage_mv: u16_at(buf, 2),
+            ocd_limit_ma: u16_at(buf, 4),
+            scd_limit_ma: u16_at(buf, 6),
+            scd_delay_us: u16_at(buf, 8),
+            ocd_delay_ms: u16_at(buf, 10),
+            ov_delay_s: buf[12],
+            uv_delay_s: buf[13],
+        })
+    }
+}
+
+/// Low-battery thresholds, matching the HID Power Device fields of the same name
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct LowBatteryConfig {
+    /// RemainingCapacityLimit (%)
+    pub remaining_capacity_limit_pct: u8,
+    /// WarningCapacityLimit (%)
+    pub warning_capacity_limit_pct: u8,
+    /// On battery, ShutdownImminent is reported below this remaining capacity (%)
+    pub shutdown_imminent_pct: u8,
+}
+
+impl Default for LowBatteryConfig {
+    fn default() -> Self {
+        Self {
+            remaining_capacity_limit_pct: battery_profile::REMAINING_CAPACITY_LIMIT_PCT,
+            warning_capacity_limit_pct: battery_profile::WARNING_CAPACITY_LIMIT_PCT,
+            shutdown_imminent_pct: battery_profile::SHUTDOWN_IMMINENT_PCT,
+        }
+    }
+}
+
+impl ConfigRecord for LowBatteryConfig {
+    const KEY: u8 = 0x04;
+    const LEN: usize = 3;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.remaining_capacity_limit_pct;
+        buf[1] = self.warning_capacity_limit_pct;
+        buf[2] = self.shutdown_imminent_pct;
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            remaining_capacity_limit_pct: buf[0],
+            warning_capacity_limit_pct: buf[1],
+            shutdown_imminent_pct: buf[2],
+        })
+    }
+}
+
+impl ConfigRecord for RestorePolicy {
+    const KEY: u8 = 0x05;
+    const LEN: usize = 4;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.mode as u8;
+        buf[1] = self.min_soc_pct;
+        buf[2..4].copy_from_slice(&self.delay_s.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::LEN {
+            return None;
+        }
+        let mode = match buf[0] {
+            0 => RestoreMode::Manual,
+            1 => RestoreMode::Auto,
+            _ => return None,
+        };
+        Some(Self {
+            mode,
+            min_soc_pct: buf[1],
+            delay_s: u16_at(buf, 2),
+        })
+    }
+}
+
+/// USB interface parameters
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct UsbConfig {
+    /// Minimum interval between status pushes (ms); 0 pushes on every measurement update
+    pub push_interval_ms: u16,
+}
+
+impl ConfigRecord for UsbConfig {
+    const KEY: u8 = 0x06;
+    const LEN: usize = 2;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.push_interval_ms.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            push_interval_ms: u16_at(buf, 0),
+        })
+    }
+}
+
+/// INA226 load monitor parameters
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct LoadMonitorConfig {
+    /// Averaging count, one of `load_monitor::AVERAGING_COUNTS`
+    pub averaging: u16,
+    /// Bus voltage conversion time (µs), one of `load_monitor::CONVERSION_TIMES_US`
+    pub bus_conversion_us: u16,
+    /// Shunt voltage conversion time (µs), same values as above
+    pub shunt_conversion_us: u16,
+    /// Load overcurrent alert threshold (mA), 0 to disable
+    pub over_current_limit_ma: u16,
+    /// Load overpower alert threshold (W), 0 to disable. The INA226 has a single alert, so it
+    /// cannot be enabled together with the overcurrent alert
+    pub over_power_limit_w: u16,
+}
+
+impl Default for LoadMonitorConfig {
+    fn default() -> Self {
+        Self {
+            averaging: 16,
+            bus_conversion_us: 1100,
+            shunt_conversion_us: 1100,
+            over_current_limit_ma: 7000,
+            over_power_limit_w: 0,
+        }
+    }
+}
+
+impl LoadMonitorConfig {
+    /// Checks that the conversion settings are supported by the INA226 and the alert thresholds
+    /// are within range
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let in_range = AVERAGING_COUNTS.contains(&self.averaging)
+            && CONVERSION_TIMES_US.contains(&self.bus_conversion_us)
+            && CONVERSION_TIMES_US.contains(&self.shunt_conversion_us)
+            && self.over_current_limit_ma <= MAX_OVER_CURRENT_LIMIT_MA
+            && self.over_power_limit_w <= MAX_OVER_POWER_LIMIT_W;
+        if !in_range {
+            return Err(ConfigError::OutOfRange);
+        }
+        if self.over_current_limit_ma != 0 && self.over_power_limit_w != 0 {
+            return Err(ConfigError::Inconsistent);
+        }
+        Ok(())
+    }
+}
+
+impl ConfigRecord for LoadMonitorConfig {
+    const KEY: u8 = 0x0A;
+    const LEN: usize = 10;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.averaging.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.bus_conversion_us.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.shunt_conversion_us.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.over_current_limit_ma.to_le_bytes());
+        buf[8..10].copy_from_slice(&self.over_power_limit_w.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            averaging: u16_at(buf, 0),
+            bus_conversion_us: u16_at(buf, 2),
+            shunt_conversion_us: u16_at(buf, 4),
+            over_current_limit_ma: u16_at(buf, 6),
+            over_power_limit_w: u16_at(buf, 8),
+        })
+    }
+}
+
+impl ConfigRecord for OverloadConfig {
+    const KEY: u8 = 0x0B;
+    const LEN: usize = 9;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.curve as u8;
+        buf[1..3].copy_from_slice(&self.rated_power_w.to_le_bytes());
+        buf[3..5].copy_from_slice(&self.trip_time_s.to_le_bytes());
+        buf[5] = self.warn_pct;
+        buf[6..8].copy_from_slice(&self.retry_delay_s.to_le_bytes());
+        buf[8] = self.max_retries;
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::LEN {
+            return None;
+        }
+        Some(Self {
+            curve: overload_protection::OverloadCurve::from_u8(buf[0])?,
+            rated_power_w: u16_at(buf, 1),
+            trip_time_s: u16_at(buf, 3),
+            warn_pct: buf[5],
+            retry_delay_s: u16_at(buf, 6),
+            max_retries: buf[8],
+        })
+    }
+}
+
+impl ConfigRecord for PowerQualityConfig {
+    const KEY: u8 = 0x0C;
+    const LEN: usize = 4;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.sag_threshold_mv.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.window_s.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        (buf.len() == Self::LEN).then(|| Self {
+            sag_threshold_mv: u16_at(buf, 0),
+            window_s: u16_at(buf, 2),
+        })
+    }
+}
+
+fn u16_at(buf: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([buf[i], buf[i + 1]])
+}
+
+/// Converts a value written by version `from_version` to the current format in place.
+///
+/// Returns `false` if it cannot be converted; the group then uses its defaults.
+fn migrate(from_version: u16, key: u8, value: &mut Vec<u8, MAX_VALUE_LEN>) -> bool {
+    match (from_version, key) {
+        (CONFIG_SCHEMA_VERSION, _) => true,
+        // v1 -> v2: CellProtectionConfig gained the short-circuit threshold and the protection
+        // delays; fill in their defaults
+        (1, CellProtectionConfig::KEY) => {
+            let mut tail = [0u8; CellProtectionConfig::LEN];
+            CellProtectionConfig::default().encode(&mut tail);
+            value.len() == 6 && value.extend_from_slice(&tail[6..]).is_ok()
+        }
+        (1, _) => true,
+        _ => {
+            defmt::warn!(
+                "Config: no migration for key {} from schema v{}",
+                key,
+                from_version
+            );
+            false
+        }
+    }
+}
+
+/// All persistent parameters
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct SystemConfig {
+    pub charger: ChargerConfig,
+    pub balancing: BalancingConfig,
+    pub cell_protection: CellProtectionConfig,
+    pub low_battery: LowBatteryConfig,
+    pub restore_policy: RestorePolicy,
+    pub usb: UsbConfig,
+    pub calibration: CalibrationData,
+    pub load_monitor: LoadMonitorConfig,
+    pub overload: OverloadConfig,
+    pub power_quality: PowerQualityConfig,
+}
+
+impl SystemConfig {
+    /// Loads every parameter from the config store, migrating older data to the current schema
+    pub async fn load<F: NorFlash>(store: &mut ConfigStore<F>) -> Self {
+        let stored_version = store.schema_version();
+        if stored_version > CONFIG_SCHEMA_VERSION {
+            // Written by newer firmware whose format we cannot rely on; leave the store alone
+            // until the newer firmware is back
+            defmt::warn!(
+                "Config: stored schema v{} is newer than v{}, using defaults",
+                stored_version,
+                CONFIG_SCHEMA_VERSION
+            );
+            return Self::default();
+        }
+
+        let mut config = Self {
+            charger: load_record(store, stored_version).await,
+            balancing: load_record(store, stored_version).await,
+            cell_protection: load_record(store, stored_version).await,
+            low_battery: load_record(store, stored_version).await,
+            restore_policy: load_record(store, stored_version).await,
+            usb: load_record(store, stored_version).await,
+            calibration: CalibrationData {
+                ina226: load_record(store, stored_version).await,
+                bq76920: load_record(store, stored_version).await,
+                bq25730: load_record(store, stored_version).await,
+            },
+            load_monitor: load_record(store, stored_version).await,
+            overload: load_record(store, stored_version).await,
+            power_quality: load_record(store, stored_version).await,
+        };
+        config.sanitize();
+
+        if stored_version < CONFIG_SCHEMA_VERSION {
+            defmt::info!(
+                "Config: migrating from schema v{} to v{}",
+                stored_version,
+                CONFIG_SCHEMA_VERSION
+            );
+            config.save(store).await;
+            if let Err(e) = store.set_schema_version(CONFIG_SCHEMA_VERSION).await {
+                defmt::error!(
+                    "Config: failed to update schema version: {:?}",
+                    defmt::Debug2Format(&e)
+                );
+            }
+        }
+
+        defmt::info!("Config: loaded {:?}", config);
+        config
+    }
+
+    /// Writes every parameter to the config store (unchanged groups do not touch the flash).
+    /// Returns `false` if a group failed to write
+    pub async fn save<F: NorFlash>(&self, store: &mut ConfigStore<F>) -> bool {
+        let mut ok = save_record(store, &self.charger).await;
+        ok &= save_record(store, &self.balancing).await;
+        ok &= save_record(store, &self.cell_protection).await;
+        ok &= save_record(store, &self.low_battery).await;
+        ok &= save_record(store, &self.restore_policy).await;
+        ok &= save_record(store, &self.usb).await;
+        ok &= save_record(store, &self.calibration.ina226).await;
+        ok &= save_record(store, &self.calibration.bq76920).await;
+        ok &= save_record(store, &self.calibration.bq25730).await;
+        ok &= save_record(store, &self.load_monitor).await;
+        ok &= save_record(store, &self.overload).await;
+        ok &= save_record(store, &self.power_quality).await;
+        ok
+    }
+
+    /// Resets groups that are out of their safe range to the defaults (for example written by
+    /// older firmware with wider limits)
+    fn sanitize(&mut self) {
+        let mut candidate = *self;
+        if let Err(e) = candidate.set_cell_protection(self.cell_protection) {
+            defmt::warn!(
+                "Config: stored cell protection {:?} rejected ({:?}), using defaults",
+                self.cell_protection,
+                e
+            );
+            self.cell_protection = CellProtectionConfig::default();
+        }
+        if !self.calibration.is_valid() {
+            defmt::warn!(
+                "Config: stored calibration {:?} out of range, using defaults",
+                self.calibration
+            );
+            self.calibration = CalibrationData::default();
+        }
+        for param in ConfigParam::ALL {
+            let mut candidate = *self;
+            if let Err(e) = candidate.set(param, self.get(param)) {
+                defmt::warn!(
+                    "Config: stored {:?} = {} rejected ({:?}), using defaults for its group",
+                    param,
+                    self.get(param),
+                    e
+                );
+                self.reset_group(param);
+            }
+        }
+    }
+
+    fn reset_group(&mut self, param: ConfigParam) {
+        use ConfigParam::*;
+        match param {
+            ChargeVoltageMv | ChargeCurrentMa | InputCurrentLimitMa | VsysMinMv => {
+                self.charger = ChargerConfig::default()
+            }
+            BalanceThresholdMv | BalanceIntervalS => self.balancing = BalancingConfig::default(),
+            RemainingCapacityLimitPct | WarningCapacityLimitPct | ShutdownImminentPct => {
+                self.low_battery = LowBatteryConfig::default()
+            }
+            PushIntervalMs => self.usb = UsbConfig::default(),
+            LoadAveraging
+            | LoadBusConversionUs
+            | LoadShuntConversionUs
+            | LoadOverCurrentLimitMa
+            | LoadOverPowerLimitW => self.load_monitor = LoadMonitorConfig::default(),
+            OverloadCurve | OverloadRatedPowerW | OverloadTripTimeS | OverloadWarnPct
+            | OverloadRetryDelayS | OverloadMaxRetries => self.overload = OverloadConfig::default(),
+            SagThresholdMv | PowerQualityWindowS => {
+                self.power_quality = PowerQualityConfig::default()
+            }
+        }
+    }
+
+    /// Reads the current value of one parameter
+    pub fn get(&self, param: ConfigParam) -> u32 {
+        use ConfigParam::*;
+        match param {
+            ChargeVoltageMv => self.charger.charge_voltage_mv as u32,
+            ChargeCurrentMa => self.charger.charge_current_ma as u32,
+            InputCurrentLimitMa => self.charger.input_current_limit_ma as u32,
+            VsysMinMv => self.charger.vsys_min_mv as u32,
+            BalanceThresholdMv => self.balancing.threshold_mv as u32,
+            BalanceIntervalS => self.balancing.interval_s as u32,
+            RemainingCapacityLimitPct => self.low_battery.remaining_capacity_limit_pct as u32,
+            WarningCapacityLimitPct => self.low_battery.warning_capacity_limit_pct as u32,
+            ShutdownImminentPct => self.low_battery.shutdown_imminent_pct as u32,
+            PushIntervalMs => self.usb.push_interval_ms as u32,
+            LoadAveraging => self.load_monitor.averaging as u32,
+            LoadBusConversionUs => self.load_monitor.bus_conversion_us as u32,
+            LoadShuntConversionUs => self.load_monitor.shunt_conversion_us as u32,
+            LoadOverCurrentLimitMa => self.load_monitor.over_current_limit_ma as u32,
+            LoadOverPowerLimitW => self.load_monitor.over_power_limit_w as u32,
+            OverloadCurve => self.overload.curve as u32,
+            OverloadRatedPowerW => self.overload.rated_power_w as u32,
+            OverloadTripTimeS => self.overload.trip_time_s as u32,
+            OverloadWarnPct => self.overload.warn_pct as u32,
+            OverloadRetryDelayS => self.overload.retry_delay_s as u32,
+            OverloadMaxRetries => self.overload.max_retries as u32,
+            SagThresholdMv => self.power_quality.sag_threshold_mv as u32,
+            PowerQualityWindowS => self.power_quality.window_s as u32,
+        }
+    }
+
+    /// Validates and changes one parameter. The config is unchanged if it is rejected.
+    pub fn set(&mut self, param: ConfigParam, value: u32) -> Result<(), ConfigError> {
+        use ConfigParam::*;
+        let (min, max) = param.bounds();
+        if value < min || value > max {
+            return Err(ConfigError::OutOfRange);
+        }
+
+        let mut updated = *self;
+        match param {
+            ChargeVoltageMv => updated.charger.charge_voltage_mv = value as u16,
+            ChargeCurrentMa => updated.charger.charge_current_ma = value as u16,
+            InputCurrentLimitMa => updated.charger.input_current_limit_ma = value as u16,
+            VsysMinMv => updated.charger.vsys_min_mv = value as u16,
+            BalanceThresholdMv => updated.balancing.threshold_mv = value as u16,
+            BalanceIntervalS => updated.balancing.interval_s = value as u16,
+            RemainingCapacityLimitPct => {
+                updated.low_battery.remaining_capacity_limit_pct = value as u8
+            }
+            WarningCapacityLimitPct => updated.low_battery.warning_capacity_limit_pct = value as u8,
+            ShutdownImminentPct => updated.low_battery.shutdown_imminent_pct = value as u8,
+            PushIntervalMs => updated.usb.push_interval_ms = value as u16,
+            LoadAveraging => updated.load_monitor.averaging = value as u16,
+            LoadBusConversionUs => updated.load_monitor.bus_conversion_us = value as u16,
+            LoadShuntConversionUs => updated.load_monitor.shunt_conversion_us = value as u16,
+            LoadOverCurrentLimitMa => updated.load_monitor.over_current_limit_ma = value as u16,
+            LoadOverPowerLimitW => updated.load_monitor.over_power_limit_w = value as u16,
+            OverloadCurve => {
+                updated.overload.curve = overload_protection::OverloadCurve::from_u8(value as u8)
+                    .ok_or(ConfigError::OutOfRange)?
+            }
+            OverloadRatedPowerW => updated.overload.rated_power_w = value as u16,
+            OverloadTripTimeS => updated.overload.trip_time_s = value as u16,
+            OverloadWarnPct => updated.overload.warn_pct = value as u8,
+            OverloadRetryDelayS => updated.overload.retry_delay_s = value as u16,
+            OverloadMaxRetries => updated.overload.max_retries = value as u8,
+            SagThresholdMv => updated.power_quality.sag_threshold_mv = value as u16,
+            PowerQualityWindowS => updated.power_quality.window_s = value as u16,
+        }
+
+        // Constraints between parameters
+        let low = &updated.low_battery;
+        if low.shutdown_imminent_pct > low.remaining_capacity_limit_pct
+            || low.remaining_capacity_limit_pct > low.warning_capacity_limit_pct
+        {
+            return Err(ConfigError::Inconsistent);
+        }
+        updated.check_charge_voltage()?;
+        updated.load_monitor.validate()?;
+
+        *self = updated;
+        Ok(())
+    }
+
+    /// Validates and replaces the BQ76920 protection thresholds. The config is unchanged if they
+    /// are rejected.
+    pub fn set_cell_protection(
+        &mut self,
+        protection: CellProtectionConfig,
+    ) -> Result<(), ConfigError> {
+        protection.validate()?;
+        let updated = Self {
+            cell_protection: protection,
+            ..*self
+        };
+        updated.check_charge_voltage()?;
+        *self = updated;
+        Ok(())
+    }
+
+    // The charge voltage must be above the minimum system voltage and must not trip the BQ76920
+    // overvoltage protection
+    fn check_charge_voltage(&self) -> Result<(), ConfigError> {
+        let charge_voltage_mv = self.charger.charge_voltage_mv as u32;
+        let ov_trip_mv =
+            self.cell_protection.overvoltage_mv as u32 * battery_profile::CELL_COUNT as u32;
+        if self.charger.vsys_min_mv as u32 >= charge_voltage_mv || charge_voltage_mv > ov_trip_mv {
+            return Err(ConfigError::Inconsistent);
+        }
+        Ok(())
+    }
+
+    /// Writes the group holding `param` to the config store; returns `false` on failure
+    pub async fn persist<F: NorFlash>(
+        &self,
+        store: &mut ConfigStore<F>,
+        param: ConfigParam,
+    ) -> bool {
+        use ConfigParam::*;
+        match param {
+            ChargeVoltageMv | ChargeCurrentMa | InputCurrentLimitMa | VsysMinMv => {
+                save_record(store, &self.charger).await
+            }
+            BalanceThresholdMv | BalanceIntervalS => save_record(store, &self.balancing).await,
+            RemainingCapacityLimitPct | WarningCapacityLimitPct | ShutdownImminentPct => {
+                save_record(store, &self.low_battery).await
+            }
+            PushIntervalMs => save_record(store, &self.usb).await,
+            LoadAveraging
+            | LoadBusConversionUs
+            | LoadShuntConversionUs
+            | LoadOverCurrentLimitMa
+            | LoadOverPowerLimitW => save_record(store, &self.load_monitor).await,
+            OverloadCurve | OverloadRatedPowerW | OverloadTripTimeS | OverloadWarnPct
+            | OverloadRetryDelayS | OverloadMaxRetries => save_record(store, &self.overload).await,
+            SagThresholdMv | PowerQualityWindowS => save_record(store, &self.power_quality).await,
+        }
+    }
+}
+
+/// Runtime parameters readable and writable over USB; the value is the parameter number in the
+/// USB protocol
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum ConfigParam {
+    ChargeVoltageMv = 0x01,
+    ChargeCurrentMa = 0x02,
+    InputCurrentLimitMa = 0x03,
+    VsysMinMv = 0x04,
+    BalanceThresholdMv = 0x10,
+    BalanceIntervalS = 0x11,
+    RemainingCapacityLimitPct = 0x20,
+    WarningCapacityLimitPct = 0x21,
+    ShutdownImminentPct = 0x22,
+    PushIntervalMs = 0x30,
+    LoadAveraging = 0x40,
+    LoadBusConversionUs = 0x41,
+    LoadShuntConversionUs = 0x42,
+    LoadOverCurrentLimitMa = 0x43,
+    LoadOverPowerLimitW = 0x44,
+    OverloadCurve = 0x50,
+    OverloadRatedPowerW = 0x51,
+    OverloadTripTimeS = 0x52,
+    OverloadWarnPct = 0x53,
+    OverloadRetryDelayS = 0x54,
+    OverloadMaxRetries = 0x55,
+    SagThresholdMv = 0x60,
+    PowerQualityWindowS = 0x61,
+}
+
+impl ConfigParam {
+    pub const ALL: [ConfigParam; 23] = [
+        Self::ChargeVoltageMv,
+        Self::ChargeCurrentMa,
+        Self::InputCurrentLimitMa,
+        Self::VsysMinMv,
+        Self::BalanceThresholdMv,
+        Self::BalanceIntervalS,
+        Self::RemainingCapacityLimitPct,
+        Self::WarningCapacityLimitPct,
+        Self::ShutdownImminentPct,
+        Self::PushIntervalMs,
+        Self::LoadAveraging,
+        Self::LoadBusConversionUs,
+        Self::LoadShuntConversionUs,
+        Self::LoadOverCurrentLimitMa,
+        Self::LoadOverPowerLimitW,
+        Self::OverloadCurve,
+        Self::OverloadRatedPowerW,
+        Self::OverloadTripTimeS,
+        Self::OverloadWarnPct,
+        Self::OverloadRetryDelayS,
+        Self::OverloadMaxRetries,
+        Self::SagThresholdMv,
+        Self::PowerQualityWindowS,
+    ];
+
+    pub fn from_u8(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|param| *param as u8 == id)
+    }
+
+    /// Hard safety range set by the battery parameters (inclusive)
+    pub fn bounds(&self) -> (u32, u32) {
+        use battery_profile::*;
+        let cells = CELL_COUNT as u32;
+        match self {
+            Self::ChargeVoltageMv => (
+                cells * CELL_CHARGE_VOLTAGE_MIN_MV as u32,
+                cells * CELL_CHARGE_VOLTAGE_MAX_MV as u32,
+            ),
+            Self::ChargeCurrentMa => (MIN_CHARGE_CURRENT_MA as u32, MAX_CHARGE_CURRENT_MA as u32),
+            Self::InputCurrentLimitMa => (
+                MIN_INPUT_CURRENT_LIMIT_MA as u32,
+                MAX_INPUT_CURRENT_LIMIT_MA as u32,
+            ),
+            Self::VsysMinMv => (MIN_VSYS_MIN_MV as u32, MAX_VSYS_MIN_MV as u32),
+            Self::BalanceThresholdMv => (10, 500),
+            Self::BalanceIntervalS => (10, u16::MAX as u32),
+            Self::RemainingCapacityLimitPct => (5, 50),
+            Self::WarningCapacityLimitPct => (5, 80),
+            Self::ShutdownImminentPct => (0, 50),
+            Self::PushIntervalMs => (0, 60_000),
+            Self::LoadAveraging => (1, 1024),
+            Self::LoadBusConversionUs | Self::LoadShuntConversionUs => (140, 8244),
+            Self::LoadOverCurrentLimitMa => (0, MAX_OVER_CURRENT_LIMIT_MA as u32),
+            Self::LoadOverPowerLimitW => (0, MAX_OVER_POWER_LIMIT_W as u32),
+            // 0 = off, 1 = I2t, 2 = accumulated time above the rated power
+            Self::OverloadCurve => (0, 2),
+            Self::OverloadRatedPowerW => (10, RATED_POWER_W as u32),
+            Self::OverloadTripTimeS => (1, 600),
+            Self::OverloadWarnPct => (10, 100),
+            Self::OverloadRetryDelayS => (5, 3600),
+            Self::OverloadMaxRetries => (0, 10),
+            // Must be above the voltage that means the adapter is present
+            Self::SagThresholdMv => (AC_PRESENT_VBUS_MV as u32 + 500, 24_000),
+            Self::PowerQualityWindowS => (60, 24 * 60 * 60 / 2),
+        }
+    }
+}
+
+/// Why a parameter change was rejected
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum ConfigError {
+    /// Outside `ConfigParam::bounds`
+    OutOfRange,
+    /// Contradicts another parameter (for example a shutdown threshold above the low-battery
+    /// threshold, a charge voltage above the overvoltage threshold, or both load overcurrent and
+    /// overpower alerts enabled)
+    Inconsistent,
+}
+
+async fn load_record<T, F>(store: &mut ConfigStore<F>, stored_version: u16) -> T
+where
+    T: ConfigRecord + Default,
+    F: NorFlash,
+{
+    read_record_from(store, stored_version)
+        .await
+        .unwrap_or_default()
+}
+
+/// Reads a record that is not part of `SystemConfig` (runtime state, for example). Returns `None`
+/// if the key is missing or cannot be decoded
+pub async fn read_record<T: ConfigRecord, F: NorFlash>(store: &mut ConfigStore<F>) -> Option<T> {
+    let stored_version = store.schema_version();
+    read_record_from(store, stored_version).await
+}
+
+async fn read_record_from<T, F>(store: &mut ConfigStore<F>, stored_version: u16) -> Option<T>
+where
+    T: ConfigRecord,
+    F: NorFlash,
+{
+    let mut buf = [0u8; MAX_VALUE_LEN];
+    let len = match store.read(T::KEY, &mut buf).await {
+        Ok(Some(len)) => len,
+        Ok(None) => return None,
+        Err(e) => {
+            defmt::error!(
+                "Config: failed to read key {}: {:?}",
+                T::KEY,
+                defmt::Debug2Format(&e)
+            );
+            return None;
+        }
+    };
+
+    let mut value: Vec<u8, MAX_VALUE_LEN> = Vec::new();
+    let _ = value.extend_from_slice(&buf[..len]);
+    if !migrate(stored_version, T::KEY, &mut value) {
+        return None;
+    }
+    let record = T::decode(&value);
+    if record.is_none() {
+        defmt::warn!(
+            "Config: invalid value for key {} ({} bytes), ignoring",
+            T::KEY,
+            len
+        );
+    }
+    record
+}
+
+/// Writes one parameter group to the config store; returns `false` on failure
+pub async fn save_record<T: ConfigRecord, F: NorFlash>(
+    store: &mut ConfigStore<F>,
+    record: &T,
+) -> bool {
+    let mut buf = [0u8; MAX_VALUE_LEN];
+    record.encode(&mut buf[..T::LEN]);
+    match store.write(T::KEY, &buf[..T::LEN]).await {
+        Ok(()) => true,
+        Err(e) => {
+            defmt::error!(
+                "Config: failed to write key {}: {:?}",
+                T::KEY,
+                defmt::Debug2Format(&e)
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::test_support::RamFlash;
+
+    /// CellProtectionConfig of v1: only the overvoltage, undervoltage and overcurrent thresholds
+    fn v1_cell_protection(overvoltage_mv: u16, undervoltage_mv: u16, ocd_limit_ma: u16) -> [u8; 6] {
+        let mut buf = [0u8; 6];
+        buf[0..2].copy_from_slice(&overvoltage_mv.to_le_bytes());
+        buf[2..4].copy_from_slice(&undervoltage_mv.to_le_bytes());
+        buf[4..6].copy_from_slice(&ocd_limit_ma.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn migrates_v1_cell_protection_to_current_schema() {
+        let mut flash = RamFlash::new(4);
+        let charger = ChargerConfig {
+            charge_current_ma: 1024,
+            ..Default::default()
+        };
+        let mut store = block_on(ConfigStore::new(&mut flash, 1)).unwrap();
+        let v1 = v1_cell_protection(3700, 2600, 8000);
+        block_on(store.write(CellProtectionConfig::KEY, &v1)).unwrap();
+        assert!(block_on(save_record(&mut store, &charger)));
+
+        let mut store = block_on(ConfigStore::new(&mut flash, CONFIG_SCHEMA_VERSION)).unwrap();
+        assert_eq!(store.schema_version(), 1);
+        let config = block_on(SystemConfig::load(&mut store));
+        let expected = CellProtectionConfig {
+            overvoltage_mv: 3700,
+            undervoltage_mv: 2600,
+            ocd_limit_ma: 8000,
+            ..Default::default()
+        };
+        assert_eq!(config.cell_protection, expected);
+        // Groups whose format did not change are kept as they were
+        assert_eq!(config.charger, charger);
+        assert_eq!(store.schema_version(), CONFIG_SCHEMA_VERSION);
+
+        // Written back in the new format after migrating, so the next load skips the migration
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        let len = block_on(store.read(CellProtectionConfig::KEY, &mut buf)).unwrap();
+        assert_eq!(len, Some(CellProtectionConfig::LEN));
+
+        let mut store = block_on(ConfigStore::new(&mut flash, CONFIG_SCHEMA_VERSION)).unwrap();
+        assert_eq!(store.schema_version(), CONFIG_SCHEMA_VERSION);
+        assert_eq!(block_on(SystemConfig::load(&mut store)), config);
+    }
+
+    #[test]
+    fn malformed_v1_record_falls_back_to_defaults() {
+        let mut flash = RamFlash::new(4);
+        let mut store = block_on(ConfigStore::new(&mut flash, 1)).unwrap();
+        let v1 = v1_cell_protection(3700, 2600, 8000);
+        block_on(store.write(CellProtectionConfig::KEY, &v1[..4])).unwrap();
+
+        let mut store = block_on(ConfigStore::new(&mut flash, CONFIG_SCHEMA_VERSION)).unwrap();
+        let config = block_on(SystemConfig::load(&mut store));
+        assert_eq!(config.cell_protection, CellProtectionConfig::default());
+        assert_eq!(store.schema_version(), CONFIG_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn newer_schema_is_left_untouched() {
+        let mut flash = RamFlash::new(4);
+        let newer = CONFIG_SCHEMA_VERSION + 1;
+        let mut store = block_on(ConfigStore::new(&mut flash, newer)).unwrap();
+        block_on(store.write(ChargerConfig::KEY, &[0xAA; 10])).unwrap();
+        let before = flash.data.clone();
+
+        let mut store = block_on(ConfigStore::new(&mut flash, CONFIG_SCHEMA_VERSION)).unwrap();
+        assert_eq!(
+            block_on(SystemConfig::load(&mut store)),
+            SystemConfig::default()
+        );
+        assert_eq!(store.schema_version(), newer);
+        assert_eq!(flash.data, before);
+    }
+
+    #[test]
+    fn migrated_value_rejected_by_validation_uses_defaults() {
+        // Overvoltage threshold below the default charge voltage (3600 mV per cell)
+        let mut flash = RamFlash::new(4);
+        let mut store = block_on(ConfigStore::new(&mut flash, 1)).unwrap();
+        let v1 = v1_cell_protection(3550, 2600, 8000);
+        block_on(store.write(CellProtectionConfig::KEY, &v1)).unwrap();
+
+        let mut store = block_on(ConfigStore::new(&mut flash, CONFIG_SCHEMA_VERSION)).unwrap();
+        let config = block_on(SystemConfig::load(&mut store));
+        assert_eq!(config.cell_protection, CellProtectionConfig::default());
+    }
+}
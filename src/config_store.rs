@@ -0,0 +1,629 @@
+//! Key-value config store in the on-chip flash.
+//!
+//! The partition consists of several pages, exactly one of which is active at a time. Records are
+//! only appended to the active page and the latest record for a key wins.
+//! When the active page is full, the latest value of each key is moved to the next page, which
+//! then gets a new page header; the pages take turns to spread the wear.
+//! The page header is written last, so the old page stays valid if power fails during the move.
+//!
+//! Page header (16 bytes): magic u32 | seq u32 | schema_version u16 | reserved u16 | CRC-32
+//! Record header (8 bytes): key u8 | len u8 | reserved u16 | CRC-32(key, len, value), followed by
+//! the value padded to the write size
+
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+
+use crate::crc::crc32;
+
+/// Maximum length of a value (bytes)
+pub const MAX_VALUE_LEN: usize = 32;
+
+const PAGE_MAGIC: u32 = 0x4746_4355; // "UCFG"
+const PAGE_HEADER_SIZE: u32 = 16;
+const RECORD_HEADER_SIZE: u32 = 8;
+/// Erased flash content, also used as the "no record here" key
+const BLANK_KEY: u8 = 0xFF;
+
+/// Config store error
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum ConfigStoreError<E> {
+    Flash(E),
+    /// The value is longer than `MAX_VALUE_LEN`
+    ValueTooLarge,
+    /// Key 0xFF is reserved
+    InvalidKey,
+    /// The latest values of all keys together do not fit in one page
+    Full,
+}
+
+impl<E> From<E> for ConfigStoreError<E> {
+    fn from(e: E) -> Self {
+        Self::Flash(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct PageHeader {
+    seq: u32,
+    schema_version: u16,
+}
+
+impl PageHeader {
+    fn encode(&self) -> [u8; PAGE_HEADER_SIZE as usize] {
+        let mut buf = [0xFFu8; PAGE_HEADER_SIZE as usize];
+        buf[0..4].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        buf[8..10].copy_from_slice(&self.schema_version.to_le_bytes());
+        let crc = crc32(&buf[..12]);
+        buf[12..16].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; PAGE_HEADER_SIZE as usize]) -> Option<Self> {
+        let magic = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let crc = u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]);
+        if magic != PAGE_MAGIC || crc != crc32(&buf[..12]) {
+            return None;
+        }
+        Some(Self {
+            seq: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            schema_version: u16::from_le_bytes([buf[8], buf[9]]),
+        })
+    }
+}
+
+/// A record in the active page
+#[derive(Debug, Copy, Clone)]
+struct RecordRef {
+    key: u8,
+    /// Offset of the value in the partition
+    value_offset: u32,
+    len: u8,
+    valid: bool,
+}
+
+/// Flash key-value store, generic over `NorFlash` so the host tests can use a RAM flash
+pub struct ConfigStore<F: NorFlash> {
+    flash: F,
+    page_count: u32,
+    active_page: u32,
+    /// Offset of the next record in the active page
+    write_offset: u32,
+    seq: u32,
+    schema_version: u16,
+}
+
+impl<F: NorFlash> ConfigStore<F> {
+    const PAGE_SIZE: u32 = F::ERASE_SIZE as u32;
+
+    /// Opens the store, taking the valid page with the highest sequence number as the active page.
+    /// An empty partition gets its first page formatted with `schema_version`
+    pub async fn new(
+        mut flash: F,
+        schema_version: u16,
+    ) -> Result<Self, ConfigStoreError<F::Error>> {
+        let page_count = flash.capacity() as u32 / Self::PAGE_SIZE;
+        let mut active: Option<(u32, PageHeader)> = None;
+        for page in 0..page_count {
+            let mut buf = [0u8; PAGE_HEADER_SIZE as usize];
+            flash.read(page * Self::PAGE_SIZE, &mut buf).await?;
+            let Some(header) = PageHeader::decode(&buf) else {
+                continue;
+            };
+            if active.is_none_or(|(_, a)| header.seq.wrapping_sub(a.seq) as i32 > 0) {
+                active = Some((page, header));
+            }
+        }
+
+        let mut store = Self {
+            flash,
+            page_count,
+            active_page: 0,
+            write_offset: PAGE_HEADER_SIZE,
+            seq: 0,
+            schema_version,
+        };
+
+        match active {
+            Some((page, header)) => {
+                store.active_page = page;
+                store.seq = header.seq;
+                store.schema_version = header.schema_version;
+                store.write_offset = store.find_end_of_records().await?;
+                defmt::info!(
+                    "ConfigStore: active page {} (seq {}, schema v{}), {} bytes used",
+                    page,
+                    header.seq,
+                    header.schema_version,
+                    store.write_offset
+                );
+            }
+            None => {
+                defmt::warn!("ConfigStore: no valid page found, formatting");
+                store.flash.erase(0, Self::PAGE_SIZE).await?;
+                store.write_page_header(0, 1, schema_version).await?;
+                store.seq = 1;
+            }
+        }
+        Ok(store)
+    }
+
+    /// Schema version of the stored data, i.e. of the firmware that wrote it
+    pub fn schema_version(&self) -> u16 {
+        self.schema_version
+    }
+
+    async fn write_page_header(
+        &mut self,
+        page: u32,
+        seq: u32,
+        schema_version: u16,
+    ) -> Result<(), F::Error> {
+        let header = PageHeader {
+            seq,
+            schema_version,
+        };
+        self.flash
+            .write(page * Self::PAGE_SIZE, &header.encode())
+            .await
+    }
+
+    fn padded_len(len: u8) -> u32 {
+        let align = F::WRITE_SIZE as u32;
+        (len as u32).div_ceil(align) * align
+    }
+
+    /// Reads the record at `offset` in `page`; returns `None` at blank space or a corrupt header
+    async fn record_at(&mut self, page: u32, offset: u32) -> Result<Option<RecordRef>, F::Error> {
+        if offset + RECORD_HEADER_SIZE > Self::PAGE_SIZE {
+            return Ok(None);
+        }
+        let base = page * Self::PAGE_SIZE + offset;
+        let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+        self.flash.read(base, &mut header).await?;
+        let (key, len) = (header[0], header[1]);
+        if key == BLANK_KEY
+            || len as usize > MAX_VALUE_LEN
+            || offset + RECORD_HEADER_SIZE + Self::padded_len(len) > Self::PAGE_SIZE
+        {
+            return Ok(None);
+        }
+
+        let mut value = [0u8; MAX_VALUE_LEN];
+        let value_offset = base + RECORD_HEADER_SIZE;
+        self.flash
+            .read(value_offset, &mut value[..len as usize])
+            .await?;
+        let stored_crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        Ok(Some(RecordRef {
+            key,
+            value_offset,
+            len,
+            valid: stored_crc == Self::record_crc(key, &value[..len as usize]),
+        }))
+    }
+
+    fn record_crc(key: u8, value: &[u8]) -> u32 {
+        let mut buf = [0u8; MAX_VALUE_LEN + 2];
+        buf[0] = key;
+        buf[1] = value.len() as u8;
+        buf[2..2 + value.len()].copy_from_slice(value);
+        crc32(&buf[..2 + value.len()])
+    }
+
+    async fn find_end_of_records(&mut self) -> Result<u32, F::Error> {
+        let mut offset = PAGE_HEADER_SIZE;
+        while let Some(record) = self.record_at(self.active_page, offset).await? {
+            if !record.valid {
+                defmt::warn!(
+                    "ConfigStore: corrupt record for key {} at offset {}",
+                    record.key,
+                    offset
+                );
+            }
+            offset += RECORD_HEADER_SIZE + Self::padded_len(record.len);
+        }
+        if offset < Self::PAGE_SIZE {
+            // The space after a corrupt header cannot be used safely; the next write moves the page
+            let mut probe = [0u8; 1];
+            self.flash
+                .read(self.active_page * Self::PAGE_SIZE + offset, &mut probe)
+                .await?;
+            if probe[0] != BLANK_KEY {
+                defmt::warn!("ConfigStore: unreadable record at offset {}", offset);
+                return Ok(Self::PAGE_SIZE);
+            }
+        }
+        Ok(offset)
+    }
+
+    /// Finds the latest valid record for `key` in `page`
+    async fn find_latest(&mut self, page: u32, key: u8) -> Result<Option<RecordRef>, F::Error> {
+        let mut latest = None;
+        let mut offset = PAGE_HEADER_SIZE;
+        while let Some(record) = self.record_at(page, offset).await? {
+            if record.key == key && record.valid {
+                latest = Some(record);
+            }
+            offset += RECORD_HEADER_SIZE + Self::padded_len(record.len);
+        }
+        Ok(latest)
+    }
+
+    /// Reads the current value of `key` into `buf` and returns its length, or `None` if the key
+    /// does not exist
+    pub async fn read(
+        &mut self,
+        key: u8,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, ConfigStoreError<F::Error>> {
+        let Some(record) = self.find_latest(self.active_page, key).await? else {
+            return Ok(None);
+        };
+        let len = record.len as usize;
+        if len > buf.len() {
+            return Err(ConfigStoreError::ValueTooLarge);
+        }
+        self.flash
+            .read(record.value_offset, &mut buf[..len])
+            .await?;
+        Ok(Some(len))
+    }
+
+    async fn append_record(
+        &mut self,
+        page: u32,
+        offset: u32,
+        key: u8,
+        value: &[u8],
+    ) -> Result<u32, F::Error> {
+        let base = page * Self::PAGE_SIZE + offset;
+        let mut buf = [0xFFu8; RECORD_HEADER_SIZE as usize + MAX_VALUE_LEN];
+        buf[0] = key;
+        buf[1] = value.len() as u8;
+        buf[4..8].copy_from_slice(&Self::record_crc(key, value).to_le_bytes());
+        buf[8..8 + value.len()].copy_from_slice(value);
+        let total = RECORD_HEADER_SIZE + Self::padded_len(value.len() as u8);
+        self.flash.write(base, &buf[..total as usize]).await?;
+        Ok(offset + total)
+    }
+
+    /// Writes the value of `key`. Nothing is written to the flash if the value is unchanged.
+    pub async fn write(&mut self, key: u8, value: &[u8]) -> Result<(), ConfigStoreError<F::Error>> {
+        if key == BLANK_KEY {
+            return Err(ConfigStoreError::InvalidKey);
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(ConfigStoreError::ValueTooLarge);
+        }
+
+        let mut current = [0u8; MAX_VALUE_LEN];
+        let current_len = self.read(key, &mut current).await?;
+        if current_len.is_some_and(|len| &current[..len] == value) {
+            return Ok(());
+        }
+
+        let needed = RECORD_HEADER_SIZE + Self::padded_len(value.len() as u8);
+        if self.write_offset + needed > Self::PAGE_SIZE {
+            self.compact(Some((key, value)), self.schema_version).await
+        } else {
+            self.write_offset = self
+                .append_record(self.active_page, self.write_offset, key, value)
+                .await?;
+            Ok(())
+        }
+    }
+
+    /// Moves the latest value of every key to the next page and writes the new page header with
+    /// `schema_version`.
+    ///
+    /// Called after a migration so the stored version matches the running firmware.
+    pub async fn set_schema_version(
+        &mut self,
+        schema_version: u16,
+    ) -> Result<(), ConfigStoreError<F::Error>> {
+        if schema_version == self.schema_version {
+            return Ok(());
+        }
+        self.compact(None, schema_version).await
+    }
+
+    fn activate(&mut self, page: u32, seq: u32, schema_version: u16, write_offset: u32) {
+        self.active_page = page;
+        self.seq = seq;
+        self.schema_version = schema_version;
+        self.write_offset = write_offset;
+    }
+
+    async fn compact(
+        &mut self,
+        pending: Option<(u8, &[u8])>,
+        schema_version: u16,
+    ) -> Result<(), ConfigStoreError<F::Error>> {
+        let old_page = self.active_page;
+        let next_page = (old_page + 1) % self.page_count;
+        let from = next_page * Self::PAGE_SIZE;
+        defmt::info!(
+            "ConfigStore: compacting page {} into {}",
+            old_page,
+            next_page
+        );
+        self.flash.erase(from, from + Self::PAGE_SIZE).await?;
+
+        let mut write_offset = PAGE_HEADER_SIZE;
+        let mut copied: Vec<u8, 64> = Vec::new();
+        let mut offset = PAGE_HEADER_SIZE;
+        while let Some(record) = self.record_at(old_page, offset).await? {
+            offset += RECORD_HEADER_SIZE + Self::padded_len(record.len);
+            let superseded = pending.is_some_and(|(key, _)| key == record.key);
+            if !record.valid || superseded || copied.contains(&record.key) {
+                continue;
+            }
+            let Some(latest) = self.find_latest(old_page, record.key).await? else {
+                continue;
+            };
+            let mut value = [0u8; MAX_VALUE_LEN];
+            let len = latest.len as usize;
+            self.flash
+                .read(latest.value_offset, &mut value[..len])
+                .await?;
+            let needed = RECORD_HEADER_SIZE + Self::padded_len(latest.len);
+            if write_offset + needed > Self::PAGE_SIZE || copied.push(record.key).is_err() {
+                return Err(ConfigStoreError::Full);
+            }
+            write_offset = self
+                .append_record(next_page, write_offset, record.key, &value[..len])
+                .await?;
+        }
+
+        if let Some((key, value)) = pending {
+            let needed = RECORD_HEADER_SIZE + Self::padded_len(value.len() as u8);
+            if write_offset + needed > Self::PAGE_SIZE {
+                return Err(ConfigStoreError::Full);
+            }
+            write_offset = self
+                .append_record(next_page, write_offset, key, value)
+                .await?;
+        }
+
+        // The page header goes last: if power fails at any earlier step, the old page is still
+        // the valid page with the highest sequence number
+        let seq = self.seq.wrapping_add(1);
+        self.write_page_header(next_page, seq, schema_version)
+            .await?;
+        self.activate(next_page, seq, schema_version, write_offset);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::test_support::RamFlash;
+
+    type Store<'a> = ConfigStore<&'a mut RamFlash>;
+
+    /// A 4-byte value takes 16 bytes with its record header
+    const RECORD_4: u32 = RECORD_HEADER_SIZE + 8;
+    /// Number of 4-byte records that fit in a page
+    const RECORDS_PER_PAGE: u32 = (RamFlash::PAGE_SIZE as u32 - PAGE_HEADER_SIZE) / RECORD_4;
+
+    fn open(flash: &mut RamFlash) -> Store<'_> {
+        block_on(ConfigStore::new(flash, 1)).unwrap()
+    }
+
+    fn read(store: &mut Store<'_>, key: u8) -> Option<Vec<u8, MAX_VALUE_LEN>> {
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        let len = block_on(store.read(key, &mut buf)).unwrap()?;
+        Some(Vec::from_slice(&buf[..len]).unwrap())
+    }
+
+    fn write(store: &mut Store<'_>, key: u8, value: &[u8]) {
+        block_on(store.write(key, value)).unwrap();
+    }
+
+    #[test]
+    fn formats_blank_flash_and_keeps_latest_value() {
+        let mut flash = RamFlash::new(4);
+        let mut store = open(&mut flash);
+        assert_eq!((store.active_page, store.seq), (0, 1));
+        assert_eq!(read(&mut store, 1), None);
+        write(&mut store, 1, b"abcd");
+        write(&mut store, 2, b"xy");
+        write(&mut store, 1, b"efgh");
+
+        let mut store = open(&mut flash);
+        assert_eq!(read(&mut store, 1).as_deref(), Some(&b"efgh"[..]));
+        assert_eq!(read(&mut store, 2).as_deref(), Some(&b"xy"[..]));
+        assert_eq!(store.write_offset, PAGE_HEADER_SIZE + 3 * RECORD_4);
+    }
+
+    #[test]
+    fn rejects_reserved_key_and_oversized_value() {
+        let mut flash = RamFlash::new(4);
+        let mut store = open(&mut flash);
+        assert_eq!(
+            block_on(store.write(BLANK_KEY, b"abcd")),
+            Err(ConfigStoreError::InvalidKey)
+        );
+        assert_eq!(
+            block_on(store.write(1, &[0; MAX_VALUE_LEN + 1])),
+            Err(ConfigStoreError::ValueTooLarge)
+        );
+    }
+
+    #[test]
+    fn unchanged_value_is_not_written() {
+        let mut flash = RamFlash::new(4);
+        let mut store = open(&mut flash);
+        write(&mut store, 1, b"abcd");
+        let offset = store.write_offset;
+        write(&mut store, 1, b"abcd");
+        assert_eq!(store.write_offset, offset);
+    }
+
+    #[test]
+    fn record_with_bad_crc_is_ignored() {
+        let mut flash = RamFlash::new(4);
+        let mut store = open(&mut flash);
+        write(&mut store, 1, b"abcd");
+        write(&mut store, 1, b"efgh");
+
+        // Value of the second record: skip the page header, the first record and another header
+        let value_offset = (PAGE_HEADER_SIZE + RECORD_4 + RECORD_HEADER_SIZE) as usize;
+        flash.data[value_offset] &= 0x0F;
+
+        let mut store = open(&mut flash);
+        assert_eq!(read(&mut store, 1).as_deref(), Some(&b"abcd"[..]));
+        // The corrupt record's length is still trusted, so the new record follows it
+        assert_eq!(store.write_offset, PAGE_HEADER_SIZE + 2 * RECORD_4);
+        write(&mut store, 1, b"ijkl");
+
+        let mut store = open(&mut flash);
+        assert_eq!(read(&mut store, 1).as_deref(), Some(&b"ijkl"[..]));
+    }
+
+    #[test]
+    fn unreadable_record_header_forces_compaction() {
+        let mut flash = RamFlash::new(4);
+        let mut store = open(&mut flash);
+        write(&mut store, 1, b"abcd");
+        write(&mut store, 2, b"wxyz");
+
+        // The length exceeds MAX_VALUE_LEN, so the records after it cannot be located
+        flash.data[(PAGE_HEADER_SIZE + RECORD_4 + 1) as usize] = 0x7F;
+
+        let mut store = open(&mut flash);
+        assert_eq!(store.write_offset, RamFlash::PAGE_SIZE as u32);
+        assert_eq!(read(&mut store, 2), None);
+        write(&mut store, 3, b"new!");
+        assert_eq!(store.active_page, 1);
+        assert_eq!(read(&mut store, 1).as_deref(), Some(&b"abcd"[..]));
+        assert_eq!(read(&mut store, 3).as_deref(), Some(&b"new!"[..]));
+    }
+
+    #[test]
+    fn full_page_is_compacted_into_next_page() {
+        let mut flash = RamFlash::new(4);
+        let mut store = open(&mut flash);
+        write(&mut store, 2, b"keep");
+        for i in 0..RECORDS_PER_PAGE - 1 {
+            write(&mut store, 1, &[i as u8; 4]);
+        }
+        assert_eq!(store.active_page, 0);
+        assert_eq!(store.write_offset, RamFlash::PAGE_SIZE as u32);
+
+        write(&mut store, 1, b"last");
+        assert_eq!((store.active_page, store.seq), (1, 2));
+        // The new page keeps only the latest value of each key
+        assert_eq!(store.write_offset, PAGE_HEADER_SIZE + 2 * RECORD_4);
+
+        let mut store = open(&mut flash);
+        assert_eq!((store.active_page, store.seq), (1, 2));
+        assert_eq!(read(&mut store, 1).as_deref(), Some(&b"last"[..]));
+        assert_eq!(read(&mut store, 2).as_deref(), Some(&b"keep"[..]));
+    }
+
+    #[test]
+    fn compaction_rotates_through_all_pages() {
+        let mut flash = RamFlash::new(4);
+        let mut store = open(&mut flash);
+        write(&mut store, 2, b"keep");
+        let mut visited = [false; 4];
+        for i in 0..5 * RECORDS_PER_PAGE {
+            write(&mut store, 1, &i.to_le_bytes());
+            visited[store.active_page as usize] = true;
+        }
+        assert_eq!(visited, [true; 4]);
+        let (page, seq) = (store.active_page, store.seq);
+        assert!(seq > 4);
+
+        let mut store = open(&mut flash);
+        assert_eq!((store.active_page, store.seq), (page, seq));
+        let last = 5 * RECORDS_PER_PAGE - 1;
+        assert_eq!(
+            read(&mut store, 1).as_deref(),
+            Some(&last.to_le_bytes()[..])
+        );
+        assert_eq!(read(&mut store, 2).as_deref(), Some(&b"keep"[..]));
+    }
+
+    #[test]
+    fn power_loss_mid_record_keeps_previous_value() {
+        // Only the key; key and len; the whole header; the header and half of the value
+        for cut in [
+            1,
+            2,
+            RECORD_HEADER_SIZE as usize,
+            RECORD_HEADER_SIZE as usize + 2,
+        ] {
+            let mut flash = RamFlash::new(4);
+            let mut store = open(&mut flash);
+            write(&mut store, 1, b"abcd");
+            store.flash.write_budget = Some(cut);
+            assert!(block_on(store.write(1, b"efgh")).is_err());
+
+            flash.write_budget = None;
+            let mut store = open(&mut flash);
+            assert_eq!(
+                read(&mut store, 1).as_deref(),
+                Some(&b"abcd"[..]),
+                "cut {}",
+                cut
+            );
+            write(&mut store, 1, b"ijkl");
+
+            let mut store = open(&mut flash);
+            assert_eq!(
+                read(&mut store, 1).as_deref(),
+                Some(&b"ijkl"[..]),
+                "cut {}",
+                cut
+            );
+        }
+    }
+
+    #[test]
+    fn power_loss_during_compaction_keeps_old_page() {
+        let mut flash = RamFlash::new(4);
+        let mut store = open(&mut flash);
+        write(&mut store, 2, b"keep");
+        for i in 0..RECORDS_PER_PAGE - 1 {
+            write(&mut store, 1, &[i as u8; 4]);
+        }
+        // "keep" and the new value were moved, but the page header is not written yet
+        store.flash.write_budget = Some(2 * RECORD_4 as usize);
+        assert!(block_on(store.write(1, b"last")).is_err());
+
+        flash.write_budget = None;
+        let mut store = open(&mut flash);
+        assert_eq!((store.active_page, store.seq), (0, 1));
+        let previous = [RECORDS_PER_PAGE as u8 - 2; 4];
+        assert_eq!(read(&mut store, 1).as_deref(), Some(&previous[..]));
+        assert_eq!(read(&mut store, 2).as_deref(), Some(&b"keep"[..]));
+
+        // The retry erases the half-written page again
+        write(&mut store, 1, b"last");
+        assert_eq!((store.active_page, store.seq), (1, 2));
+
+        let mut store = open(&mut flash);
+        assert_eq!(read(&mut store, 1).as_deref(), Some(&b"last"[..]));
+        assert_eq!(read(&mut store, 2).as_deref(), Some(&b"keep"[..]));
+    }
+
+    #[test]
+    fn schema_version_is_kept_in_page_header() {
+        let mut flash = RamFlash::new(4);
+        let mut store = open(&mut flash);
+        write(&mut store, 1, b"abcd");
+        block_on(store.set_schema_version(2)).unwrap();
+        assert_eq!((store.active_page, store.schema_version()), (1, 2));
+
+        // Opening existing data uses the version from the page header
+        let mut store = block_on(ConfigStore::new(&mut flash, 3)).unwrap();
+        assert_eq!(store.schema_version(), 2);
+        assert_eq!(read(&mut store, 1).as_deref(), Some(&b"abcd"[..]));
+    }
+}
@@ -0,0 +1,207 @@
+//! Config transactions: the host stages a set of parameter changes, applies them to the BQ25730
+//! and BQ76920 in one go, and must confirm within a deadline or the config rolls back to what it
+//! was before the commit (like "commit confirmed" on network switches).
+//!
+//! The state machine only stages and times, driven by `usb_task`. The changes go out through the
+//! runtime config channel, and the devices report the write outcome in their measurements.
+
+use embassy_time::{Duration, Instant};
+
+use crate::config::{CellProtectionConfig, ConfigError, ConfigParam, SystemConfig};
+
+/// Confirmation deadline when none is given (s)
+pub const DEFAULT_CONFIRM_TIMEOUT_S: u16 = 60;
+
+/// Allowed confirmation deadline range (s)
+pub const MIN_CONFIRM_TIMEOUT_S: u16 = 5;
+pub const MAX_CONFIRM_TIMEOUT_S: u16 = 3600;
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum TransactionState {
+    #[default]
+    Idle = 0,
+    /// Staging changes, not applied yet
+    Staging = 1,
+    /// Applied, waiting for the device tasks to report the write outcome
+    Applying = 2,
+    /// In effect on the devices, waiting for the host to confirm
+    AwaitingConfirm = 3,
+}
+
+/// How the previous transaction ended
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum TransactionOutcome {
+    #[default]
+    None = 0,
+    Confirmed = 1,
+    /// Abandoned by the host; applied changes were rolled back
+    Aborted = 2,
+    /// The confirmation timed out and the changes were rolled back
+    TimedOut = 3,
+    /// A device write failed its check and the changes were rolled back
+    ApplyFailed = 4,
+}
+
+/// Why a transaction operation was rejected
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum TransactionError {
+    /// Not allowed in the current state
+    InvalidState,
+    /// The staged parameters failed validation
+    Config(ConfigError),
+}
+
+impl From<ConfigError> for TransactionError {
+    fn from(e: ConfigError) -> Self {
+        Self::Config(e)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigTransaction {
+    state: TransactionState,
+    staged: SystemConfig,
+    /// Config before the commit, restored on rollback
+    previous: SystemConfig,
+    /// Confirmation deadline, counted from the commit and including the device writes
+    deadline: Option<Instant>,
+    last_outcome: TransactionOutcome,
+}
+
+impl ConfigTransaction {
+    pub fn state(&self) -> TransactionState {
+        self.state
+    }
+
+    pub fn last_outcome(&self) -> TransactionOutcome {
+        self.last_outcome
+    }
+
+    /// Committed, neither confirmed nor rolled back yet
+    pub fn is_committed(&self) -> bool {
+        matches!(
+            self.state,
+            TransactionState::Applying | TransactionState::AwaitingConfirm
+        )
+    }
+
+    /// Config applied by the committed transaction
+    pub fn staged(&self) -> &SystemConfig {
+        &self.staged
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Seconds left until the confirmation deadline
+    pub fn remaining_s(&self, now: Instant) -> u16 {
+        self.deadline.map_or(0, |deadline| {
+            deadline
+                .checked_duration_since(now)
+                .map_or(0, |d| d.as_secs().min(u16::MAX as u64) as u16)
+        })
+    }
+
+    /// Starts staging on top of `current`; calling it again drops the staged changes
+    pub fn begin(&mut self, current: SystemConfig) -> Result<(), TransactionError> {
+        if self.is_committed() {
+            return Err(TransactionError::InvalidState);
+        }
+        self.staged = current;
+        self.state = TransactionState::Staging;
+        Ok(())
+    }
+
+    pub fn stage(&mut self, param: ConfigParam, value: u32) -> Result<(), TransactionError> {
+        if self.state != TransactionState::Staging {
+            return Err(TransactionError::InvalidState);
+        }
+        Ok(self.staged.set(param, value)?)
+    }
+
+    pub fn stage_protection(
+        &mut self,
+        protection: CellProtectionConfig,
+    ) -> Result<(), TransactionError> {
+        if self.state != TransactionState::Staging {
+            return Err(TransactionError::InvalidState);
+        }
+        Ok(self.staged.set_cell_protection(protection)?)
+    }
+
+    /// Commits the staged changes and returns the config to apply
+    pub fn commit(
+        &mut self,
+        current: SystemConfig,
+        confirm_timeout_s: u16,
+        now: Instant,
+    ) -> Result<SystemConfig, TransactionError> {
+        if self.state != TransactionState::Staging {
+            return Err(TransactionError::InvalidState);
+        }
+        self.previous = current;
+        self.deadline = Some(now + Duration::from_secs(confirm_timeout_s as u64));
+        self.state = TransactionState::Applying;
+        Ok(self.staged)
+    }
+
+    /// The device tasks report that every change is in effect
+    pub fn applied(&mut self) {
+        if self.state == TransactionState::Applying {
+            self.state = TransactionState::AwaitingConfirm;
+        }
+    }
+
+    /// Confirmed by the host; the changes stay
+    pub fn confirm(&mut self) -> Result<SystemConfig, TransactionError> {
+        if self.state != TransactionState::AwaitingConfirm {
+            return Err(TransactionError::InvalidState);
+        }
+        self.finish(TransactionOutcome::Confirmed);
+        Ok(self.staged)
+    }
+
+    /// Abandons the transaction. Once committed, returns the config to restore
+    pub fn abort(&mut self) -> Option<SystemConfig> {
+        match self.state {
+            TransactionState::Idle => None,
+            TransactionState::Staging => {
+                self.finish(TransactionOutcome::Aborted);
+                None
+            }
+            _ => self.roll_back(TransactionOutcome::Aborted),
+        }
+    }
+
+    /// A device write failed; returns the config to restore
+    pub fn apply_failed(&mut self) -> Option<SystemConfig> {
+        if !self.is_committed() {
+            return None;
+        }
+        self.roll_back(TransactionOutcome::ApplyFailed)
+    }
+
+    /// Returns the config to restore when the confirmation times out
+    pub fn check_deadline(&mut self, now: Instant) -> Option<SystemConfig> {
+        match self.deadline {
+            Some(deadline) if self.is_committed() && now >= deadline => {
+                self.roll_back(TransactionOutcome::TimedOut)
+            }
+            _ => None,
+        }
+    }
+
+    fn roll_back(&mut self, outcome: TransactionOutcome) -> Option<SystemConfig> {
+        self.finish(outcome);
+        Some(self.previous)
+    }
+
+    fn finish(&mut self, outcome: TransactionOutcome) {
+        self.state = TransactionState::Idle;
+        self.deadline = None;
+        self.last_outcome = outcome;
+    }
+}
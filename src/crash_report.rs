@@ -0,0 +1,291 @@
+//! Crash record: on panic, the message, location, uptime, reset reason of this boot and the latest
+//! measurement snapshot are saved in RAM that survives a reset, then the MCU resets. The next boot
+//! reads the record back, writes it to the event log
+//! and makes it available over USB.
+//!
+//! `usb_task` updates the snapshot every time it aggregates the measurements, using the same
+//! encoding as the `StatusResponse` payload.
+//! This module provides the firmware's `#[panic_handler]`; the panic is still printed over defmt.
+
+use core::fmt::{self, Write};
+use core::mem::{MaybeUninit, offset_of};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use binrw::BinWrite;
+use binrw::io::Cursor;
+use embassy_time::Instant;
+
+use crate::config::LowBatteryConfig;
+use crate::crc::crc32;
+use crate::data_types::AllMeasurements;
+use crate::event_log::{self, EventLogEntry, PowerEvent, PowerEventCode};
+use crate::task_watchdog::ResetReason;
+
+/// Bytes kept of the panic message; the rest is truncated
+pub const MESSAGE_LEN: usize = 96;
+/// Bytes kept of the source file path; longer paths keep their end
+pub const FILE_LEN: usize = 64;
+/// Snapshot capacity; an encoded `AllMeasurementsUsbPayload` takes about 205 bytes
+pub const SNAPSHOT_LEN: usize = 224;
+
+const RETAINED_MAGIC: u32 = 0x4352_5331; // "CRS1"
+
+/// `take_last_report` has not been called yet in this boot
+const RESET_REASON_UNSET: u8 = 0xFF;
+
+/// Record in the retained RAM. The fields are ordered by alignment so there is no padding, and
+/// the CRC covers every byte before `checksum`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct RetainedCrash {
+    magic: u32,
+    reset_reason: u8,
+    message_len: u8,
+    file_len: u8,
+    snapshot_len: u8,
+    uptime_s: u32,
+    line: u32,
+    column: u32,
+    // Measurements for the event log entry, taken from the latest snapshot
+    pack_voltage_mv: u16,
+    battery_current_ma: i16,
+    vbus_mv: u16,
+    load_power_10mw: u16,
+    temperature_0_01c: i16,
+    soc_pct: u8,
+    _reserved: u8,
+    message: [u8; MESSAGE_LEN],
+    file: [u8; FILE_LEN],
+    snapshot: [u8; SNAPSHOT_LEN],
+    checksum: u32,
+}
+
+impl RetainedCrash {
+    const EMPTY: Self = Self {
+        magic: 0,
+        reset_reason: 0,
+        message_len: 0,
+        file_len: 0,
+        snapshot_len: 0,
+        uptime_s: 0,
+        line: 0,
+        column: 0,
+        pack_voltage_mv: 0,
+        battery_current_ma: 0,
+        vbus_mv: 0,
+        load_power_10mw: 0,
+        temperature_0_01c: 0,
+        soc_pct: 0,
+        _reserved: 0,
+        message: [0; MESSAGE_LEN],
+        file: [0; FILE_LEN],
+        snapshot: [0; SNAPSHOT_LEN],
+        checksum: 0,
+    };
+
+    fn checksum(&self) -> u32 {
+        // SAFETY: `repr(C)` without padding, and every byte before `checksum` is initialised
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>(),
+                offset_of!(Self, checksum),
+            )
+        };
+        crc32(bytes)
+    }
+}
+
+#[unsafe(link_section = ".uninit.crash_report")]
+static mut RETAINED: MaybeUninit<RetainedCrash> = MaybeUninit::uninit();
+
+/// Reset reason of this boot, saved in the record on panic
+static BOOT_RESET_REASON: AtomicU8 = AtomicU8::new(RESET_REASON_UNSET);
+
+/// Set once the panic handler runs, so a nested panic does not overwrite the record
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+fn retained() -> *mut RetainedCrash {
+    core::ptr::addr_of_mut!(RETAINED).cast::<RetainedCrash>()
+}
+
+/// Panic record of the previous run
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CrashReport {
+    /// Reset reason of the run that crashed
+    pub reset_reason: ResetReason,
+    /// Uptime at the panic (s)
+    pub uptime_s: u32,
+    pub line: u32,
+    pub column: u32,
+    pub message: [u8; MESSAGE_LEN],
+    pub message_len: u8,
+    /// End of the source file path
+    pub file: [u8; FILE_LEN],
+    pub file_len: u8,
+    /// Encoded `AllMeasurementsUsbPayload`, empty if the panic came before any measurement
+    pub snapshot: [u8; SNAPSHOT_LEN],
+    pub snapshot_len: u8,
+    log_entry: EventLogEntry,
+}
+
+impl CrashReport {
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.message[..self.message_len as usize]).unwrap_or("?")
+    }
+
+    pub fn file(&self) -> &str {
+        core::str::from_utf8(&self.file[..self.file_len as usize]).unwrap_or("?")
+    }
+
+    /// Event log entry, with the uptime and measurements from before the crash
+    pub fn log_entry(&self) -> EventLogEntry {
+        self.log_entry
+    }
+}
+
+/// Reads and clears the panic record left by the previous run. Called once at boot, before the
+/// tasks start.
+/// `reset_reason` is the reset reason of this boot, saved with the record if this run panics.
+pub fn take_last_report(reset_reason: ResetReason) -> Option<CrashReport> {
+    BOOT_RESET_REASON.store(reset_reason as u8, Ordering::Relaxed);
+    // SAFETY: the tasks have not started, so nothing else accesses it. The content is arbitrary
+    // after power-up; the magic and CRC tell whether it is valid
+    let record = unsafe { retained().read_volatile() };
+    unsafe { retained().write_volatile(RetainedCrash::EMPTY) };
+
+    if record.magic != RETAINED_MAGIC || record.checksum != record.checksum() {
+        return None;
+    }
+    let message_len = record.message_len.min(MESSAGE_LEN as u8);
+    let file_len = record.file_len.min(FILE_LEN as u8);
+    let snapshot_len = record.snapshot_len.min(SNAPSHOT_LEN as u8);
+    Some(CrashReport {
+        reset_reason: ResetReason::from_u8(record.reset_reason),
+        uptime_s: record.uptime_s,
+        line: record.line,
+        column: record.column,
+        message: record.message,
+        message_len,
+        file: record.file,
+        file_len,
+        snapshot: record.snapshot,
+        snapshot_len,
+        log_entry: EventLogEntry {
+            uptime_s: record.uptime_s,
+            code: PowerEventCode::Panic as u8,
+            detail: record.line.min(u16::MAX as u32) as u16,
+            soc_pct: record.soc_pct,
+            pack_voltage_mv: record.pack_voltage_mv,
+            battery_current_ma: record.battery_current_ma,
+            vbus_mv: record.vbus_mv,
+            load_power_10mw: record.load_power_10mw,
+            temperature_0_01c: record.temperature_0_01c,
+            ..Default::default()
+        },
+    })
+}
+
+/// Updates the measurement snapshot saved on panic
+pub fn update_snapshot(measurements: &AllMeasurements<5>, low_battery: &LowBatteryConfig) {
+    let mut buf = [0u8; SNAPSHOT_LEN];
+    let mut writer = Cursor::new(&mut buf[..]);
+    let len = match measurements.to_usb_payload().write_be(&mut writer) {
+        Ok(()) => writer.position() as usize,
+        Err(_) => 0,
+    };
+    let entry = event_log::snapshot_entry(
+        PowerEvent::new(PowerEventCode::Panic, 0),
+        measurements,
+        low_battery,
+    );
+    cortex_m::interrupt::free(|_| {
+        // SAFETY: the record is only accessed with interrupts disabled: here, at boot (before the
+        // tasks start) and in the panic handler
+        let record = unsafe { &mut *retained() };
+        record.snapshot[..len].copy_from_slice(&buf[..len]);
+        record.snapshot_len = len as u8;
+        record.pack_voltage_mv = entry.pack_voltage_mv;
+        record.battery_current_ma = entry.battery_current_ma;
+        record.vbus_mv = entry.vbus_mv;
+        record.load_power_10mw = entry.load_power_10mw;
+        record.temperature_0_01c = entry.temperature_0_01c;
+        record.soc_pct = entry.soc_pct;
+    });
+}
+
+/// Drops whatever does not fit once full, truncating only at character boundaries
+struct TruncatingWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    full: bool,
+}
+
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.full {
+            return Ok(());
+        }
+        let mut n = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        self.full = n < s.len();
+        Ok(())
+    }
+}
+
+/// Returns the last `max` bytes of a string at most
+fn tail(s: &str, max: usize) -> &str {
+    let mut start = s.len().saturating_sub(max);
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+    &s[start..]
+}
+
+fn store_panic(info: &PanicInfo) {
+    // SAFETY: interrupts are disabled, so `update_snapshot` cannot run at the same time
+    let record = unsafe { &mut *retained() };
+    let reset_reason = BOOT_RESET_REASON.load(Ordering::Relaxed);
+    if reset_reason == RESET_REASON_UNSET {
+        // Panic during boot: the record still holds the previous run
+        *record = RetainedCrash::EMPTY;
+        record.reset_reason = ResetReason::Unknown as u8;
+    } else {
+        record.reset_reason = reset_reason;
+    }
+
+    let mut message = TruncatingWriter {
+        buf: &mut record.message,
+        len: 0,
+        full: false,
+    };
+    let _ = write!(message, "{}", info.message());
+    record.message_len = message.len as u8;
+
+    let (file, line, column) = info.location().map_or(("", 0, 0), |l| {
+        (tail(l.file(), FILE_LEN), l.line(), l.column())
+    });
+    record.file[..file.len()].copy_from_slice(file.as_bytes());
+    record.file_len = file.len() as u8;
+    record.line = line;
+    record.column = column;
+    record.uptime_s = Instant::now().as_secs().min(u32::MAX as u64) as u32;
+    record.magic = RETAINED_MAGIC;
+    record.checksum = record.checksum();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        store_panic(info);
+        defmt::error!("{}", defmt::Display2Format(info));
+    }
+    // Reset right away in debug and release builds alike. Waiting in HardFault for the IWDG reset
+    // would make the next boot report an executor stall instead of a panic
+    cortex_m::peripheral::SCB::sys_reset()
+}
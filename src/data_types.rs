@@ -2,14 +2,80 @@
 
 use bq769x0_async_rs::data_types::{Bq76920Measurements as Bq76920CoreMeasurements, SystemStatus};
 use bq25730_async_rs::data_types::{AdcMeasurements, ChargerStatus, ProchotStatus};
+use embassy_time::{Duration, Instant};
+
+use crate::battery_gauge::GaugeStatus;
+use crate::battery_test::{BatteryTestResult, BatteryTestStatus};
+use crate::config::ChargerConfig;
+use crate::energy_meter::EnergyStatus;
+use crate::event_log::EventLogEntry;
+use crate::host_watchdog::HostWatchdogStatus;
+use crate::i2c_supervisor::{BusHealth, DeviceHealth, I2cDeviceId};
+use crate::load_control::LoadControlStatus;
+use crate::low_power::CurrentEstimate;
+use crate::overload_protection::OverloadStatus;
+use crate::power_quality::PowerQualityStatus;
+use crate::protection::ProtectionStatus;
+use crate::sampling::SamplingStatus;
+use crate::sensor_consistency::ConsistencyStatus;
 
 // use crate::shared::Bq76920RuntimeConfig; // Removed as Bq76920RuntimeConfig is no longer needed by to_usb_payload
 
+pub use crate::sampling::MEASUREMENT_MAX_AGE;
+
+/// Validity and sampling time of a set of measurements
+///
+/// When a read fails, the tasks keep publishing the last good reading (the default if there never
+/// was one) with `valid` cleared, so users can tell a reading of 0 from an unreachable sensor.
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct SampleInfo {
+    /// The most recent read succeeded
+    pub valid: bool,
+    /// Time of the most recent successful read, `None` if there has not been one
+    pub sampled_at: Option<Instant>,
+}
+
+impl SampleInfo {
+    /// Records the outcome of a read
+    pub fn record(&mut self, ok: bool, now: Instant) {
+        self.valid = ok;
+        if ok {
+            self.sampled_at = Some(now);
+        }
+    }
+
+    /// Time since the most recent successful read
+    pub fn age(&self, now: Instant) -> Option<Duration> {
+        self.sampled_at.map(|t| now.saturating_duration_since(t))
+    }
+
+    /// The most recent read succeeded and is no older than `MEASUREMENT_MAX_AGE`
+    pub fn is_fresh(&self, now: Instant) -> bool {
+        self.valid && self.age(now).is_some_and(|age| age <= MEASUREMENT_MAX_AGE)
+    }
+
+    fn timestamp_ms(&self) -> u32 {
+        self.sampled_at.map_or(0, |t| t.as_millis() as u32)
+    }
+
+    fn age_ms(&self, now: Instant) -> u16 {
+        self.age(now)
+            .map_or(u16::MAX, |age| age.as_millis().min(u16::MAX as u64) as u16)
+    }
+}
+
 /// BQ25730 测量数据
 #[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
 
 pub struct Bq25730Measurements {
     pub adc_measurements: AdcMeasurements,
+    pub battery_test: BatteryTestStatus,
+    /// Input power quality statistics
+    pub power_quality: PowerQualityStatus,
+    /// Charge parameters last written to the chip, `None` if writing them failed at boot
+    pub applied_charger: Option<ChargerConfig>,
+    /// Validity and sampling time of `adc_measurements`
+    pub sample: SampleInfo,
     // 添加其他非告警相关的测量数据字段（如果需要）
 }
 
@@ -17,6 +83,10 @@ impl Default for Bq25730Measurements {
     fn default() -> Self {
         Self {
             adc_measurements: AdcMeasurements::default(),
+            battery_test: BatteryTestStatus::default(),
+            power_quality: PowerQualityStatus::default(),
+            applied_charger: None,
+            sample: SampleInfo::default(),
         }
     }
 }
@@ -43,12 +113,25 @@ impl Default for Bq25730Alerts {
 
 pub struct Bq76920Measurements<const N: usize> {
     pub core_measurements: Bq76920CoreMeasurements<N>,
+    pub load_control: LoadControlStatus,
+    pub host_watchdog: HostWatchdogStatus,
+    pub overload: OverloadStatus,
+    pub protection: ProtectionStatus,
+    pub gauge: GaugeStatus,
+    /// Validity and sampling time of `core_measurements`
+    pub sample: SampleInfo,
 }
 
 impl<const N: usize> Default for Bq76920Measurements<N> {
     fn default() -> Self {
         Self {
             core_measurements: Bq76920CoreMeasurements::default(),
+            load_control: LoadControlStatus::default(),
+            host_watchdog: HostWatchdogStatus::default(),
+            overload: OverloadStatus::default(),
+            protection: ProtectionStatus::default(),
+            gauge: GaugeStatus::default(),
+            sample: SampleInfo::default(),
         }
     }
 }
@@ -74,6 +157,9 @@ pub struct Ina226Measurements {
     pub voltage: f32,
     pub current: f32,
     pub power: f32, // 假设需要功率，如果不需要可以调整
+    pub sample: SampleInfo,
+    /// Overcurrent/overpower events reported on the ALERT pin since boot
+    pub alert_count: u16,
 }
 
 impl Default for Ina226Measurements {
@@ -82,6 +168,8 @@ impl Default for Ina226Measurements {
             voltage: 0.0,
             current: 0.0,
             power: 0.0,
+            sample: SampleInfo::default(),
+            alert_count: 0,
         }
     }
 }
@@ -95,6 +183,14 @@ pub struct AllMeasurements<const N: usize> {
     pub ina226: Ina226Measurements,
     pub bq25730_alerts: Bq25730Alerts,
     pub bq76920_alerts: Bq76920Alerts,
+    /// Cross-check of the sensor readings, computed by `usb_task`
+    pub consistency: ConsistencyStatus,
+    /// I2C bus and device statistics, read by `usb_task` from the bus supervisor
+    pub i2c: BusHealth,
+    /// Energy totals and efficiency, read by `usb_task` from the energy meter
+    pub energy: EnergyStatus,
+    /// Current sampling mode, read by `usb_task`
+    pub sampling: SamplingStatus,
 }
 
 impl<const N: usize> Default for AllMeasurements<N> {
@@ -105,6 +201,10 @@ impl<const N: usize> Default for AllMeasurements<N> {
             ina226: Ina226Measurements::default(),
             bq25730_alerts: Bq25730Alerts::default(),
             bq76920_alerts: Bq76920Alerts::default(),
+            consistency: ConsistencyStatus::default(),
+            i2c: BusHealth::default(),
+            energy: EnergyStatus::default(),
+            sampling: SamplingStatus::default(),
         }
     }
 }
@@ -114,6 +214,9 @@ impl<const N: usize> AllMeasurements<N> {
     /// Converts the aggregated measurements into the flattened USB payload structure.
     /// Assumes that BQ76920 temperatures and current are already in physical units within `self.bq76920.core_measurements`.
     pub fn to_usb_payload(&self) -> AllMeasurementsUsbPayload {
+        let now = Instant::now();
+        let current_estimate = CurrentEstimate::new(&self.sampling);
+
         // BQ25730 Voltages (already in mV in self.bq25730.adc_measurements)
         let bq25730_adc_vbat_mv = self.bq25730.adc_measurements.vbat.0;
         let bq25730_adc_vsys_mv = self.bq25730.adc_measurements.vsys.0;
@@ -220,11 +323,85 @@ impl<const N: usize> AllMeasurements<N> {
             ina226_voltage_f32: self.ina226.voltage,
             ina226_current_f32: self.ina226.current,
             ina226_power_f32: self.ina226.power,
+            ina226_alert_count: self.ina226.alert_count,
 
             bq25730_charger_status_flags: self.bq25730_alerts.charger_status.to_u16(),
             bq25730_prochot_status_flags: self.bq25730_alerts.prochot_status.to_u16(),
 
             bq76920_alerts_system_status_mask: self.bq76920_alerts.system_status.0.bits(),
+
+            load_pending_action: self.bq76920.load_control.pending_action as u8,
+            load_countdown_s: self.bq76920.load_control.countdown_s,
+            load_low_battery_shutdown: self.bq76920.load_control.low_battery_shutdown as u8,
+
+            host_watchdog_armed: self.bq76920.host_watchdog.armed as u8,
+            host_watchdog_seconds_since_heartbeat: self
+                .bq76920
+                .host_watchdog
+                .seconds_since_heartbeat,
+            host_watchdog_trip_count: self.bq76920.host_watchdog.trip_count,
+
+            overload_state: self.bq76920.overload.state as u8,
+            overload_level_pct: self.bq76920.overload.level_pct,
+            overload_retry_countdown_s: self.bq76920.overload.retry_countdown_s,
+            overload_retries_used: self.bq76920.overload.retries_used,
+            overload_trip_count: self.bq76920.overload.trip_count,
+
+            battery_test_phase: self.bq25730.battery_test.phase as u8,
+            battery_test_verdict: self.bq25730.battery_test.last_result.verdict as u8,
+
+            gauge_valid: self.bq76920.gauge.valid as u8,
+            gauge_soc_pct: self.bq76920.gauge.soc_pct,
+            gauge_remaining_mah: self.bq76920.gauge.remaining_mah,
+            gauge_full_capacity_mah: self.bq76920.gauge.full_capacity_mah,
+            gauge_cycle_count: self.bq76920.gauge.cycle_count,
+            gauge_charged_mwh: self.bq76920.gauge.charged_mwh,
+            gauge_discharged_mwh: self.bq76920.gauge.discharged_mwh,
+
+            energy_input_mwh: self.energy.input_mwh,
+            energy_load_mwh: self.energy.load_mwh,
+            energy_charged_mwh: self.energy.charged_mwh,
+            energy_discharged_mwh: self.energy.discharged_mwh,
+            energy_round_trip_efficiency_0_1pct: self.energy.round_trip_efficiency_0_1pct,
+            energy_conversion_efficiency_0_1pct: self.energy.conversion_efficiency_0_1pct,
+
+            sampling_mode: self.sampling.mode as u8,
+            sampling_bq76920_ms: self.sampling.rates().bq76920_ms,
+            sampling_bq25730_ms: self.sampling.rates().bq25730_ms,
+            sampling_ina226_ms: self.sampling.rates().ina226_ms,
+            sampling_usb_suspended: self.sampling.usb_suspended as u8,
+            sampling_stop_allowed: self.sampling.stop_allowed() as u8,
+            estimated_current_ua: current_estimate.current_ua.min(u16::MAX as u32) as u16,
+            estimated_saving_ua: current_estimate.saving_ua.min(u16::MAX as u32) as u16,
+
+            consistency_mismatches: self.consistency.mismatches,
+            consistency_faults: self.consistency.faults,
+            consistency_voltage_source: self.consistency.voltage_source as u8,
+            consistency_current_source: self.consistency.current_source as u8,
+            consistency_voltage_spread_mv: self.consistency.voltage_spread_mv,
+            consistency_current_spread_ma: self.consistency.current_spread_ma,
+
+            bq25730_valid: self.bq25730.sample.valid as u8,
+            bq25730_timestamp_ms: self.bq25730.sample.timestamp_ms(),
+            bq25730_age_ms: self.bq25730.sample.age_ms(now),
+            bq76920_valid: self.bq76920.sample.valid as u8,
+            bq76920_timestamp_ms: self.bq76920.sample.timestamp_ms(),
+            bq76920_age_ms: self.bq76920.sample.age_ms(now),
+            ina226_valid: self.ina226.sample.valid as u8,
+            ina226_timestamp_ms: self.ina226.sample.timestamp_ms(),
+            ina226_age_ms: self.ina226.sample.age_ms(now),
+
+            i2c_bq76920: I2cDeviceHealthPayload::from(
+                &self.i2c.devices[I2cDeviceId::Bq76920 as usize],
+            ),
+            i2c_bq25730: I2cDeviceHealthPayload::from(
+                &self.i2c.devices[I2cDeviceId::Bq25730 as usize],
+            ),
+            i2c_ina226: I2cDeviceHealthPayload::from(
+                &self.i2c.devices[I2cDeviceId::Ina226 as usize],
+            ),
+            i2c_bus_recoveries: self.i2c.recoveries,
+            i2c_failed_clears: self.i2c.failed_clears,
         }
     }
 }
@@ -243,14 +420,14 @@ pub struct AllMeasurementsUsbPayload {
     pub bq25730_adc_cmpin_mv: u16, // Was bq25730_adc_cmpin_raw, unit: mV
 
     // Fields from Bq76920Measurements -> Bq76920CoreMeasurements<N>
-    pub bq76920_cell1_mv: i32,       // Unchanged
-    pub bq76920_cell2_mv: i32,       // Unchanged
-    pub bq76920_cell3_mv: i32,       // Unchanged
-    pub bq76920_cell4_mv: i32,       // Unchanged
-    pub bq76920_cell5_mv: i32,       // Unchanged (assuming N=5 for this example)
+    pub bq76920_cell1_mv: i32,         // Unchanged
+    pub bq76920_cell2_mv: i32,         // Unchanged
+    pub bq76920_cell3_mv: i32,         // Unchanged
+    pub bq76920_cell4_mv: i32,         // Unchanged
+    pub bq76920_cell5_mv: i32,         // Unchanged (assuming N=5 for this example)
     pub bq76920_total_voltage_mv: i32, // Added: Total voltage of the BQ76920 pack
-    pub bq76920_ts1_temp_0_01c: i16, // Was bq76920_ts1_raw_adc, unit: 0.01 °C
-    pub bq76920_ts2_present: u8,     // Unchanged
+    pub bq76920_ts1_temp_0_01c: i16,   // Was bq76920_ts1_raw_adc, unit: 0.01 °C
+    pub bq76920_ts2_present: u8,       // Unchanged
     pub bq76920_ts2_temp_0_01c: i16, // Was bq76920_ts2_raw_adc, unit: 0.01 °C (use i16::MIN if not present)
     pub bq76920_ts3_present: u8,     // Unchanged
     pub bq76920_ts3_temp_0_01c: i16, // Was bq76920_ts3_raw_adc, unit: 0.01 °C (use i16::MIN if not present)
@@ -264,6 +441,7 @@ pub struct AllMeasurementsUsbPayload {
     pub ina226_voltage_f32: f32, // Unchanged
     pub ina226_current_f32: f32, // Unchanged
     pub ina226_power_f32: f32,   // Unchanged
+    pub ina226_alert_count: u16, // Over-current/over-power alerts from the INA226 since boot
 
     // Fields from Bq25730Alerts
     pub bq25730_charger_status_flags: u16, // Was bq25730_charger_status_raw_u16
@@ -271,6 +449,145 @@ pub struct AllMeasurementsUsbPayload {
 
     // Fields from Bq76920Alerts
     pub bq76920_alerts_system_status_mask: u8, // Was bq76920_alerts_system_status_bits
+
+    // Fields from LoadControlStatus
+    pub load_pending_action: u8, // PendingAction: 0=None, 1=Shutdown, 2=ShutdownRestore, 3=Restore
+    pub load_countdown_s: u16,   // Seconds until the pending action is executed
+    pub load_low_battery_shutdown: u8, // 1 while the load is off after a low-battery shutdown
+
+    // Fields from HostWatchdogStatus
+    pub host_watchdog_armed: u8,
+    pub host_watchdog_seconds_since_heartbeat: u16,
+    pub host_watchdog_trip_count: u16, // Power cycles triggered by missed heartbeats since boot
+
+    // Fields from OverloadStatus
+    pub overload_state: u8, // 0=Normal, 1=Warning, 2=Tripped (waiting to retry), 3=Latched off
+    pub overload_level_pct: u8, // Accumulated overload as a percentage of the trip level
+    pub overload_retry_countdown_s: u16,
+    pub overload_retries_used: u8,
+    pub overload_trip_count: u16, // Overload trips since boot
+
+    // Fields from BatteryTestStatus
+    pub battery_test_phase: u8, // BatteryTestPhase: 0=Idle, 1=Rest, 2=Load
+    pub battery_test_verdict: u8, // TestVerdict of the last test: 0=None, 1=Pass, 2=Warn, 3=Fail
+
+    // Fields from GaugeStatus
+    pub gauge_valid: u8, // 0 until the gauge is restored from flash or initialised from cell voltages
+    pub gauge_soc_pct: u8,
+    pub gauge_remaining_mah: u16,
+    pub gauge_full_capacity_mah: u16, // Learned capacity
+    pub gauge_cycle_count: u16,
+    pub gauge_charged_mwh: u32, // Energy charged into the battery, persisted across resets
+    pub gauge_discharged_mwh: u32, // Energy drawn from the battery, persisted across resets
+
+    // Fields from EnergyStatus: counters since the last ResetEnergyCounters, persisted across resets
+    pub energy_input_mwh: u32,                    // VBUS x IIN
+    pub energy_load_mwh: u32,                     // INA226 load power
+    pub energy_charged_mwh: u32,                  // Battery current x pack voltage while charging
+    pub energy_discharged_mwh: u32, // Battery current x pack voltage while discharging
+    pub energy_round_trip_efficiency_0_1pct: u16, // Discharged / charged, 65535 if nothing charged yet
+    pub energy_conversion_efficiency_0_1pct: u16, // (Load + charged) / (input + discharged), 65535 if unknown
+
+    // Fields from SamplingStatus
+    pub sampling_mode: u8, // 0 = Idle, 1 = Normal, 2 = Fast, 3 = LowPower
+    pub sampling_bq76920_ms: u16, // Active measurement periods
+    pub sampling_bq25730_ms: u16,
+    pub sampling_ina226_ms: u16,
+    pub sampling_usb_suspended: u8, // USB bus suspended or no host
+    pub sampling_stop_allowed: u8,  // MCU may enter STOP mode between wakeups
+    pub estimated_current_ua: u16,  // Estimated MCU and BQ25730 ADC current, not measured
+    pub estimated_saving_ua: u16,   // Estimated saving against Normal sampling without STOP
+
+    // Fields from ConsistencyStatus
+    pub consistency_mismatches: u8, // bit0 = pack voltage, bit1 = battery current readings disagree
+    pub consistency_faults: u8, // Faulty sensor bits: 0..=2 voltage, 4..=6 current (0=BQ76920, 1=BQ25730, 2=INA226)
+    pub consistency_voltage_source: u8, // Sensor trusted for the pack voltage
+    pub consistency_current_source: u8, // Sensor trusted for the battery current
+    pub consistency_voltage_spread_mv: u16, // Largest difference between the voltage readings
+    pub consistency_current_spread_ma: u16, // Largest difference between the current readings
+
+    // Fields from SampleInfo of each measurement group. When `*_valid` is 0 the last read
+    // failed and the group holds the last good reading (zeros if it was never read).
+    pub bq25730_valid: u8,
+    pub bq25730_timestamp_ms: u32, // Uptime of the last successful ADC read, 0 if never read
+    pub bq25730_age_ms: u16, // Time since the last successful read, 65535 if never read or older
+    pub bq76920_valid: u8,
+    pub bq76920_timestamp_ms: u32,
+    pub bq76920_age_ms: u16,
+    pub ina226_valid: u8,
+    pub ina226_timestamp_ms: u32,
+    pub ina226_age_ms: u16,
+
+    // Fields from BusHealth: per-device I2C statistics and bus recoveries since boot
+    pub i2c_bq76920: I2cDeviceHealthPayload,
+    pub i2c_bq25730: I2cDeviceHealthPayload,
+    pub i2c_ina226: I2cDeviceHealthPayload,
+    pub i2c_bus_recoveries: u16,
+    pub i2c_failed_clears: u16, // Recoveries after which SDA or SCL was still held low
+}
+
+/// I2C statistics of one device in `AllMeasurementsUsbPayload`.
+#[derive(Debug, Copy, Clone, PartialEq, binrw::BinWrite, defmt::Format)]
+pub struct I2cDeviceHealthPayload {
+    pub responding: u8, // 1 if the last transfer succeeded
+    pub last_error: u8, // I2cErrorClass: 0=None, 1=Nack, 2=ArbitrationLoss, 3=Bus, 4=Overrun, 5=Timeout, 6=Other
+    pub successes: u32,
+    pub errors: u16,   // Failed transfers excluding timeouts, saturating
+    pub timeouts: u16, // Saturating
+}
+
+impl From<&DeviceHealth> for I2cDeviceHealthPayload {
+    fn from(health: &DeviceHealth) -> Self {
+        Self {
+            responding: health.responding() as u8,
+            last_error: health.last_error as u8,
+            successes: health.successes,
+            errors: health.errors.min(u16::MAX as u32) as u16,
+            timeouts: health.timeouts.min(u16::MAX as u32) as u16,
+        }
+    }
+}
+
+/// Payload of `UsbData::BatteryTestReport`, the result of the last battery self-test.
+#[derive(Debug, Copy, Clone, PartialEq, binrw::BinWrite, defmt::Format)]
+pub struct BatteryTestReportPayload {
+    pub verdict: u8,      // TestVerdict: 0=None, 1=Pass, 2=Warn, 3=Fail
+    pub abort_reason: u8, // TestAbortReason: 0=None, 1=LowSoc, 2=LowCellVoltage, 3=Cancelled, 4=NoData
+    pub duration_s: u16,
+    pub rest_voltage_mv: i32,
+    pub min_loaded_voltage_mv: i32,
+    pub voltage_sag_mv: i32,
+    pub internal_resistance_mohm: u32,
+    pub avg_discharge_ma: i32,
+    pub soc_drop_pct: u8,
+}
+
+impl From<BatteryTestResult> for BatteryTestReportPayload {
+    fn from(result: BatteryTestResult) -> Self {
+        Self {
+            verdict: result.verdict as u8,
+            abort_reason: result.abort_reason as u8,
+            duration_s: result.duration_s,
+            rest_voltage_mv: result.rest_voltage_mv,
+            min_loaded_voltage_mv: result.min_loaded_voltage_mv,
+            voltage_sag_mv: result.voltage_sag_mv,
+            internal_resistance_mohm: result.internal_resistance_mohm,
+            avg_discharge_ma: result.avg_discharge_ma,
+            soc_drop_pct: result.soc_drop_pct,
+        }
+    }
+}
+
+/// Number of event log entries returned per `UsbData::EventLogPage`.
+pub const EVENT_LOG_PAGE_ENTRIES: usize = 4;
+
+/// Payload of `UsbData::EventLogPage`, a page of the power event log, newest first.
+#[derive(Debug, Copy, Clone, PartialEq, binrw::BinWrite, defmt::Format)]
+pub struct EventLogPagePayload {
+    pub total: u16,       // Number of valid entries in the log
+    pub start_index: u16, // Index of the first entry in this page, 0 = newest
+    pub count: u8,        // Number of valid entries in `entries`, the rest are zeroed
+    pub entries: [EventLogEntry; EVENT_LOG_PAGE_ENTRIES],
 }
 
 // Removed the complex Format impl for AllMeasurements<N>
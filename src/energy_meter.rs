@@ -0,0 +1,278 @@
+//! Energy metering: integrates the existing power readings into input (VBUS × IIN), load
+//! (INA226 power), and battery charged and discharged (BQ76920 current × pack voltage) energy,
+//! and derives efficiencies from them.
+//!
+//! Each device task calls its `record_*` after every successful read, and each channel
+//! integrates over the interval between its own consecutive readings, independent of the USB
+//! connection. The host can reset the counters, and `energy_meter_task` writes them to the config
+//! store periodically; the charge and discharge energy in `battery_gauge` are lifetime totals that
+//! are never reset.
+//!
+//! Energy balance: input + discharged = load + charged + losses.
+//! - Round-trip efficiency = discharged / charged, only meaningful over full cycles;
+//! - Conversion efficiency = (load + charged) / (input + discharged); the rest is lost in the
+//!   UPS power path itself.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::config::{self, ConfigRecord};
+use crate::flash_layout::SharedConfigStore;
+
+/// Longest time one reading counts for, so that a gap in the readings is not integrated at the
+/// last power (ms)
+const MAX_SAMPLE_INTERVAL_MS: u64 = 5000;
+
+/// Interval of writing changed counters to the config store
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Reported when an efficiency can't be computed because its denominator is 0
+pub const EFFICIENCY_UNKNOWN: u16 = u16::MAX;
+
+/// Energy counters and efficiencies, read by `usb_task` and published with the measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct EnergyStatus {
+    /// Input energy (mWh)
+    pub input_mwh: u32,
+    /// Load energy (mWh)
+    pub load_mwh: u32,
+    /// Energy charged into the battery (mWh)
+    pub charged_mwh: u32,
+    /// Energy discharged from the battery (mWh)
+    pub discharged_mwh: u32,
+    /// Round-trip efficiency (0.1 %), `EFFICIENCY_UNKNOWN` if it can't be computed
+    pub round_trip_efficiency_0_1pct: u16,
+    /// Conversion efficiency (0.1 %), `EFFICIENCY_UNKNOWN` if it can't be computed
+    pub conversion_efficiency_0_1pct: u16,
+}
+
+/// Energy counters as saved in the config store
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct EnergyCheckpoint {
+    pub input_mwh: u32,
+    pub load_mwh: u32,
+    pub charged_mwh: u32,
+    pub discharged_mwh: u32,
+}
+
+impl ConfigRecord for EnergyCheckpoint {
+    const KEY: u8 = 0x81;
+    const LEN: usize = 16;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.input_mwh.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.load_mwh.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.charged_mwh.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.discharged_mwh.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        (buf.len() == Self::LEN).then(|| Self {
+            input_mwh: u32_at(0),
+            load_mwh: u32_at(4),
+            charged_mwh: u32_at(8),
+            discharged_mwh: u32_at(12),
+        })
+    }
+}
+
+/// Integration of one power channel
+#[derive(Debug, Default)]
+struct Integrator {
+    last_sample: Option<Instant>,
+}
+
+impl Integrator {
+    /// Time this reading stands for (ms), 0 for the first reading
+    fn interval_ms(&mut self, sampled_at: Instant) -> u64 {
+        let interval_ms = self.last_sample.map_or(0, |t| {
+            sampled_at
+                .saturating_duration_since(t)
+                .as_millis()
+                .min(MAX_SAMPLE_INTERVAL_MS)
+        });
+        self.last_sample = Some(sampled_at);
+        interval_ms
+    }
+}
+
+/// Converts mW × ms to µWh
+fn energy_uwh(power_mw: f32, interval_ms: u64) -> u64 {
+    if power_mw <= 0.0 {
+        return 0;
+    }
+    (power_mw as f64 * interval_ms as f64 / 3600.0) as u64
+}
+
+fn to_mwh(uwh: u64) -> u32 {
+    (uwh / 1000).min(u32::MAX as u64) as u32
+}
+
+fn efficiency_0_1pct(numerator_uwh: u64, denominator_uwh: u64) -> u16 {
+    if denominator_uwh == 0 {
+        return EFFICIENCY_UNKNOWN;
+    }
+    (numerator_uwh as u128 * 1000 / denominator_uwh as u128).min(EFFICIENCY_UNKNOWN as u128 - 1)
+        as u16
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    input_uwh: u64,
+    load_uwh: u64,
+    charged_uwh: u64,
+    discharged_uwh: u64,
+    input: Integrator,
+    load: Integrator,
+    battery: Integrator,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            input_uwh: 0,
+            load_uwh: 0,
+            charged_uwh: 0,
+            discharged_uwh: 0,
+            input: Integrator { last_sample: None },
+            load: Integrator { last_sample: None },
+            battery: Integrator { last_sample: None },
+        }
+    }
+
+    fn status(&self) -> EnergyStatus {
+        EnergyStatus {
+            input_mwh: to_mwh(self.input_uwh),
+            load_mwh: to_mwh(self.load_uwh),
+            charged_mwh: to_mwh(self.charged_uwh),
+            discharged_mwh: to_mwh(self.discharged_uwh),
+            round_trip_efficiency_0_1pct: efficiency_0_1pct(self.discharged_uwh, self.charged_uwh),
+            conversion_efficiency_0_1pct: efficiency_0_1pct(
+                self.load_uwh + self.charged_uwh,
+                self.input_uwh + self.discharged_uwh,
+            ),
+        }
+    }
+}
+
+/// Energy counters shared by the device tasks, `usb_task` and `energy_meter_task`
+pub struct EnergyMeter {
+    counters: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Counters>>,
+}
+
+impl Default for EnergyMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EnergyMeter {
+    pub const fn new() -> Self {
+        Self {
+            counters: blocking_mutex::Mutex::new(RefCell::new(Counters::new())),
+        }
+    }
+
+    /// Records a BQ25730 ADC reading
+    pub fn record_input(&self, vbus_mv: u16, iin_ma: u16, sampled_at: Instant) {
+        let power_mw = vbus_mv as f32 * iin_ma as f32 / 1000.0;
+        self.counters.lock(|counters| {
+            let mut counters = counters.borrow_mut();
+            let interval_ms = counters.input.interval_ms(sampled_at);
+            counters.input_uwh += energy_uwh(power_mw, interval_ms);
+        });
+    }
+
+    /// Records an INA226 load power reading
+    pub fn record_load(&self, power_mw: f32, sampled_at: Instant) {
+        self.counters.lock(|counters| {
+            let mut counters = counters.borrow_mut();
+            let interval_ms = counters.load.interval_ms(sampled_at);
+            counters.load_uwh += energy_uwh(power_mw, interval_ms);
+        });
+    }
+
+    /// Records a BQ76920 reading; a positive `current_ma` is charging
+    pub fn record_battery(&self, current_ma: i32, pack_voltage_mv: i32, sampled_at: Instant) {
+        let power_mw = current_ma as f32 * pack_voltage_mv as f32 / 1000.0;
+        self.counters.lock(|counters| {
+            let mut counters = counters.borrow_mut();
+            let interval_ms = counters.battery.interval_ms(sampled_at);
+            if power_mw >= 0.0 {
+                counters.charged_uwh += energy_uwh(power_mw, interval_ms);
+            } else {
+                counters.discharged_uwh += energy_uwh(-power_mw, interval_ms);
+            }
+        });
+    }
+
+    /// Resets all counters and returns the checkpoint to write to the config store
+    pub fn reset(&self) -> EnergyCheckpoint {
+        defmt::info!("EnergyMeter: counters reset");
+        self.counters.lock(|counters| {
+            let mut counters = counters.borrow_mut();
+            counters.input_uwh = 0;
+            counters.load_uwh = 0;
+            counters.charged_uwh = 0;
+            counters.discharged_uwh = 0;
+        });
+        EnergyCheckpoint::default()
+    }
+
+    pub fn status(&self) -> EnergyStatus {
+        self.counters.lock(|counters| counters.borrow().status())
+    }
+
+    pub fn checkpoint(&self) -> EnergyCheckpoint {
+        let status = self.status();
+        EnergyCheckpoint {
+            input_mwh: status.input_mwh,
+            load_mwh: status.load_mwh,
+            charged_mwh: status.charged_mwh,
+            discharged_mwh: status.discharged_mwh,
+        }
+    }
+
+    /// Adds the saved counters. The device tasks may already have recorded readings since boot, so
+    /// this adds to the counters instead of overwriting them.
+    fn restore(&self, checkpoint: &EnergyCheckpoint) {
+        self.counters.lock(|counters| {
+            let mut counters = counters.borrow_mut();
+            counters.input_uwh += checkpoint.input_mwh as u64 * 1000;
+            counters.load_uwh += checkpoint.load_mwh as u64 * 1000;
+            counters.charged_uwh += checkpoint.charged_mwh as u64 * 1000;
+            counters.discharged_uwh += checkpoint.discharged_mwh as u64 * 1000;
+        });
+    }
+}
+
+/// Embassy task that restores the energy counters from `config_store` at boot and writes
+/// them back every `CHECKPOINT_INTERVAL` while they change.
+#[embassy_executor::task]
+pub async fn energy_meter_task(
+    energy_meter: &'static EnergyMeter,
+    config_store: &'static SharedConfigStore,
+) {
+    let mut last_checkpoint =
+        config::read_record::<EnergyCheckpoint, _>(&mut *config_store.lock().await).await;
+    if let Some(checkpoint) = &last_checkpoint {
+        defmt::info!("EnergyMeter: restored {:?}", checkpoint);
+        energy_meter.restore(checkpoint);
+    }
+
+    loop {
+        Timer::after(CHECKPOINT_INTERVAL).await;
+        let checkpoint = energy_meter.checkpoint();
+        if last_checkpoint == Some(checkpoint) {
+            continue;
+        }
+        if config::save_record(&mut *config_store.lock().await, &checkpoint).await {
+            last_checkpoint = Some(checkpoint);
+        } else {
+            defmt::warn!("EnergyMeter: failed to checkpoint energy counters");
+        }
+    }
+}
@@ -0,0 +1,479 @@
+//! Power event log: a fixed-size ring buffer in on-chip flash.
+//!
+//! Each 32-byte entry holds a sequence number, boot id, uptime, event code and the key
+//! measurements at the time of the event, protected by a CRC-32. Writes only append: writing the
+//! first entry of a page erases that page first, dropping the oldest page of entries. At power-up
+//! the whole partition is scanned and the highest sequence number gives the write position.
+
+use binrw::BinWrite;
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Instant;
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+
+use crate::config::LowBatteryConfig;
+use crate::crc::crc32;
+use crate::data_types::{AllMeasurements, Bq76920Alerts};
+use crate::flash_layout::FlashPartition;
+use crate::shared::{
+    Bq25730AlertsSubscriber, Bq25730MeasurementsSubscriber, Bq76920MeasurementsSubscriber,
+    Ina226MeasurementsSubscriber, PowerEventReceiver, SystemConfigSubscriber,
+};
+use crate::ups_state::{self, UpsStatus};
+
+/// Bytes per entry in flash, a multiple of the 8-byte flash write granularity
+pub const ENTRY_SIZE: usize = 32;
+
+/// Log instance shared by the event log task and the USB task
+pub type SharedEventLog = Mutex<CriticalSectionRawMutex, EventLog<FlashPartition>>;
+
+/// Event codes. The meaning of `detail` depends on the event.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum PowerEventCode {
+    /// Firmware started
+    Reset = 0x01,
+    /// Input power lost
+    AcLost = 0x10,
+    /// Input power restored
+    AcRestored = 0x11,
+    /// Battery started supplying the load
+    EnteredBattery = 0x12,
+    /// Battery stopped supplying the load
+    LeftBattery = 0x13,
+    /// Remaining capacity fell below the limit on battery, detail = remaining capacity (%)
+    LowBattery = 0x14,
+    /// New BQ76920 fault, detail = newly set SYS_STAT bits
+    Bq76920Fault = 0x20,
+    /// New BQ25730 fault, detail = newly set ChargerStatus fault bits
+    Bq25730Fault = 0x21,
+    /// A sensor keeps disagreeing with the others,
+    /// detail = newly set `ConsistencyStatus::faults` bits
+    SensorFault = 0x22,
+    /// The I2C bus was recovered after repeated failures, detail = total recoveries
+    I2cBusRecovery = 0x23,
+    /// Watchdog reset, recorded at boot. detail low byte = the overdue `SupervisedTask`
+    /// (0xFF for an executor stall), high byte = `WatchdogVerdict`
+    WatchdogReset = 0x24,
+    /// The previous run reset after a panic, recorded at boot. detail = line of the panic; uptime
+    /// and measurements in the entry are from before the crash
+    Panic = 0x25,
+    /// INA226 alert: load current above the over-current limit, detail = load current (mA)
+    LoadOverCurrent = 0x26,
+    /// INA226 alert: load power above the over-power limit, detail = load power (W)
+    LoadOverPower = 0x27,
+    /// BQ76920 FET state changed, detail = new CHG_ON/DSG_ON bits
+    FetChange = 0x30,
+    /// Host heartbeat timed out and the load was power-cycled, detail = total trips
+    HostWatchdogTrip = 0x31,
+    /// Sustained overload, the output is about to be switched off, detail = load power (W)
+    OverloadWarning = 0x32,
+    /// Overload protection switched the output off, detail = total trips
+    OverloadTrip = 0x33,
+    /// Configuration changed, detail low byte = USB command byte,
+    /// high byte = `SetConfig` parameter id
+    ConfigChange = 0x40,
+    /// Configuration transaction rolled back, detail = `TransactionOutcome`
+    ConfigRollback = 0x41,
+}
+
+/// Event submitted by a task for recording
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct PowerEvent {
+    pub code: PowerEventCode,
+    pub detail: u16,
+}
+
+impl PowerEvent {
+    pub const fn new(code: PowerEventCode, detail: u16) -> Self {
+        Self { code, detail }
+    }
+}
+
+/// A log entry without its CRC; also the format of USB page reads
+#[derive(Debug, Copy, Clone, PartialEq, Default, BinWrite, defmt::Format)]
+pub struct EventLogEntry {
+    /// Monotonic sequence number, kept across resets
+    pub seq: u32,
+    /// Boot id, incremented on every reset
+    pub boot_id: u16,
+    /// Uptime since this boot (s)
+    pub uptime_s: u32,
+    /// `PowerEventCode`
+    pub code: u8,
+    pub detail: u16,
+    pub soc_pct: u8,
+    pub pack_voltage_mv: u16,
+    /// Positive when charging, negative when discharging
+    pub battery_current_ma: i16,
+    pub vbus_mv: u16,
+    /// Load power in units of 10 mW
+    pub load_power_10mw: u16,
+    pub temperature_0_01c: i16,
+}
+
+impl EventLogEntry {
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut buf = [0u8; ENTRY_SIZE];
+        buf[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.boot_id.to_le_bytes());
+        buf[6..10].copy_from_slice(&self.uptime_s.to_le_bytes());
+        buf[10] = self.code;
+        buf[11] = self.soc_pct;
+        buf[12..14].copy_from_slice(&self.detail.to_le_bytes());
+        buf[14..16].copy_from_slice(&self.pack_voltage_mv.to_le_bytes());
+        buf[16..18].copy_from_slice(&self.battery_current_ma.to_le_bytes());
+        buf[18..20].copy_from_slice(&self.vbus_mv.to_le_bytes());
+        buf[20..22].copy_from_slice(&self.load_power_10mw.to_le_bytes());
+        buf[22..24].copy_from_slice(&self.temperature_0_01c.to_le_bytes());
+        // 24..28 reserved
+        let crc = crc32(&buf[..ENTRY_SIZE - 4]);
+        buf[ENTRY_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; ENTRY_SIZE]) -> Option<Self> {
+        let crc = u32::from_le_bytes([buf[28], buf[29], buf[30], buf[31]]);
+        if crc != crc32(&buf[..ENTRY_SIZE - 4]) {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        Some(Self {
+            seq: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            boot_id: u16_at(4),
+            uptime_s: u32::from_le_bytes([buf[6], buf[7], buf[8], buf[9]]),
+            code: buf[10],
+            soc_pct: buf[11],
+            detail: u16_at(12),
+            pack_voltage_mv: u16_at(14),
+            battery_current_ma: u16_at(16) as i16,
+            vbus_mv: u16_at(18),
+            load_power_10mw: u16_at(20),
+            temperature_0_01c: u16_at(22) as i16,
+        })
+    }
+}
+
+/// Ring event log in flash, generic over `NorFlash` so that it can run on a RAM-backed flash
+pub struct EventLog<F: NorFlash> {
+    flash: F,
+    /// Entries that fit in the partition
+    capacity: u32,
+    next_slot: u32,
+    next_seq: u32,
+    /// Number of valid entries
+    count: u32,
+    boot_id: u16,
+}
+
+impl<F: NorFlash> EventLog<F> {
+    const SLOTS_PER_PAGE: u32 = (F::ERASE_SIZE / ENTRY_SIZE) as u32;
+
+    /// Scans the partition, restores the write position and assigns a new boot id
+    pub async fn new(mut flash: F) -> Self {
+        let capacity = (flash.capacity() / ENTRY_SIZE) as u32;
+        let mut newest: Option<(u32, EventLogEntry)> = None;
+        let mut count = 0;
+        for slot in 0..capacity {
+            if let Some(entry) = Self::read_slot(&mut flash, slot).await {
+                count += 1;
+                if newest.is_none_or(|(_, n)| entry.seq > n.seq) {
+                    newest = Some((slot, entry));
+                }
+            }
+        }
+
+        let (next_slot, next_seq, boot_id) = match newest {
+            Some((slot, entry)) => (
+                (slot + 1) % capacity,
+                entry.seq.wrapping_add(1),
+                entry.boot_id.wrapping_add(1),
+            ),
+            None => (0, 0, 0),
+        };
+        defmt::info!(
+            "EventLog: {} valid entries, next seq {}, boot id {}",
+            count,
+            next_seq,
+            boot_id
+        );
+
+        Self {
+            flash,
+            capacity,
+            next_slot,
+            next_seq,
+            count,
+            boot_id,
+        }
+    }
+
+    async fn read_raw(flash: &mut F, slot: u32) -> Option<[u8; ENTRY_SIZE]> {
+        let mut buf = [0u8; ENTRY_SIZE];
+        match flash.read(slot * ENTRY_SIZE as u32, &mut buf).await {
+            Ok(()) => Some(buf),
+            Err(e) => {
+                defmt::error!("EventLog: flash read failed: {:?}", defmt::Debug2Format(&e));
+                None
+            }
+        }
+    }
+
+    async fn read_slot(flash: &mut F, slot: u32) -> Option<EventLogEntry> {
+        let buf = Self::read_raw(flash, slot).await?;
+        if buf.iter().all(|b| *b == 0xFF) {
+            return None;
+        }
+        EventLogEntry::decode(&buf)
+    }
+
+    async fn erase_page_of(&mut self, slot: u32) -> Result<(), F::Error> {
+        let page_start = slot - slot % Self::SLOTS_PER_PAGE;
+        let from = page_start * ENTRY_SIZE as u32;
+        self.flash.erase(from, from + F::ERASE_SIZE as u32).await?;
+        self.count = self.count.min(self.capacity - Self::SLOTS_PER_PAGE);
+        Ok(())
+    }
+
+    /// Appends an entry; `seq` and `boot_id` are filled in by the log
+    pub async fn append(&mut self, mut entry: EventLogEntry) -> Result<(), F::Error> {
+        entry.seq = self.next_seq;
+        entry.boot_id = self.boot_id;
+
+        if self.next_slot % Self::SLOTS_PER_PAGE == 0 {
+            self.erase_page_of(self.next_slot).await?;
+        } else if Self::read_raw(&mut self.flash, self.next_slot)
+            .await
+            .is_none_or(|raw| raw.iter().any(|b| *b != 0xFF))
+        {
+            // The write position is not blank (e.g. power was lost mid-write), start over on
+            // the next page
+            defmt::warn!(
+                "EventLog: slot {} not blank, skipping to next page",
+                self.next_slot
+            );
+            let next_page = (self.next_slot / Self::SLOTS_PER_PAGE + 1) * Self::SLOTS_PER_PAGE;
+            self.next_slot = next_page % self.capacity;
+            self.erase_page_of(self.next_slot).await?;
+        }
+
+        self.flash
+            .write(self.next_slot * ENTRY_SIZE as u32, &entry.encode())
+            .await?;
+        self.next_slot = (self.next_slot + 1) % self.capacity;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.count = (self.count + 1).min(self.capacity);
+        Ok(())
+    }
+
+    /// Number of valid entries
+    pub fn len(&self) -> u32 {
+        self.count
+    }
+
+    /// Reads the `index`-th newest entry (0 is the newest)
+    pub async fn read_newest(&mut self, index: u32) -> Option<EventLogEntry> {
+        if index >= self.count {
+            return None;
+        }
+        let slot = (self.next_slot + self.capacity - 1 - index) % self.capacity;
+        let entry = Self::read_slot(&mut self.flash, slot).await?;
+        // A gap in the sequence means the entry is stale (a damaged page was skipped)
+        (entry.seq == self.next_seq.wrapping_sub(1 + index)).then_some(entry)
+    }
+}
+
+/// Compares consecutive measurements and generates state change events
+#[derive(Default)]
+struct EventDetector {
+    previous: Option<UpsStatus>,
+    bq76920_status_bits: u8,
+    mos_status_bits: u8,
+    bq25730_fault_bits: u8,
+}
+
+impl EventDetector {
+    fn update(
+        &mut self,
+        measurements: &AllMeasurements<5>,
+        low_battery: &LowBatteryConfig,
+        events: &mut Vec<PowerEvent, 8>,
+    ) {
+        let status = ups_state::from_measurements(measurements, low_battery);
+        let core = &measurements.bq76920.core_measurements;
+        let bq76920_status_bits = core.system_status.0.bits();
+        let mos_status_bits = core.mos_status.0.bits();
+        let bq25730_fault_bits = measurements
+            .bq25730_alerts
+            .charger_status
+            .fault_flags
+            .bits();
+
+        let mut push = |code, detail| {
+            if events.push(PowerEvent::new(code, detail)).is_err() {
+                defmt::warn!(
+                    "EventLog: too many events in one cycle, dropping {:?}",
+                    code
+                );
+            }
+        };
+
+        if let Some(previous) = self.previous {
+            let on_battery = !status.ac_present && status.discharging;
+            let was_on_battery = !previous.ac_present && previous.discharging;
+
+            if previous.ac_present && !status.ac_present {
+                push(PowerEventCode::AcLost, 0);
+            } else if !previous.ac_present && status.ac_present {
+                push(PowerEventCode::AcRestored, 0);
+            }
+            if on_battery && !was_on_battery {
+                push(PowerEventCode::EnteredBattery, 0);
+            } else if !on_battery && was_on_battery {
+                push(PowerEventCode::LeftBattery, 0);
+            }
+            if !status.ac_present
+                && status.below_remaining_capacity_limit
+                && !(previous.below_remaining_capacity_limit && !previous.ac_present)
+            {
+                push(
+                    PowerEventCode::LowBattery,
+                    status.remaining_capacity_pct as u16,
+                );
+            }
+            if mos_status_bits != self.mos_status_bits {
+                push(PowerEventCode::FetChange, mos_status_bits as u16);
+            }
+        }
+
+        // The status flags are cleared after every read, so any set bit is a new event
+        if bq76920_status_bits != 0 {
+            push(PowerEventCode::Bq76920Fault, bq76920_status_bits as u16);
+        }
+        let new_bq25730_faults = bq25730_fault_bits & !self.bq25730_fault_bits;
+        if new_bq25730_faults != 0 {
+            push(PowerEventCode::Bq25730Fault, new_bq25730_faults as u16);
+        }
+
+        self.previous = Some(status);
+        self.bq76920_status_bits = bq76920_status_bits;
+        self.mos_status_bits = mos_status_bits;
+        self.bq25730_fault_bits = bq25730_fault_bits;
+    }
+}
+
+/// Builds an entry from the measurements; sequence number and boot id are filled in on append
+pub fn snapshot_entry(
+    event: PowerEvent,
+    measurements: &AllMeasurements<5>,
+    low_battery: &LowBatteryConfig,
+) -> EventLogEntry {
+    let status = ups_state::from_measurements(measurements, low_battery);
+    EventLogEntry {
+        uptime_s: Instant::now().as_secs() as u32,
+        code: event.code as u8,
+        detail: event.detail,
+        soc_pct: status.remaining_capacity_pct,
+        pack_voltage_mv: status.pack_voltage_mv,
+        battery_current_ma: status.battery_current_ma,
+        vbus_mv: measurements.bq25730.adc_measurements.vbus.0,
+        load_power_10mw: (measurements.ina226.power / 10.0).clamp(0.0, u16::MAX as f32) as u16,
+        temperature_0_01c: status.temperature_0_01c,
+        ..Default::default()
+    }
+}
+
+async fn record(
+    event_log: &SharedEventLog,
+    event: PowerEvent,
+    measurements: &AllMeasurements<5>,
+    low_battery: &LowBatteryConfig,
+) {
+    defmt::info!("EventLog: recording {:?}", event);
+    append(event_log, snapshot_entry(event, measurements, low_battery)).await;
+}
+
+async fn append(event_log: &SharedEventLog, entry: EventLogEntry) {
+    if let Err(e) = event_log.lock().await.append(entry).await {
+        defmt::error!(
+            "EventLog: failed to append entry: {:?}",
+            defmt::Debug2Format(&e)
+        );
+    }
+}
+
+/// Embassy task that records power events into the flash event log.
+///
+/// State transitions (AC lost/restored, battery operation, low battery, faults and FET
+/// changes) are derived from the device measurements directly, so events are recorded
+/// whether or not a USB host is connected. Other tasks submit explicit events
+/// (configuration changes, watchdog trips) via `power_event_receiver`. Low-battery
+/// thresholds follow live configuration updates from `system_config_subscriber`.
+/// `crash_entry`, the record of a panic in the previous run, is appended after the reset entry.
+#[embassy_executor::task]
+pub async fn event_log_task(
+    event_log: &'static SharedEventLog,
+    power_event_receiver: PowerEventReceiver<'static>,
+    mut bq76920_measurements_subscriber: Bq76920MeasurementsSubscriber<'static, 5>,
+    mut bq25730_measurements_subscriber: Bq25730MeasurementsSubscriber<'static>,
+    mut ina226_measurements_subscriber: Ina226MeasurementsSubscriber<'static>,
+    mut bq25730_alerts_subscriber: Bq25730AlertsSubscriber<'static>,
+    mut system_config_subscriber: SystemConfigSubscriber<'static>,
+    mut low_battery: LowBatteryConfig,
+    crash_entry: Option<EventLogEntry>,
+) {
+    defmt::info!("Event log task started.");
+
+    let mut measurements = AllMeasurements::<5>::default();
+    let mut detector = EventDetector::default();
+
+    record(
+        event_log,
+        PowerEvent::new(PowerEventCode::Reset, 0),
+        &measurements,
+        &low_battery,
+    )
+    .await;
+    if let Some(entry) = crash_entry {
+        defmt::info!("EventLog: recording panic of the previous run");
+        append(event_log, entry).await;
+    }
+
+    loop {
+        while let Some(config) = system_config_subscriber.try_next_message_pure() {
+            low_battery = config.low_battery;
+        }
+
+        match select(
+            power_event_receiver.receive(),
+            bq76920_measurements_subscriber.next_message_pure(),
+        )
+        .await
+        {
+            Either::First(event) => record(event_log, event, &measurements, &low_battery).await,
+            Either::Second(bq76920) => {
+                while let Some(m) = bq25730_measurements_subscriber.try_next_message_pure() {
+                    measurements.bq25730 = m;
+                }
+                while let Some(m) = ina226_measurements_subscriber.try_next_message_pure() {
+                    measurements.ina226 = m;
+                }
+                while let Some(m) = bq25730_alerts_subscriber.try_next_message_pure() {
+                    measurements.bq25730_alerts = m;
+                }
+                measurements.bq76920_alerts = Bq76920Alerts {
+                    system_status: bq76920.core_measurements.system_status,
+                };
+                measurements.bq76920 = bq76920;
+
+                let mut events = Vec::new();
+                detector.update(&measurements, &low_battery, &mut events);
+                for event in events {
+                    record(event_log, event, &measurements, &low_battery).await;
+                }
+            }
+        }
+    }
+}
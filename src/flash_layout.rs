@@ -0,0 +1,34 @@
+//! Partition layout of the on-chip flash.
+//!
+//! `memory.x` gives the firmware only the first 112K of flash; the last 16K is reserved for the
+//! persistent data below.
+//! Offsets are relative to the start of flash (0x0800_0000) and sizes must be whole pages.
+
+use embassy_embedded_hal::adapter::BlockingAsync;
+use embassy_embedded_hal::flash::partition::Partition;
+use embassy_stm32::flash::{Blocking, Flash};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+
+use crate::config_store::ConfigStore;
+
+/// Flash page (erase unit) size of the STM32G431
+pub const FLASH_PAGE_SIZE: u32 = 2048;
+
+/// Power event log
+pub const EVENT_LOG_OFFSET: u32 = 0x1C000;
+pub const EVENT_LOG_SIZE: u32 = 4 * FLASH_PAGE_SIZE;
+
+/// Key-value config store
+pub const CONFIG_STORE_OFFSET: u32 = 0x1E000;
+pub const CONFIG_STORE_SIZE: u32 = 4 * FLASH_PAGE_SIZE;
+
+/// Flash driver shared by the partitions
+pub type SharedFlash = Mutex<CriticalSectionRawMutex, BlockingAsync<Flash<'static, Blocking>>>;
+
+/// One partition of the flash
+pub type FlashPartition =
+    Partition<'static, CriticalSectionRawMutex, BlockingAsync<Flash<'static, Blocking>>>;
+
+/// Config store opened by `main` and shared by the tasks
+pub type SharedConfigStore = Mutex<CriticalSectionRawMutex, ConfigStore<FlashPartition>>;
@@ -0,0 +1,244 @@
+//! Device probe at boot: looks for the BQ76920, BQ25730 and INA226 on I2C1 and reads their
+//! identification registers to decide which device tasks to start and which mode the system
+//! runs in. The probe result is reported over USB.
+//!
+//! `OperatingMode` describes the degraded modes for missing devices. The INA226 only measures the
+//! load power, so without it the mode is unchanged; only the run time estimate and the load-side
+//! consistency check are unavailable.
+
+use defmt::{info, warn};
+use embassy_time::{Duration, Timer};
+use embedded_hal_async::i2c::I2c;
+
+use crate::i2c_supervisor::{I2cBus, I2cDeviceId, I2cSupervisor, SupervisedI2c};
+
+/// Addresses used by the different BQ76920 part numbers
+const BQ76920_ADDRESSES: [u8; 2] = [0x08, 0x18];
+const BQ25730_ADDRESS: u8 = 0x6B;
+const INA226_ADDRESS: u8 = 0x40;
+
+/// The BQ76920 has no identification register; reading back SYS_CTRL1/SYS_CTRL2 tells whether
+/// the part adds a CRC
+const BQ76920_REG_SYS_CTRL1: u8 = 0x04;
+const BQ76920_REG_SYS_CTRL2: u8 = 0x05;
+
+/// BQ25730 ManufacturerID register, directly followed by DeviceID
+const BQ25730_REG_MANUFACTURER_ID: u8 = 0x2E;
+const BQ25730_MANUFACTURER_ID: u8 = 0x40;
+
+const INA226_REG_MANUFACTURER_ID: u8 = 0xFE;
+const INA226_REG_DIE_ID: u8 = 0xFF;
+/// "TI"
+const INA226_MANUFACTURER_ID: u16 = 0x5449;
+/// Upper 12 bits of the die ID; the lower 4 bits are the revision
+const INA226_DEVICE_ID: u16 = 0x226;
+
+/// Attempts per address, since a device may not be ready right after power-up. The failures when
+/// neither BQ76920 address answers must stay below the bus recovery threshold
+const PROBE_ATTEMPTS: u8 = 2;
+const PROBE_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// Probe outcome
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum ChipStatus {
+    /// Nothing answered at the address
+    #[default]
+    Missing = 0,
+    Present = 1,
+    /// The address answered, but the identification registers do not match or the part is not
+    /// supported by the firmware
+    Unsupported = 2,
+}
+
+/// Probe result of one device
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct ChipInfo {
+    pub status: ChipStatus,
+    /// Address that answered, or the expected address if none did
+    pub address: u8,
+    /// Raw identification registers: ManufacturerID/DeviceID for the BQ25730, the die ID for the
+    /// INA226 and SYS_CTRL1/SYS_CTRL2 for the BQ76920
+    pub id: u16,
+}
+
+impl ChipInfo {
+    pub fn present(&self) -> bool {
+        self.status == ChipStatus::Present
+    }
+}
+
+/// Operating mode with missing devices
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum OperatingMode {
+    /// Both the battery monitor and the charger are present
+    #[default]
+    Full = 0,
+    /// No charger: monitor the battery and control the load output only; no charging and no input
+    /// supply detection
+    MonitorOnly = 1,
+    /// No usable battery monitor: the charger is only a power path, charging is not allowed and
+    /// the load output cannot be switched
+    ChargerOnly = 2,
+    /// Neither is available; only USB communication works
+    NoDevices = 3,
+}
+
+/// Hardware found by the boot probe
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct HardwareInventory {
+    pub bq76920: ChipInfo,
+    /// BQ76920 reads carry a CRC. The firmware only supports the CRC part numbers
+    pub bq76920_crc: bool,
+    pub bq25730: ChipInfo,
+    pub ina226: ChipInfo,
+}
+
+impl HardwareInventory {
+    pub fn mode(&self) -> OperatingMode {
+        match (self.bq76920.present(), self.bq25730.present()) {
+            (true, true) => OperatingMode::Full,
+            (true, false) => OperatingMode::MonitorOnly,
+            (false, true) => OperatingMode::ChargerOnly,
+            (false, false) => OperatingMode::NoDevices,
+        }
+    }
+}
+
+/// BQ76920 CRC-8 (polynomial 0x07, initial value 0)
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Reads registers with retries; returns `None` if every attempt fails
+async fn read_registers<BUS: I2c, const LEN: usize>(
+    i2c: &mut SupervisedI2c<'_, BUS>,
+    address: u8,
+    register: u8,
+) -> Option<[u8; LEN]> {
+    for attempt in 0..PROBE_ATTEMPTS {
+        if attempt > 0 {
+            Timer::after(PROBE_RETRY_DELAY).await;
+        }
+        let mut buf = [0u8; LEN];
+        if i2c.write_read(address, &[register], &mut buf).await.is_ok() {
+            return Some(buf);
+        }
+    }
+    None
+}
+
+/// Parts with CRC append a CRC to every data byte, the first one also covering the read address
+/// byte, while parts without CRC return the next register instead. The part only counts as
+/// having CRC when two consecutive registers check out.
+async fn probe_bq76920<BUS: I2c>(i2c: &mut SupervisedI2c<'_, BUS>) -> (ChipInfo, bool) {
+    for address in BQ76920_ADDRESSES {
+        let Some(ctrl1) = read_registers::<_, 2>(i2c, address, BQ76920_REG_SYS_CTRL1).await else {
+            continue;
+        };
+        let ctrl2 = read_registers::<_, 2>(i2c, address, BQ76920_REG_SYS_CTRL2).await;
+        let read_address = (address << 1) | 1;
+        let crc = ctrl2.is_some_and(|ctrl2| {
+            ctrl1[1] == crc8(&[read_address, ctrl1[0]])
+                && ctrl2[1] == crc8(&[read_address, ctrl2[0]])
+        });
+        let info = ChipInfo {
+            status: if crc {
+                ChipStatus::Present
+            } else {
+                ChipStatus::Unsupported
+            },
+            address,
+            id: u16::from_be_bytes([ctrl1[0], ctrl2.map_or(ctrl1[1], |c| c[0])]),
+        };
+        return (info, crc);
+    }
+    (
+        ChipInfo {
+            address: BQ76920_ADDRESSES[0],
+            ..Default::default()
+        },
+        false,
+    )
+}
+
+async fn probe_bq25730<BUS: I2c>(i2c: &mut SupervisedI2c<'_, BUS>) -> ChipInfo {
+    let mut info = ChipInfo {
+        address: BQ25730_ADDRESS,
+        ..Default::default()
+    };
+    if let Some(ids) =
+        read_registers::<_, 2>(i2c, BQ25730_ADDRESS, BQ25730_REG_MANUFACTURER_ID).await
+    {
+        info.id = u16::from_be_bytes(ids);
+        info.status = if ids[0] == BQ25730_MANUFACTURER_ID {
+            ChipStatus::Present
+        } else {
+            ChipStatus::Unsupported
+        };
+    }
+    info
+}
+
+async fn probe_ina226<BUS: I2c>(i2c: &mut SupervisedI2c<'_, BUS>) -> ChipInfo {
+    let mut info = ChipInfo {
+        address: INA226_ADDRESS,
+        ..Default::default()
+    };
+    let Some(manufacturer) =
+        read_registers::<_, 2>(i2c, INA226_ADDRESS, INA226_REG_MANUFACTURER_ID).await
+    else {
+        return info;
+    };
+    let die = read_registers::<_, 2>(i2c, INA226_ADDRESS, INA226_REG_DIE_ID).await;
+    info.id = die.map_or(0, u16::from_be_bytes);
+    info.status = if u16::from_be_bytes(manufacturer) == INA226_MANUFACTURER_ID
+        && info.id >> 4 == INA226_DEVICE_ID
+    {
+        ChipStatus::Present
+    } else {
+        ChipStatus::Unsupported
+    };
+    info
+}
+
+/// Probes the devices on the bus. The probe transfers count towards each device's statistics.
+pub async fn probe<BUS: I2c>(bus: &I2cBus<BUS>, supervisor: &I2cSupervisor) -> HardwareInventory {
+    let mut bq76920_i2c = SupervisedI2c::new(bus, supervisor, I2cDeviceId::Bq76920);
+    let mut bq25730_i2c = SupervisedI2c::new(bus, supervisor, I2cDeviceId::Bq25730);
+    let mut ina226_i2c = SupervisedI2c::new(bus, supervisor, I2cDeviceId::Ina226);
+    let (bq76920, bq76920_crc) = probe_bq76920(&mut bq76920_i2c).await;
+    let bq25730 = probe_bq25730(&mut bq25730_i2c).await;
+    let ina226 = probe_ina226(&mut ina226_i2c).await;
+
+    let inventory = HardwareInventory {
+        bq76920,
+        bq76920_crc,
+        bq25730,
+        ina226,
+    };
+    info!("Hardware probe: {:?}", inventory);
+    if bq76920.status == ChipStatus::Unsupported {
+        warn!(
+            "BQ76920 at {:#04x} does not use CRC, only CRC variants are supported",
+            bq76920.address
+        );
+    }
+    match inventory.mode() {
+        OperatingMode::Full => {}
+        mode => warn!("Running in degraded mode {:?}", mode),
+    }
+    inventory
+}
@@ -0,0 +1,484 @@
+//! HID Power Device Class report descriptor and report encoding.
+//!
+//! Describes the UPS state with the standard Power Device (0x84) and Battery System (0x85)
+//! usage pages. The USB side (`usb::hid_power`) serves these reports on the interrupt and
+//! control endpoints.
+
+use crate::battery_profile;
+use crate::ups_status::UpsStatus;
+
+pub const REPORT_ID_PRESENT_STATUS: u8 = 0x01;
+pub const REPORT_ID_REMAINING_CAPACITY: u8 = 0x02;
+pub const REPORT_ID_RUN_TIME_TO_EMPTY: u8 = 0x03;
+pub const REPORT_ID_VOLTAGE: u8 = 0x04;
+pub const REPORT_ID_CURRENT: u8 = 0x05;
+pub const REPORT_ID_TEMPERATURE: u8 = 0x06;
+pub const REPORT_ID_CAPACITY_INFO: u8 = 0x07;
+
+/// Maximum length of any report, including the report ID
+pub const MAX_REPORT_SIZE: usize = 8;
+
+/// Input reports sent on the interrupt endpoint when their content changes
+pub const INPUT_REPORT_IDS: [u8; 6] = [
+    REPORT_ID_PRESENT_STATUS,
+    REPORT_ID_REMAINING_CAPACITY,
+    REPORT_ID_RUN_TIME_TO_EMPTY,
+    REPORT_ID_VOLTAGE,
+    REPORT_ID_CURRENT,
+    REPORT_ID_TEMPERATURE,
+];
+
+// PresentStatus bits, in the order of the usages in the report descriptor
+pub const STATUS_AC_PRESENT: u8 = 1 << 0;
+pub const STATUS_CHARGING: u8 = 1 << 1;
+pub const STATUS_DISCHARGING: u8 = 1 << 2;
+pub const STATUS_BELOW_REMAINING_CAPACITY_LIMIT: u8 = 1 << 3;
+pub const STATUS_FULLY_CHARGED: u8 = 1 << 4;
+pub const STATUS_NEED_REPLACEMENT: u8 = 1 << 5;
+pub const STATUS_SHUTDOWN_IMMINENT: u8 = 1 << 6;
+pub const STATUS_OVERLOAD: u8 = 1 << 7;
+
+/// CapacityMode 2: capacities are given in percent
+const CAPACITY_MODE_PERCENT: u8 = 2;
+
+#[rustfmt::skip]
+pub const REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x84,                   // Usage Page (Power Device)
+    0x09, 0x04,                   // Usage (UPS)
+    0xA1, 0x01,                   // Collection (Application)
+    0x09, 0x24,                   //   Usage (PowerSummary)
+    0xA1, 0x00,                   //   Collection (Physical)
+
+    // Report 1: PresentStatus (8 x 1 bit)
+    0x85, REPORT_ID_PRESENT_STATUS, // Report ID
+    0x09, 0x02,                   //     Usage (PresentStatus)
+    0xA1, 0x02,                   //     Collection (Logical)
+    0x15, 0x00,                   //       Logical Minimum (0)
+    0x25, 0x01,                   //       Logical Maximum (1)
+    0x75, 0x01,                   //       Report Size (1)
+    0x95, 0x06,                   //       Report Count (6)
+    0x05, 0x85,                   //       Usage Page (Battery System)
+    0x09, 0xD0,                   //       Usage (ACPresent)
+    0x09, 0x44,                   //       Usage (Charging)
+    0x09, 0x45,                   //       Usage (Discharging)
+    0x09, 0x42,                   //       Usage (BelowRemainingCapacityLimit)
+    0x09, 0x46,                   //       Usage (FullyCharged)
+    0x09, 0x4B,                   //       Usage (NeedReplacement)
+    0x81, 0x02,                   //       Input (Data,Var,Abs)
+    0x09, 0xD0,                   //       Usage (ACPresent)
+    0x09, 0x44,                   //       Usage (Charging)
+    0x09, 0x45,                   //       Usage (Discharging)
+    0x09, 0x42,                   //       Usage (BelowRemainingCapacityLimit)
+    0x09, 0x46,                   //       Usage (FullyCharged)
+    0x09, 0x4B,                   //       Usage (NeedReplacement)
+    0xB1, 0xA2,                   //       Feature (Data,Var,Abs,NoPref,Volatile)
+    0x95, 0x02,                   //       Report Count (2)
+    0x05, 0x84,                   //       Usage Page (Power Device)
+    0x09, 0x69,                   //       Usage (ShutdownImminent)
+    0x09, 0x65,                   //       Usage (Overload)
+    0x81, 0x02,                   //       Input (Data,Var,Abs)
+    0x09, 0x69,                   //       Usage (ShutdownImminent)
+    0x09, 0x65,                   //       Usage (Overload)
+    0xB1, 0xA2,                   //       Feature (Data,Var,Abs,NoPref,Volatile)
+    0xC0,                         //     End Collection
+
+    // Report 2: RemainingCapacity (u8, %)
+    0x05, 0x85,                   //     Usage Page (Battery System)
+    0x85, REPORT_ID_REMAINING_CAPACITY, // Report ID
+    0x75, 0x08,                   //     Report Size (8)
+    0x95, 0x01,                   //     Report Count (1)
+    0x25, 0x64,                   //     Logical Maximum (100)
+    0x09, 0x66,                   //     Usage (RemainingCapacity)
+    0x81, 0x02,                   //     Input (Data,Var,Abs)
+    0x09, 0x66,                   //     Usage (RemainingCapacity)
+    0xB1, 0xA2,                   //     Feature (Data,Var,Abs,NoPref,Volatile)
+
+    // Report 3: RunTimeToEmpty (u16, s)
+    0x85, REPORT_ID_RUN_TIME_TO_EMPTY, // Report ID
+    0x75, 0x10,                   //     Report Size (16)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, //     Logical Maximum (65535)
+    0x66, 0x01, 0x10,             //     Unit (SI Lin: Time, s)
+    0x55, 0x00,                   //     Unit Exponent (0)
+    0x09, 0x68,                   //     Usage (RunTimeToEmpty)
+    0x81, 0x02,                   //     Input (Data,Var,Abs)
+    0x09, 0x68,                   //     Usage (RunTimeToEmpty)
+    0xB1, 0xA2,                   //     Feature (Data,Var,Abs,NoPref,Volatile)
+
+    // Report 4: Voltage (u16, mV)
+    0x05, 0x84,                   //     Usage Page (Power Device)
+    0x85, REPORT_ID_VOLTAGE,      //     Report ID
+    0x67, 0x21, 0xD1, 0xF0, 0x00, //     Unit (SI Lin: Volt)
+    0x55, 0x04,                   //     Unit Exponent (4), 1 LSB = 1 mV
+    0x09, 0x30,                   //     Usage (Voltage)
+    0x81, 0x02,                   //     Input (Data,Var,Abs)
+    0x09, 0x30,                   //     Usage (Voltage)
+    0xB1, 0xA2,                   //     Feature (Data,Var,Abs,NoPref,Volatile)
+
+    // Report 5: Current (i16, mA)
+    0x85, REPORT_ID_CURRENT,      //     Report ID
+    0x16, 0x00, 0x80,             //     Logical Minimum (-32768)
+    0x26, 0xFF, 0x7F,             //     Logical Maximum (32767)
+    0x67, 0x01, 0x00, 0x10, 0x00, //     Unit (SI Lin: Ampere)
+    0x55, 0x0D,                   //     Unit Exponent (-3), 1 LSB = 1 mA
+    0x09, 0x31,                   //     Usage (Current)
+    0x81, 0x02,                   //     Input (Data,Var,Abs)
+    0x09, 0x31,                   //     Usage (Current)
+    0xB1, 0xA2,                   //     Feature (Data,Var,Abs,NoPref,Volatile)
+
+    // Report 6: Temperature (u16, 0.1 K)
+    0x85, REPORT_ID_TEMPERATURE,  //     Report ID
+    0x15, 0x00,                   //     Logical Minimum (0)
+    0x27, 0xFF, 0xFF, 0x00, 0x00, //     Logical Maximum (65535)
+    0x67, 0x01, 0x00, 0x01, 0x00, //     Unit (SI Lin: Kelvin)
+    0x55, 0x0F,                   //     Unit Exponent (-1), 1 LSB = 0.1 K
+    0x09, 0x36,                   //     Usage (Temperature)
+    0x81, 0x02,                   //     Input (Data,Var,Abs)
+    0x09, 0x36,                   //     Usage (Temperature)
+    0xB1, 0xA2,                   //     Feature (Data,Var,Abs,NoPref,Volatile)
+    0x65, 0x00,                   //     Unit (None)
+    0x55, 0x00,                   //     Unit Exponent (0)
+
+    // Report 7: Capacity information (6 x u8, Feature only)
+    0x05, 0x85,                   //     Usage Page (Battery System)
+    0x85, REPORT_ID_CAPACITY_INFO, //    Report ID
+    0x75, 0x08,                   //     Report Size (8)
+    0x95, 0x06,                   //     Report Count (6)
+    0x26, 0xFF, 0x00,             //     Logical Maximum (255)
+    0x09, 0x2C,                   //     Usage (CapacityMode)
+    0x09, 0x83,                   //     Usage (DesignCapacity)
+    0x09, 0x67,                   //     Usage (FullChargeCapacity)
+    0x09, 0x29,                   //     Usage (RemainingCapacityLimit)
+    0x09, 0x8C,                   //     Usage (WarningCapacityLimit)
+    0x09, 0x8B,                   //     Usage (Rechargable)
+    0xB1, 0xA2,                   //     Feature (Data,Var,Abs,NoPref,Volatile)
+
+    0xC0,                         //   End Collection
+    0xC0,                         // End Collection
+];
+
+/// Encodes the UPS status as the PresentStatus bitmap.
+pub fn present_status_bits(status: &UpsStatus) -> u8 {
+    let mut bits = 0;
+    if status.ac_present {
+        bits |= STATUS_AC_PRESENT;
+    }
+    if status.charging {
+        bits |= STATUS_CHARGING;
+    }
+    if status.discharging {
+        bits |= STATUS_DISCHARGING;
+    }
+    if status.below_remaining_capacity_limit {
+        bits |= STATUS_BELOW_REMAINING_CAPACITY_LIMIT;
+    }
+    if status.fully_charged {
+        bits |= STATUS_FULLY_CHARGED;
+    }
+    if status.need_replacement {
+        bits |= STATUS_NEED_REPLACEMENT;
+    }
+    if status.shutdown_imminent {
+        bits |= STATUS_SHUTDOWN_IMMINENT;
+    }
+    if status.overload {
+        bits |= STATUS_OVERLOAD;
+    }
+    bits
+}
+
+/// Encodes the report `report_id` into `buf`, report ID first, and returns its length.
+///
+/// Without a `status` (no measurements received yet) only the capacity information can be
+/// encoded.
+pub fn encode_report(report_id: u8, status: Option<&UpsStatus>, buf: &mut [u8]) -> Option<usize> {
+    let mut payload = [0u8; MAX_REPORT_SIZE - 1];
+    let len = match (report_id, status) {
+        (REPORT_ID_CAPACITY_INFO, _) => {
+            let (full_charge_capacity, remaining_limit, warning_limit) = status.map_or(
+                (
+                    100,
+                    battery_profile::REMAINING_CAPACITY_LIMIT_PCT,
+                    battery_profile::WARNING_CAPACITY_LIMIT_PCT,
+                ),
+                |s| {
+                    (
+                        s.full_charge_capacity_pct,
+                        s.remaining_capacity_limit_pct,
+                        s.warning_capacity_limit_pct,
+                    )
+                },
+            );
+            payload[..6].copy_from_slice(&[
+                CAPACITY_MODE_PERCENT,
+                100,
+                full_charge_capacity,
+                remaining_limit,
+                warning_limit,
+                1,
+            ]);
+            6
+        }
+        (REPORT_ID_PRESENT_STATUS, Some(s)) => {
+            payload[0] = present_status_bits(s);
+            1
+        }
+        (REPORT_ID_REMAINING_CAPACITY, Some(s)) => {
+            payload[0] = s.remaining_capacity_pct.min(100);
+            1
+        }
+        (REPORT_ID_RUN_TIME_TO_EMPTY, Some(s)) => {
+            payload[..2].copy_from_slice(&s.run_time_to_empty_s.to_le_bytes());
+            2
+        }
+        (REPORT_ID_VOLTAGE, Some(s)) => {
+            payload[..2].copy_from_slice(&s.pack_voltage_mv.to_le_bytes());
+            2
+        }
+        (REPORT_ID_CURRENT, Some(s)) => {
+            payload[..2].copy_from_slice(&s.battery_current_ma.to_le_bytes());
+            2
+        }
+        (REPORT_ID_TEMPERATURE, Some(s)) => {
+            // 0.01 °C -> 0.1 K
+            let deci_kelvin = (s.temperature_0_01c as i32 / 10 + 2732).clamp(0, u16::MAX as i32);
+            payload[..2].copy_from_slice(&(deci_kelvin as u16).to_le_bytes());
+            2
+        }
+        _ => return None,
+    };
+
+    if buf.len() < len + 1 {
+        return None;
+    }
+    buf[0] = report_id;
+    buf[1..=len].copy_from_slice(&payload[..len]);
+    Some(len + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_status() -> UpsStatus {
+        UpsStatus {
+            ac_present: true,
+            charging: true,
+            discharging: false,
+            fully_charged: false,
+            below_remaining_capacity_limit: false,
+            shutdown_imminent: false,
+            overload: false,
+            need_replacement: false,
+            discharge_fet_on: true,
+            remaining_capacity_pct: 57,
+            run_time_to_empty_s: 5400,
+            full_charge_capacity_pct: 93,
+            pack_voltage_mv: 16_250,
+            battery_current_ma: -1234,
+            temperature_0_01c: 2500,
+            remaining_capacity_limit_pct: 15,
+            warning_capacity_limit_pct: 35,
+        }
+    }
+
+    /// Data bits of each report in the descriptor, split into input and feature items
+    #[derive(Debug, Default, Clone, Copy)]
+    struct ReportBits {
+        input: u32,
+        feature: u32,
+        /// Whether any main item is declared constant
+        constant: bool,
+    }
+
+    /// Walks the short items of `REPORT_DESCRIPTOR`, returning the bits declared per report ID
+    /// and the final collection depth.
+    fn parse_descriptor() -> ([ReportBits; 8], i32) {
+        let mut reports = [ReportBits::default(); 8];
+        let (mut report_id, mut report_size, mut report_count) = (0usize, 0u32, 0u32);
+        let mut depth = 0;
+        let mut i = 0;
+        while i < REPORT_DESCRIPTOR.len() {
+            let prefix = REPORT_DESCRIPTOR[i];
+            let len = [0, 1, 2, 4][(prefix & 0x03) as usize];
+            let data = REPORT_DESCRIPTOR[i + 1..i + 1 + len]
+                .iter()
+                .rev()
+                .fold(0u32, |acc, &b| (acc << 8) | b as u32);
+            match prefix & 0xFC {
+                0x84 => report_id = data as usize,
+                0x74 => report_size = data,
+                0x94 => report_count = data,
+                0xA0 => depth += 1,
+                0xC0 => depth -= 1,
+                0x80 | 0xB0 => {
+                    let report = &mut reports[report_id];
+                    if prefix & 0xFC == 0x80 {
+                        report.input += report_size * report_count;
+                    } else {
+                        report.feature += report_size * report_count;
+                    }
+                    report.constant |= data & 0x01 != 0;
+                }
+                _ => {}
+            }
+            i += 1 + len;
+        }
+        (reports, depth)
+    }
+
+    #[test]
+    fn descriptor_is_a_ups_application_collection() {
+        assert_eq!(
+            &REPORT_DESCRIPTOR[..6],
+            &[0x05, 0x84, 0x09, 0x04, 0xA1, 0x01]
+        );
+        assert_eq!(
+            &REPORT_DESCRIPTOR[REPORT_DESCRIPTOR.len() - 2..],
+            &[0xC0, 0xC0]
+        );
+        let (_, depth) = parse_descriptor();
+        assert_eq!(depth, 0);
+    }
+
+    #[test]
+    fn descriptor_declares_every_report_as_data() {
+        let (reports, _) = parse_descriptor();
+        for (id, report) in reports.iter().enumerate() {
+            assert!(!report.constant, "report {} has a constant main item", id);
+        }
+    }
+
+    #[test]
+    fn encoded_lengths_match_descriptor() {
+        let (reports, _) = parse_descriptor();
+        let status = sample_status();
+        let mut buf = [0u8; MAX_REPORT_SIZE];
+        for id in INPUT_REPORT_IDS {
+            let len = encode_report(id, Some(&status), &mut buf).unwrap();
+            let report = reports[id as usize];
+            assert_eq!(report.input, report.feature, "report {}", id);
+            assert_eq!((len as u32 - 1) * 8, report.input, "report {}", id);
+            assert_eq!(buf[0], id);
+        }
+
+        let len = encode_report(REPORT_ID_CAPACITY_INFO, Some(&status), &mut buf).unwrap();
+        let capacity = reports[REPORT_ID_CAPACITY_INFO as usize];
+        assert_eq!(capacity.input, 0);
+        assert_eq!((len as u32 - 1) * 8, capacity.feature);
+        assert!(len <= MAX_REPORT_SIZE);
+    }
+
+    #[test]
+    fn present_status_bits_follow_flags() {
+        let mut status = sample_status();
+        assert_eq!(
+            present_status_bits(&status),
+            STATUS_AC_PRESENT | STATUS_CHARGING
+        );
+
+        status = UpsStatus {
+            ac_present: false,
+            charging: false,
+            discharging: true,
+            below_remaining_capacity_limit: true,
+            need_replacement: true,
+            shutdown_imminent: true,
+            overload: true,
+            ..status
+        };
+        assert_eq!(
+            present_status_bits(&status),
+            STATUS_DISCHARGING
+                | STATUS_BELOW_REMAINING_CAPACITY_LIMIT
+                | STATUS_NEED_REPLACEMENT
+                | STATUS_SHUTDOWN_IMMINENT
+                | STATUS_OVERLOAD
+        );
+
+        let full = UpsStatus {
+            fully_charged: true,
+            charging: false,
+            ..sample_status()
+        };
+        assert_eq!(
+            present_status_bits(&full),
+            STATUS_AC_PRESENT | STATUS_FULLY_CHARGED
+        );
+    }
+
+    #[test]
+    fn encodes_measurement_values() {
+        let status = sample_status();
+        let mut buf = [0u8; MAX_REPORT_SIZE];
+        let mut encode = |id| {
+            let len = encode_report(id, Some(&status), &mut buf).unwrap();
+            buf[..len].to_vec()
+        };
+
+        assert_eq!(encode(REPORT_ID_PRESENT_STATUS), [0x01, 0x03]);
+        assert_eq!(encode(REPORT_ID_REMAINING_CAPACITY), [0x02, 57]);
+        assert_eq!(encode(REPORT_ID_RUN_TIME_TO_EMPTY), [0x03, 0x18, 0x15]);
+        assert_eq!(encode(REPORT_ID_VOLTAGE), [0x04, 0x7A, 0x3F]);
+        // -1234 mA in two's complement
+        assert_eq!(encode(REPORT_ID_CURRENT), [0x05, 0x2E, 0xFB]);
+        // 25.00 °C = 298.2 K
+        assert_eq!(encode(REPORT_ID_TEMPERATURE), [0x06, 0xA6, 0x0B]);
+        assert_eq!(
+            encode(REPORT_ID_CAPACITY_INFO),
+            [0x07, CAPACITY_MODE_PERCENT, 100, 93, 15, 35, 1]
+        );
+    }
+
+    #[test]
+    fn clamps_out_of_range_values() {
+        let status = UpsStatus {
+            remaining_capacity_pct: 120,
+            temperature_0_01c: -30_000,
+            ..sample_status()
+        };
+        let mut buf = [0u8; MAX_REPORT_SIZE];
+        encode_report(REPORT_ID_REMAINING_CAPACITY, Some(&status), &mut buf).unwrap();
+        assert_eq!(buf[1], 100);
+        encode_report(REPORT_ID_TEMPERATURE, Some(&status), &mut buf).unwrap();
+        assert_eq!(u16::from_le_bytes([buf[1], buf[2]]), 0);
+    }
+
+    #[test]
+    fn without_status_only_capacity_info_is_available() {
+        let mut buf = [0u8; MAX_REPORT_SIZE];
+        for id in INPUT_REPORT_IDS {
+            assert_eq!(encode_report(id, None, &mut buf), None);
+        }
+        let len = encode_report(REPORT_ID_CAPACITY_INFO, None, &mut buf).unwrap();
+        assert_eq!(
+            buf[..len],
+            [
+                REPORT_ID_CAPACITY_INFO,
+                CAPACITY_MODE_PERCENT,
+                100,
+                100,
+                battery_profile::REMAINING_CAPACITY_LIMIT_PCT,
+                battery_profile::WARNING_CAPACITY_LIMIT_PCT,
+                1,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_reports_and_short_buffers() {
+        let status = sample_status();
+        let mut buf = [0u8; MAX_REPORT_SIZE];
+        assert_eq!(encode_report(0x00, Some(&status), &mut buf), None);
+        assert_eq!(encode_report(0x08, Some(&status), &mut buf), None);
+
+        let mut short = [0u8; 2];
+        assert_eq!(
+            encode_report(REPORT_ID_VOLTAGE, Some(&status), &mut short),
+            None
+        );
+        assert_eq!(
+            encode_report(REPORT_ID_PRESENT_STATUS, Some(&status), &mut short),
+            Some(2)
+        );
+    }
+}
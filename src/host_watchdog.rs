@@ -0,0 +1,150 @@
+//! Host heartbeat watchdog: power cycles the load output once the host has stopped sending
+//! heartbeats for longer than the grace period.
+
+use crate::load_control::LoadCommand;
+
+/// Upper limit for the load off time of a power cycle (s)
+pub const MAX_OFF_TIME_S: u16 = 3600;
+/// Upper limit for consecutive power cycles
+pub const MAX_RETRIES: u8 = 10;
+/// Upper limit for the extra wait after each power cycle (s)
+pub const MAX_BACKOFF_S: u16 = 600;
+
+/// Watchdog parameters registered by the host over USB
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct HostWatchdogConfig {
+    /// Heartbeat interval the host promises to keep (s)
+    pub interval_s: u16,
+    /// Extra time tolerated on top of the heartbeat interval (s)
+    pub grace_s: u16,
+    /// How long the load stays off during a power cycle (s)
+    pub off_time_s: u16,
+    /// Maximum number of consecutive power cycles; once used up there are no more until a
+    /// heartbeat arrives
+    pub max_retries: u8,
+    /// Extra time for the host to boot after each power cycle, growing linearly with the number
+    /// of power cycles so far (s)
+    pub backoff_s: u16,
+}
+
+impl HostWatchdogConfig {
+    /// The heartbeat interval must be non-zero and the off time, power cycle count and wait must
+    /// not exceed their limits
+    pub fn is_valid(&self) -> bool {
+        self.interval_s > 0
+            && (1..=MAX_OFF_TIME_S).contains(&self.off_time_s)
+            && self.max_retries <= MAX_RETRIES
+            && self.backoff_s <= MAX_BACKOFF_S
+    }
+}
+
+/// Host watchdog command
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub enum HostWatchdogCommand {
+    Configure(HostWatchdogConfig),
+    Heartbeat,
+    Disable,
+}
+
+/// Watchdog state, published with the BQ76920 measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct HostWatchdogStatus {
+    pub armed: bool,
+    /// Time since the last heartbeat (s)
+    pub seconds_since_heartbeat: u16,
+    /// Power cycles performed in the current round
+    pub retries_used: u8,
+    /// Total power cycles since power-up, so a reconnecting host can tell that one happened
+    pub trip_count: u16,
+}
+
+#[derive(Debug, Default)]
+pub struct HostWatchdog {
+    config: Option<HostWatchdogConfig>,
+    seconds_since_heartbeat: u16,
+    /// Time left for the host to boot after a power cycle; the timeout does not run meanwhile
+    holdoff_s: u16,
+    retries_used: u8,
+    trip_count: u16,
+}
+
+impl HostWatchdog {
+    pub const fn new() -> Self {
+        Self {
+            config: None,
+            seconds_since_heartbeat: 0,
+            holdoff_s: 0,
+            retries_used: 0,
+            trip_count: 0,
+        }
+    }
+
+    pub fn handle_command(&mut self, command: HostWatchdogCommand) {
+        match command {
+            HostWatchdogCommand::Configure(config) => {
+                self.config = Some(config);
+                self.reset_countdown();
+            }
+            HostWatchdogCommand::Heartbeat => self.reset_countdown(),
+            HostWatchdogCommand::Disable => {
+                self.config = None;
+                self.reset_countdown();
+            }
+        }
+    }
+
+    fn reset_countdown(&mut self) {
+        self.seconds_since_heartbeat = 0;
+        self.holdoff_s = 0;
+        self.retries_used = 0;
+    }
+
+    /// Advances by `elapsed_s` seconds. On a heartbeat timeout it returns the power cycle command
+    /// to hand to `LoadScheduler`
+    pub fn tick(&mut self, elapsed_s: u16) -> Option<LoadCommand> {
+        let config = self.config?;
+
+        if self.holdoff_s > 0 {
+            self.holdoff_s = self.holdoff_s.saturating_sub(elapsed_s);
+            return None;
+        }
+
+        self.seconds_since_heartbeat = self.seconds_since_heartbeat.saturating_add(elapsed_s);
+        let deadline_s = config.interval_s.saturating_add(config.grace_s);
+        if self.seconds_since_heartbeat <= deadline_s {
+            return None;
+        }
+
+        if self.retries_used >= config.max_retries {
+            // Out of power cycles: keep the load on and wait for the host's heartbeat
+            return None;
+        }
+
+        self.retries_used += 1;
+        self.trip_count = self.trip_count.saturating_add(1);
+        self.seconds_since_heartbeat = 0;
+        self.holdoff_s = config
+            .off_time_s
+            .saturating_add(config.backoff_s.saturating_mul(self.retries_used as u16));
+        defmt::warn!(
+            "HostWatchdog: no heartbeat for more than {}s, power-cycling load (retry {}/{}, trips {})",
+            deadline_s,
+            self.retries_used,
+            config.max_retries,
+            self.trip_count
+        );
+        Some(LoadCommand::ShutdownAndRestore {
+            delay_s: 0,
+            off_time_s: config.off_time_s,
+        })
+    }
+
+    pub fn status(&self) -> HostWatchdogStatus {
+        HostWatchdogStatus {
+            armed: self.config.is_some(),
+            seconds_since_heartbeat: self.seconds_since_heartbeat,
+            retries_used: self.retries_used,
+            trip_count: self.trip_count,
+        }
+    }
+}
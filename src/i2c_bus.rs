@@ -0,0 +1,641 @@
+//! Supervision of a shared I2C bus: counts transfer results per device and decides when to
+//! recover the bus and when to re-initialise a device.
+//!
+//! Independent of the I2C peripheral: `SupervisedI2c` works on any
+//! `embedded_hal_async::i2c::I2c` bus, so it can be tested with a fault-injecting mock bus.
+//! Creating the on-board I2C1 and clearing the bus is in `i2c_supervisor`.
+
+use core::cell::RefCell;
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, with_timeout};
+use embedded_hal::i2c::{Error as _, ErrorKind, ErrorType, Operation};
+
+/// Timeout of one transfer, including clock stretching but not waiting for the bus lock
+const TRANSFER_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Consecutive failures on the bus, with no successful transfer in between, before a recovery
+/// is requested
+const BUS_RECOVERY_FAILURES: u16 = 6;
+
+/// Consecutive failures of one device before it is re-initialised; the bus is left alone while
+/// the other devices work
+const DEVICE_REINIT_FAILURES: u16 = 10;
+
+/// Minimum interval between bus recoveries, doubled each time failures persist after one
+const RECOVERY_BACKOFF_MIN: Duration = Duration::from_secs(2);
+const RECOVERY_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Devices on the bus, also the index into `BusHealth::devices`
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum I2cDeviceId {
+    Bq76920 = 0,
+    Bq25730 = 1,
+    Ina226 = 2,
+}
+
+impl I2cDeviceId {
+    pub const COUNT: usize = 3;
+}
+
+/// Class of a transfer error
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum I2cErrorClass {
+    #[default]
+    None = 0,
+    /// The slave did not acknowledge
+    Nack = 1,
+    ArbitrationLoss = 2,
+    /// Bus error (misplaced START/STOP)
+    Bus = 3,
+    Overrun = 4,
+    Timeout = 5,
+    Other = 6,
+}
+
+impl I2cErrorClass {
+    fn from_kind(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::NoAcknowledge(_) => Self::Nack,
+            ErrorKind::ArbitrationLoss => Self::ArbitrationLoss,
+            ErrorKind::Bus => Self::Bus,
+            ErrorKind::Overrun => Self::Overrun,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Communication statistics of one device
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct DeviceHealth {
+    pub successes: u32,
+    /// Failed transfers, excluding timeouts
+    pub errors: u32,
+    pub timeouts: u32,
+    pub consecutive_failures: u16,
+    pub last_error: I2cErrorClass,
+    /// Incremented whenever the device needs re-initialising; the task remembers the value it
+    /// handled
+    pub reinit_generation: u16,
+}
+
+impl DeviceHealth {
+    /// The device has had a successful transfer and the most recent one succeeded
+    pub fn responding(&self) -> bool {
+        self.successes > 0 && self.consecutive_failures == 0
+    }
+}
+
+/// Communication statistics of the bus and its devices, published with the measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct BusHealth {
+    /// Indexed by `I2cDeviceId`
+    pub devices: [DeviceHealth; I2cDeviceId::COUNT],
+    /// Recent consecutive failed transfers on the bus, cleared by a success on any device
+    pub consecutive_failures: u16,
+    /// Bus recoveries performed
+    pub recoveries: u16,
+    /// Bus clears after which SDA or SCL was still held low
+    pub failed_clears: u16,
+}
+
+/// Decides from transfer results when to recover the bus and when to re-initialise a device
+#[derive(Debug)]
+pub struct BusMonitor {
+    health: BusHealth,
+    recovery_pending: bool,
+    next_recovery: Option<Instant>,
+    backoff: Duration,
+}
+
+impl Default for BusMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BusMonitor {
+    pub const fn new() -> Self {
+        Self {
+            health: BusHealth {
+                devices: [DeviceHealth {
+                    successes: 0,
+                    errors: 0,
+                    timeouts: 0,
+                    consecutive_failures: 0,
+                    last_error: I2cErrorClass::None,
+                    reinit_generation: 0,
+                }; I2cDeviceId::COUNT],
+                consecutive_failures: 0,
+                recoveries: 0,
+                failed_clears: 0,
+            },
+            recovery_pending: false,
+            next_recovery: None,
+            backoff: RECOVERY_BACKOFF_MIN,
+        }
+    }
+
+    pub fn health(&self) -> BusHealth {
+        self.health
+    }
+
+    /// Records a transfer result; returns whether the bus needs recovering
+    pub fn record(
+        &mut self,
+        device: I2cDeviceId,
+        result: Result<(), I2cErrorClass>,
+        now: Instant,
+    ) -> bool {
+        let stats = &mut self.health.devices[device as usize];
+        let error = match result {
+            Ok(()) => {
+                stats.successes = stats.successes.wrapping_add(1);
+                stats.consecutive_failures = 0;
+                // A device can communicate, so the bus itself is not stuck
+                self.health.consecutive_failures = 0;
+                self.backoff = RECOVERY_BACKOFF_MIN;
+                return false;
+            }
+            Err(error) => error,
+        };
+
+        if error == I2cErrorClass::Timeout {
+            stats.timeouts = stats.timeouts.wrapping_add(1);
+        } else {
+            stats.errors = stats.errors.wrapping_add(1);
+        }
+        stats.last_error = error;
+        stats.consecutive_failures = stats.consecutive_failures.saturating_add(1);
+        if stats
+            .consecutive_failures
+            .is_multiple_of(DEVICE_REINIT_FAILURES)
+        {
+            stats.reinit_generation = stats.reinit_generation.wrapping_add(1);
+        }
+        self.health.consecutive_failures = self.health.consecutive_failures.saturating_add(1);
+
+        if self.recovery_pending
+            || self.health.consecutive_failures < BUS_RECOVERY_FAILURES
+            || self.next_recovery.is_some_and(|at| now < at)
+        {
+            return false;
+        }
+        self.recovery_pending = true;
+        true
+    }
+
+    /// A bus recovery finished; `released` tells whether SDA and SCL were both free after the
+    /// clear. Every device needs re-initialising.
+    pub fn recovered(&mut self, released: bool, now: Instant) {
+        self.recovery_pending = false;
+        self.health.recoveries = self.health.recoveries.wrapping_add(1);
+        if !released {
+            self.health.failed_clears = self.health.failed_clears.wrapping_add(1);
+        }
+        self.health.consecutive_failures = 0;
+        self.next_recovery = Some(now + self.backoff);
+        self.backoff = (self.backoff * 2).min(RECOVERY_BACKOFF_MAX);
+        for stats in self.health.devices.iter_mut() {
+            stats.reinit_generation = stats.reinit_generation.wrapping_add(1);
+        }
+    }
+
+    /// Returns `true` if the device needs re-initialising and updates `seen` to the current
+    /// generation
+    pub fn take_reinit(&self, device: I2cDeviceId, seen: &mut u16) -> bool {
+        let generation = self.health.devices[device as usize].reinit_generation;
+        let pending = generation != *seen;
+        *seen = generation;
+        pending
+    }
+}
+
+/// The shared bus. `None` while the peripheral is released during a recovery
+pub type I2cBus<BUS> = Mutex<CriticalSectionRawMutex, Option<BUS>>;
+
+/// Bus supervision state shared by the tasks
+pub struct I2cSupervisor {
+    monitor: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<BusMonitor>>,
+    recovery_request: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl Default for I2cSupervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl I2cSupervisor {
+    pub const fn new() -> Self {
+        Self {
+            monitor: blocking_mutex::Mutex::new(RefCell::new(BusMonitor::new())),
+            recovery_request: Signal::new(),
+        }
+    }
+
+    pub fn health(&self) -> BusHealth {
+        self.monitor.lock(|monitor| monitor.borrow().health())
+    }
+
+    /// Called by device tasks at the start of every cycle: returns `true` after a bus recovery or
+    /// repeated device failures, and the task should then re-initialise its device
+    pub fn take_reinit(&self, device: I2cDeviceId, seen: &mut u16) -> bool {
+        self.monitor
+            .lock(|monitor| monitor.borrow().take_reinit(device, seen))
+    }
+
+    fn record(&self, device: I2cDeviceId, result: Result<(), I2cErrorClass>) {
+        let recover = self
+            .monitor
+            .lock(|monitor| monitor.borrow_mut().record(device, result, Instant::now()));
+        if recover {
+            warn!(
+                "I2C: repeated bus failures (last from {:?}), requesting recovery",
+                device
+            );
+            self.recovery_request.signal(());
+        }
+    }
+
+    /// Waits for a recovery request and runs `recover` (which returns whether the bus was released)
+    /// with the bus locked. Returns the total number of recoveries.
+    pub async fn recover<BUS>(
+        &self,
+        bus: &I2cBus<BUS>,
+        recover: impl FnOnce(&mut Option<BUS>) -> bool,
+    ) -> u16 {
+        self.recovery_request.wait().await;
+        let released = recover(&mut *bus.lock().await);
+        self.monitor.lock(|monitor| {
+            let mut monitor = monitor.borrow_mut();
+            monitor.recovered(released, Instant::now());
+            monitor.health().recoveries
+        })
+    }
+}
+
+/// Error of `SupervisedI2c`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum SupervisedI2cError<E> {
+    Bus(E),
+    Timeout,
+    /// The bus is being recovered and the peripheral has not been rebuilt yet
+    Unavailable,
+}
+
+impl<E: embedded_hal::i2c::Error> embedded_hal::i2c::Error for SupervisedI2cError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Bus(e) => e.kind(),
+            Self::Timeout | Self::Unavailable => ErrorKind::Other,
+        }
+    }
+}
+
+/// Access handle for one device on the shared bus that records the result of every transfer
+pub struct SupervisedI2c<'a, BUS> {
+    bus: &'a I2cBus<BUS>,
+    supervisor: &'a I2cSupervisor,
+    device: I2cDeviceId,
+}
+
+impl<'a, BUS> SupervisedI2c<'a, BUS> {
+    pub fn new(bus: &'a I2cBus<BUS>, supervisor: &'a I2cSupervisor, device: I2cDeviceId) -> Self {
+        Self {
+            bus,
+            supervisor,
+            device,
+        }
+    }
+}
+
+impl<BUS: embedded_hal_async::i2c::I2c> ErrorType for SupervisedI2c<'_, BUS> {
+    type Error = SupervisedI2cError<BUS::Error>;
+}
+
+impl<BUS: embedded_hal_async::i2c::I2c> embedded_hal_async::i2c::I2c for SupervisedI2c<'_, BUS> {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let result = {
+            let mut bus = self.bus.lock().await;
+            let Some(bus) = bus.as_mut() else {
+                return Err(SupervisedI2cError::Unavailable);
+            };
+            match with_timeout(TRANSFER_TIMEOUT, bus.transaction(address, operations)).await {
+                Ok(result) => result.map_err(SupervisedI2cError::Bus),
+                Err(_) => Err(SupervisedI2cError::Timeout),
+            }
+        };
+        self.supervisor.record(
+            self.device,
+            result.as_ref().map(|_| ()).map_err(|e| match e {
+                SupervisedI2cError::Timeout => I2cErrorClass::Timeout,
+                e => I2cErrorClass::from_kind(e.kind()),
+            }),
+        );
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::poll_fn;
+    use core::task::Poll;
+
+    use embassy_futures::block_on;
+    use embedded_hal::i2c::NoAcknowledgeSource;
+    use embedded_hal_async::i2c::I2c as _;
+
+    use super::*;
+    use crate::test_support::advance_time;
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    enum Fault {
+        None,
+        Nack,
+        Bus,
+        /// A slave holds SCL low and the transfer never finishes
+        Hang,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct MockError(ErrorKind);
+
+    impl embedded_hal::i2c::Error for MockError {
+        fn kind(&self) -> ErrorKind {
+            self.0
+        }
+    }
+
+    /// Bus that injects `fault`
+    struct MockBus {
+        fault: Fault,
+    }
+
+    impl ErrorType for MockBus {
+        type Error = MockError;
+    }
+
+    impl embedded_hal_async::i2c::I2c for MockBus {
+        async fn transaction(
+            &mut self,
+            _address: u8,
+            _operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            match self.fault {
+                Fault::None => Ok(()),
+                Fault::Nack => Err(MockError(ErrorKind::NoAcknowledge(
+                    NoAcknowledgeSource::Address,
+                ))),
+                Fault::Bus => Err(MockError(ErrorKind::Bus)),
+                // The test executor keeps polling; every poll advances the clock by 1 ms until the
+                // timeout
+                Fault::Hang => {
+                    poll_fn(|_| {
+                        advance_time(Duration::from_millis(1));
+                        Poll::Pending
+                    })
+                    .await
+                }
+            }
+        }
+    }
+
+    fn mock_bus() -> I2cBus<MockBus> {
+        Mutex::new(Some(MockBus { fault: Fault::None }))
+    }
+
+    fn set_fault(bus: &I2cBus<MockBus>, fault: Fault) {
+        let mut bus = bus.try_lock().expect("mock bus still locked by a transfer");
+        bus.as_mut()
+            .expect("mock bus was taken out of the mutex")
+            .fault = fault;
+    }
+
+    fn transfer(
+        bus: &I2cBus<MockBus>,
+        supervisor: &I2cSupervisor,
+        device: I2cDeviceId,
+    ) -> Result<(), SupervisedI2cError<MockError>> {
+        block_on(SupervisedI2c::new(bus, supervisor, device).write(0x08, &[0]))
+    }
+
+    fn nack() -> Result<(), I2cErrorClass> {
+        Err(I2cErrorClass::Nack)
+    }
+
+    #[test]
+    fn errors_and_timeouts_counted_per_device() {
+        let bus = mock_bus();
+        let supervisor = I2cSupervisor::new();
+
+        assert_eq!(transfer(&bus, &supervisor, I2cDeviceId::Bq76920), Ok(()));
+        set_fault(&bus, Fault::Nack);
+        assert_eq!(
+            transfer(&bus, &supervisor, I2cDeviceId::Bq76920),
+            Err(SupervisedI2cError::Bus(MockError(
+                ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)
+            )))
+        );
+        set_fault(&bus, Fault::Hang);
+        let start = Instant::now();
+        assert_eq!(
+            transfer(&bus, &supervisor, I2cDeviceId::Bq76920),
+            Err(SupervisedI2cError::Timeout)
+        );
+        assert!(Instant::now() - start >= TRANSFER_TIMEOUT);
+        set_fault(&bus, Fault::Bus);
+        assert!(transfer(&bus, &supervisor, I2cDeviceId::Bq25730).is_err());
+
+        let health = supervisor.health();
+        let bq76920 = health.devices[I2cDeviceId::Bq76920 as usize];
+        assert_eq!(bq76920.successes, 1);
+        assert_eq!(bq76920.errors, 1);
+        assert_eq!(bq76920.timeouts, 1);
+        assert_eq!(bq76920.consecutive_failures, 2);
+        assert_eq!(bq76920.last_error, I2cErrorClass::Timeout);
+        assert!(!bq76920.responding());
+        let bq25730 = health.devices[I2cDeviceId::Bq25730 as usize];
+        assert_eq!(bq25730.errors, 1);
+        assert_eq!(bq25730.last_error, I2cErrorClass::Bus);
+        assert_eq!(
+            health.devices[I2cDeviceId::Ina226 as usize],
+            DeviceHealth::default()
+        );
+        assert_eq!(health.consecutive_failures, 3);
+    }
+
+    #[test]
+    fn success_on_any_device_clears_bus_failures() {
+        let bus = mock_bus();
+        let supervisor = I2cSupervisor::new();
+
+        set_fault(&bus, Fault::Nack);
+        for _ in 1..BUS_RECOVERY_FAILURES {
+            assert!(transfer(&bus, &supervisor, I2cDeviceId::Bq76920).is_err());
+        }
+        set_fault(&bus, Fault::None);
+        assert_eq!(transfer(&bus, &supervisor, I2cDeviceId::Ina226), Ok(()));
+        set_fault(&bus, Fault::Nack);
+        assert!(transfer(&bus, &supervisor, I2cDeviceId::Bq76920).is_err());
+
+        let health = supervisor.health();
+        assert_eq!(health.consecutive_failures, 1);
+        assert_eq!(
+            health.devices[I2cDeviceId::Bq76920 as usize].consecutive_failures,
+            BUS_RECOVERY_FAILURES
+        );
+        assert!(health.devices[I2cDeviceId::Ina226 as usize].responding());
+        assert!(!supervisor.recovery_request.signaled());
+    }
+
+    #[test]
+    fn repeated_bus_failures_request_recovery() {
+        let bus = mock_bus();
+        let supervisor = I2cSupervisor::new();
+        let mut seen = [0u16; I2cDeviceId::COUNT];
+        let devices = [
+            I2cDeviceId::Bq76920,
+            I2cDeviceId::Bq25730,
+            I2cDeviceId::Ina226,
+        ];
+
+        set_fault(&bus, Fault::Nack);
+        for n in 1..=BUS_RECOVERY_FAILURES {
+            let device = devices[n as usize % devices.len()];
+            assert!(transfer(&bus, &supervisor, device).is_err());
+            assert_eq!(
+                supervisor.recovery_request.signaled(),
+                n == BUS_RECOVERY_FAILURES
+            );
+        }
+        for device in devices {
+            assert!(!supervisor.take_reinit(device, &mut seen[device as usize]));
+        }
+
+        let recoveries = block_on(supervisor.recover(&bus, |bus| {
+            assert!(bus.is_some());
+            true
+        }));
+        assert_eq!(recoveries, 1);
+        assert!(!supervisor.recovery_request.signaled());
+        let health = supervisor.health();
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.failed_clears, 0);
+
+        // After a recovery every device re-initialises once
+        for device in devices {
+            assert!(supervisor.take_reinit(device, &mut seen[device as usize]));
+            assert!(!supervisor.take_reinit(device, &mut seen[device as usize]));
+        }
+    }
+
+    #[test]
+    fn unavailable_bus_is_not_counted() {
+        let bus: I2cBus<MockBus> = Mutex::new(None);
+        let supervisor = I2cSupervisor::new();
+
+        assert_eq!(
+            transfer(&bus, &supervisor, I2cDeviceId::Bq76920),
+            Err(SupervisedI2cError::Unavailable)
+        );
+        assert_eq!(supervisor.health(), BusHealth::default());
+    }
+
+    #[test]
+    fn failing_device_reinitialised_while_bus_works() {
+        let mut monitor = BusMonitor::new();
+        let now = Instant::from_secs(1);
+        let mut seen = 0;
+
+        for _ in 1..DEVICE_REINIT_FAILURES {
+            assert!(!monitor.record(I2cDeviceId::Bq25730, nack(), now));
+            // Another device works, so no bus recovery is requested
+            assert!(!monitor.record(I2cDeviceId::Bq76920, Ok(()), now));
+        }
+        assert!(!monitor.take_reinit(I2cDeviceId::Bq25730, &mut seen));
+
+        assert!(!monitor.record(I2cDeviceId::Bq25730, nack(), now));
+        assert!(monitor.take_reinit(I2cDeviceId::Bq25730, &mut seen));
+        assert!(!monitor.take_reinit(I2cDeviceId::Bq25730, &mut seen));
+        assert!(!monitor.take_reinit(I2cDeviceId::Bq76920, &mut 0));
+
+        // And again after every further DEVICE_REINIT_FAILURES consecutive failures
+        for _ in 1..DEVICE_REINIT_FAILURES {
+            monitor.record(I2cDeviceId::Bq25730, nack(), now);
+            monitor.record(I2cDeviceId::Bq76920, Ok(()), now);
+        }
+        assert!(!monitor.take_reinit(I2cDeviceId::Bq25730, &mut seen));
+        monitor.record(I2cDeviceId::Bq25730, nack(), now);
+        assert!(monitor.take_reinit(I2cDeviceId::Bq25730, &mut seen));
+        assert_eq!(monitor.health().recoveries, 0);
+    }
+
+    #[test]
+    fn recovery_backs_off_until_a_transfer_succeeds() {
+        let mut monitor = BusMonitor::new();
+        let fail_until_request = |monitor: &mut BusMonitor, now| {
+            (0..BUS_RECOVERY_FAILURES).any(|_| monitor.record(I2cDeviceId::Bq76920, nack(), now))
+        };
+        let t0 = Instant::from_secs(100);
+
+        assert!(fail_until_request(&mut monitor, t0));
+        // No second request while the recovery is pending
+        assert!(!fail_until_request(&mut monitor, t0));
+        monitor.recovered(false, t0);
+        assert_eq!(monitor.health().failed_clears, 1);
+
+        // At least RECOVERY_BACKOFF_MIN after the first recovery
+        let retry = t0 + RECOVERY_BACKOFF_MIN;
+        assert!(!fail_until_request(
+            &mut monitor,
+            retry - Duration::from_millis(1)
+        ));
+        assert!(monitor.record(I2cDeviceId::Bq76920, nack(), retry));
+        monitor.recovered(false, retry);
+
+        // Still failing, so the interval doubles
+        let retry = retry + RECOVERY_BACKOFF_MIN * 2;
+        assert!(!fail_until_request(
+            &mut monitor,
+            retry - Duration::from_millis(1)
+        ));
+        assert!(monitor.record(I2cDeviceId::Bq76920, nack(), retry));
+        monitor.recovered(true, retry);
+
+        // A successful transfer resets the interval to RECOVERY_BACKOFF_MIN
+        monitor.record(I2cDeviceId::Ina226, Ok(()), retry);
+        monitor.recovered(true, retry);
+        let t1 = retry + RECOVERY_BACKOFF_MIN;
+        assert!(fail_until_request(&mut monitor, t1));
+
+        let health = monitor.health();
+        assert_eq!(health.recoveries, 4);
+        assert_eq!(health.failed_clears, 2);
+    }
+
+    #[test]
+    fn recovery_backoff_is_capped() {
+        let mut monitor = BusMonitor::new();
+        let mut now = Instant::from_secs(0);
+        for _ in 0..10 {
+            monitor.recovered(false, now);
+            now += RECOVERY_BACKOFF_MAX;
+        }
+        assert!(!(1..BUS_RECOVERY_FAILURES).any(|_| monitor.record(
+            I2cDeviceId::Bq76920,
+            nack(),
+            now - Duration::from_millis(1)
+        )));
+        assert!(monitor.record(I2cDeviceId::Bq76920, nack(), now));
+    }
+}
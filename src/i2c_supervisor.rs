@@ -0,0 +1,187 @@
+//! I2C1 bus supervision. The BQ76920, BQ25730 and INA226 share one I2C bus; a slave holding SDA
+//! low or a peripheral stuck in an error state makes every task fail.
+//!
+//! Tasks access the bus through `SupervisedI2c`: every transfer has a timeout, and successes,
+//! errors and timeouts are counted per device. After repeated failures with no success in
+//! between a recovery is requested: `i2c_recovery_task` locks the bus, clocks SCL by hand until
+//! the slave releases SDA and sends a STOP. `I2c1Bus` builds the driver for every transfer, so
+//! the peripheral starts from reset afterwards. The tasks then re-initialise their devices.
+//!
+//! The counting, the recovery decisions and `SupervisedI2c` don't touch the hardware and live in
+//! `i2c_bus`; this module holds the I2C1-specific parts.
+
+use defmt::{error, info, warn};
+use embassy_stm32::Peri;
+use embassy_stm32::gpio::{Flex, Pull, Speed};
+use embassy_stm32::i2c::{self, I2c};
+use embassy_stm32::mode::Async;
+use embassy_stm32::peripherals;
+use embassy_stm32::time::Hertz;
+use embassy_time::{Duration, block_for};
+use embedded_hal::i2c::{ErrorType, Operation};
+
+use crate::event_log::{PowerEvent, PowerEventCode};
+pub use crate::i2c_bus::{
+    BusHealth, DeviceHealth, I2cBus, I2cDeviceId, I2cSupervisor, SupervisedI2c,
+};
+use crate::shared::PowerEventSender;
+
+/// Maximum SCL clocks for a bus clear: enough for a slave to shift out a partial byte and ACK
+const BUS_CLEAR_CLOCKS: u8 = 9;
+
+/// SCL half period during a bus clear, about 50 kHz
+const BUS_CLEAR_HALF_PERIOD: Duration = Duration::from_micros(10);
+
+/// I2C1 clock frequency
+const I2C1_FREQUENCY: Hertz = Hertz(100_000);
+
+/// The on-board I2C1 bus
+pub type SharedI2cBus = I2cBus<I2c1Bus>;
+
+/// I2C1 access handle used by the device tasks
+pub type SharedI2cDevice = SupervisedI2c<'static, I2c1Bus>;
+
+/// I2C1 with its pins and DMA channels: SCL = PA15, SDA = PB7, DMA1_CH3/CH4, internal pull-ups.
+///
+/// The driver is built from reborrowed handles for every transfer, which also resets the
+/// peripheral, so a recovery only has to clear the bus through the pins. Between transfers SCL
+/// and SDA are held high by the internal pull-ups.
+pub struct I2c1Bus {
+    i2c: Peri<'static, peripherals::I2C1>,
+    scl: Peri<'static, peripherals::PA15>,
+    sda: Peri<'static, peripherals::PB7>,
+    tx_dma: Peri<'static, peripherals::DMA1_CH3>,
+    rx_dma: Peri<'static, peripherals::DMA1_CH4>,
+}
+
+impl I2c1Bus {
+    pub fn new(
+        i2c: Peri<'static, peripherals::I2C1>,
+        scl: Peri<'static, peripherals::PA15>,
+        sda: Peri<'static, peripherals::PB7>,
+        tx_dma: Peri<'static, peripherals::DMA1_CH3>,
+        rx_dma: Peri<'static, peripherals::DMA1_CH4>,
+    ) -> Self {
+        let mut bus = Self {
+            i2c,
+            scl,
+            sda,
+            tx_dma,
+            rx_dma,
+        };
+        bus.release_lines();
+        bus
+    }
+
+    fn driver(&mut self) -> I2c<'_, Async> {
+        let mut config = i2c::Config::default();
+        config.scl_pullup = true;
+        config.sda_pullup = true;
+        I2c::new(
+            self.i2c.reborrow(),
+            self.scl.reborrow(),
+            self.sda.reborrow(),
+            crate::Irqs,
+            self.tx_dma.reborrow(),
+            self.rx_dma.reborrow(),
+            I2C1_FREQUENCY,
+            config,
+        )
+    }
+
+    /// Leaves SCL and SDA as inputs with pull-ups, the idle state of the bus
+    fn release_lines(&mut self) {
+        for mut pin in [
+            Flex::new(self.scl.reborrow()),
+            Flex::new(self.sda.reborrow()),
+        ] {
+            pin.set_as_input(Pull::Up);
+            // Dropping a `Flex` disconnects the pin, which would let the line float
+            core::mem::forget(pin);
+        }
+    }
+
+    /// Clears the bus as described in the I2C specification: while a slave holds SDA low,
+    /// outputs up to 9 SCL clocks so it can shift out the partial byte and release SDA, then
+    /// sends a STOP. Returns whether both SDA and SCL are released.
+    pub fn clear_bus(&mut self) -> bool {
+        let released = {
+            let mut scl = Flex::new(self.scl.reborrow());
+            let mut sda = Flex::new(self.sda.reborrow());
+            scl.set_high();
+            sda.set_high();
+            scl.set_as_input_output_pull(Speed::Low, Pull::Up);
+            sda.set_as_input_output_pull(Speed::Low, Pull::Up);
+            block_for(BUS_CLEAR_HALF_PERIOD);
+
+            for _ in 0..BUS_CLEAR_CLOCKS {
+                if sda.is_high() {
+                    break;
+                }
+                scl.set_low();
+                block_for(BUS_CLEAR_HALF_PERIOD);
+                scl.set_high();
+                block_for(BUS_CLEAR_HALF_PERIOD);
+            }
+
+            // STOP: SDA rises while SCL is high
+            scl.set_low();
+            block_for(BUS_CLEAR_HALF_PERIOD);
+            sda.set_low();
+            block_for(BUS_CLEAR_HALF_PERIOD);
+            scl.set_high();
+            block_for(BUS_CLEAR_HALF_PERIOD);
+            sda.set_high();
+            block_for(BUS_CLEAR_HALF_PERIOD);
+
+            scl.is_high() && sda.is_high()
+        };
+        self.release_lines();
+        released
+    }
+}
+
+impl ErrorType for I2c1Bus {
+    type Error = i2c::Error;
+}
+
+impl embedded_hal_async::i2c::I2c for I2c1Bus {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        // A transfer cancelled by the supervisor's timeout leaves the lines disconnected until
+        // the next transfer or the bus clear that follows
+        let result = self.driver().transaction(address, operations).await;
+        self.release_lines();
+        result
+    }
+}
+
+/// Task performing bus recoveries: clears the bus with the bus locked and records the recovery
+/// in the event log
+#[embassy_executor::task]
+pub async fn i2c_recovery_task(
+    bus: &'static SharedI2cBus,
+    supervisor: &'static I2cSupervisor,
+    power_event_sender: PowerEventSender<'static>,
+) {
+    loop {
+        let recoveries = supervisor
+            .recover(bus, |i2c1| {
+                let released = i2c1.as_mut().is_some_and(I2c1Bus::clear_bus);
+                if !released {
+                    error!("I2C: SDA/SCL still held low after bus clear");
+                }
+                released
+            })
+            .await;
+
+        info!("I2C: bus recovery #{} complete", recoveries);
+        let event = PowerEvent::new(PowerEventCode::I2cBusRecovery, recoveries);
+        if power_event_sender.try_send(event).is_err() {
+            warn!("I2C: Power event queue full, bus recovery not logged");
+        }
+    }
+}
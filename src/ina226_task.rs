@@ -1,54 +1,221 @@
 use defmt::*;
-use embassy_time::{Duration, Timer};
+use embassy_futures::select::{Either, select};
+use embassy_stm32::exti::ExtiInput;
+use embassy_time::{Duration, Instant, Timer};
 
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
-use embassy_stm32::i2c::I2c;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-
-use ina226::INA226;
-
-use crate::shared::Ina226MeasurementsPublisher;
+use crate::calibration::Ina226Calibration;
+use crate::config::LoadMonitorConfig;
+use crate::energy_meter::EnergyMeter;
+use crate::event_log::{PowerEvent, PowerEventCode};
+use crate::i2c_supervisor::{I2cDeviceId, I2cSupervisor, SharedI2cDevice};
+use crate::load_monitor::{Ina226, LoadAlert, mask};
+use crate::sampling::SamplingControl;
+use crate::shared::{Ina226MeasurementsPublisher, PowerEventSender, SystemConfigSubscriber};
+use crate::task_watchdog::{SupervisedTask, TaskWatchdog};
 
+/// Embassy task for the INA226 load monitor.
+///
+/// The INA226 converts continuously with the averaging and conversion times from `config`.
+/// Once per cycle the task reads the conversion-ready flag and publishes a reading only when
+/// a new conversion has completed since the last one. If a read fails, the last good readings
+/// are published again with `sample.valid` cleared. Readings are corrected with the factory
+/// `calibration`. Both are updated live from `system_config_subscriber`. Every fresh reading
+/// feeds the load power to `energy_meter`.
+///
+/// The cycle interval follows the active `sampling` mode, which also raises the averaging while
+/// the UPS is idle and lowers the conversion rate in low-power operation; the INA226 is
+/// configured again whenever the resulting parameters change.
+///
+/// The INA226 ALERT output (`alert`, active low, latched) signals load over-current or
+/// over-power as configured. Between cycles the task waits on it: a fresh reading is published
+/// immediately and the start of each over-limit episode is counted and reported through
+/// `power_event_sender`. The INA226 is configured again whenever `i2c_supervisor` reports a
+/// bus recovery or repeated failures. Each cycle checks in with `task_watchdog`.
 #[embassy_executor::task]
 pub async fn ina226_task(
-    i2c_bus: I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, embassy_stm32::mode::Async>>,
+    i2c_bus: SharedI2cDevice,
+    i2c_supervisor: &'static I2cSupervisor,
     address: u8,
+    mut alert: ExtiInput<'static>,
     ina226_measurements_publisher: Ina226MeasurementsPublisher<'static>,
+    power_event_sender: PowerEventSender<'static>,
+    mut system_config_subscriber: SystemConfigSubscriber<'static>,
+    mut config: LoadMonitorConfig,
+    mut calibration: Ina226Calibration,
+    energy_meter: &'static EnergyMeter,
+    sampling: &'static SamplingControl,
+    task_watchdog: &'static TaskWatchdog,
 ) {
     info!("INA226 task started.");
-    // Create temporary I2cDevice instance for each operation
-    let mut ina226 = INA226::new(i2c_bus, address); // Use the passed address
+    let mut ina226 = Ina226::new(i2c_bus, address);
 
-    // Resistance 10mOhm, Max 10A
-    if let Err(_) = ina226.callibrate(0.01, 10.0).await {
-        defmt::error!("INA226: Failed to calibrate INA226");
-    }
+    let mut ina226_measurements = crate::data_types::Ina226Measurements::default();
+    let mut reinit_generation = 0;
+    let mut configured = false;
+    // Parameters written to the chip: `config` with the sampling mode applied
+    let mut applied = sampling.rates().load_monitor(&config);
+    // An over-limit episode lasts until a cycle passes without the alert flag set
+    let mut over_limit = false;
 
     loop {
+        let cycle_start = Instant::now();
+        task_watchdog.check_in(SupervisedTask::Ina226);
+
+        while let Some(system_config) = system_config_subscriber.try_next_message_pure() {
+            calibration = system_config.calibration.ina226;
+            config = system_config.load_monitor;
+        }
+        // The slow sampling modes override the averaging and conversion times
+        let rates = sampling.rates();
+        if rates.load_monitor(&config) != applied {
+            applied = rates.load_monitor(&config);
+            configured = false;
+        }
+
+        if !configured || i2c_supervisor.take_reinit(I2cDeviceId::Ina226, &mut reinit_generation) {
+            configured = match ina226.configure(&applied).await {
+                Ok(()) => {
+                    info!(
+                        "INA226: configured {:?}, alert {:?}",
+                        applied,
+                        applied.alert()
+                    );
+                    true
+                }
+                Err(e) => {
+                    error!("INA226: Failed to configure: {:?}", e);
+                    false
+                }
+            };
+        }
+
         // --- Reading INA226 Data ---
-        let voltage_mv_f64 = ina226.bus_voltage_millivolts().await.unwrap_or(0.0);
-        let current_ma_f64 = ina226
-            .current_amps()
-            .await
-            .unwrap_or(None)
-            .map_or(0.0, |c| c * 1000.0);
-        let power_mw_f64 = ina226
-            .power_watts()
-            .await
-            .unwrap_or(None)
-            .map_or(0.0, |p| p * 1000.0);
-
-        let ina226_measurements = crate::data_types::Ina226Measurements {
-            voltage: voltage_mv_f64 as f32,
-            current: current_ma_f64 as f32,
-            power: power_mw_f64 as f32,
+        // Reading the flags clears CVRF, so a set flag means a conversion finished since the
+        // previous read. Without one the last published reading is still current.
+        let flags = if configured {
+            ina226.read_flags().await.ok()
+        } else {
+            None
         };
-        ina226_measurements_publisher.publish_immediate(ina226_measurements);
+        match flags {
+            Some(flags) => {
+                let alerted = flags & mask::AFF != 0;
+                if flags & mask::CVRF != 0 || alerted {
+                    read_measurements(
+                        &mut ina226,
+                        &calibration,
+                        energy_meter,
+                        &mut ina226_measurements,
+                    )
+                    .await;
+                    if alerted {
+                        report_alert(
+                            &config,
+                            &mut over_limit,
+                            &mut ina226_measurements,
+                            &power_event_sender,
+                        );
+                    }
+                    ina226_measurements_publisher.publish_immediate(ina226_measurements);
+                }
+                over_limit = alerted;
+            }
+            None => {
+                defmt::error!("INA226: Failed to read measurements");
+                ina226_measurements.sample.record(false, Instant::now());
+                ina226_measurements_publisher.publish_immediate(ina226_measurements);
+            }
+        }
         info!(
             "INA226 Measurements: Voltage: {}mV, Current: {}mA, Power: {}mW",
             ina226_measurements.voltage, ina226_measurements.current, ina226_measurements.power
         );
 
-        Timer::after(Duration::from_secs(1)).await; // Adjust delay as needed
+        // Until the next cycle, serve the ALERT pin. It stays low until the flags are read.
+        let next_cycle = cycle_start + Duration::from_millis(rates.ina226_ms as u64);
+        if !configured || config.alert() == LoadAlert::Disabled {
+            Timer::at(next_cycle).await;
+            continue;
+        }
+        if let Either::Second(()) = select(alert.wait_for_low(), Timer::at(next_cycle)).await {
+            continue;
+        }
+        match ina226.read_flags().await {
+            Ok(flags) if flags & mask::AFF != 0 => {
+                read_measurements(
+                    &mut ina226,
+                    &calibration,
+                    energy_meter,
+                    &mut ina226_measurements,
+                )
+                .await;
+                report_alert(
+                    &config,
+                    &mut over_limit,
+                    &mut ina226_measurements,
+                    &power_event_sender,
+                );
+                ina226_measurements_publisher.publish_immediate(ina226_measurements);
+            }
+            Ok(_) => warn!("INA226 ALERT: pin asserted without the alert flag"),
+            Err(e) => error!("INA226 ALERT: failed to read flags: {:?}", e),
+        }
+        // While the load stays over the limit the alert re-latches after every conversion;
+        // the rest of the episode is followed by the cycle reads.
+        Timer::at(next_cycle).await;
+    }
+}
+
+async fn read_measurements(
+    ina226: &mut Ina226<SharedI2cDevice>,
+    calibration: &Ina226Calibration,
+    energy_meter: &EnergyMeter,
+    measurements: &mut crate::data_types::Ina226Measurements,
+) {
+    match ina226.read_sample().await {
+        Ok(sample) => {
+            let (voltage, current, power) =
+                calibration.apply(sample.voltage_mv, sample.current_ma, sample.power_mw);
+            measurements.voltage = voltage;
+            measurements.current = current;
+            measurements.power = power;
+            let now = Instant::now();
+            measurements.sample.record(true, now);
+            energy_meter.record_load(power, now);
+        }
+        Err(e) => {
+            defmt::error!("INA226: Failed to read measurements: {:?}", e);
+            measurements.sample.record(false, Instant::now());
+        }
+    }
+}
+
+/// Counts and logs the start of an over-limit episode
+fn report_alert(
+    config: &LoadMonitorConfig,
+    over_limit: &mut bool,
+    measurements: &mut crate::data_types::Ina226Measurements,
+    power_event_sender: &PowerEventSender<'static>,
+) {
+    if core::mem::replace(over_limit, true) {
+        return;
+    }
+    measurements.alert_count = measurements.alert_count.saturating_add(1);
+    let event = match config.alert() {
+        LoadAlert::OverPower => PowerEvent::new(
+            PowerEventCode::LoadOverPower,
+            (measurements.power / 1000.0).clamp(0.0, u16::MAX as f32) as u16,
+        ),
+        _ => PowerEvent::new(
+            PowerEventCode::LoadOverCurrent,
+            measurements.current.clamp(0.0, u16::MAX as f32) as u16,
+        ),
+    };
+    warn!(
+        "INA226 ALERT: load over limit, {}mA {}mW",
+        measurements.current, measurements.power
+    );
+    if power_event_sender.try_send(event).is_err() {
+        warn!("Power event queue full, load over-limit alert not logged");
     }
 }
@@ -0,0 +1,27 @@
+//! Hardware-independent parts of the firmware.
+//!
+//! Built as a library so that the state machines and encoders can be unit tested on the host
+//! (`make test`). The firmware binary re-exports these modules at its crate root, so code in
+//! the binary refers to them as `crate::<module>` like its own modules.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod battery_profile;
+pub mod calibration;
+pub mod config;
+pub mod config_store;
+pub mod crc;
+pub mod hid_report;
+pub mod host_watchdog;
+pub mod i2c_bus;
+pub mod load_control;
+pub mod load_monitor;
+pub mod overload_protection;
+pub mod power_quality;
+pub mod protection;
+pub mod sampling;
+pub mod task_monitor;
+pub mod ups_status;
+
+#[cfg(test)]
+mod test_support;
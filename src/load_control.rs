@@ -0,0 +1,244 @@
+//! Load output control: delayed shutdown, delayed restore after a shutdown (remote power cycling
+//! of a hung device), and the automatic restore policy when mains returns after a low-battery
+//! shutdown.
+//!
+//! The load output is switched by the BQ76920 discharge MOSFET (DSG FET). This module only holds
+//! the state machines; `bq76920_task` drives the FET according to the returned [`LoadAction`].
+
+use crate::host_watchdog::HostWatchdogCommand;
+
+/// Load control command from the host
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub enum LoadCommand {
+    /// Switch the load output off after `delay_s` seconds
+    ShutdownAfter { delay_s: u16 },
+    /// Switch the load output off after `delay_s` seconds and back on `off_time_s` seconds later
+    ShutdownAndRestore { delay_s: u16, off_time_s: u16 },
+    /// Cancel the pending action
+    Cancel,
+    /// Switch the load output back on now (how the manual policy restores after a low-battery
+    /// shutdown)
+    RestoreNow,
+    /// Host heartbeat watchdog command, handled by `HostWatchdog`
+    Watchdog(HostWatchdogCommand),
+}
+
+/// How the load is restored after a low-battery shutdown
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum RestoreMode {
+    /// Stay off until the host sends `RestoreNow`
+    Manual = 0,
+    /// Restore once mains is back and the charge has been sufficient for a while
+    Auto = 1,
+}
+
+/// Load restore policy after a low-battery shutdown
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct RestorePolicy {
+    pub mode: RestoreMode,
+    /// Lowest remaining capacity to restore the load (%)
+    pub min_soc_pct: u8,
+    /// How long mains must be present, so a flickering input does not toggle the load (s)
+    pub delay_s: u16,
+}
+
+impl Default for RestorePolicy {
+    fn default() -> Self {
+        Self {
+            mode: RestoreMode::Auto,
+            min_soc_pct: 20,
+            delay_s: 30,
+        }
+    }
+}
+
+/// Pending action; the value appears in the USB status frame
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum PendingAction {
+    #[default]
+    None = 0,
+    /// Switch off when the countdown expires
+    Shutdown = 1,
+    /// Switch off when the countdown expires, then restore automatically
+    ShutdownRestore = 2,
+    /// Off; restore when the countdown expires
+    Restore = 3,
+}
+
+/// FET operation requested by the state machine
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub enum LoadAction {
+    DisableOutput,
+    EnableOutput,
+}
+
+/// Load control state, published with the BQ76920 measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct LoadControlStatus {
+    pub pending_action: PendingAction,
+    /// Seconds until the pending action runs
+    pub countdown_s: u16,
+    /// The load was switched off on low battery and is waiting to be restored
+    pub low_battery_shutdown: bool,
+}
+
+/// Delayed load shutdown/restore state machine, advanced once per second by `bq76920_task`
+#[derive(Debug, Default)]
+pub struct LoadScheduler {
+    pending: PendingAction,
+    countdown_s: u16,
+    off_time_s: u16,
+}
+
+impl LoadScheduler {
+    pub const fn new() -> Self {
+        Self {
+            pending: PendingAction::None,
+            countdown_s: 0,
+            off_time_s: 0,
+        }
+    }
+
+    /// Handles a host command. Commands with zero delay run on the next `tick`.
+    pub fn handle_command(&mut self, command: LoadCommand) {
+        match command {
+            LoadCommand::ShutdownAfter { delay_s } => {
+                self.pending = PendingAction::Shutdown;
+                self.countdown_s = delay_s;
+                self.off_time_s = 0;
+            }
+            LoadCommand::ShutdownAndRestore {
+                delay_s,
+                off_time_s,
+            } => {
+                self.pending = PendingAction::ShutdownRestore;
+                self.countdown_s = delay_s;
+                self.off_time_s = off_time_s;
+            }
+            LoadCommand::Cancel => {
+                // Cancelling while already off and waiting to restore means staying off
+                self.pending = PendingAction::None;
+                self.countdown_s = 0;
+                self.off_time_s = 0;
+            }
+            LoadCommand::RestoreNow => {
+                self.pending = PendingAction::Restore;
+                self.countdown_s = 0;
+                self.off_time_s = 0;
+            }
+            LoadCommand::Watchdog(_) => {}
+        }
+    }
+
+    /// Advances by `elapsed_s` seconds and returns the FET operation to perform, if any
+    pub fn tick(&mut self, elapsed_s: u16) -> Option<LoadAction> {
+        if self.pending == PendingAction::None {
+            return None;
+        }
+        self.countdown_s = self.countdown_s.saturating_sub(elapsed_s);
+        if self.countdown_s > 0 {
+            return None;
+        }
+        match self.pending {
+            PendingAction::None => None,
+            PendingAction::Shutdown => {
+                self.pending = PendingAction::None;
+                Some(LoadAction::DisableOutput)
+            }
+            PendingAction::ShutdownRestore => {
+                self.pending = PendingAction::Restore;
+                self.countdown_s = self.off_time_s;
+                Some(LoadAction::DisableOutput)
+            }
+            PendingAction::Restore => {
+                self.pending = PendingAction::None;
+                self.off_time_s = 0;
+                Some(LoadAction::EnableOutput)
+            }
+        }
+    }
+
+    pub fn status(&self) -> LoadControlStatus {
+        LoadControlStatus {
+            pending_action: self.pending,
+            countdown_s: self.countdown_s,
+            low_battery_shutdown: false,
+        }
+    }
+}
+
+/// Automatic restore after a low-battery shutdown
+///
+/// The BQ76920 undervoltage protection (UV) switches the DSG FET off by itself and nothing turns
+/// it back on. This state machine latches the UV shutdown and asks for the output to be restored
+/// once mains is back and the charge has been sufficient for long enough.
+#[derive(Debug)]
+pub struct AutoRestore {
+    policy: RestorePolicy,
+    latched: bool,
+    stable_s: u16,
+}
+
+impl AutoRestore {
+    pub fn new(policy: RestorePolicy) -> Self {
+        Self {
+            policy,
+            latched: false,
+            stable_s: 0,
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: RestorePolicy) {
+        defmt::info!("AutoRestore: policy updated to {:?}", policy);
+        self.policy = policy;
+        self.stable_s = 0;
+    }
+
+    pub fn policy(&self) -> RestorePolicy {
+        self.policy
+    }
+
+    pub fn is_latched(&self) -> bool {
+        self.latched
+    }
+
+    /// Records a low-battery shutdown (UV reported by the BQ76920)
+    pub fn note_low_battery_shutdown(&mut self) {
+        if !self.latched {
+            defmt::warn!("AutoRestore: load switched off by low battery, waiting for input power");
+        }
+        self.latched = true;
+        self.stable_s = 0;
+    }
+
+    /// The load was switched back on some other way (manual command, automatic restore)
+    pub fn clear(&mut self) {
+        self.latched = false;
+        self.stable_s = 0;
+    }
+
+    /// Advances by `elapsed_s` seconds and returns the restore action once the conditions hold
+    pub fn tick(&mut self, elapsed_s: u16, ac_present: bool, soc_pct: u8) -> Option<LoadAction> {
+        if !self.latched || self.policy.mode == RestoreMode::Manual {
+            return None;
+        }
+        if !ac_present || soc_pct < self.policy.min_soc_pct {
+            self.stable_s = 0;
+            return None;
+        }
+        self.stable_s = self.stable_s.saturating_add(elapsed_s);
+        if self.stable_s < self.policy.delay_s {
+            return None;
+        }
+        defmt::info!(
+            "AutoRestore: input power stable for {}s and SoC {}% >= {}%, restoring load",
+            self.stable_s,
+            soc_pct,
+            self.policy.min_soc_pct
+        );
+        self.clear();
+        Some(LoadAction::EnableOutput)
+    }
+}
@@ -0,0 +1,169 @@
+//! INA226 load monitor: register encoding, alert threshold conversion and register access.
+//!
+//! With a 10 mΩ shunt and a 0.25 mA current LSB, full scale matches the shunt voltage range
+//! (81.92 mV), about 8.19 A; the power LSB is 25 times the current LSB, 6.25 mW.
+//!
+//! The open-drain, active-low ALERT pin is only used for the overcurrent (shunt voltage over
+//! limit, SOL) or overpower (POL) alert and is latched: it stays active until the Mask/Enable
+//! register is read. That read also clears the conversion ready flag CVRF, so both the
+//! measurement loop and the alert handling must check every flag they read.
+
+use embedded_hal_async::i2c::I2c;
+
+use crate::config::LoadMonitorConfig;
+
+const REG_CONFIGURATION: u8 = 0x00;
+const REG_BUS_VOLTAGE: u8 = 0x02;
+const REG_POWER: u8 = 0x03;
+const REG_CURRENT: u8 = 0x04;
+const REG_CALIBRATION: u8 = 0x05;
+const REG_MASK_ENABLE: u8 = 0x06;
+const REG_ALERT_LIMIT: u8 = 0x07;
+
+/// 0.00512 / (0.25 mA × 10 mΩ)
+const CALIBRATION: u16 = 2048;
+/// The shunt voltage LSB is 2.5 µV; 1 mA through 10 mΩ gives 10 µV
+const SHUNT_LSB_PER_MA: u32 = 4;
+/// The power LSB is 6.25 mW
+const POWER_LSB_PER_W: u32 = 160;
+const BUS_VOLTAGE_LSB_MV: f32 = 1.25;
+const CURRENT_LSB_MA: f32 = 0.25;
+const POWER_LSB_MW: f32 = 6.25;
+
+/// Reset value of the reserved bits in the Configuration register
+const CONFIGURATION_RESERVED: u16 = 0x4000;
+/// Continuous shunt and bus voltage conversion
+const MODE_SHUNT_BUS_CONTINUOUS: u16 = 0b111;
+
+/// Supported averaging counts, indexed by the AVG field value of the Configuration register
+pub const AVERAGING_COUNTS: [u16; 8] = [1, 4, 16, 64, 128, 256, 512, 1024];
+/// Supported conversion times (µs), indexed by the VBUSCT/VSHCT field value
+pub const CONVERSION_TIMES_US: [u16; 8] = [140, 204, 332, 588, 1100, 2116, 4156, 8244];
+
+/// Upper limit of the overcurrent threshold, set by the shunt voltage range
+pub const MAX_OVER_CURRENT_LIMIT_MA: u16 = 8000;
+/// Upper limit of the overpower threshold, set by the power register range
+pub const MAX_OVER_POWER_LIMIT_W: u16 = 400;
+
+/// Bits of the Mask/Enable register
+pub mod mask {
+    /// Alert when the shunt voltage exceeds the limit
+    pub const SOL: u16 = 1 << 15;
+    /// Alert when the power exceeds the limit
+    pub const POL: u16 = 1 << 11;
+    /// The alert function triggered (cleared by reading, in latch mode)
+    pub const AFF: u16 = 1 << 4;
+    /// Conversion ready, cleared by reading Mask/Enable or writing Configuration
+    pub const CVRF: u16 = 1 << 3;
+    /// Alert latch enable
+    pub const LEN: u16 = 1 << 0;
+}
+
+/// Quantity watched by the alert
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum LoadAlert {
+    Disabled,
+    OverCurrent,
+    OverPower,
+}
+
+impl LoadMonitorConfig {
+    /// Enabled alert; at most one of the two thresholds is non-zero
+    pub fn alert(&self) -> LoadAlert {
+        if self.over_current_limit_ma != 0 {
+            LoadAlert::OverCurrent
+        } else if self.over_power_limit_w != 0 {
+            LoadAlert::OverPower
+        } else {
+            LoadAlert::Disabled
+        }
+    }
+
+    /// Configuration register value; the averaging count and conversion times must already be
+    /// validated
+    fn configuration(&self) -> u16 {
+        let index =
+            |table: &[u16], value: u16| table.iter().position(|v| *v == value).unwrap_or(0) as u16;
+        CONFIGURATION_RESERVED
+            | index(&AVERAGING_COUNTS, self.averaging) << 9
+            | index(&CONVERSION_TIMES_US, self.bus_conversion_us) << 6
+            | index(&CONVERSION_TIMES_US, self.shunt_conversion_us) << 3
+            | MODE_SHUNT_BUS_CONTINUOUS
+    }
+
+    /// Mask/Enable and Alert Limit register values
+    fn alert_registers(&self) -> (u16, u16) {
+        match self.alert() {
+            LoadAlert::Disabled => (0, 0),
+            LoadAlert::OverCurrent => (
+                mask::SOL | mask::LEN,
+                (self.over_current_limit_ma as u32 * SHUNT_LSB_PER_MA).min(i16::MAX as u32) as u16,
+            ),
+            LoadAlert::OverPower => (
+                mask::POL | mask::LEN,
+                (self.over_power_limit_w as u32 * POWER_LSB_PER_W).min(u16::MAX as u32) as u16,
+            ),
+        }
+    }
+}
+
+/// Result of one conversion (mV, mA, mW), uncalibrated
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct LoadSample {
+    pub voltage_mv: f32,
+    pub current_ma: f32,
+    pub power_mw: f32,
+}
+
+/// INA226 register access
+pub struct Ina226<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Ina226<I2C> {
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+
+    /// Writes the calibration, conversion settings and alert threshold. Writing Configuration
+    /// restarts the conversion.
+    pub async fn configure(&mut self, config: &LoadMonitorConfig) -> Result<(), I2C::Error> {
+        let (mask_enable, alert_limit) = config.alert_registers();
+        self.write(REG_CALIBRATION, CALIBRATION).await?;
+        self.write(REG_CONFIGURATION, config.configuration())
+            .await?;
+        self.write(REG_ALERT_LIMIT, alert_limit).await?;
+        self.write(REG_MASK_ENABLE, mask_enable).await
+    }
+
+    /// Reads the Mask/Enable register, which also clears CVRF and the latched alert
+    pub async fn read_flags(&mut self) -> Result<u16, I2C::Error> {
+        self.read(REG_MASK_ENABLE).await
+    }
+
+    /// Reads the result of the latest conversion
+    pub async fn read_sample(&mut self) -> Result<LoadSample, I2C::Error> {
+        let bus_voltage = self.read(REG_BUS_VOLTAGE).await?;
+        let current = self.read(REG_CURRENT).await? as i16;
+        let power = self.read(REG_POWER).await?;
+        Ok(LoadSample {
+            voltage_mv: bus_voltage as f32 * BUS_VOLTAGE_LSB_MV,
+            current_ma: current as f32 * CURRENT_LSB_MA,
+            power_mw: power as f32 * POWER_LSB_MW,
+        })
+    }
+
+    async fn read(&mut self, register: u8) -> Result<u16, I2C::Error> {
+        let mut buf = [0u8; 2];
+        self.i2c
+            .write_read(self.address, &[register], &mut buf)
+            .await?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    async fn write(&mut self, register: u8, value: u16) -> Result<(), I2C::Error> {
+        let [high, low] = value.to_be_bytes();
+        self.i2c.write(self.address, &[register, high, low]).await
+    }
+}
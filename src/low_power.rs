@@ -0,0 +1,102 @@
+//! MCU low-power operation: STOP mode on battery and current estimation.
+//!
+//! `sampling` selects the low-power mode from the UPS state (`SamplingMode::LowPower`) and the
+//! device tasks slow their sampling accordingly. While USB is also suspended (or there is no
+//! host) the MCU may enter STOP mode between wake-ups:
+//! - STOP is entered by the embassy low-power executor and needs the `stop-mode` feature. The
+//!   executor enters STOP when it is idle and the next timer is far enough away; the RTC wakes it
+//!   to keep the timers running, so periodic tasks such as the watchdog carry on;
+//! - the BQ76920 ALERT (EXTI) wakes the MCU on faults and every CC_READY. USB bus resume is not
+//!   a wake-up source: the USB wake-up EXTI line is not set up, so the USB peripheral only sees
+//!   the resume at the next RTC or ALERT wake-up;
+//! - while STOP is not allowed, `low_power_task` holds a `DeviceBusy` guard so that the executor
+//!   only enters normal sleep and the USB peripheral clock keeps running. The guard adds no
+//!   wake-ups, so the estimate below only counts the sampling and CC_READY reads.
+//!
+//! There is no way to measure the MCU current. The saving is estimated from datasheet typical
+//! values and the wake-ups of each task; it is only for comparing modes, not a measurement.
+
+#[cfg(feature = "stop-mode")]
+use embassy_stm32::low_power::DeviceBusy;
+
+#[cfg(feature = "stop-mode")]
+use crate::sampling::SamplingControl;
+use crate::sampling::{BQ25730_ADC_ONE_SHOT_TIME, SamplingMode, SamplingRates, SamplingStatus};
+
+/// MCU run current (µA) on the HSI16 system clock
+const MCU_RUN_UA: u32 = 3000;
+/// MCU sleep (WFE) current (µA)
+const MCU_SLEEP_UA: u32 = 1000;
+/// MCU current in STOP mode with the RTC running (µA)
+const MCU_STOP_UA: u32 = 100;
+/// Additional current while the BQ25730 ADC converts (µA)
+const BQ25730_ADC_UA: u32 = 500;
+
+/// Time the MCU stays awake per read (µs), mostly I2C transfers
+const BQ76920_READ_US: u32 = 3000;
+const BQ25730_READ_US: u32 = 1500;
+const INA226_READ_US: u32 = 1000;
+/// Time to read the coulomb counter on CC_READY (µs), every 250 ms regardless of the sampling
+/// mode
+const CC_READY_READ_US: u32 = 500;
+const CC_READY_PER_S: u32 = 4;
+
+/// Estimated current of the MCU and the BQ25730 ADC
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub struct CurrentEstimate {
+    /// Current in the present mode (µA)
+    pub current_ua: u32,
+    /// Saving compared to `Normal` mode without STOP (µA)
+    pub saving_ua: u32,
+}
+
+impl CurrentEstimate {
+    pub fn new(status: &SamplingStatus) -> Self {
+        let stop = status.stop_allowed() && cfg!(feature = "stop-mode");
+        let current_ua = estimate_ua(&status.rates(), stop);
+        let baseline_ua = estimate_ua(&SamplingMode::Normal.rates(), false);
+        Self {
+            current_ua,
+            saving_ua: baseline_ua.saturating_sub(current_ua),
+        }
+    }
+}
+
+/// Estimates the current from the share of time awake (µA)
+fn estimate_ua(rates: &SamplingRates, stop: bool) -> u32 {
+    // Time awake per second (µs)
+    let per_s = |read_us: u32, period_ms: u16| read_us * 1000 / period_ms.max(1) as u32;
+    let awake_us = (per_s(BQ76920_READ_US, rates.bq76920_ms)
+        + per_s(BQ25730_READ_US, rates.bq25730_ms)
+        + per_s(INA226_READ_US, rates.ina226_ms)
+        + CC_READY_PER_S * CC_READY_READ_US)
+        .min(1_000_000);
+    let idle_ua = if stop { MCU_STOP_UA } else { MCU_SLEEP_UA };
+    let mcu_ua = (MCU_RUN_UA as u64 * awake_us as u64
+        + idle_ua as u64 * (1_000_000 - awake_us) as u64)
+        / 1_000_000;
+
+    let adc_ua = if rates.bq25730_adc_one_shot {
+        BQ25730_ADC_UA as u64 * BQ25730_ADC_ONE_SHOT_TIME.as_millis() / rates.bq25730_ms as u64
+    } else {
+        BQ25730_ADC_UA as u64
+    };
+    (mcu_ua + adc_ua) as u32
+}
+
+/// Embassy task that keeps the low-power executor out of STOP mode unless `sampling` allows
+/// it. STOP stops the USB clock, so it is only permitted while the bus is suspended.
+#[cfg(feature = "stop-mode")]
+#[embassy_executor::task]
+pub async fn low_power_task(sampling: &'static SamplingControl) {
+    loop {
+        // Blocking STOP1 also blocks the deeper STOP modes; dropping the guard releases it
+        let _busy = if sampling.status().stop_allowed() {
+            defmt::info!("LowPower: STOP mode allowed");
+            None
+        } else {
+            Some(DeviceBusy::new_stop1())
+        };
+        sampling.wait_stop_changed().await;
+    }
+}
@@ -5,14 +5,18 @@
 extern crate alloc; // Required for global allocator
 
 // use defmt::*; // Removed unused import
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_embedded_hal::adapter::BlockingAsync;
+use embassy_embedded_hal::flash::partition::Partition;
 use embassy_executor::Spawner;
 use embassy_stm32::{
     bind_interrupts,
-    i2c::{self, I2c},
+    exti::ExtiInput,
+    flash::Flash,
+    gpio::Pull,
+    i2c,
     peripherals, // Keep peripherals here
-    time::Hertz,
     usb::Driver, // Remove InterruptHandler as it's not directly used here
+    wdg::IndependentWatchdog,
 };
 // Import NtcParameters if it's to be configured here
 use bq769x0_async_rs::data_types::NtcParameters;
@@ -24,20 +28,44 @@ bind_interrupts!(
         I2C1_ER => i2c::ErrorInterruptHandler<peripherals::I2C1>;
     }
 );
+use defmt_rtt as _;
 use embassy_time::{Duration, Timer};
-use {defmt_rtt as _, panic_probe as _};
 
 // 声明共享模块
+mod battery_gauge;
+mod battery_test;
 mod bq25730_task;
+mod bq76920_alert;
 mod bq76920_task;
+mod config_transaction;
+mod crash_report;
 mod data_types;
+mod energy_meter;
+mod event_log;
+mod flash_layout;
+mod hardware_probe;
+mod i2c_supervisor;
 mod ina226_task;
+mod low_power;
+mod sensor_consistency;
 mod shared;
+mod task_watchdog;
+mod ups_state;
 mod usb; // Keep this for our local usb module
 
+// Hardware-independent modules live in the library so that they can be tested on the host
+use ups120::{
+    battery_profile, calibration, config, config_store, crc, hid_report, host_watchdog, i2c_bus,
+    load_control, load_monitor, overload_protection, power_quality, protection, sampling,
+    task_monitor, ups_status,
+};
+
 // For sharing I2C bus
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
+use i2c_supervisor::{I2cDeviceId, I2cSupervisor, SupervisedI2c};
+
+use event_log::{PowerEvent, PowerEventCode};
+use task_watchdog::{SupervisedTask, TaskWatchdog};
 
 // Global allocator
 use embedded_alloc::LlffHeap as Heap; // Import Heap from embedded_alloc
@@ -45,7 +73,12 @@ use embedded_alloc::LlffHeap as Heap; // Import Heap from embedded_alloc
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
 
-#[embassy_executor::main]
+// With `stop-mode` the executor enters STOP mode when idle and STOP is not held off
+#[cfg_attr(not(feature = "stop-mode"), embassy_executor::main)]
+#[cfg_attr(
+    feature = "stop-mode",
+    embassy_executor::main(executor = "embassy_stm32::low_power::Executor")
+)]
 async fn main(spawner: Spawner) {
     // Initialize global allocator
     {
@@ -72,13 +105,153 @@ async fn main(spawner: Spawner) {
         bq76920_measurements_channel, // Channel for BQ76920 Measurements, used to create subscriber
         ina226_measurements_publisher,
         ina226_measurements_channel, // Channel for INA226 Measurements, used to create subscriber
-                                     // Removed runtime config pub/sub from destructuring
+        load_command_channel,        // Load control commands, usb_task -> bq76920_task
+        battery_test_command_channel, // Battery self-test commands, usb_task -> bq25730_task
+        power_event_channel, // Power events, usb_task/bq76920_task/ina226_task -> event_log_task
+        system_config_publisher, // Live configuration updates, usb_task -> device tasks
+        system_config_channel, // Channel for configuration updates, used to create subscribers
     ) = shared::init_pubsubs();
 
-    let config = embassy_stm32::Config::default();
+    #[allow(unused_mut)]
+    let mut config = embassy_stm32::Config::default();
+    #[cfg(feature = "stop-mode")]
+    {
+        // The RTC wakes the executor from STOP mode; debug access would keep the clocks running
+        config.rcc.ls = embassy_stm32::rcc::LsConfig::default_lsi();
+        config.enable_debug_during_sleep = false;
+    }
     let p = embassy_stm32::init(config);
 
+    #[cfg(feature = "stop-mode")]
+    {
+        use embassy_stm32::rtc::{Rtc, RtcConfig};
+        static RTC_CELL: static_cell::StaticCell<Rtc> = static_cell::StaticCell::new();
+        let rtc = RTC_CELL.init(Rtc::new(p.RTC, RtcConfig::default()));
+        embassy_stm32::low_power::stop_with_rtc(rtc);
+    }
+
+    // Read back why the previous run ended before the watchdog task overwrites the record
+    let watchdog_report = task_watchdog::take_last_report();
+    defmt::info!("Last watchdog report: {:?}", watchdog_report);
+    if watchdog_report.is_watchdog_reset() {
+        defmt::warn!("Recovered from a watchdog reset");
+        let detail = watchdog_report.task.map_or(0xFF, |task| task as u16)
+            | (watchdog_report.verdict as u16) << 8;
+        let event = PowerEvent::new(PowerEventCode::WatchdogReset, detail);
+        if power_event_channel.sender().try_send(event).is_err() {
+            defmt::warn!("Power event queue full, watchdog reset not logged");
+        }
+    }
+    // A panic in the previous run is recorded by the panic handler and logged after the reset entry
+    let last_crash = crash_report::take_last_report(watchdog_report.reset_reason);
+    if let Some(crash) = &last_crash {
+        defmt::warn!(
+            "Previous run panicked after {} s at {}:{}:{}: {}",
+            crash.uptime_s,
+            crash.file(),
+            crash.line,
+            crash.column,
+            crash.message()
+        );
+    }
+
+    // Every supervised task registers before it is spawned and must check in within its own
+    // deadline, otherwise the IWDG is no longer fed
+    static TASK_WATCHDOG_CELL: static_cell::StaticCell<TaskWatchdog> =
+        static_cell::StaticCell::new();
+    let task_watchdog = TASK_WATCHDOG_CELL.init(TaskWatchdog::new());
+    spawner
+        .spawn(task_watchdog::watchdog_task(
+            IndependentWatchdog::new(p.IWDG, task_watchdog::IWDG_TIMEOUT_US),
+            task_watchdog,
+        ))
+        .unwrap();
+
+    // On-chip flash: the last 16K holds the persistent data, and the partitions share one driver
+    static FLASH_CELL: static_cell::StaticCell<flash_layout::SharedFlash> =
+        static_cell::StaticCell::new();
+    let flash = FLASH_CELL.init(Mutex::new(BlockingAsync::new(Flash::new_blocking(p.FLASH))));
+
+    static EVENT_LOG_CELL: static_cell::StaticCell<event_log::SharedEventLog> =
+        static_cell::StaticCell::new();
+    let event_log = EVENT_LOG_CELL.init(Mutex::new(
+        event_log::EventLog::new(Partition::new(
+            flash,
+            flash_layout::EVENT_LOG_OFFSET,
+            flash_layout::EVENT_LOG_SIZE,
+        ))
+        .await,
+    ));
+
+    // Load the persistent config, falling back to the built-in defaults without a store
+    static CONFIG_STORE_CELL: static_cell::StaticCell<flash_layout::SharedConfigStore> =
+        static_cell::StaticCell::new();
+    let config_partition = Partition::new(
+        flash,
+        flash_layout::CONFIG_STORE_OFFSET,
+        flash_layout::CONFIG_STORE_SIZE,
+    );
+    let (system_config, config_store) =
+        match config_store::ConfigStore::new(config_partition, config::CONFIG_SCHEMA_VERSION).await
+        {
+            Ok(mut store) => {
+                let system_config = config::SystemConfig::load(&mut store).await;
+                (
+                    system_config,
+                    Some(&*CONFIG_STORE_CELL.init(Mutex::new(store))),
+                )
+            }
+            Err(e) => {
+                defmt::error!(
+                    "Failed to open config store, using defaults: {:?}",
+                    defmt::Debug2Format(&e)
+                );
+                (config::SystemConfig::default(), None)
+            }
+        };
+
+    // Energy counters fed by the device tasks; restored from and checkpointed to the config store
+    static ENERGY_METER_CELL: static_cell::StaticCell<energy_meter::EnergyMeter> =
+        static_cell::StaticCell::new();
+    let energy_meter = ENERGY_METER_CELL.init(energy_meter::EnergyMeter::new());
+    if let Some(store) = config_store {
+        spawner
+            .spawn(energy_meter::energy_meter_task(energy_meter, store))
+            .unwrap();
+    }
+
+    // Sampling mode chosen by the BQ76920 task from the UPS state and read by the device tasks
+    static SAMPLING_CELL: static_cell::StaticCell<sampling::SamplingControl> =
+        static_cell::StaticCell::new();
+    let sampling = SAMPLING_CELL.init(sampling::SamplingControl::new());
+    #[cfg(feature = "stop-mode")]
+    spawner.spawn(low_power::low_power_task(sampling)).unwrap();
+
+    // Create a static Mutex to share the I2C1 bus (PA15 SCL, PB7 SDA, with DMA) between
+    // multiple drivers. The supervisor tracks per-device health and clears the bus after a
+    // bus fault; `I2c1Bus` owns the peripheral, pins and DMA channels for that.
+    static I2C_BUS_MUTEX_CELL: static_cell::StaticCell<i2c_supervisor::SharedI2cBus> =
+        static_cell::StaticCell::new();
+    static I2C_SUPERVISOR_CELL: static_cell::StaticCell<I2cSupervisor> =
+        static_cell::StaticCell::new();
+    let i2c_bus_mutex = I2C_BUS_MUTEX_CELL.init(Mutex::new(Some(i2c_supervisor::I2c1Bus::new(
+        p.I2C1, p.PA15, p.PB7, p.DMA1_CH3, p.DMA1_CH4,
+    ))));
+    let i2c_bus_supervisor = I2C_SUPERVISOR_CELL.init(I2cSupervisor::new());
+
+    spawner
+        .spawn(i2c_supervisor::i2c_recovery_task(
+            i2c_bus_mutex,
+            i2c_bus_supervisor,
+            power_event_channel.sender(), // Log bus recoveries
+        ))
+        .unwrap();
+
+    // Find out which chips are fitted before spawning the tasks that drive them
+    let inventory = hardware_probe::probe(i2c_bus_mutex, i2c_bus_supervisor).await;
+
     let usb_driver = Driver::new(p.USB, Irqs, p.PA12, p.PA11);
+    task_watchdog.register(SupervisedTask::Usb);
     spawner
         .spawn(usb::usb_task(
             usb_driver,
@@ -88,65 +261,86 @@ async fn main(spawner: Spawner) {
             bq76920_measurements_channel.subscriber().unwrap(), // Create BQ76920 measurements subscriber
             bq25730_alerts_channel.subscriber().unwrap(),       // Create BQ25730 alerts subscriber
             bq76920_alerts_channel.subscriber().unwrap(),       // Create BQ76920 alerts subscriber
+            load_command_channel.sender(),                      // Forward load control commands
+            battery_test_command_channel.sender(), // Forward battery self-test commands
+            power_event_channel.sender(),          // Log configuration changes
+            event_log,                             // Page out the event log on request
+            system_config,                         // Authoritative runtime configuration
+            system_config_publisher,               // Broadcast configuration changes
+            config_store,                          // Persist configuration changes on request
+            energy_meter,                          // Energy counters for telemetry and reset
+            sampling,           // Report the sampling mode, hold it while subscribed
+            i2c_bus_supervisor, // I2C device health for telemetry
+            inventory,          // Hardware found by the boot probe
+            task_watchdog,      // Check in with the task watchdog
+            watchdog_report,    // Why the previous run ended
+            last_crash,         // Panic in the previous run, if any
         ))
         .unwrap();
 
-    // Configure I2C1 (PB6 SCL, PB7 SDA) with DMA
-    let mut i2c_config = i2c::Config::default();
-    i2c_config.scl_pullup = true;
-    i2c_config.sda_pullup = true;
-
-    // Create a static Mutex to share the I2C bus between multiple drivers
-    static I2C_BUS_MUTEX_CELL: static_cell::StaticCell<
-        Mutex<CriticalSectionRawMutex, I2c<'static, embassy_stm32::mode::Async>>,
-    > = static_cell::StaticCell::new();
-    let i2c_instance = embassy_stm32::i2c::I2c::new(
-        p.I2C1,
-        p.PA15, // Assuming PA15 is SCL, PB7 is SDA. Please verify.
-        p.PB7,
-        Irqs,
-        p.DMA1_CH3, // DMA for TX
-        p.DMA1_CH4, // DMA for RX
-        Hertz(100_000),
-        i2c_config,
-    );
-
-    // Initialize the static Mutex with the I2C instance
-    let i2c_bus_mutex = I2C_BUS_MUTEX_CELL.init(Mutex::new(unsafe {
-        core::mem::transmute::<
-            embassy_stm32::i2c::I2c<'_, embassy_stm32::mode::Async>,
-            embassy_stm32::i2c::I2c<'static, embassy_stm32::mode::Async>,
-        >(i2c_instance)
-    }));
-
-    // BQ76920 I2C address (7-bit)
-    let bq76920_address = 0x08;
-    // BQ25730 I2C address (7-bit)
-    let bq25730_address = 0x6B; // Confirmed from bq25730.pdf
-    // INA226 I2C address (7-bit)
-    let ina226_address = 0x40;
-
-    // Spawn device tasks
     spawner
-        .spawn(bq25730_task::bq25730_task(
-            I2cDevice::new(i2c_bus_mutex), // Create a new I2cDevice for the task using the static mutex
-            bq25730_address,
-            bq25730_alerts_publisher,
-            bq25730_measurements_publisher, // This is Bq25730MeasurementsPublisher
-            bq76920_measurements_channel.subscriber().unwrap(), // Create BQ76920 measurements subscriber for bq25730_task
-                                                                // Removed bq25730_runtime_config_publisher from arguments
+        .spawn(event_log::event_log_task(
+            event_log,
+            power_event_channel.receiver(),
+            bq76920_measurements_channel.subscriber().unwrap(),
+            bq25730_measurements_channel.subscriber().unwrap(),
+            ina226_measurements_channel.subscriber().unwrap(),
+            bq25730_alerts_channel.subscriber().unwrap(),
+            system_config_channel.subscriber().unwrap(),
+            system_config.low_battery,
+            last_crash.map(|crash| crash.log_entry()),
         ))
         .unwrap();
 
-    spawner
-        .spawn(ina226_task::ina226_task(
-            I2cDevice::new(i2c_bus_mutex), // Create a new I2cDevice for the task using the static mutex
-            ina226_address,
-            ina226_measurements_publisher,
-        ))
-        .unwrap();
+    // Spawn device tasks for the chips that were found. Without a BQ76920 the charger never
+    // gets charge permission; without a BQ25730 the battery is only monitored.
+    if inventory.bq25730.present() {
+        task_watchdog.register(SupervisedTask::Bq25730);
+        spawner
+            .spawn(bq25730_task::bq25730_task(
+                SupervisedI2c::new(i2c_bus_mutex, i2c_bus_supervisor, I2cDeviceId::Bq25730),
+                i2c_bus_supervisor,
+                inventory.bq25730.address,
+                bq25730_alerts_publisher,
+                bq25730_measurements_publisher, // This is Bq25730MeasurementsPublisher
+                bq76920_measurements_channel.subscriber().unwrap(), // Create BQ76920 measurements subscriber for bq25730_task
+                battery_test_command_channel.receiver(), // Receive battery self-test commands from USB
+                system_config_channel.subscriber().unwrap(), // Live charger configuration updates
+                system_config.charger,
+                system_config.power_quality,
+                system_config.calibration.bq25730,
+                energy_meter, // Input energy
+                sampling,     // ADC period and one-shot mode
+                task_watchdog,
+                // Removed bq25730_runtime_config_publisher from arguments
+            ))
+            .unwrap();
+    }
+
+    if inventory.ina226.present() {
+        // INA226 ALERT on PB4: open drain, active low
+        let ina226_alert = ExtiInput::new(p.PB4, p.EXTI4, Pull::Up);
+        task_watchdog.register(SupervisedTask::Ina226);
+        spawner
+            .spawn(ina226_task::ina226_task(
+                SupervisedI2c::new(i2c_bus_mutex, i2c_bus_supervisor, I2cDeviceId::Ina226),
+                i2c_bus_supervisor,
+                inventory.ina226.address,
+                ina226_alert, // ALERT pin
+                ina226_measurements_publisher,
+                power_event_channel.sender(), // Log load over-limit alerts
+                system_config_channel.subscriber().unwrap(), // Live ADC, alert and calibration updates
+                system_config.load_monitor,
+                system_config.calibration.ina226,
+                energy_meter, // Load energy
+                sampling,     // Read period and averaging
+                task_watchdog,
+            ))
+            .unwrap();
+    }
 
-    let bq76920_i2c_bus = I2cDevice::new(i2c_bus_mutex); // Create a new I2cDevice for the task using the static mutex
+    let bq76920_i2c_bus =
+        SupervisedI2c::new(i2c_bus_mutex, i2c_bus_supervisor, I2cDeviceId::Bq76920);
 
     // Define BQ76920 specific configurations needed for its driver initialization
     let bq76920_sense_resistor_m_ohm: u32 = 3; // Example: 3 mΩ
@@ -159,16 +353,37 @@ async fn main(spawner: Spawner) {
     // ref_resistance_ohm: 10000,
     // });
 
-    spawner
-        .spawn(bq76920_task::bq76920_task(
-            bq76920_i2c_bus,
-            bq76920_address,
-            bq76920_sense_resistor_m_ohm, // Pass sense resistor value
-            bq76920_ntc_params,           // Pass NTC parameters
-            bq76920_alerts_publisher,
-            bq76920_measurements_publisher, // Pass the BQ76920 measurements publisher
-        ))
-        .unwrap();
+    if inventory.bq76920.present() {
+        // BQ76920 ALERT on PB5. Pulled down so that an open line is detected as stuck low.
+        let bq76920_alert = ExtiInput::new(p.PB5, p.EXTI5, Pull::Down);
+        task_watchdog.register(SupervisedTask::Bq76920);
+        spawner
+            .spawn(bq76920_task::bq76920_task(
+                bq76920_i2c_bus,
+                i2c_bus_supervisor,
+                inventory.bq76920.address,
+                bq76920_alert,                // ALERT pin
+                bq76920_sense_resistor_m_ohm, // Pass sense resistor value
+                bq76920_ntc_params,           // Pass NTC parameters
+                bq76920_alerts_publisher,
+                bq76920_measurements_publisher, // Pass the BQ76920 measurements publisher
+                load_command_channel.receiver(), // Receive load control commands from USB
+                bq25730_measurements_channel.subscriber().unwrap(), // VBUS for automatic load restore
+                ina226_measurements_channel.subscriber().unwrap(), // Load power for overload protection
+                power_event_channel.sender(), // Log host watchdog trips and overloads
+                system_config_channel.subscriber().unwrap(), // Live balancing configuration updates
+                system_config.cell_protection,
+                system_config.balancing,
+                system_config.restore_policy,
+                system_config.overload,
+                system_config.calibration.bq76920,
+                config_store, // Battery gauge checkpoint
+                energy_meter, // Battery charge and discharge energy
+                sampling,     // Select the sampling mode each cycle
+                task_watchdog,
+            ))
+            .unwrap();
+    }
 
     // The main loop is no longer needed here as device logic is in separate tasks
     // This task can now just idle or perform other high-level coordination if needed.
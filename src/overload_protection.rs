@@ -0,0 +1,527 @@
+//! Load output overload protection: accumulates overload from the load power measured by the
+//! INA226, switches the DSG FET off under sustained overload, and then retries the output as
+//! configured.
+//!
+//! The overload level is in seconds and trips at `trip_time_s`:
+//! - `I2t`: with the load ratio x = P / rated power, x² − 1 is added every second (negative, i.e.
+//!   cooling down, for x < 1), so the trip time is trip_time_s / (x² − 1) and a short surge only
+//!   uses part of the margin;
+//! - `TimeOverThreshold`: 1 is added every second above the rated power and subtracted otherwise.
+//!
+//! A warning comes first when the level reaches `warn_pct` of the trip value. After a trip the
+//! output is switched back on after `retry_delay_s`; if it is still overloaded after
+//! `max_retries` retries in a row, it stays off until the host sends `RestoreNow`.
+//! The state machine only depends on the power and the elapsed time passed in.
+
+use crate::load_control::LoadAction;
+
+/// Rated output power of the unit (W); the overload protection's rated power cannot be higher
+pub const RATED_POWER_W: u16 = 120;
+
+/// How long the output must run normally before the retry count is cleared (s)
+const RETRY_RESET_S: u16 = 600;
+
+/// Longest time counted by one update, so a gap in the measurements is not all counted as
+/// overload (ms)
+const MAX_UPDATE_INTERVAL_MS: u32 = 2000;
+
+/// Overload curve
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum OverloadCurve {
+    Disabled = 0,
+    I2t = 1,
+    TimeOverThreshold = 2,
+}
+
+impl OverloadCurve {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Disabled),
+            1 => Some(Self::I2t),
+            2 => Some(Self::TimeOverThreshold),
+            _ => None,
+        }
+    }
+}
+
+/// Overload protection parameters
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct OverloadConfig {
+    pub curve: OverloadCurve,
+    /// Rated output power (W)
+    pub rated_power_w: u16,
+    /// `I2t`: the curve constant, i.e. the trip time at √2 times the rated power;
+    /// `TimeOverThreshold`: the accumulated time allowed above the rated power (s)
+    pub trip_time_s: u16,
+    /// Warn when the overload level reaches this percentage of the trip value (%)
+    pub warn_pct: u8,
+    /// Wait after a trip before switching the output back on (s)
+    pub retry_delay_s: u16,
+    /// Automatic retries in a row; 0 keeps the output off after a trip
+    pub max_retries: u8,
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        Self {
+            curve: OverloadCurve::I2t,
+            rated_power_w: RATED_POWER_W,
+            trip_time_s: 60,
+            warn_pct: 50,
+            retry_delay_s: 60,
+            max_retries: 3,
+        }
+    }
+}
+
+/// Overload protection state; the value appears in the USB status frame
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum OverloadState {
+    #[default]
+    Normal = 0,
+    /// The overload level is above the warning value
+    Warning = 1,
+    /// The output is off, waiting for the automatic retry
+    Tripped = 2,
+    /// Out of retries; the output stays off until the host restores it
+    Latched = 3,
+}
+
+/// State changes worth logging
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum OverloadEvent {
+    Warning,
+    Trip,
+}
+
+/// Overload protection status, published with the BQ76920 measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct OverloadStatus {
+    pub state: OverloadState,
+    /// Overload level as a percentage of the trip value
+    pub level_pct: u8,
+    /// Seconds until the automatic retry
+    pub retry_countdown_s: u16,
+    /// Retries used in the current round
+    pub retries_used: u8,
+    /// Trips since power-up
+    pub trip_count: u16,
+}
+
+#[derive(Debug)]
+pub struct OverloadProtection {
+    config: OverloadConfig,
+    state: OverloadState,
+    /// Accumulated overload (s)
+    level_s: f32,
+    retry_countdown_s: u16,
+    retries_used: u8,
+    trip_count: u16,
+    /// How long the output has been on without tripping, used to clear the retry count
+    normal_s: u16,
+}
+
+impl OverloadProtection {
+    pub fn new(config: OverloadConfig) -> Self {
+        Self {
+            config,
+            state: OverloadState::Normal,
+            level_s: 0.0,
+            retry_countdown_s: 0,
+            retries_used: 0,
+            trip_count: 0,
+            normal_s: 0,
+        }
+    }
+
+    /// Updates the parameters. Disabling the protection clears a trip and `tick` restores the
+    /// output.
+    pub fn set_config(&mut self, config: OverloadConfig) {
+        if config.curve != self.config.curve {
+            self.level_s = 0.0;
+        }
+        self.config = config;
+        if config.curve == OverloadCurve::Disabled {
+            match self.state {
+                OverloadState::Warning => self.state = OverloadState::Normal,
+                OverloadState::Latched => {
+                    self.state = OverloadState::Tripped;
+                    self.retry_countdown_s = 0;
+                }
+                OverloadState::Normal | OverloadState::Tripped => {}
+            }
+        }
+    }
+
+    /// After a trip the output must stay off, and nothing else may switch it back on
+    pub fn holds_output_off(&self) -> bool {
+        matches!(self.state, OverloadState::Tripped | OverloadState::Latched)
+    }
+
+    /// The host restores the output by hand (`RestoreNow`): clears the trip and the retry count
+    pub fn reset(&mut self) {
+        if self.holds_output_off() {
+            defmt::info!("Overload: cleared by host");
+        }
+        self.state = OverloadState::Normal;
+        self.level_s = 0.0;
+        self.retry_countdown_s = 0;
+        self.retries_used = 0;
+        self.normal_s = 0;
+    }
+
+    /// Adds one load power reading; `elapsed_ms` is the time since the previous reading
+    pub fn update(&mut self, power_mw: f32, elapsed_ms: u32) -> Option<OverloadEvent> {
+        let config = self.config;
+        if config.curve == OverloadCurve::Disabled {
+            return None;
+        }
+        let elapsed_s = elapsed_ms.min(MAX_UPDATE_INTERVAL_MS) as f32 / 1000.0;
+        let load = power_mw / (config.rated_power_w as f32 * 1000.0);
+        let rate = match config.curve {
+            OverloadCurve::Disabled => 0.0,
+            OverloadCurve::I2t => load * load - 1.0,
+            OverloadCurve::TimeOverThreshold => {
+                if load > 1.0 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+        };
+        let trip_s = config.trip_time_s as f32;
+        self.level_s = (self.level_s + rate * elapsed_s).clamp(0.0, trip_s);
+
+        let warn_s = trip_s * config.warn_pct as f32 / 100.0;
+        match self.state {
+            OverloadState::Normal | OverloadState::Warning if self.level_s >= trip_s => {
+                self.trip_count = self.trip_count.saturating_add(1);
+                self.normal_s = 0;
+                if self.retries_used < config.max_retries {
+                    self.state = OverloadState::Tripped;
+                    self.retry_countdown_s = config.retry_delay_s;
+                } else {
+                    self.state = OverloadState::Latched;
+                    self.retry_countdown_s = 0;
+                }
+                defmt::warn!(
+                    "Overload: load {} mW over {} W rating, output off ({:?}, retry {}/{}, trips {})",
+                    power_mw,
+                    config.rated_power_w,
+                    self.state,
+                    self.retries_used,
+                    config.max_retries,
+                    self.trip_count
+                );
+                Some(OverloadEvent::Trip)
+            }
+            OverloadState::Normal if self.level_s >= warn_s => {
+                self.state = OverloadState::Warning;
+                defmt::warn!(
+                    "Overload: load {} mW over {} W rating, {}% of trip level",
+                    power_mw,
+                    config.rated_power_w,
+                    self.level_pct()
+                );
+                Some(OverloadEvent::Warning)
+            }
+            // The warning only clears below half the warning value
+            OverloadState::Warning if self.level_s < warn_s / 2.0 => {
+                self.state = OverloadState::Normal;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Advances by `elapsed_s` seconds and returns the action that switches the output back on
+    /// once the retry is due
+    pub fn tick(&mut self, elapsed_s: u16) -> Option<LoadAction> {
+        match self.state {
+            OverloadState::Tripped => {
+                self.retry_countdown_s = self.retry_countdown_s.saturating_sub(elapsed_s);
+                if self.retry_countdown_s > 0 {
+                    return None;
+                }
+                if self.config.curve != OverloadCurve::Disabled {
+                    self.retries_used = self.retries_used.saturating_add(1);
+                }
+                self.state = OverloadState::Normal;
+                defmt::info!(
+                    "Overload: retrying output ({}/{})",
+                    self.retries_used,
+                    self.config.max_retries
+                );
+                Some(LoadAction::EnableOutput)
+            }
+            OverloadState::Latched => None,
+            OverloadState::Normal | OverloadState::Warning => {
+                if self.retries_used > 0 {
+                    self.normal_s = self.normal_s.saturating_add(elapsed_s);
+                    if self.normal_s >= RETRY_RESET_S {
+                        self.retries_used = 0;
+                        self.normal_s = 0;
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    fn level_pct(&self) -> u8 {
+        if self.config.trip_time_s == 0 {
+            return 0;
+        }
+        (self.level_s * 100.0 / self.config.trip_time_s as f32).clamp(0.0, 100.0) as u8
+    }
+
+    pub fn status(&self) -> OverloadStatus {
+        OverloadStatus {
+            state: self.state,
+            level_pct: self.level_pct(),
+            retry_countdown_s: self.retry_countdown_s,
+            retries_used: self.retries_used,
+            trip_count: self.trip_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(curve: OverloadCurve) -> OverloadConfig {
+        OverloadConfig {
+            curve,
+            ..Default::default()
+        }
+    }
+
+    /// Power at load ratio `load` (mW)
+    fn power_mw(load: f32) -> f32 {
+        load * RATED_POWER_W as f32 * 1000.0
+    }
+
+    /// Advances by one 1 s step, adding the reading before the `tick` like `bq76920_task` does.
+    /// The load power is 0 while the output is off.
+    fn step(
+        protection: &mut OverloadProtection,
+        load: f32,
+    ) -> (Option<OverloadEvent>, Option<LoadAction>) {
+        let load = if protection.holds_output_off() {
+            0.0
+        } else {
+            load
+        };
+        (protection.update(power_mw(load), 1000), protection.tick(1))
+    }
+
+    /// Runs at load ratio `load` until the trip and returns the time taken (s)
+    fn seconds_to_trip(
+        protection: &mut OverloadProtection,
+        load: f32,
+        limit_s: u32,
+    ) -> Option<u32> {
+        (1..=limit_s).find(|_| step(protection, load).0 == Some(OverloadEvent::Trip))
+    }
+
+    /// Runs until the automatic retry switches the output on and returns the time taken (s)
+    fn seconds_to_retry(protection: &mut OverloadProtection, limit_s: u32) -> Option<u32> {
+        (1..=limit_s).find(|_| step(protection, 0.0).1 == Some(LoadAction::EnableOutput))
+    }
+
+    #[test]
+    fn i2t_trips_after_trip_time_at_sqrt2_load() {
+        let mut protection = OverloadProtection::new(config(OverloadCurve::I2t));
+        let trip_s = seconds_to_trip(&mut protection, core::f32::consts::SQRT_2, 120).unwrap();
+        assert!((60..=61).contains(&trip_s), "tripped after {trip_s} s");
+        assert_eq!(protection.status().state, OverloadState::Tripped);
+        assert!(protection.holds_output_off());
+
+        // At twice the rated power x² − 1 = 3, so the trip comes in a third of the time
+        let mut protection = OverloadProtection::new(config(OverloadCurve::I2t));
+        assert_eq!(seconds_to_trip(&mut protection, 2.0, 120), Some(20));
+    }
+
+    #[test]
+    fn i2t_does_not_trip_at_rated_load() {
+        let mut protection = OverloadProtection::new(config(OverloadCurve::I2t));
+        assert_eq!(seconds_to_trip(&mut protection, 1.0, 3600), None);
+        assert_eq!(protection.status(), OverloadStatus::default());
+    }
+
+    #[test]
+    fn surge_is_tolerated_and_cools_down() {
+        let mut protection = OverloadProtection::new(config(OverloadCurve::I2t));
+        // A 5 s surge at twice the rated power uses a quarter of the margin
+        for _ in 0..5 {
+            assert_eq!(step(&mut protection, 2.0), (None, None));
+        }
+        assert_eq!(protection.status().level_pct, 25);
+
+        // At half load it cools by 0.75 s every second
+        for _ in 0..20 {
+            assert_eq!(step(&mut protection, 0.5), (None, None));
+        }
+        assert_eq!(protection.status().level_pct, 0);
+
+        // Once cooled down the same surge can repeat
+        for _ in 0..10 {
+            for _ in 0..5 {
+                step(&mut protection, 2.0);
+            }
+            for _ in 0..20 {
+                step(&mut protection, 0.5);
+            }
+        }
+        assert_eq!(protection.status().state, OverloadState::Normal);
+        assert_eq!(protection.status().trip_count, 0);
+
+        // Without enough margin left the surge trips too
+        for _ in 0..5 {
+            step(&mut protection, 2.0);
+        }
+        assert_eq!(seconds_to_trip(&mut protection, 2.0, 60), Some(15));
+    }
+
+    #[test]
+    fn warning_has_hysteresis() {
+        // Trips at 60 s, warns at 50% and clears below 25%
+        let mut protection = OverloadProtection::new(config(OverloadCurve::TimeOverThreshold));
+        for _ in 1..30 {
+            assert_eq!(step(&mut protection, 1.5), (None, None));
+        }
+        assert_eq!(step(&mut protection, 1.5).0, Some(OverloadEvent::Warning));
+        assert_eq!(protection.status().state, OverloadState::Warning);
+        assert_eq!(protection.status().level_pct, 50);
+        // The warning is reported only once
+        assert_eq!(step(&mut protection, 1.5).0, None);
+
+        // Down from 31 s to 15 s, still warning
+        for _ in 0..16 {
+            step(&mut protection, 0.5);
+        }
+        assert_eq!(protection.status().state, OverloadState::Warning);
+        step(&mut protection, 0.5);
+        assert_eq!(protection.status().state, OverloadState::Normal);
+        assert!(!protection.holds_output_off());
+
+        // Reaching the warning value again warns again
+        let warning =
+            (1..=30).find(|_| step(&mut protection, 1.5).0 == Some(OverloadEvent::Warning));
+        assert_eq!(warning, Some(16));
+    }
+
+    #[test]
+    fn retries_after_countdown() {
+        let mut protection = OverloadProtection::new(OverloadConfig {
+            retry_delay_s: 5,
+            ..config(OverloadCurve::TimeOverThreshold)
+        });
+        assert_eq!(seconds_to_trip(&mut protection, 1.5, 120), Some(60));
+        // The `tick` of the second that tripped already counts towards the countdown
+        let status = protection.status();
+        assert_eq!(status.state, OverloadState::Tripped);
+        assert_eq!(status.retry_countdown_s, 4);
+        assert_eq!(status.trip_count, 1);
+        assert_eq!(status.retries_used, 0);
+
+        for remaining in (1..4).rev() {
+            assert_eq!(step(&mut protection, 1.5), (None, None));
+            assert_eq!(protection.status().retry_countdown_s, remaining);
+        }
+        assert_eq!(step(&mut protection, 1.5).1, Some(LoadAction::EnableOutput));
+        let status = protection.status();
+        assert_eq!(status.state, OverloadState::Normal);
+        assert_eq!(status.retries_used, 1);
+
+        // The level falls during the 4 s off, so after the retry it again takes 4 s to trip
+        assert_eq!(seconds_to_trip(&mut protection, 1.5, 120), Some(4));
+    }
+
+    #[test]
+    fn latches_after_max_retries() {
+        let mut protection = OverloadProtection::new(config(OverloadCurve::I2t));
+        for retry in 1..=3 {
+            assert!(seconds_to_trip(&mut protection, 2.0, 120).is_some());
+            assert!(seconds_to_retry(&mut protection, 120).is_some());
+            assert_eq!(protection.status().retries_used, retry);
+        }
+
+        assert!(seconds_to_trip(&mut protection, 2.0, 120).is_some());
+        let status = protection.status();
+        assert_eq!(status.state, OverloadState::Latched);
+        assert_eq!(status.retry_countdown_s, 0);
+        assert_eq!(status.trip_count, 4);
+        assert_eq!(seconds_to_retry(&mut protection, 3600), None);
+        assert!(protection.holds_output_off());
+
+        // Only the host can clear it
+        protection.reset();
+        let status = protection.status();
+        assert_eq!(status.state, OverloadState::Normal);
+        assert_eq!(status.retries_used, 0);
+        assert_eq!(status.level_pct, 0);
+        assert!(!protection.holds_output_off());
+    }
+
+    #[test]
+    fn zero_retries_latches_on_first_trip() {
+        let mut protection = OverloadProtection::new(OverloadConfig {
+            max_retries: 0,
+            ..config(OverloadCurve::I2t)
+        });
+        assert!(seconds_to_trip(&mut protection, 2.0, 120).is_some());
+        assert_eq!(protection.status().state, OverloadState::Latched);
+    }
+
+    #[test]
+    fn retries_reset_after_normal_operation() {
+        let mut protection = OverloadProtection::new(config(OverloadCurve::I2t));
+        seconds_to_trip(&mut protection, 2.0, 120).unwrap();
+        seconds_to_retry(&mut protection, 120).unwrap();
+        assert_eq!(protection.status().retries_used, 1);
+
+        // A warning after the retry also counts as running normally: overload up to the warning
+        // value, then hold the rated load
+        for _ in 1..RETRY_RESET_S {
+            let load = match protection.status().state {
+                OverloadState::Warning => 1.0,
+                _ => 1.9,
+            };
+            step(&mut protection, load);
+        }
+        assert_eq!(protection.status().state, OverloadState::Warning);
+        assert_eq!(protection.status().retries_used, 1);
+        step(&mut protection, 1.0);
+        assert_eq!(protection.status().retries_used, 0);
+    }
+
+    #[test]
+    fn trip_before_retry_reset_keeps_counting() {
+        let mut protection = OverloadProtection::new(config(OverloadCurve::I2t));
+        seconds_to_trip(&mut protection, 2.0, 120).unwrap();
+        seconds_to_retry(&mut protection, 120).unwrap();
+        for _ in 1..RETRY_RESET_S / 2 {
+            step(&mut protection, 0.5);
+        }
+        seconds_to_trip(&mut protection, 2.0, 120).unwrap();
+        seconds_to_retry(&mut protection, 120).unwrap();
+        assert_eq!(protection.status().retries_used, 2);
+
+        // The trip cleared the normal running time
+        for _ in 1..RETRY_RESET_S {
+            step(&mut protection, 0.5);
+        }
+        assert_eq!(protection.status().retries_used, 2);
+    }
+
+    #[test]
+    fn long_update_interval_is_capped() {
+        let mut protection = OverloadProtection::new(config(OverloadCurve::TimeOverThreshold));
+        assert_eq!(protection.update(power_mw(1.5), 60_000), None);
+        assert_eq!(protection.status().level_pct, 3);
+    }
+}
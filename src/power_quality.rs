@@ -0,0 +1,267 @@
+//! Input supply (adapter) quality statistics, accumulated from the BQ25730 ADC VBUS and IIN
+//! readings:
+//!
+//! - statistics window: minimum, maximum and time-weighted average VBUS while the adapter is
+//!   present, and maximum and average IIN. The window length is configurable; the current window
+//!   and the last complete one are reported;
+//! - dropouts: VBUS below `AC_PRESENT_VBUS_MV`, with count and duration. Those shorter than
+//!   `SHORT_DROPOUT_MS` are counted separately (a loose adapter shows up as frequent short
+//!   dropouts, a mains outage as a few long ones);
+//! - sags: adapter present but VBUS below `sag_threshold_mv`, with count, lowest voltage and
+//!   duration;
+//! - battery time per day: 24-hour days counted from power-up, keeping the last `DAYS` days.
+//!
+//! The resolution is limited by the ADC read interval, so a dropout shorter than that may be
+//! missed. The statistics only depend on the times passed in.
+
+use embassy_time::{Duration, Instant};
+
+use crate::ups_status::AC_PRESENT_VBUS_MV;
+
+/// Dropouts shorter than this count as short dropouts (ms)
+pub const SHORT_DROPOUT_MS: u32 = 10_000;
+
+/// Days of battery time kept; index 0 is the current day
+pub const DAYS: usize = 7;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longest time counted by one reading, so a gap in the reads is not all counted in the last
+/// state (ms)
+const MAX_SAMPLE_INTERVAL_MS: u64 = 5000;
+
+/// Input supply quality parameters
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct PowerQualityConfig {
+    /// With the adapter present, VBUS below this counts as a sag (mV)
+    pub sag_threshold_mv: u16,
+    /// Statistics window length (s)
+    pub window_s: u16,
+}
+
+impl Default for PowerQualityConfig {
+    fn default() -> Self {
+        Self {
+            // Matches the BQ25730 VBUS VAP threshold, below which the charger draws on the battery
+            sag_threshold_mv: 9000,
+            window_s: 15 * 60,
+        }
+    }
+}
+
+/// VBUS and IIN while the adapter was present during one statistics window
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct VbusWindow {
+    pub vbus_min_mv: u16,
+    pub vbus_max_mv: u16,
+    pub vbus_mean_mv: u16,
+    pub iin_max_ma: u16,
+    pub iin_mean_ma: u16,
+    /// Time the adapter was present during the window (s)
+    pub present_s: u16,
+}
+
+/// Running sums of a window
+#[derive(Debug, Copy, Clone, Default)]
+struct WindowAccumulator {
+    vbus_min_mv: u16,
+    vbus_max_mv: u16,
+    iin_max_ma: u16,
+    /// mV × ms
+    vbus_sum: u64,
+    /// mA × ms
+    iin_sum: u64,
+    present_ms: u64,
+    elapsed_ms: u64,
+}
+
+impl WindowAccumulator {
+    fn add(&mut self, vbus_mv: u16, iin_ma: u16, interval_ms: u64) {
+        if self.present_ms == 0 {
+            self.vbus_min_mv = vbus_mv;
+        }
+        self.vbus_min_mv = self.vbus_min_mv.min(vbus_mv);
+        self.vbus_max_mv = self.vbus_max_mv.max(vbus_mv);
+        self.iin_max_ma = self.iin_max_ma.max(iin_ma);
+        self.vbus_sum += vbus_mv as u64 * interval_ms;
+        self.iin_sum += iin_ma as u64 * interval_ms;
+        self.present_ms += interval_ms;
+    }
+
+    fn summary(&self) -> VbusWindow {
+        let mean = |sum: u64| sum.checked_div(self.present_ms).unwrap_or(0) as u16;
+        VbusWindow {
+            vbus_min_mv: self.vbus_min_mv,
+            vbus_max_mv: self.vbus_max_mv,
+            vbus_mean_mv: mean(self.vbus_sum),
+            iin_max_ma: self.iin_max_ma,
+            iin_mean_ma: mean(self.iin_sum),
+            present_s: (self.present_ms / 1000).min(u16::MAX as u64) as u16,
+        }
+    }
+}
+
+/// Input supply quality statistics, published with the BQ25730 measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct PowerQualityStatus {
+    /// Window in progress
+    pub window: VbusWindow,
+    /// Last complete window
+    pub previous_window: VbusWindow,
+    /// Currently in a dropout (on battery)
+    pub dropout_active: bool,
+    pub dropout_count: u16,
+    /// Dropouts shorter than `SHORT_DROPOUT_MS`
+    pub short_dropout_count: u16,
+    pub longest_dropout_ms: u32,
+    /// Duration of the last finished dropout (ms)
+    pub last_dropout_ms: u32,
+    pub sag_count: u16,
+    /// Lowest VBUS during a sag (mV), 0 if there has been no sag
+    pub sag_min_mv: u16,
+    pub sag_total_ms: u32,
+    /// Battery time per day (s); index 0 is the current day
+    pub on_battery_s: [u32; DAYS],
+    /// Time elapsed in the current day (s)
+    pub day_elapsed_s: u32,
+}
+
+#[derive(Debug)]
+pub struct PowerQualityMonitor {
+    config: PowerQualityConfig,
+    status: PowerQualityStatus,
+    window: WindowAccumulator,
+    last_sample: Option<Instant>,
+    day_start: Option<Instant>,
+    /// How long the dropout in progress has lasted (ms)
+    dropout_ms: u32,
+    sagging: bool,
+    /// Battery time of the current day below a whole second (ms)
+    on_battery_remainder_ms: u32,
+}
+
+impl PowerQualityMonitor {
+    pub fn new(config: PowerQualityConfig) -> Self {
+        Self {
+            config,
+            status: PowerQualityStatus::default(),
+            window: WindowAccumulator::default(),
+            last_sample: None,
+            day_start: None,
+            dropout_ms: 0,
+            sagging: false,
+            on_battery_remainder_ms: 0,
+        }
+    }
+
+    /// Updates the parameters; a new window length restarts the current window
+    pub fn set_config(&mut self, config: PowerQualityConfig) {
+        if config.window_s != self.config.window_s {
+            self.window = WindowAccumulator::default();
+        }
+        self.config = config;
+    }
+
+    /// Adds one ADC reading
+    pub fn update(&mut self, vbus_mv: u16, iin_ma: u16, now: Instant) {
+        let interval_ms = self.last_sample.map_or(0, |t| {
+            now.saturating_duration_since(t)
+                .as_millis()
+                .min(MAX_SAMPLE_INTERVAL_MS)
+        });
+        self.last_sample = Some(now);
+        self.roll_days(now);
+
+        let present = vbus_mv >= AC_PRESENT_VBUS_MV;
+        self.update_dropout(present, interval_ms as u32);
+        self.update_sag(present, vbus_mv, interval_ms as u32);
+
+        if present {
+            self.window.add(vbus_mv, iin_ma, interval_ms);
+        }
+        self.window.elapsed_ms += interval_ms;
+        self.status.window = self.window.summary();
+        if self.window.elapsed_ms >= self.config.window_s as u64 * 1000 {
+            self.status.previous_window = self.status.window;
+            self.window = WindowAccumulator::default();
+        }
+    }
+
+    fn update_dropout(&mut self, present: bool, interval_ms: u32) {
+        match (self.status.dropout_active, present) {
+            (false, false) => {
+                self.status.dropout_active = true;
+                self.status.dropout_count = self.status.dropout_count.saturating_add(1);
+                // The dropout began some time after the previous reading; take half the interval
+                self.dropout_ms = interval_ms / 2;
+                self.add_on_battery(interval_ms / 2);
+                defmt::warn!("PowerQuality: VBUS dropout");
+            }
+            (true, false) => {
+                self.dropout_ms = self.dropout_ms.saturating_add(interval_ms);
+                self.add_on_battery(interval_ms);
+            }
+            (true, true) => {
+                // Estimate the end the same way, at half the read interval
+                let duration_ms = self.dropout_ms.saturating_add(interval_ms / 2);
+                self.add_on_battery(interval_ms / 2);
+                self.status.dropout_active = false;
+                self.status.last_dropout_ms = duration_ms;
+                self.status.longest_dropout_ms = self.status.longest_dropout_ms.max(duration_ms);
+                if duration_ms < SHORT_DROPOUT_MS {
+                    self.status.short_dropout_count =
+                        self.status.short_dropout_count.saturating_add(1);
+                }
+                defmt::info!("PowerQuality: VBUS dropout ended after {} ms", duration_ms);
+            }
+            (false, true) => {}
+        }
+        self.status.longest_dropout_ms = self.status.longest_dropout_ms.max(self.dropout_ms);
+    }
+
+    fn update_sag(&mut self, present: bool, vbus_mv: u16, interval_ms: u32) {
+        let sagging = present && vbus_mv < self.config.sag_threshold_mv;
+        if sagging {
+            if !self.sagging {
+                self.status.sag_count = self.status.sag_count.saturating_add(1);
+                defmt::warn!(
+                    "PowerQuality: VBUS sag to {} mV (threshold {} mV)",
+                    vbus_mv,
+                    self.config.sag_threshold_mv
+                );
+            } else {
+                self.status.sag_total_ms = self.status.sag_total_ms.saturating_add(interval_ms);
+            }
+            if self.status.sag_min_mv == 0 || vbus_mv < self.status.sag_min_mv {
+                self.status.sag_min_mv = vbus_mv;
+            }
+        }
+        self.sagging = sagging;
+    }
+
+    fn add_on_battery(&mut self, interval_ms: u32) {
+        let total_ms = self.on_battery_remainder_ms + interval_ms;
+        self.status.on_battery_s[0] = self.status.on_battery_s[0].saturating_add(total_ms / 1000);
+        self.on_battery_remainder_ms = total_ms % 1000;
+    }
+
+    /// Every 24 hours, shifts the battery time per day by one slot
+    fn roll_days(&mut self, now: Instant) {
+        let day_start = *self.day_start.get_or_insert(now);
+        let mut elapsed = now.saturating_duration_since(day_start);
+        let mut rolled = Duration::from_ticks(0);
+        while elapsed >= DAY {
+            self.status.on_battery_s.copy_within(0..DAYS - 1, 1);
+            self.status.on_battery_s[0] = 0;
+            self.on_battery_remainder_ms = 0;
+            elapsed -= DAY;
+            rolled += DAY;
+        }
+        self.day_start = Some(day_start + rolled);
+        self.status.day_elapsed_s = elapsed.as_secs() as u32;
+    }
+
+    pub fn status(&self) -> PowerQualityStatus {
+        self.status
+    }
+}
@@ -0,0 +1,45 @@
+//! Changing the BQ76920 hardware protection thresholds at run time.
+//!
+//! `bq76920_task` writes new thresholds through `try_apply_config` and reads them back to verify.
+//! If the check fails it writes the last known-good thresholds again; the outcome is published
+//! with the BQ76920 measurements.
+
+use crate::config::CellProtectionConfig;
+
+/// Short-circuit delays supported by the BQ76920 (µs)
+pub const SCD_DELAYS_US: [u16; 4] = [70, 100, 200, 400];
+
+/// Overcurrent delays supported by the BQ76920 (ms)
+pub const OCD_DELAYS_MS: [u16; 8] = [8, 20, 40, 80, 160, 320, 640, 1280];
+
+/// Overvoltage delays supported by the BQ76920 (s)
+pub const OV_DELAYS_S: [u8; 4] = [1, 2, 4, 8];
+
+/// Undervoltage delays supported by the BQ76920 (s)
+pub const UV_DELAYS_S: [u8; 4] = [1, 4, 8, 16];
+
+/// Outcome of the latest protection threshold write
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum ProtectionApplyResult {
+    /// Not changed since boot
+    #[default]
+    None = 0,
+    /// The new thresholds were written and verified
+    Applied = 1,
+    /// The new thresholds failed the check and the previous ones were restored
+    Reverted = 2,
+    /// Both the new and the restored thresholds failed the check; the chip's thresholds are
+    /// unknown
+    RevertFailed = 3,
+}
+
+/// Protection threshold status, published with the BQ76920 measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct ProtectionStatus {
+    /// The last thresholds that passed the check
+    pub active: CellProtectionConfig,
+    /// Incremented on every attempt to write new thresholds, to match `last_result` to its request
+    pub apply_count: u16,
+    pub last_result: ProtectionApplyResult,
+}
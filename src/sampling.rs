@@ -0,0 +1,354 @@
+//! Adjusts the sampling intervals of the device tasks to the UPS state.
+//!
+//! - `Fast`: on a fault (BQ76920 fault bits, load overload), for `ON_BATTERY_FAST_PERIOD` after
+//!   switching to battery, or while the host is watching; tracks current and cell voltages closely;
+//! - `Normal`: the default, and while on mains with the battery not yet full or charging;
+//! - `Idle`: on mains with the battery full and at rest for `IDLE_ENTRY_DELAY`; sampling slows
+//!   down, the BQ25730 ADC converts one-shot and the INA226 averages more for less noise and power;
+//! - `LowPower`: on battery without a fault, once `ON_BATTERY_FAST_PERIOD` has passed; keeps MCU
+//!   and I2C activity down to extend the run time: slower sampling, one-shot BQ25730 ADC
+//!   conversions and a slower INA226 conversion rate.
+//!   While USB is also suspended the MCU may enter STOP mode between wake-ups (see `low_power`).
+//!
+//! `Idle` and `LowPower` are not used while the host is subscribed to status pushes and the bus is
+//! not suspended. `bq76920_task` picks the mode from the battery state every measurement cycle,
+//! and the device tasks read the current sampling parameters at the start of each cycle.
+//! The decisions only depend on the times passed in.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+
+use crate::config::LoadMonitorConfig;
+use crate::ups_status::IDLE_CURRENT_THRESHOLD_MA;
+
+/// Measurements not updated for longer than this are stale and must not drive control decisions.
+/// Every mode's sampling period plus the read and queueing time must be well below it.
+pub const MEASUREMENT_MAX_AGE: Duration = Duration::from_secs(3);
+
+/// At or above this remaining capacity and at rest, the battery counts as full (%)
+const IDLE_MIN_SOC_PCT: u8 = 95;
+
+/// The idle conditions must hold this long before sampling slows down; leaving idle is immediate
+const IDLE_ENTRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How long sampling stays fast after switching to battery, to follow the transfer and the
+/// voltage drop at the start of the discharge
+const ON_BATTERY_FAST_PERIOD: Duration = Duration::from_secs(60);
+
+/// Time for one BQ25730 one-shot conversion of every enabled ADC channel (about 25 ms each)
+pub const BQ25730_ADC_ONE_SHOT_TIME: Duration = Duration::from_millis(250);
+
+/// Sampling mode; the value appears in the USB status frame
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum SamplingMode {
+    Idle = 0,
+    #[default]
+    Normal = 1,
+    Fast = 2,
+    LowPower = 3,
+}
+
+/// Device task parameters for one sampling mode
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct SamplingRates {
+    /// BQ76920 measurement period (ms)
+    pub bq76920_ms: u16,
+    /// BQ25730 ADC read period (ms)
+    pub bq25730_ms: u16,
+    /// INA226 read period (ms)
+    pub ina226_ms: u16,
+    /// Run a one-shot BQ25730 ADC conversion before each read instead of converting continuously
+    pub bq25730_adc_one_shot: bool,
+    /// Overrides the configured INA226 averaging count; `None` uses the configured value
+    pub ina226_averaging: Option<u16>,
+    /// Overrides the configured INA226 bus and shunt conversion time (µs); `None` uses the
+    /// configured value
+    pub ina226_conversion_us: Option<u16>,
+}
+
+impl SamplingMode {
+    /// Sampling parameters of each mode. The slowest period must be shorter than the task
+    /// watchdog's check-in deadline and well below `MEASUREMENT_MAX_AGE`.
+    pub const fn rates(self) -> SamplingRates {
+        match self {
+            // BQ76920 and BQ25730 readings must be refreshed within `MEASUREMENT_MAX_AGE`, or
+            // charging is not allowed
+            Self::Idle => SamplingRates {
+                bq76920_ms: 2000,
+                bq25730_ms: 2000,
+                ina226_ms: 2000,
+                bq25730_adc_one_shot: true,
+                // 256 averages at 1.1 ms conversion time give a result about every 560 ms
+                ina226_averaging: Some(256),
+                ina226_conversion_us: None,
+            },
+            Self::Normal => SamplingRates {
+                bq76920_ms: 1000,
+                bq25730_ms: 1000,
+                ina226_ms: 1000,
+                bq25730_adc_one_shot: false,
+                ina226_averaging: None,
+                ina226_conversion_us: None,
+            },
+            Self::Fast => SamplingRates {
+                bq76920_ms: 250,
+                bq25730_ms: 500,
+                ina226_ms: 250,
+                bq25730_adc_one_shot: false,
+                ina226_averaging: None,
+                ina226_conversion_us: None,
+            },
+            // The BQ25730 VBUS reading is what notices mains coming back
+            Self::LowPower => SamplingRates {
+                bq76920_ms: 2000,
+                bq25730_ms: 2000,
+                ina226_ms: 2000,
+                bq25730_adc_one_shot: true,
+                // 64 averages at 8.244 ms conversion time give a result about every second
+                ina226_averaging: Some(64),
+                ina226_conversion_us: Some(8244),
+            },
+        }
+    }
+}
+
+impl SamplingRates {
+    /// INA226 parameters actually written in the current sampling mode
+    pub fn load_monitor(&self, config: &LoadMonitorConfig) -> LoadMonitorConfig {
+        LoadMonitorConfig {
+            averaging: self.ina226_averaging.unwrap_or(config.averaging),
+            bus_conversion_us: self
+                .ina226_conversion_us
+                .unwrap_or(config.bus_conversion_us),
+            shunt_conversion_us: self
+                .ina226_conversion_us
+                .unwrap_or(config.shunt_conversion_us),
+            ..*config
+        }
+    }
+}
+
+/// Battery state needed to pick the sampling mode
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct SamplingInputs {
+    pub ac_present: bool,
+    /// Battery current (mA), positive while charging
+    pub battery_current_ma: i32,
+    pub soc_pct: u8,
+    /// The BQ76920 reports a fault or the load is overloaded
+    pub fault: bool,
+}
+
+/// Current sampling mode and parameters, published with the measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct SamplingStatus {
+    pub mode: SamplingMode,
+    pub host_subscribed: bool,
+    /// The USB bus is suspended, or there is no host
+    pub usb_suspended: bool,
+}
+
+impl SamplingStatus {
+    pub fn rates(&self) -> SamplingRates {
+        self.mode.rates()
+    }
+
+    /// The host is receiving status pushes
+    fn host_active(&self) -> bool {
+        self.host_subscribed && !self.usb_suspended
+    }
+
+    /// The MCU may enter STOP mode between wake-ups
+    pub fn stop_allowed(&self) -> bool {
+        self.mode == SamplingMode::LowPower && self.usb_suspended
+    }
+}
+
+#[derive(Debug)]
+struct Policy {
+    status: SamplingStatus,
+    /// When the idle conditions started to hold
+    idle_since: Option<Instant>,
+    /// When the UPS switched to battery
+    on_battery_since: Option<Instant>,
+}
+
+impl Policy {
+    /// Leaves the slow modes as soon as the host starts watching, without waiting for the next
+    /// measurement cycle
+    fn leave_slow_modes(&mut self) {
+        if !self.status.host_active() {
+            return;
+        }
+        self.status.mode = match self.status.mode {
+            SamplingMode::Idle => SamplingMode::Normal,
+            SamplingMode::LowPower => SamplingMode::Fast,
+            mode => mode,
+        };
+    }
+}
+
+/// Sampling mode picked by `bq76920_task` and read by the device tasks
+pub struct SamplingControl {
+    policy: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Policy>>,
+    /// Whether STOP mode is allowed has changed
+    stop_changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl Default for SamplingControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SamplingControl {
+    pub const fn new() -> Self {
+        Self {
+            policy: blocking_mutex::Mutex::new(RefCell::new(Policy {
+                status: SamplingStatus {
+                    mode: SamplingMode::Normal,
+                    host_subscribed: false,
+                    usb_suspended: false,
+                },
+                idle_since: None,
+                on_battery_since: None,
+            })),
+            stop_changed: Signal::new(),
+        }
+    }
+
+    /// Picks the sampling mode again from the battery state
+    pub fn update(&self, inputs: SamplingInputs, now: Instant) {
+        self.modify(|policy| {
+            let idle = inputs.ac_present
+                && !inputs.fault
+                && inputs.soc_pct >= IDLE_MIN_SOC_PCT
+                && inputs.battery_current_ma.abs() < IDLE_CURRENT_THRESHOLD_MA;
+            if idle {
+                policy.idle_since.get_or_insert(now);
+            } else {
+                policy.idle_since = None;
+            }
+            if inputs.ac_present {
+                policy.on_battery_since = None;
+            } else {
+                policy.on_battery_since.get_or_insert(now);
+            }
+
+            let mode = if inputs.fault {
+                SamplingMode::Fast
+            } else if !inputs.ac_present {
+                let settled = policy
+                    .on_battery_since
+                    .is_some_and(|t| now.saturating_duration_since(t) >= ON_BATTERY_FAST_PERIOD);
+                if settled && !policy.status.host_active() {
+                    SamplingMode::LowPower
+                } else {
+                    SamplingMode::Fast
+                }
+            } else if policy
+                .idle_since
+                .is_some_and(|t| now.saturating_duration_since(t) >= IDLE_ENTRY_DELAY)
+                && !policy.status.host_active()
+            {
+                SamplingMode::Idle
+            } else {
+                SamplingMode::Normal
+            };
+            if mode != policy.status.mode {
+                defmt::info!(
+                    "Sampling: {:?} -> {:?} ({:?})",
+                    policy.status.mode,
+                    mode,
+                    inputs
+                );
+                policy.status.mode = mode;
+            }
+        });
+    }
+
+    /// No `Idle` or `LowPower` while the host is subscribed to status pushes
+    pub fn set_host_subscribed(&self, subscribed: bool) {
+        self.modify(|policy| {
+            policy.status.host_subscribed = subscribed;
+            policy.leave_slow_modes();
+        });
+    }
+
+    /// Called on USB bus suspend and resume
+    pub fn set_usb_suspended(&self, suspended: bool) {
+        self.modify(|policy| {
+            policy.status.usb_suspended = suspended;
+            policy.leave_slow_modes();
+        });
+    }
+
+    /// Modifies the state and signals when whether STOP mode is allowed changes
+    fn modify(&self, f: impl FnOnce(&mut Policy)) {
+        let changed = self.policy.lock(|policy| {
+            let mut policy = policy.borrow_mut();
+            let stop_allowed = policy.status.stop_allowed();
+            f(&mut policy);
+            policy.status.stop_allowed() != stop_allowed
+        });
+        if changed {
+            self.stop_changed.signal(());
+        }
+    }
+
+    /// Waits until whether STOP mode is allowed changes
+    #[cfg(feature = "stop-mode")]
+    pub async fn wait_stop_changed(&self) {
+        self.stop_changed.wait().await
+    }
+
+    pub fn status(&self) -> SamplingStatus {
+        self.policy.lock(|policy| policy.borrow().status)
+    }
+
+    pub fn rates(&self) -> SamplingRates {
+        self.status().rates()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [SamplingMode; 4] = [
+        SamplingMode::Idle,
+        SamplingMode::Normal,
+        SamplingMode::Fast,
+        SamplingMode::LowPower,
+    ];
+
+    /// Margin for the read, I2C waits and message queueing
+    const READ_MARGIN: Duration = Duration::from_millis(500);
+
+    #[test]
+    fn every_period_is_well_within_measurement_max_age() {
+        for mode in MODES {
+            let rates = mode.rates();
+            let adc_ms = if rates.bq25730_adc_one_shot {
+                BQ25730_ADC_ONE_SHOT_TIME.as_millis()
+            } else {
+                0
+            };
+            for (name, period_ms, extra_ms) in [
+                ("BQ76920", rates.bq76920_ms, 0),
+                ("BQ25730", rates.bq25730_ms, adc_ms),
+                ("INA226", rates.ina226_ms, 0),
+            ] {
+                let latest = Duration::from_millis(period_ms as u64 + extra_ms) + READ_MARGIN;
+                assert!(
+                    latest < MEASUREMENT_MAX_AGE,
+                    "{mode:?}: {name} period {period_ms} ms leaves no margin to MEASUREMENT_MAX_AGE"
+                );
+            }
+        }
+    }
+}
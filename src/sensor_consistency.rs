@@ -0,0 +1,276 @@
+//! Sensor consistency monitor: compares the battery voltage and current measured by the BQ76920,
+//! BQ25730 and INA226, finds a chip whose readings keep disagreeing with the others (a failed
+//! sensor or shunt resistor), and picks one trusted source each for battery voltage and current.
+//!
+//! The INA226 measures the load side, so it only takes part while there is no input supply and
+//! the battery alone powers the load.
+//! `usb_task` calls it whenever it aggregates the measurements; the result is published in
+//! `AllMeasurements`.
+
+use embassy_time::{Duration, Instant};
+
+use crate::data_types::{Bq25730Measurements, Bq76920Measurements, Ina226Measurements};
+use crate::ups_state::AC_PRESENT_VBUS_MV;
+
+/// How long a mismatch must last before it counts, so chips sampling at different moments do not
+/// cause false alarms
+const CONFIRM_TIME: Duration = Duration::from_secs(5);
+
+/// How long the readings must agree again before a fault clears
+const CLEAR_TIME: Duration = Duration::from_secs(30);
+
+/// Allowed relative difference between two readings (%), on top of each sensor's absolute error
+const TOLERANCE_PCT: u32 = 5;
+
+/// Bits of `ConsistencyStatus::mismatches`
+pub const MISMATCH_PACK_VOLTAGE: u8 = 1 << 0;
+pub const MISMATCH_BATTERY_CURRENT: u8 = 1 << 1;
+
+/// First current fault bit in `ConsistencyStatus::faults`; the voltage fault bits start at 0
+pub const CURRENT_FAULT_SHIFT: u8 = 4;
+
+/// Sensors taking part in the comparison
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum Sensor {
+    #[default]
+    Bq76920 = 0,
+    Bq25730 = 1,
+    Ina226 = 2,
+}
+
+impl Sensor {
+    const ALL: [Self; 3] = [Self::Bq76920, Self::Bq25730, Self::Ina226];
+
+    /// Absolute error of the voltage measurement (mV)
+    const fn voltage_tolerance_mv(self) -> u32 {
+        match self {
+            Self::Bq76920 => 100,
+            Self::Bq25730 => 150,
+            // Drop across the MOSFETs and cables in the load path
+            Self::Ina226 => 300,
+        }
+    }
+
+    /// Absolute error of the current measurement (mA)
+    const fn current_tolerance_ma(self) -> u32 {
+        match self {
+            Self::Bq76920 => 50,
+            // The ICHG/IDCHG ADC has a coarse resolution
+            Self::Bq25730 => 300,
+            Self::Ina226 => 50,
+        }
+    }
+}
+
+/// Preferred voltage sources, best first
+const VOLTAGE_PRIORITY: [Sensor; 3] = [Sensor::Bq76920, Sensor::Bq25730, Sensor::Ina226];
+
+/// Preferred current sources, best first
+const CURRENT_PRIORITY: [Sensor; 3] = [Sensor::Bq76920, Sensor::Ina226, Sensor::Bq25730];
+
+/// Consistency monitor result, published with the aggregated measurements
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct ConsistencyStatus {
+    /// Quantities whose readings contradict each other, see `MISMATCH_*`
+    pub mismatches: u8,
+    /// Sensors judged faulty: bits 0..=2 for voltage and 4..=6 for current, numbered by `Sensor`
+    pub faults: u8,
+    pub voltage_source: Sensor,
+    pub current_source: Sensor,
+    /// Pack voltage from the trusted source (mV), `None` while no reading is available
+    pub pack_voltage_mv: Option<i32>,
+    /// Battery current from the trusted source (mA), positive while charging
+    pub battery_current_ma: Option<i32>,
+    /// Spread between the highest and lowest compared voltage readings (mV)
+    pub voltage_spread_mv: u16,
+    /// Spread between the highest and lowest compared current readings (mA)
+    pub current_spread_ma: u16,
+}
+
+/// State debounced by duration
+#[derive(Debug, Default, Copy, Clone)]
+struct Debounced {
+    active: bool,
+    since: Option<Instant>,
+}
+
+impl Debounced {
+    fn update(&mut self, raw: bool, now: Instant) -> bool {
+        if raw == self.active {
+            self.since = None;
+            return self.active;
+        }
+        let since = *self.since.get_or_insert(now);
+        let hold = if raw { CONFIRM_TIME } else { CLEAR_TIME };
+        if now.saturating_duration_since(since) >= hold {
+            self.active = raw;
+            self.since = None;
+        }
+        self.active
+    }
+}
+
+/// Readings indexed by `Sensor`; `None` means the sensor does not take part
+type Readings = [Option<i32>; 3];
+
+/// Comparison result for one quantity
+struct Comparison {
+    mismatch: bool,
+    /// Fault bits indexed by `Sensor`
+    faults: u8,
+    spread: u16,
+    source: Sensor,
+    value: Option<i32>,
+}
+
+#[derive(Debug, Default)]
+struct QuantityMonitor {
+    mismatch: Debounced,
+    faults: [Debounced; 3],
+}
+
+impl QuantityMonitor {
+    fn update(
+        &mut self,
+        readings: &Readings,
+        tolerance: fn(Sensor) -> u32,
+        priority: &[Sensor; 3],
+        now: Instant,
+    ) -> Comparison {
+        let agree = |a: Sensor, b: Sensor| -> Option<bool> {
+            let (x, y) = (readings[a as usize]?, readings[b as usize]?);
+            let magnitude = x.unsigned_abs().max(y.unsigned_abs());
+            let limit = tolerance(a) + tolerance(b) + magnitude / 100 * TOLERANCE_PCT;
+            Some(x.abs_diff(y) <= limit)
+        };
+
+        let mut raw_mismatch = false;
+        let mut faults = 0u8;
+        for (i, sensor) in Sensor::ALL.into_iter().enumerate() {
+            let first = Sensor::ALL[(i + 1) % 3];
+            let second = Sensor::ALL[(i + 2) % 3];
+            raw_mismatch |= agree(first, second) == Some(false);
+            // The faulty sensor is only known when it disagrees with both others while those two
+            // agree
+            let suspect = agree(sensor, first) == Some(false)
+                && agree(sensor, second) == Some(false)
+                && agree(first, second) == Some(true);
+            if self.faults[i].update(suspect, now) {
+                faults |= 1 << i;
+            }
+        }
+
+        let available = || readings.iter().flatten();
+        let spread = match (available().min(), available().max()) {
+            (Some(min), Some(max)) => max.abs_diff(*min).min(u16::MAX as u32) as u16,
+            _ => 0,
+        };
+
+        // Prefer a source not judged faulty; fall back to any source with a reading
+        let source = priority
+            .iter()
+            .copied()
+            .find(|&s| readings[s as usize].is_some() && faults & (1 << s as u8) == 0)
+            .or_else(|| {
+                priority
+                    .iter()
+                    .copied()
+                    .find(|&s| readings[s as usize].is_some())
+            })
+            .unwrap_or(priority[0]);
+
+        Comparison {
+            mismatch: self.mismatch.update(raw_mismatch, now),
+            faults,
+            spread,
+            source,
+            value: readings[source as usize],
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConsistencyMonitor {
+    voltage: QuantityMonitor,
+    current: QuantityMonitor,
+    status: ConsistencyStatus,
+}
+
+impl ConsistencyMonitor {
+    pub fn status(&self) -> ConsistencyStatus {
+        self.status
+    }
+
+    /// Updates the result from each task's latest measurements (`None` for a task without
+    /// valid, fresh data) and returns the bits newly judged faulty
+    pub fn update<const N: usize>(
+        &mut self,
+        bq76920: Option<&Bq76920Measurements<N>>,
+        bq25730: Option<&Bq25730Measurements>,
+        ina226: Option<&Ina226Measurements>,
+        now: Instant,
+    ) -> u8 {
+        // The load current equals the battery discharge current only when there is definitely no
+        // input supply
+        let on_battery = bq25730.is_some_and(|m| m.adc_measurements.vbus.0 < AC_PRESENT_VBUS_MV);
+        let ina226 = ina226.filter(|_| on_battery);
+
+        let voltages: Readings = [
+            bq76920.map(|m| m.core_measurements.total_voltage_mv),
+            bq25730.map(|m| m.adc_measurements.vbat.0 as i32),
+            ina226.map(|m| m.voltage as i32),
+        ];
+        let currents: Readings = [
+            bq76920.map(|m| m.core_measurements.current_ma),
+            bq25730.map(|m| {
+                m.adc_measurements.ichg.milliamps as i32 - m.adc_measurements.idchg.milliamps as i32
+            }),
+            ina226.map(|m| -(m.current as i32)),
+        ];
+
+        let voltage = self.voltage.update(
+            &voltages,
+            Sensor::voltage_tolerance_mv,
+            &VOLTAGE_PRIORITY,
+            now,
+        );
+        let current = self.current.update(
+            &currents,
+            Sensor::current_tolerance_ma,
+            &CURRENT_PRIORITY,
+            now,
+        );
+
+        let mut mismatches = 0;
+        if voltage.mismatch {
+            mismatches |= MISMATCH_PACK_VOLTAGE;
+        }
+        if current.mismatch {
+            mismatches |= MISMATCH_BATTERY_CURRENT;
+        }
+        let faults = voltage.faults | (current.faults << CURRENT_FAULT_SHIFT);
+        let new_faults = faults & !self.status.faults;
+        if mismatches != self.status.mismatches || faults != self.status.faults {
+            defmt::warn!(
+                "Sensor consistency: mismatches {:#04x}, faults {:#04x}, voltages {:?}, currents {:?}",
+                mismatches,
+                faults,
+                voltages,
+                currents
+            );
+        }
+
+        self.status = ConsistencyStatus {
+            mismatches,
+            faults,
+            voltage_source: voltage.source,
+            current_source: current.source,
+            pack_voltage_mv: voltage.value,
+            battery_current_ma: current.value,
+            voltage_spread_mv: voltage.spread,
+            current_spread_ma: current.spread,
+        };
+        new_faults
+    }
+}
@@ -5,9 +5,15 @@ use crate::data_types::{
     Ina226Measurements,
 };
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, Receiver, Sender};
 use embassy_sync::pubsub::{PubSubChannel, Publisher, Subscriber};
 use static_cell::StaticCell;
 
+use crate::battery_test::BatteryTestCommand;
+use crate::config::SystemConfig;
+use crate::event_log::PowerEvent;
+use crate::load_control::LoadCommand;
+
 // 从 bq25730_async_rs 和 bq769x0_async_rs 导入必要的类型
 // 注意：这些路径可能需要根据您的项目结构进行调整
 use bq25730_async_rs::data_types::SenseResistorValue;
@@ -75,7 +81,7 @@ static BQ76920_ALERTS_PUBSUB: StaticCell<
 
 // BQ76920 测量数据 PubSub
 const BQ76920_MEASUREMENTS_PUBSUB_DEPTH: usize = 4; // 消息队列深度
-const BQ76920_MEASUREMENTS_PUBSUB_READERS: usize = 2; // 消费者数量 (usb_task, bq25730_task)
+const BQ76920_MEASUREMENTS_PUBSUB_READERS: usize = 3; // 消费者数量 (usb_task, bq25730_task, event_log_task)
 static BQ76920_MEASUREMENTS_PUBSUB: StaticCell<
     PubSubChannel<
         CriticalSectionRawMutex,
@@ -88,7 +94,7 @@ static BQ76920_MEASUREMENTS_PUBSUB: StaticCell<
 
 // BQ25730 测量数据 PubSub
 const BQ25730_MEASUREMENTS_PUBSUB_DEPTH: usize = 4; // 消息队列深度
-const BQ25730_MEASUREMENTS_PUBSUB_READERS: usize = 1; // 消费者数量 (目前只有 bq76920_task)
+const BQ25730_MEASUREMENTS_PUBSUB_READERS: usize = 3; // 消费者数量 (usb_task, bq76920_task, event_log_task)
 static BQ25730_MEASUREMENTS_PUBSUB: StaticCell<
     PubSubChannel<
         CriticalSectionRawMutex,
@@ -101,7 +107,7 @@ static BQ25730_MEASUREMENTS_PUBSUB: StaticCell<
 
 // INA226 测量数据 PubSub
 const INA226_MEASUREMENTS_PUBSUB_DEPTH: usize = 4; // 消息队列深度
-const INA226_MEASUREMENTS_PUBSUB_READERS: usize = 2; // 消费者数量
+const INA226_MEASUREMENTS_PUBSUB_READERS: usize = 3; // 消费者数量 (usb_task, bq76920_task, event_log_task)
 static INA226_MEASUREMENTS_PUBSUB: StaticCell<
     PubSubChannel<
         CriticalSectionRawMutex,
@@ -112,6 +118,37 @@ static INA226_MEASUREMENTS_PUBSUB: StaticCell<
     >,
 > = StaticCell::new();
 
+// 负载控制命令队列 (usb_task -> bq76920_task)
+const LOAD_COMMAND_CHANNEL_DEPTH: usize = 4;
+static LOAD_COMMAND_CHANNEL: StaticCell<
+    Channel<CriticalSectionRawMutex, LoadCommand, LOAD_COMMAND_CHANNEL_DEPTH>,
+> = StaticCell::new();
+
+// 电池自检命令队列 (usb_task -> bq25730_task)
+const BATTERY_TEST_COMMAND_CHANNEL_DEPTH: usize = 2;
+static BATTERY_TEST_COMMAND_CHANNEL: StaticCell<
+    Channel<CriticalSectionRawMutex, BatteryTestCommand, BATTERY_TEST_COMMAND_CHANNEL_DEPTH>,
+> = StaticCell::new();
+
+// 电源事件队列 (usb_task, bq76920_task, ina226_task -> event_log_task)
+const POWER_EVENT_CHANNEL_DEPTH: usize = 8;
+static POWER_EVENT_CHANNEL: StaticCell<
+    Channel<CriticalSectionRawMutex, PowerEvent, POWER_EVENT_CHANNEL_DEPTH>,
+> = StaticCell::new();
+
+// 运行参数 PubSub (usb_task -> bq25730_task, bq76920_task, ina226_task, event_log_task)
+const SYSTEM_CONFIG_PUBSUB_DEPTH: usize = 2; // 只关心最新的配置
+const SYSTEM_CONFIG_PUBSUB_READERS: usize = 4; // 消费者数量
+static SYSTEM_CONFIG_PUBSUB: StaticCell<
+    PubSubChannel<
+        CriticalSectionRawMutex,
+        SystemConfig,
+        SYSTEM_CONFIG_PUBSUB_DEPTH,
+        SYSTEM_CONFIG_PUBSUB_READERS,
+        1,
+    >,
+> = StaticCell::new();
+
 // BQ25730_RUNTIME_CONFIG_PUBSUB related consts and StaticCell were removed.
 // BQ76920_RUNTIME_CONFIG_PUBSUB related consts and StaticCell were removed.
 
@@ -211,6 +248,38 @@ pub type Ina226MeasurementsSubscriber<'a> = Subscriber<
     1,
 >;
 
+pub type SystemConfigPublisher<'a> = Publisher<
+    'a,
+    CriticalSectionRawMutex,
+    SystemConfig,
+    SYSTEM_CONFIG_PUBSUB_DEPTH,
+    SYSTEM_CONFIG_PUBSUB_READERS,
+    1,
+>;
+pub type SystemConfigSubscriber<'a> = Subscriber<
+    'a,
+    CriticalSectionRawMutex,
+    SystemConfig,
+    SYSTEM_CONFIG_PUBSUB_DEPTH,
+    SYSTEM_CONFIG_PUBSUB_READERS,
+    1,
+>;
+
+pub type LoadCommandSender<'a> =
+    Sender<'a, CriticalSectionRawMutex, LoadCommand, LOAD_COMMAND_CHANNEL_DEPTH>;
+pub type LoadCommandReceiver<'a> =
+    Receiver<'a, CriticalSectionRawMutex, LoadCommand, LOAD_COMMAND_CHANNEL_DEPTH>;
+
+pub type BatteryTestCommandSender<'a> =
+    Sender<'a, CriticalSectionRawMutex, BatteryTestCommand, BATTERY_TEST_COMMAND_CHANNEL_DEPTH>;
+pub type BatteryTestCommandReceiver<'a> =
+    Receiver<'a, CriticalSectionRawMutex, BatteryTestCommand, BATTERY_TEST_COMMAND_CHANNEL_DEPTH>;
+
+pub type PowerEventSender<'a> =
+    Sender<'a, CriticalSectionRawMutex, PowerEvent, POWER_EVENT_CHANNEL_DEPTH>;
+pub type PowerEventReceiver<'a> =
+    Receiver<'a, CriticalSectionRawMutex, PowerEvent, POWER_EVENT_CHANNEL_DEPTH>;
+
 // Removed Bq25730RuntimeConfigPublisher and Bq25730RuntimeConfigSubscriber type aliases
 // Removed Bq76920RuntimeConfigPublisher and Bq76920RuntimeConfigSubscriber type aliases
 
@@ -257,6 +326,19 @@ pub type Ina226MeasurementsChannelType = PubSubChannel<
     INA226_MEASUREMENTS_PUBSUB_READERS,
     1,
 >;
+pub type SystemConfigChannelType = PubSubChannel<
+    CriticalSectionRawMutex,
+    SystemConfig,
+    SYSTEM_CONFIG_PUBSUB_DEPTH,
+    SYSTEM_CONFIG_PUBSUB_READERS,
+    1,
+>;
+pub type LoadCommandChannelType =
+    Channel<CriticalSectionRawMutex, LoadCommand, LOAD_COMMAND_CHANNEL_DEPTH>;
+pub type BatteryTestCommandChannelType =
+    Channel<CriticalSectionRawMutex, BatteryTestCommand, BATTERY_TEST_COMMAND_CHANNEL_DEPTH>;
+pub type PowerEventChannelType =
+    Channel<CriticalSectionRawMutex, PowerEvent, POWER_EVENT_CHANNEL_DEPTH>;
 // Removed Bq25730RuntimeConfigChannelType type alias.
 // Bq76920RuntimeConfigChannelType type alias was removed.
 
@@ -277,6 +359,11 @@ pub type PubSubSetup<'a, const N: usize> = (
     &'a Bq76920MeasurementsChannelType<N>,
     Ina226MeasurementsPublisher<'a>,
     &'a Ina226MeasurementsChannelType,
+    &'a LoadCommandChannelType,
+    &'a BatteryTestCommandChannelType,
+    &'a PowerEventChannelType,
+    SystemConfigPublisher<'a>,
+    &'a SystemConfigChannelType,
     // Removed Bq25730RuntimeConfigPublisher and its ChannelType from PubSubSetup
     // Removed Bq76920RuntimeConfigPublisher and its ChannelType from PubSubSetup
 );
@@ -295,6 +382,14 @@ pub fn init_pubsubs() -> PubSubSetup<'static, 5> {
         BQ25730_MEASUREMENTS_PUBSUB.init(PubSubChannel::new());
     let ina226_measurements_pubsub: &'static Ina226MeasurementsChannelType =
         INA226_MEASUREMENTS_PUBSUB.init(PubSubChannel::new());
+    let load_command_channel: &'static LoadCommandChannelType =
+        LOAD_COMMAND_CHANNEL.init(Channel::new());
+    let battery_test_command_channel: &'static BatteryTestCommandChannelType =
+        BATTERY_TEST_COMMAND_CHANNEL.init(Channel::new());
+    let power_event_channel: &'static PowerEventChannelType =
+        POWER_EVENT_CHANNEL.init(Channel::new());
+    let system_config_pubsub: &'static SystemConfigChannelType =
+        SYSTEM_CONFIG_PUBSUB.init(PubSubChannel::new());
     // Removed initialization of bq25730_runtime_config_pubsub
     // Removed initialization of bq76920_runtime_config_pubsub
 
@@ -311,6 +406,11 @@ pub fn init_pubsubs() -> PubSubSetup<'static, 5> {
         bq76920_measurements_pubsub,
         ina226_measurements_pubsub.publisher().unwrap(),
         ina226_measurements_pubsub,
+        load_command_channel,
+        battery_test_command_channel,
+        power_event_channel,
+        system_config_pubsub.publisher().unwrap(),
+        system_config_pubsub,
         // Removed bq25730_runtime_config_pubsub publisher and channel from return tuple
         // Removed bq76920_runtime_config_pubsub publisher and channel from return tuple
     )
@@ -0,0 +1,358 @@
+//! Task check-in judgement and the record format of watchdog verdicts, without the IWDG and RCC.
+//!
+//! The judgement only depends on the time passed in, so it can be tested with a fake clock. The
+//! watchdog task and the retained RAM are in `task_watchdog`.
+
+use embassy_time::{Duration, Instant};
+
+use crate::crc::crc32;
+
+/// Where a task may wait for a long time, it checks in at least this often
+pub const CHECK_IN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Supervised tasks
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum SupervisedTask {
+    Bq76920 = 0,
+    Bq25730 = 1,
+    Ina226 = 2,
+    Usb = 3,
+}
+
+impl SupervisedTask {
+    pub const COUNT: usize = 4;
+    const ALL: [Self; Self::COUNT] = [Self::Bq76920, Self::Bq25730, Self::Ina226, Self::Usb];
+
+    fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+
+    /// Longest allowed interval between two check-ins
+    const fn deadline(self) -> Duration {
+        match self {
+            // 1 s loop, including I2C transfers and the flash write of the gauge snapshot
+            Self::Bq76920 => Duration::from_secs(5),
+            // Waits up to 3 s for BMS data per cycle
+            Self::Bq25730 => Duration::from_secs(8),
+            Self::Ina226 => Duration::from_secs(5),
+            // Checks in every `CHECK_IN_INTERVAL` while no host is connected or idle
+            Self::Usb => Duration::from_secs(10),
+        }
+    }
+}
+
+/// A task past its check-in deadline
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Overdue {
+    pub task: SupervisedTask,
+    /// Time past the deadline
+    pub late: Duration,
+}
+
+/// Check-in judgement
+#[derive(Debug, Default)]
+pub struct TaskMonitor {
+    /// `None` for tasks that are not registered (not spawned)
+    last_check_in: [Option<Instant>; SupervisedTask::COUNT],
+}
+
+impl TaskMonitor {
+    pub const fn new() -> Self {
+        Self {
+            last_check_in: [None; SupervisedTask::COUNT],
+        }
+    }
+
+    /// Starts supervising a task; its deadline runs from `now`
+    pub fn register(&mut self, task: SupervisedTask, now: Instant) {
+        self.last_check_in[task as usize] = Some(now);
+    }
+
+    pub fn check_in(&mut self, task: SupervisedTask, now: Instant) {
+        if let Some(last) = self.last_check_in[task as usize].as_mut() {
+            *last = now;
+        }
+    }
+
+    /// Returns the task furthest past its deadline, `None` if all checked in on time
+    pub fn check(&self, now: Instant) -> Option<Overdue> {
+        SupervisedTask::ALL
+            .into_iter()
+            .filter_map(|task| {
+                let last = self.last_check_in[task as usize]?;
+                let elapsed = now.saturating_duration_since(last);
+                (elapsed > task.deadline()).then(|| Overdue {
+                    task,
+                    late: elapsed - task.deadline(),
+                })
+            })
+            .max_by_key(|overdue| overdue.late)
+    }
+}
+
+/// Reset reason, from the RCC_CSR reset flags
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum ResetReason {
+    #[default]
+    Unknown = 0,
+    /// Power-on or brown-out
+    PowerOn = 1,
+    /// NRST pin
+    Pin = 2,
+    Software = 3,
+    IndependentWatchdog = 4,
+    WindowWatchdog = 5,
+    LowPower = 6,
+    OptionByteLoad = 7,
+}
+
+impl ResetReason {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::PowerOn,
+            2 => Self::Pin,
+            3 => Self::Software,
+            4 => Self::IndependentWatchdog,
+            5 => Self::WindowWatchdog,
+            6 => Self::LowPower,
+            7 => Self::OptionByteLoad,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Last supervision verdict before the reset
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+pub enum WatchdogVerdict {
+    /// No retained record: power-on reset or a corrupt record
+    #[default]
+    None = 0,
+    /// All tasks checked in on time
+    Healthy = 1,
+    /// A task missed its check-in deadline and the watchdog task stopped feeding
+    TaskOverdue = 2,
+    /// IWDG reset without an overdue task on record: the executor stalled and the watchdog task
+    /// itself could not run
+    ExecutorStall = 3,
+}
+
+/// Supervision verdict of the previous run, determined at boot
+#[derive(Debug, Copy, Clone, PartialEq, Default, defmt::Format)]
+pub struct WatchdogReport {
+    pub reset_reason: ResetReason,
+    pub verdict: WatchdogVerdict,
+    /// The overdue task for `TaskOverdue`
+    pub task: Option<SupervisedTask>,
+    /// Time past the deadline (ms)
+    pub late_ms: u32,
+    /// Uptime when the verdict was recorded (s)
+    pub uptime_s: u32,
+}
+
+impl WatchdogReport {
+    /// The reset was caused by the watchdog
+    pub fn is_watchdog_reset(&self) -> bool {
+        matches!(
+            self.verdict,
+            WatchdogVerdict::TaskOverdue | WatchdogVerdict::ExecutorStall
+        )
+    }
+}
+
+const RETAINED_MAGIC: u32 = 0x5744_4731; // "WDG1"
+
+/// Record in retained RAM: magic, verdict and task, time late (ms), uptime (s), CRC-32 of the
+/// first four words
+pub type RetainedRecord = [u32; 5];
+
+fn record_checksum(record: &RetainedRecord) -> u32 {
+    let mut bytes = [0u8; 16];
+    for (chunk, word) in bytes.chunks_exact_mut(4).zip(record.iter()) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    crc32(&bytes)
+}
+
+/// Encodes a verdict reached at `now`
+pub fn encode_record(
+    verdict: WatchdogVerdict,
+    overdue: Option<Overdue>,
+    now: Instant,
+) -> RetainedRecord {
+    let mut record: RetainedRecord = [
+        RETAINED_MAGIC,
+        verdict as u32 | (overdue.map_or(0xFF, |o| o.task as u32) << 8),
+        overdue.map_or(0, |o| o.late.as_millis().min(u32::MAX as u64) as u32),
+        now.as_secs().min(u32::MAX as u64) as u32,
+        0,
+    ];
+    record[4] = record_checksum(&record);
+    record
+}
+
+/// Derives the verdict of the previous run from the retained record and the reset reason of this
+/// boot. An invalid record counts as no verdict.
+pub fn decode_record(record: &RetainedRecord, reset_reason: ResetReason) -> WatchdogReport {
+    let valid = record[0] == RETAINED_MAGIC && record[4] == record_checksum(record);
+    let mut report = WatchdogReport {
+        reset_reason,
+        ..Default::default()
+    };
+    if valid {
+        report.verdict = match record[1] & 0xFF {
+            1 => WatchdogVerdict::Healthy,
+            2 => WatchdogVerdict::TaskOverdue,
+            _ => WatchdogVerdict::None,
+        };
+        report.task = SupervisedTask::from_u8((record[1] >> 8) as u8);
+        report.late_ms = record[2];
+        report.uptime_s = record[3];
+    }
+    if reset_reason == ResetReason::IndependentWatchdog
+        && report.verdict != WatchdogVerdict::TaskOverdue
+    {
+        report.verdict = WatchdogVerdict::ExecutorStall;
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T0: Instant = Instant::from_secs(100);
+
+    fn overdue(task: SupervisedTask, late: Duration) -> Option<Overdue> {
+        Some(Overdue { task, late })
+    }
+
+    #[test]
+    fn missed_check_in_is_overdue() {
+        let mut monitor = TaskMonitor::new();
+        let deadline = SupervisedTask::Bq76920.deadline();
+        monitor.register(SupervisedTask::Bq76920, T0);
+
+        assert_eq!(monitor.check(T0 + deadline), None);
+        assert_eq!(
+            monitor.check(T0 + deadline + Duration::from_millis(1)),
+            overdue(SupervisedTask::Bq76920, Duration::from_millis(1))
+        );
+
+        // A check-in restarts the deadline
+        let check_in = T0 + deadline / 2;
+        monitor.check_in(SupervisedTask::Bq76920, check_in);
+        assert_eq!(
+            monitor.check(T0 + deadline + Duration::from_millis(1)),
+            None
+        );
+        assert_eq!(
+            monitor.check(check_in + deadline + Duration::from_secs(1)),
+            overdue(SupervisedTask::Bq76920, Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn unregistered_task_is_not_supervised() {
+        let mut monitor = TaskMonitor::new();
+        // Check-ins before registration don't count
+        monitor.check_in(SupervisedTask::Ina226, T0);
+        assert_eq!(monitor.check(T0 + Duration::from_secs(3600)), None);
+
+        monitor.register(SupervisedTask::Usb, T0);
+        monitor.check_in(SupervisedTask::Ina226, T0);
+        let late = T0 + SupervisedTask::Usb.deadline() + Duration::from_secs(1);
+        assert_eq!(
+            monitor.check(late),
+            overdue(SupervisedTask::Usb, Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn reports_task_furthest_past_its_deadline() {
+        let mut monitor = TaskMonitor::new();
+        for task in SupervisedTask::ALL {
+            monitor.register(task, T0);
+        }
+        let now = T0 + Duration::from_secs(9);
+
+        // Deadlines: 5 s for BQ76920 and INA226, 8 s for BQ25730, 10 s for USB
+        monitor.check_in(SupervisedTask::Bq76920, T0 + Duration::from_secs(1));
+        assert_eq!(
+            monitor.check(now),
+            overdue(SupervisedTask::Ina226, Duration::from_secs(4))
+        );
+        monitor.check_in(SupervisedTask::Ina226, now);
+        assert_eq!(
+            monitor.check(now),
+            overdue(SupervisedTask::Bq76920, Duration::from_secs(3))
+        );
+    }
+
+    #[test]
+    fn clock_before_registration_is_not_overdue() {
+        let mut monitor = TaskMonitor::new();
+        monitor.register(SupervisedTask::Bq25730, T0);
+        assert_eq!(monitor.check(T0 - Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn overdue_verdict_round_trips() {
+        let record = encode_record(
+            WatchdogVerdict::TaskOverdue,
+            overdue(SupervisedTask::Bq25730, Duration::from_millis(1234)),
+            Instant::from_secs(3600),
+        );
+        let report = decode_record(&record, ResetReason::IndependentWatchdog);
+        assert_eq!(
+            report,
+            WatchdogReport {
+                reset_reason: ResetReason::IndependentWatchdog,
+                verdict: WatchdogVerdict::TaskOverdue,
+                task: Some(SupervisedTask::Bq25730),
+                late_ms: 1234,
+                uptime_s: 3600,
+            }
+        );
+        assert!(report.is_watchdog_reset());
+    }
+
+    #[test]
+    fn watchdog_reset_without_overdue_task_is_executor_stall() {
+        let record = encode_record(WatchdogVerdict::Healthy, None, Instant::from_secs(42));
+
+        let report = decode_record(&record, ResetReason::IndependentWatchdog);
+        assert_eq!(report.verdict, WatchdogVerdict::ExecutorStall);
+        assert_eq!(report.task, None);
+        assert_eq!(report.uptime_s, 42);
+        assert!(report.is_watchdog_reset());
+
+        let report = decode_record(&record, ResetReason::Software);
+        assert_eq!(report.verdict, WatchdogVerdict::Healthy);
+        assert!(!report.is_watchdog_reset());
+    }
+
+    #[test]
+    fn corrupt_record_is_ignored() {
+        let mut record = encode_record(
+            WatchdogVerdict::TaskOverdue,
+            overdue(SupervisedTask::Usb, Duration::from_secs(1)),
+            Instant::from_secs(10),
+        );
+        record[2] ^= 1;
+        assert_eq!(
+            decode_record(&record, ResetReason::PowerOn),
+            WatchdogReport {
+                reset_reason: ResetReason::PowerOn,
+                ..Default::default()
+            }
+        );
+
+        // Retained RAM holds arbitrary data after power-up
+        let report = decode_record(&[0xDEAD_BEEF; 5], ResetReason::IndependentWatchdog);
+        assert_eq!(report.verdict, WatchdogVerdict::ExecutorStall);
+        assert_eq!(report.task, None);
+    }
+}
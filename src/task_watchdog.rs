@@ -0,0 +1,156 @@
+//! Independent watchdog (IWDG) and task check-in supervision.
+//!
+//! Every supervised task calls `TaskWatchdog::check_in` within its own deadline. `watchdog_task`
+//! checks the check-ins periodically and only feeds the watchdog when all are on time; if a task
+//! is overdue it records which one and stops feeding, so the IWDG resets the MCU. If the whole
+//! executor stalls, `watchdog_task` can't run either and the IWDG resets the MCU as well.
+//!
+//! The verdict is kept in RAM that survives a reset. At the next boot it is combined with the RCC
+//! reset flags into a `WatchdogReport`, which goes to the event log and can be read over USB.
+//!
+//! The check-in judgement (`TaskMonitor`) and the record format don't touch the hardware and
+//! live in `task_monitor`.
+
+use core::cell::RefCell;
+use core::mem::MaybeUninit;
+
+use defmt::{error, info};
+use embassy_stm32::pac;
+use embassy_stm32::peripherals;
+use embassy_stm32::wdg::IndependentWatchdog;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+
+pub use crate::task_monitor::{CHECK_IN_INTERVAL, ResetReason, SupervisedTask, WatchdogReport};
+use crate::task_monitor::{
+    Overdue, RetainedRecord, TaskMonitor, WatchdogVerdict, decode_record, encode_record,
+};
+
+/// IWDG timeout (µs): the MCU resets this long after the last feed
+pub const IWDG_TIMEOUT_US: u32 = 4_000_000;
+
+/// Interval of checking the check-ins and feeding the watchdog
+const FEED_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Check-in state shared by the tasks
+pub struct TaskWatchdog {
+    monitor: Mutex<CriticalSectionRawMutex, RefCell<TaskMonitor>>,
+}
+
+impl Default for TaskWatchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskWatchdog {
+    pub const fn new() -> Self {
+        Self {
+            monitor: Mutex::new(RefCell::new(TaskMonitor::new())),
+        }
+    }
+
+    /// Call before spawning the task
+    pub fn register(&self, task: SupervisedTask) {
+        self.monitor
+            .lock(|monitor| monitor.borrow_mut().register(task, Instant::now()));
+    }
+
+    pub fn check_in(&self, task: SupervisedTask) {
+        self.monitor
+            .lock(|monitor| monitor.borrow_mut().check_in(task, Instant::now()));
+    }
+
+    fn check(&self, now: Instant) -> Option<Overdue> {
+        self.monitor.lock(|monitor| monitor.borrow().check(now))
+    }
+}
+
+/// Reads and clears the reset flags. Internal reset sources also pull NRST low, so PINRSTF has
+/// the lowest priority.
+fn take_reset_reason() -> ResetReason {
+    let csr = pac::RCC.csr().read();
+    let reason = if csr.iwdgrstf() {
+        ResetReason::IndependentWatchdog
+    } else if csr.wwdgrstf() {
+        ResetReason::WindowWatchdog
+    } else if csr.lpwrrstf() {
+        ResetReason::LowPower
+    } else if csr.sftrstf() {
+        ResetReason::Software
+    } else if csr.oblrstf() {
+        ResetReason::OptionByteLoad
+    } else if csr.borrstf() {
+        ResetReason::PowerOn
+    } else if csr.pinrstf() {
+        ResetReason::Pin
+    } else {
+        ResetReason::Unknown
+    };
+    pac::RCC.csr().modify(|w| w.set_rmvf(true));
+    reason
+}
+
+#[unsafe(link_section = ".uninit.task_watchdog")]
+static mut RETAINED: MaybeUninit<RetainedRecord> = MaybeUninit::uninit();
+
+fn store_verdict(verdict: WatchdogVerdict, overdue: Option<Overdue>, now: Instant) {
+    let record = encode_record(verdict, overdue, now);
+    // SAFETY: after boot only `watchdog_task` writes, and the read at boot happens before it runs
+    unsafe {
+        core::ptr::addr_of_mut!(RETAINED)
+            .cast::<RetainedRecord>()
+            .write_volatile(record)
+    };
+}
+
+/// Reads the verdict left by the previous run and clears the record. Call once at boot, before
+/// `watchdog_task` runs.
+pub fn take_last_report() -> WatchdogReport {
+    let reset_reason = take_reset_reason();
+    // SAFETY: retained RAM holds arbitrary data after power-up; magic and CRC tell whether it is
+    // valid
+    let record = unsafe {
+        core::ptr::addr_of!(RETAINED)
+            .cast::<RetainedRecord>()
+            .read_volatile()
+    };
+    store_verdict(WatchdogVerdict::None, None, Instant::now());
+    decode_record(&record, reset_reason)
+}
+
+/// Embassy task that checks the task check-ins and feeds the IWDG. When a task is overdue it
+/// records the verdict and stops feeding.
+#[embassy_executor::task]
+pub async fn watchdog_task(
+    mut iwdg: IndependentWatchdog<'static, peripherals::IWDG>,
+    task_watchdog: &'static TaskWatchdog,
+) {
+    info!(
+        "Task watchdog started, IWDG timeout {} ms",
+        IWDG_TIMEOUT_US / 1000
+    );
+    iwdg.unleash();
+    loop {
+        let now = Instant::now();
+        match task_watchdog.check(now) {
+            None => {
+                store_verdict(WatchdogVerdict::Healthy, None, now);
+                iwdg.pet();
+            }
+            Some(overdue) => {
+                error!(
+                    "Task watchdog: {:?} missed its check-in deadline by {} ms, waiting for IWDG reset",
+                    overdue.task,
+                    overdue.late.as_millis()
+                );
+                store_verdict(WatchdogVerdict::TaskOverdue, Some(overdue), now);
+                // Stop feeding
+                loop {
+                    Timer::after(Duration::from_secs(1)).await;
+                }
+            }
+        }
+        Timer::after(FEED_INTERVAL).await;
+    }
+}
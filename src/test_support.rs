@@ -0,0 +1,136 @@
+//! Host stand-ins for what the firmware gets from the target: a defmt logger that discards
+//! its output and panics like `core::panic!`, a time driver whose clock only moves when a
+//! test advances it and a RAM-backed NOR flash. Code under test takes the current time as a
+//! parameter wherever it can; the clock is for code that has to go through `Instant::now()`
+//! or a timer.
+
+use core::cell::Cell;
+use core::task::Waker;
+
+use embassy_time::Duration;
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+
+#[defmt::global_logger]
+struct NullLogger;
+
+unsafe impl defmt::Logger for NullLogger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
+
+std::thread_local! {
+    // Per thread, so that tests running in parallel don't move each other's clock
+    static NOW: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Moves the clock of the calling thread forward.
+pub fn advance_time(by: Duration) {
+    NOW.with(|now| now.set(now.get() + by.as_ticks()));
+}
+
+struct ManualTimeDriver;
+
+impl embassy_time_driver::Driver for ManualTimeDriver {
+    fn now(&self) -> u64 {
+        NOW.with(Cell::get)
+    }
+
+    // Tests drive futures with a busy-polling executor, so there is nothing to wake
+    fn schedule_wake(&self, _at: u64, _waker: &Waker) {}
+}
+
+embassy_time_driver::time_driver_impl!(static DRIVER: ManualTimeDriver = ManualTimeDriver);
+
+/// NOR flash in RAM with the write granularity of the STM32G4 and small pages, so that tests
+/// reach page rotation after a few records. Like the real part it can only program erased
+/// bytes.
+pub struct RamFlash {
+    pub data: Vec<u8>,
+    /// Bytes that can still be programmed before a simulated power loss; the write that runs
+    /// out stops part-way and fails, as do all later writes. `None` never loses power.
+    pub write_budget: Option<usize>,
+}
+
+impl RamFlash {
+    pub const PAGE_SIZE: usize = 256;
+
+    pub fn new(pages: usize) -> Self {
+        Self {
+            data: vec![0xFF; pages * Self::PAGE_SIZE],
+            write_budget: None,
+        }
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        let src = self
+            .data
+            .get(start..start + bytes.len())
+            .ok_or(NorFlashErrorKind::OutOfBounds)?;
+        bytes.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 8;
+    const ERASE_SIZE: usize = RamFlash::PAGE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let (from, to) = (from as usize, to as usize);
+        if !from.is_multiple_of(Self::ERASE_SIZE) || !to.is_multiple_of(Self::ERASE_SIZE) {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        self.data
+            .get_mut(from..to)
+            .ok_or(NorFlashErrorKind::OutOfBounds)?
+            .fill(0xFF);
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        if !start.is_multiple_of(Self::WRITE_SIZE) || !bytes.len().is_multiple_of(Self::WRITE_SIZE)
+        {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        let target = self
+            .data
+            .get_mut(start..start + bytes.len())
+            .ok_or(NorFlashErrorKind::OutOfBounds)?;
+        assert!(
+            target.iter().all(|&b| b == 0xFF),
+            "write to unerased flash at {offset:#x}"
+        );
+        let len = self
+            .write_budget
+            .map_or(bytes.len(), |b| b.min(bytes.len()));
+        target[..len].copy_from_slice(&bytes[..len]);
+        if let Some(budget) = self.write_budget.as_mut() {
+            *budget -= len;
+            if len < bytes.len() {
+                return Err(NorFlashErrorKind::Other);
+            }
+        }
+        Ok(())
+    }
+}
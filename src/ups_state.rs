@@ -0,0 +1,84 @@
+//! Overall UPS status (mains, charging and discharging, remaining capacity and so on), derived
+//! from the aggregated measurements.
+
+use bq769x0_async_rs::registers::{SysCtrl2Flags, SysStatFlags};
+
+use crate::battery_profile;
+use crate::config::LowBatteryConfig;
+use crate::data_types::AllMeasurements;
+use crate::overload_protection::OverloadState;
+pub use crate::ups_status::{AC_PRESENT_VBUS_MV, IDLE_CURRENT_THRESHOLD_MA, UpsStatus};
+
+/// Below this load power the run time is no longer estimated (mW)
+const MIN_LOAD_POWER_FOR_RUNTIME_MW: f32 = 100.0;
+
+/// Derives the overall UPS status from the aggregated measurements
+pub fn from_measurements<const N: usize>(
+    measurements: &AllMeasurements<N>,
+    low_battery: &LowBatteryConfig,
+) -> UpsStatus {
+    let core = &measurements.bq76920.core_measurements;
+    let adc = &measurements.bq25730.adc_measurements;
+
+    let ac_present = adc.vbus.0 >= AC_PRESENT_VBUS_MV;
+    let consistency = &measurements.consistency;
+    let current_ma = consistency.battery_current_ma.unwrap_or(core.current_ma);
+    let charging = current_ma > IDLE_CURRENT_THRESHOLD_MA;
+    let discharging = current_ma < -IDLE_CURRENT_THRESHOLD_MA;
+
+    let remaining_capacity_pct = measurements
+        .bq76920
+        .gauge
+        .soc_or_ocv(&core.cell_voltages.voltages);
+
+    let load_power_mw = measurements.ina226.power;
+    let run_time_to_empty_s = if load_power_mw > MIN_LOAD_POWER_FOR_RUNTIME_MW {
+        let remaining_mwh = battery_profile::remaining_energy_mwh(remaining_capacity_pct);
+        let seconds = remaining_mwh as f32 * 3600.0 / load_power_mw;
+        if seconds >= u16::MAX as f32 {
+            u16::MAX
+        } else {
+            seconds as u16
+        }
+    } else {
+        u16::MAX
+    };
+
+    // Until the coulomb counter has learned the full charge capacity, use the nominal capacity
+    let gauge = &measurements.bq76920.gauge;
+    let full_charge_capacity_pct = if gauge.valid {
+        (gauge.full_capacity_mah as u32 * 100 / battery_profile::NOMINAL_CAPACITY_MAH).min(100)
+            as u8
+    } else {
+        100
+    };
+
+    let alerts = measurements.bq76920_alerts.system_status.0 | core.system_status.0;
+
+    UpsStatus {
+        ac_present,
+        charging,
+        discharging,
+        fully_charged: ac_present && !charging && remaining_capacity_pct >= 100,
+        below_remaining_capacity_limit: remaining_capacity_pct
+            < low_battery.remaining_capacity_limit_pct,
+        shutdown_imminent: !ac_present
+            && (remaining_capacity_pct < low_battery.shutdown_imminent_pct
+                || alerts.contains(SysStatFlags::UV)),
+        overload: alerts.intersects(SysStatFlags::OCD | SysStatFlags::SCD)
+            || measurements.bq76920.overload.state != OverloadState::Normal,
+        need_replacement: full_charge_capacity_pct < battery_profile::REPLACEMENT_CAPACITY_PCT,
+        discharge_fet_on: core.mos_status.0.contains(SysCtrl2Flags::DSG_ON),
+        remaining_capacity_pct,
+        run_time_to_empty_s,
+        full_charge_capacity_pct,
+        pack_voltage_mv: consistency
+            .pack_voltage_mv
+            .unwrap_or(core.total_voltage_mv)
+            .clamp(0, u16::MAX as i32) as u16,
+        battery_current_ma: current_ma.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+        temperature_0_01c: core.temperatures.ts1,
+        remaining_capacity_limit_pct: low_battery.remaining_capacity_limit_pct,
+        warning_capacity_limit_pct: low_battery.warning_capacity_limit_pct,
+    }
+}
@@ -0,0 +1,39 @@
+//! Overall UPS status (mains, charging and discharging, remaining capacity and so on), derived
+//! from the measurements by `ups_state` and reported through the HID reports and the event log.
+
+/// VBUS above this voltage means the input supply (adapter) is present (mV)
+pub const AC_PRESENT_VBUS_MV: u16 = 4500;
+
+/// Below this absolute current the battery is considered at rest (mA)
+pub const IDLE_CURRENT_THRESHOLD_MA: i32 = 50;
+
+/// Snapshot of the overall UPS status
+#[derive(Debug, Copy, Clone, PartialEq, defmt::Format)]
+pub struct UpsStatus {
+    pub ac_present: bool,
+    pub charging: bool,
+    pub discharging: bool,
+    pub fully_charged: bool,
+    pub below_remaining_capacity_limit: bool,
+    pub shutdown_imminent: bool,
+    pub overload: bool,
+    /// The full charge capacity has faded below `REPLACEMENT_CAPACITY_PCT`
+    pub need_replacement: bool,
+    pub discharge_fet_on: bool,
+    /// Remaining capacity (0-100 %)
+    pub remaining_capacity_pct: u8,
+    /// Estimated run time at the present load (s), `u16::MAX` when it cannot be estimated
+    pub run_time_to_empty_s: u16,
+    /// Full charge capacity as a percentage of the nominal capacity (0-100)
+    pub full_charge_capacity_pct: u8,
+    /// Battery pack voltage (mV)
+    pub pack_voltage_mv: u16,
+    /// Battery current (mA), positive while charging and negative while discharging
+    pub battery_current_ma: i16,
+    /// TS1 temperature (0.01 °C)
+    pub temperature_0_01c: i16,
+    /// RemainingCapacityLimit in effect (%)
+    pub remaining_capacity_limit_pct: u8,
+    /// WarningCapacityLimit in effect (%)
+    pub warning_capacity_limit_pct: u8,
+}
@@ -1,11 +1,557 @@
 use binrw::io::Cursor;
 use binrw::io::{Read, Seek};
 use binrw::{BinRead, BinResult, BinWrite, Endian};
+use embassy_time::{Duration, Instant, with_timeout};
 use embassy_usb::Builder;
 use embassy_usb::driver::EndpointError;
 use embassy_usb::driver::{Driver, Endpoint, EndpointIn, EndpointOut};
 
-use crate::data_types::AllMeasurementsUsbPayload; // Added AllMeasurementsUsbPayload
+use crate::battery_test::{BatteryTestCommand, BatteryTestParams};
+use crate::calibration::{CalibrationChannel, CalibrationError, CalibrationSession};
+use crate::config::{
+    CellProtectionConfig, ChargerConfig, ConfigError, ConfigParam, SystemConfig, save_record,
+};
+use crate::config_transaction::{
+    ConfigTransaction, DEFAULT_CONFIRM_TIMEOUT_S, MAX_CONFIRM_TIMEOUT_S, MIN_CONFIRM_TIMEOUT_S,
+    TransactionError, TransactionState,
+};
+use crate::crash_report::{CrashReport, FILE_LEN, MESSAGE_LEN, SNAPSHOT_LEN};
+use crate::data_types::{
+    AllMeasurements, AllMeasurementsUsbPayload, BatteryTestReportPayload, EVENT_LOG_PAGE_ENTRIES,
+    EventLogPagePayload,
+};
+use crate::energy_meter::EnergyMeter;
+use crate::event_log::{EventLogEntry, PowerEvent, PowerEventCode, SharedEventLog};
+use crate::flash_layout::SharedConfigStore;
+use crate::hardware_probe::{ChipInfo, HardwareInventory};
+use crate::host_watchdog::{HostWatchdogCommand, HostWatchdogConfig};
+use crate::load_control::{LoadCommand, RestoreMode, RestorePolicy};
+use crate::power_quality::{self, PowerQualityConfig, PowerQualityStatus, VbusWindow};
+use crate::protection::{ProtectionApplyResult, ProtectionStatus};
+use crate::shared::{
+    BatteryTestCommandSender, LoadCommandSender, PowerEventSender, SystemConfigPublisher,
+};
+use crate::task_watchdog::WatchdogReport;
+
+/// A host that stops reading the IN endpoints must not stall `usb_task` past its watchdog
+/// deadline; a packet not collected within this time is treated as a disconnect.
+const PACKET_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Result code carried by `UsbData::CommandAck`
+#[repr(u8)]
+#[derive(BinWrite, Debug, Clone, Copy, PartialEq, defmt::Format)]
+#[bw(repr = u8)]
+pub enum CommandStatus {
+    Ok = 0x00,
+    /// The command queue of the target task is full, retry later
+    Busy = 0x01,
+    /// A command argument is out of range
+    InvalidArgument = 0x02,
+    /// The command took effect but could not be written to flash
+    PersistFailed = 0x03,
+}
+
+/// Acknowledgement for commands that do not return data
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct CommandAck {
+    /// Magic byte of the acknowledged command
+    pub command: u8,
+    pub status: CommandStatus,
+}
+
+/// Result code carried by `UsbData::ConfigResponse`
+#[repr(u8)]
+#[derive(BinWrite, Debug, Clone, Copy, PartialEq, defmt::Format)]
+#[bw(repr = u8)]
+pub enum ConfigStatus {
+    Ok = 0x00,
+    UnknownParameter = 0x01,
+    /// The value is outside `min..=max`
+    OutOfRange = 0x02,
+    /// The value conflicts with another parameter
+    Inconsistent = 0x03,
+    /// The value was applied but could not be written to flash
+    PersistFailed = 0x04,
+    /// A previous change is still being applied, or a configuration transaction is open
+    Busy = 0x05,
+    /// The configuration transaction is not in the state the command requires
+    InvalidState = 0x06,
+}
+
+impl From<TransactionError> for ConfigStatus {
+    fn from(e: TransactionError) -> Self {
+        match e {
+            TransactionError::InvalidState => Self::InvalidState,
+            TransactionError::Config(e) => e.into(),
+        }
+    }
+}
+
+impl From<ConfigError> for ConfigStatus {
+    fn from(e: ConfigError) -> Self {
+        match e {
+            ConfigError::OutOfRange => Self::OutOfRange,
+            ConfigError::Inconsistent => Self::Inconsistent,
+        }
+    }
+}
+
+impl From<CalibrationError> for ConfigStatus {
+    fn from(e: CalibrationError) -> Self {
+        match e {
+            CalibrationError::NoPoints => Self::InvalidState,
+            CalibrationError::PointsTooClose => Self::Inconsistent,
+            CalibrationError::OutOfRange => Self::OutOfRange,
+        }
+    }
+}
+
+/// Response to `GetConfig` and `SetConfig`
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct ConfigResponse {
+    pub param: u8,
+    pub status: ConfigStatus,
+    /// Value in effect after the command
+    pub value: u32,
+    /// Accepted range, inclusive
+    pub min: u32,
+    pub max: u32,
+}
+
+/// BQ76920 protection thresholds as carried by `SetProtection` and `ProtectionResponse`
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct ProtectionThresholds {
+    pub overvoltage_mv: u16,
+    pub undervoltage_mv: u16,
+    pub ocd_limit_ma: u16,
+    pub ocd_delay_ms: u16,
+    pub scd_limit_ma: u16,
+    pub scd_delay_us: u16,
+    pub ov_delay_s: u8,
+    pub uv_delay_s: u8,
+}
+
+impl From<CellProtectionConfig> for ProtectionThresholds {
+    fn from(c: CellProtectionConfig) -> Self {
+        Self {
+            overvoltage_mv: c.overvoltage_mv,
+            undervoltage_mv: c.undervoltage_mv,
+            ocd_limit_ma: c.ocd_limit_ma,
+            ocd_delay_ms: c.ocd_delay_ms,
+            scd_limit_ma: c.scd_limit_ma,
+            scd_delay_us: c.scd_delay_us,
+            ov_delay_s: c.ov_delay_s,
+            uv_delay_s: c.uv_delay_s,
+        }
+    }
+}
+
+impl From<ProtectionThresholds> for CellProtectionConfig {
+    fn from(t: ProtectionThresholds) -> Self {
+        Self {
+            overvoltage_mv: t.overvoltage_mv,
+            undervoltage_mv: t.undervoltage_mv,
+            ocd_limit_ma: t.ocd_limit_ma,
+            scd_limit_ma: t.scd_limit_ma,
+            scd_delay_us: t.scd_delay_us,
+            ocd_delay_ms: t.ocd_delay_ms,
+            ov_delay_s: t.ov_delay_s,
+            uv_delay_s: t.uv_delay_s,
+        }
+    }
+}
+
+/// Response to `GetProtection` and `SetProtection`
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct ProtectionResponse {
+    /// Outcome of the command; for `GetProtection`, `Busy` while a change is being applied
+    /// and `PersistFailed` if the last applied change could not be written to flash
+    pub status: ConfigStatus,
+    /// ProtectionApplyResult of the last change: 0=None, 1=Applied, 2=Reverted, 3=RevertFailed
+    pub last_result: u8,
+    /// Thresholds in effect in the BQ76920, i.e. the last ones that passed verification
+    pub active: ProtectionThresholds,
+}
+
+/// Response to the configuration transaction commands
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct TransactionResponse {
+    /// Magic byte of the command this responds to
+    pub command: u8,
+    pub status: ConfigStatus,
+    /// TransactionState: 0=Idle, 1=Staging, 2=Applying, 3=AwaitingConfirm
+    pub state: u8,
+    /// TransactionOutcome of the last finished transaction:
+    /// 0=None, 1=Confirmed, 2=Aborted, 3=TimedOut, 4=ApplyFailed
+    pub last_outcome: u8,
+    /// Seconds left to confirm before the device rolls back, 0 if nothing is committed
+    pub remaining_s: u16,
+}
+
+/// Response to the calibration commands
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct CalibrationResponse {
+    pub channel: u8,
+    /// `UnknownParameter` for an unknown channel, `InvalidState` if no point was captured,
+    /// `Inconsistent` if the two points are too close and `OutOfRange` if the computed
+    /// correction exceeds the allowed range
+    pub status: ConfigStatus,
+    /// Calibration points captured for this channel so far
+    pub points: u8,
+    /// Current reading of the channel with the calibration in effect (mV or mA)
+    pub measured: i32,
+    /// Gain correction in effect, in units of 10 ppm
+    pub gain_trim: i16,
+    /// Offset correction in effect (mV or mA)
+    pub offset: i16,
+}
+
+/// One chip in `HardwareInventoryResponse`
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct ChipInfoPayload {
+    /// ChipStatus: 0=Missing, 1=Present, 2=Unsupported
+    pub status: u8,
+    /// 7-bit I2C address that answered, or the expected one if missing
+    pub address: u8,
+    /// Raw identification registers
+    pub id: u16,
+}
+
+impl From<ChipInfo> for ChipInfoPayload {
+    fn from(info: ChipInfo) -> Self {
+        Self {
+            status: info.status as u8,
+            address: info.address,
+            id: info.id,
+        }
+    }
+}
+
+/// Response to `GetHardwareInventory`, the chips found by the boot probe
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct HardwareInventoryResponse {
+    /// OperatingMode: 0=Full, 1=MonitorOnly, 2=ChargerOnly, 3=NoDevices
+    pub mode: u8,
+    pub bq76920: ChipInfoPayload,
+    /// 1 if the BQ76920 appends a CRC to read data
+    pub bq76920_crc: u8,
+    pub bq25730: ChipInfoPayload,
+    pub ina226: ChipInfoPayload,
+}
+
+impl From<HardwareInventory> for HardwareInventoryResponse {
+    fn from(inventory: HardwareInventory) -> Self {
+        Self {
+            mode: inventory.mode() as u8,
+            bq76920: inventory.bq76920.into(),
+            bq76920_crc: inventory.bq76920_crc as u8,
+            bq25730: inventory.bq25730.into(),
+            ina226: inventory.ina226.into(),
+        }
+    }
+}
+
+/// Response to `GetWatchdogReport`, how the previous run ended
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct WatchdogReportResponse {
+    /// ResetReason: 0=Unknown, 1=PowerOn, 2=Pin, 3=Software, 4=IndependentWatchdog,
+    /// 5=WindowWatchdog, 6=LowPower, 7=OptionByteLoad
+    pub reset_reason: u8,
+    /// WatchdogVerdict: 0=None, 1=Healthy, 2=TaskOverdue, 3=ExecutorStall
+    pub verdict: u8,
+    /// SupervisedTask that missed its deadline (0=BQ76920, 1=BQ25730, 2=INA226, 3=USB),
+    /// 0xFF if none
+    pub task: u8,
+    /// How far past its deadline the task was (ms)
+    pub late_ms: u32,
+    /// Uptime when the verdict was recorded (s)
+    pub uptime_s: u32,
+}
+
+impl From<WatchdogReport> for WatchdogReportResponse {
+    fn from(report: WatchdogReport) -> Self {
+        Self {
+            reset_reason: report.reset_reason as u8,
+            verdict: report.verdict as u8,
+            task: report.task.map_or(0xFF, |task| task as u8),
+            late_ms: report.late_ms,
+            uptime_s: report.uptime_s,
+        }
+    }
+}
+
+/// VBUS and IIN over one statistics window of `PowerQualityResponse`, while the adapter was present
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct VbusWindowPayload {
+    pub vbus_min_mv: u16,
+    pub vbus_max_mv: u16,
+    pub vbus_mean_mv: u16, // Time-weighted
+    pub iin_max_ma: u16,
+    pub iin_mean_ma: u16,
+    pub present_s: u16, // Time the adapter was present in the window
+}
+
+impl From<VbusWindow> for VbusWindowPayload {
+    fn from(window: VbusWindow) -> Self {
+        Self {
+            vbus_min_mv: window.vbus_min_mv,
+            vbus_max_mv: window.vbus_max_mv,
+            vbus_mean_mv: window.vbus_mean_mv,
+            iin_max_ma: window.iin_max_ma,
+            iin_mean_ma: window.iin_mean_ma,
+            present_s: window.present_s,
+        }
+    }
+}
+
+/// Response to `GetPowerQuality`, input adapter statistics since boot
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct PowerQualityResponse {
+    /// Configured window length (s)
+    pub window_s: u16,
+    /// Window in progress
+    pub window: VbusWindowPayload,
+    /// Last complete window, zeros until the first window has completed
+    pub previous_window: VbusWindowPayload,
+    /// 1 while VBUS is below the adapter-present threshold
+    pub dropout_active: u8,
+    pub dropout_count: u16,
+    /// Dropouts shorter than 10 s, typical of a loose or failing adapter
+    pub short_dropout_count: u16,
+    pub longest_dropout_ms: u32,
+    pub last_dropout_ms: u32,
+    /// Configured sag threshold (mV)
+    pub sag_threshold_mv: u16,
+    pub sag_count: u16,
+    /// Lowest VBUS during a sag, 0 if none
+    pub sag_min_mv: u16,
+    pub sag_total_ms: u32,
+    /// Time on battery per 24 h of uptime (s), index 0 = current day
+    pub on_battery_s: [u32; power_quality::DAYS],
+    /// Time elapsed in the current day (s)
+    pub day_elapsed_s: u32,
+}
+
+impl PowerQualityResponse {
+    fn new(status: &PowerQualityStatus, config: &PowerQualityConfig) -> Self {
+        Self {
+            window_s: config.window_s,
+            window: status.window.into(),
+            previous_window: status.previous_window.into(),
+            dropout_active: status.dropout_active as u8,
+            dropout_count: status.dropout_count,
+            short_dropout_count: status.short_dropout_count,
+            longest_dropout_ms: status.longest_dropout_ms,
+            last_dropout_ms: status.last_dropout_ms,
+            sag_threshold_mv: config.sag_threshold_mv,
+            sag_count: status.sag_count,
+            sag_min_mv: status.sag_min_mv,
+            sag_total_ms: status.sag_total_ms,
+            on_battery_s: status.on_battery_s,
+            day_elapsed_s: status.day_elapsed_s,
+        }
+    }
+}
+
+/// Response to `GetCrashReport`, the panic recorded in the previous run
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct CrashReportResponse {
+    /// 1 if the previous run ended in a panic, all other fields are zero otherwise
+    pub present: u8,
+    /// ResetReason of the run that panicked, see `WatchdogReportResponse`
+    pub reset_reason: u8,
+    /// Uptime at the panic (s)
+    pub uptime_s: u32,
+    pub line: u32,
+    pub column: u32,
+    pub message_len: u8,
+    /// UTF-8 panic message, truncated
+    pub message: [u8; MESSAGE_LEN],
+    pub file_len: u8,
+    /// UTF-8 source file path, the end is kept if too long
+    pub file: [u8; FILE_LEN],
+}
+
+/// Sent after `CrashReportResponse`: the last measurements before the panic
+#[derive(BinWrite, Debug, Clone, Copy, defmt::Format)]
+pub struct CrashSnapshotResponse {
+    /// Number of valid bytes, 0 if no measurements were taken before the panic
+    pub len: u8,
+    /// `StatusResponse` payload encoding of the measurements
+    pub data: [u8; SNAPSHOT_LEN],
+}
+
+impl From<Option<CrashReport>> for CrashReportResponse {
+    fn from(crash: Option<CrashReport>) -> Self {
+        match crash {
+            Some(crash) => Self {
+                present: 1,
+                reset_reason: crash.reset_reason as u8,
+                uptime_s: crash.uptime_s,
+                line: crash.line,
+                column: crash.column,
+                message_len: crash.message_len,
+                message: crash.message,
+                file_len: crash.file_len,
+                file: crash.file,
+            },
+            None => Self {
+                present: 0,
+                reset_reason: 0,
+                uptime_s: 0,
+                line: 0,
+                column: 0,
+                message_len: 0,
+                message: [0; MESSAGE_LEN],
+                file_len: 0,
+                file: [0; FILE_LEN],
+            },
+        }
+    }
+}
+
+impl From<Option<CrashReport>> for CrashSnapshotResponse {
+    fn from(crash: Option<CrashReport>) -> Self {
+        match crash {
+            Some(crash) => Self {
+                len: crash.snapshot_len,
+                data: crash.snapshot,
+            },
+            None => Self {
+                len: 0,
+                data: [0; SNAPSHOT_LEN],
+            },
+        }
+    }
+}
+
+/// A protection threshold change waiting for `bq76920_task` to report the verification result
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct PendingProtection {
+    pub requested: CellProtectionConfig,
+    pub persist: bool,
+    /// USB command to record in the event log once the change is applied
+    pub log_command: Option<u8>,
+    /// `ProtectionStatus::apply_count` when the request was made
+    pub apply_count: u16,
+}
+
+/// Handles to other tasks that USB commands are forwarded to.
+pub struct CommandContext<'a> {
+    pub load_command_sender: LoadCommandSender<'a>,
+    pub battery_test_command_sender: BatteryTestCommandSender<'a>,
+    pub power_event_sender: PowerEventSender<'a>,
+    pub event_log: &'a SharedEventLog,
+    /// Runtime configuration in effect, changed by `SetConfig`
+    pub config: SystemConfig,
+    pub config_publisher: SystemConfigPublisher<'a>,
+    /// `None` if the configuration store could not be opened at boot
+    pub config_store: Option<&'a SharedConfigStore>,
+    /// Latest protection status reported by `bq76920_task`
+    pub protection_status: ProtectionStatus,
+    pub pending_protection: Option<PendingProtection>,
+    /// Whether the last applied `SetProtection` failed to persist
+    pub protection_persist_failed: bool,
+    pub transaction: ConfigTransaction,
+    pub calibration_session: CalibrationSession,
+    /// Chips found by the boot probe
+    pub inventory: HardwareInventory,
+    /// Task watchdog verdict of the previous run, read at boot
+    pub watchdog_report: WatchdogReport,
+    /// Panic recorded in the previous run, read at boot
+    pub last_crash: Option<CrashReport>,
+    /// Energy counters, cleared by `ResetEnergyCounters`
+    pub energy_meter: &'a EnergyMeter,
+}
+
+impl CommandContext<'_> {
+    /// Completes a pending `SetProtection` once `bq76920_task` reports the verification result,
+    /// adopting the thresholds that are actually in effect and persisting them if requested.
+    pub async fn update_protection(&mut self, status: &ProtectionStatus) {
+        self.protection_status = *status;
+        let Some(pending) = self.pending_protection else {
+            return;
+        };
+        if status.apply_count == pending.apply_count {
+            return;
+        }
+        self.pending_protection = None;
+        // After a revert the BQ76920 runs the last known-good thresholds again
+        self.config.cell_protection = status.active;
+
+        if status.last_result != ProtectionApplyResult::Applied
+            || status.active != pending.requested
+        {
+            defmt::warn!(
+                "Protection: {:?} failed verification ({:?}), active {:?}",
+                pending.requested,
+                status.last_result,
+                status.active
+            );
+            return;
+        }
+        defmt::info!("Protection: Applied {:?}", status.active);
+        if let Some(command) = pending.log_command {
+            log_config_change(self, command);
+        }
+        if pending.persist {
+            self.protection_persist_failed = !self.persist_protection().await;
+        }
+    }
+
+    /// Advances a committed configuration transaction: marks it applied once both device
+    /// tasks report the staged settings, and rolls it back when the protection thresholds
+    /// fail verification or the confirm deadline passes.
+    pub fn update_transaction(&mut self, applied_charger: Option<ChargerConfig>) {
+        // Wait for an in-flight protection change so its result is not mistaken for the rollback's
+        if !self.transaction.is_committed() || self.pending_protection.is_some() {
+            return;
+        }
+        let staged = *self.transaction.staged();
+        let rollback = if self.protection_status.active != staged.cell_protection {
+            self.transaction.apply_failed()
+        } else {
+            if applied_charger == Some(staged.charger) {
+                self.transaction.applied();
+            }
+            self.transaction.check_deadline(Instant::now())
+        };
+
+        if let Some(previous) = rollback {
+            let outcome = self.transaction.last_outcome();
+            defmt::warn!("Config transaction rolled back: {:?}", outcome);
+            self.deploy_config(previous, None);
+            let event = PowerEvent::new(PowerEventCode::ConfigRollback, outcome as u16);
+            if self.power_event_sender.try_send(event).is_err() {
+                defmt::warn!("Config transaction: Power event queue full, rollback not logged");
+            }
+        }
+    }
+
+    /// Makes `config` the runtime configuration and publishes it to the device tasks.
+    /// A protection threshold change is tracked until `bq76920_task` has verified it.
+    fn deploy_config(&mut self, config: SystemConfig, log_command: Option<u8>) {
+        self.config = config;
+        if config.cell_protection != self.protection_status.active {
+            self.pending_protection = Some(PendingProtection {
+                requested: config.cell_protection,
+                persist: false,
+                log_command,
+                apply_count: self.protection_status.apply_count,
+            });
+        }
+        self.config_publisher.publish_immediate(config);
+    }
+
+    async fn persist_protection(&self) -> bool {
+        match self.config_store {
+            Some(store) => {
+                save_record(&mut *store.lock().await, &self.config.cell_protection).await
+            }
+            None => false,
+        }
+    }
+}
 
 #[repr(u8)]
 #[derive(BinWrite, Debug, Clone, Copy, defmt::Format)] // Removed BinRead from derive
@@ -15,10 +561,126 @@ pub enum UsbData {
     SubscribeStatus,
     #[brw(magic = 0x01u8)]
     UnsubscribeStatus,
+    #[brw(magic = 0x10u8)]
+    ScheduleShutdown { delay_s: u16 },
+    #[brw(magic = 0x11u8)]
+    ScheduleShutdownRestore { delay_s: u16, off_time_s: u16 },
+    #[brw(magic = 0x12u8)]
+    CancelLoadAction,
+    #[brw(magic = 0x13u8)]
+    ConfigureHeartbeat {
+        interval_s: u16,
+        grace_s: u16,
+        off_time_s: u16,
+        max_retries: u8,
+        backoff_s: u16,
+    },
+    #[brw(magic = 0x14u8)]
+    Heartbeat,
+    #[brw(magic = 0x15u8)]
+    DisableHeartbeat,
+    #[brw(magic = 0x16u8)]
+    RestoreLoad,
+    #[brw(magic = 0x17u8)]
+    SetRestorePolicy {
+        mode: u8, // 0 = manual, 1 = auto
+        min_soc_pct: u8,
+        delay_s: u16,
+    },
+    #[brw(magic = 0x18u8)]
+    StartBatteryTest { duration_s: u16, max_depth_pct: u8 },
+    #[brw(magic = 0x19u8)]
+    AbortBatteryTest,
+    #[brw(magic = 0x1Au8)]
+    GetBatteryTestResult,
+    #[brw(magic = 0x1Bu8)]
+    GetEventLog { start_index: u16, count: u8 }, // start_index 0 = newest entry
+    #[brw(magic = 0x1Cu8)]
+    GetConfig { param: u8 },
+    #[brw(magic = 0x1Du8)]
+    SetConfig {
+        param: u8,
+        value: u32,
+        persist: u8, // non-zero = also write to flash
+    },
+    #[brw(magic = 0x1Eu8)]
+    GetProtection,
+    #[brw(magic = 0x1Fu8)]
+    SetProtection {
+        thresholds: ProtectionThresholds,
+        persist: u8, // non-zero = write to flash once verified
+    },
+    #[brw(magic = 0x20u8)]
+    BeginConfigTransaction,
+    #[brw(magic = 0x21u8)]
+    StageConfig { param: u8, value: u32 },
+    #[brw(magic = 0x22u8)]
+    StageProtection { thresholds: ProtectionThresholds },
+    #[brw(magic = 0x23u8)]
+    CommitConfig { confirm_timeout_s: u16 }, // 0 = default timeout
+    #[brw(magic = 0x24u8)]
+    ConfirmConfig {
+        persist: u8, // non-zero = also write to flash
+    },
+    #[brw(magic = 0x25u8)]
+    AbortConfigTransaction,
+    #[brw(magic = 0x26u8)]
+    GetTransactionStatus,
+    #[brw(magic = 0x27u8)]
+    GetCalibration { channel: u8 },
+    #[brw(magic = 0x28u8)]
+    CaptureCalibrationPoint {
+        channel: u8,
+        reference: i32, // Reference meter reading in mV or mA
+    },
+    #[brw(magic = 0x29u8)]
+    ApplyCalibration {
+        channel: u8,
+        persist: u8, // non-zero = also write to flash
+    },
+    #[brw(magic = 0x2Au8)]
+    ResetCalibration {
+        channel: u8,
+        persist: u8, // non-zero = also write to flash
+    },
+    #[brw(magic = 0x2Bu8)]
+    GetHardwareInventory,
+    #[brw(magic = 0x2Cu8)]
+    GetWatchdogReport,
+    #[brw(magic = 0x2Du8)]
+    GetCrashReport,
+    #[brw(magic = 0x2Eu8)]
+    ResetEnergyCounters,
+    #[brw(magic = 0x2Fu8)]
+    GetPowerQuality,
 
     // Responses
     #[brw(magic = 0x80u8)]
     StatusResponse(AllMeasurementsUsbPayload),
+    #[brw(magic = 0x81u8)]
+    CommandAck(CommandAck),
+    #[brw(magic = 0x82u8)]
+    BatteryTestReport(BatteryTestReportPayload),
+    #[brw(magic = 0x83u8)]
+    EventLogPage(EventLogPagePayload),
+    #[brw(magic = 0x84u8)]
+    ConfigResponse(ConfigResponse),
+    #[brw(magic = 0x85u8)]
+    ProtectionResponse(ProtectionResponse),
+    #[brw(magic = 0x86u8)]
+    TransactionResponse(TransactionResponse),
+    #[brw(magic = 0x87u8)]
+    CalibrationResponse(CalibrationResponse),
+    #[brw(magic = 0x88u8)]
+    HardwareInventoryResponse(HardwareInventoryResponse),
+    #[brw(magic = 0x89u8)]
+    WatchdogReportResponse(WatchdogReportResponse),
+    #[brw(magic = 0x8Au8)]
+    CrashReportResponse(CrashReportResponse),
+    #[brw(magic = 0x8Bu8)]
+    CrashSnapshot(CrashSnapshotResponse),
+    #[brw(magic = 0x8Cu8)]
+    PowerQualityResponse(PowerQualityResponse),
 
     // Push Data
     #[brw(magic = 0xC0u8)]
@@ -37,8 +699,90 @@ impl BinRead for UsbData {
         match magic {
             0x00 => Ok(UsbData::SubscribeStatus),
             0x01 => Ok(UsbData::UnsubscribeStatus),
-            // We don't expect to READ StatusResponse or StatusPush from the host
-            0x80 | 0xC0 => {
+            0x10 => Ok(UsbData::ScheduleShutdown {
+                delay_s: <u16 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x11 => Ok(UsbData::ScheduleShutdownRestore {
+                delay_s: <u16 as BinRead>::read_options(reader, endian, ())?,
+                off_time_s: <u16 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x12 => Ok(UsbData::CancelLoadAction),
+            0x13 => Ok(UsbData::ConfigureHeartbeat {
+                interval_s: <u16 as BinRead>::read_options(reader, endian, ())?,
+                grace_s: <u16 as BinRead>::read_options(reader, endian, ())?,
+                off_time_s: <u16 as BinRead>::read_options(reader, endian, ())?,
+                max_retries: <u8 as BinRead>::read_options(reader, endian, ())?,
+                backoff_s: <u16 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x14 => Ok(UsbData::Heartbeat),
+            0x15 => Ok(UsbData::DisableHeartbeat),
+            0x16 => Ok(UsbData::RestoreLoad),
+            0x17 => Ok(UsbData::SetRestorePolicy {
+                mode: <u8 as BinRead>::read_options(reader, endian, ())?,
+                min_soc_pct: <u8 as BinRead>::read_options(reader, endian, ())?,
+                delay_s: <u16 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x18 => Ok(UsbData::StartBatteryTest {
+                duration_s: <u16 as BinRead>::read_options(reader, endian, ())?,
+                max_depth_pct: <u8 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x19 => Ok(UsbData::AbortBatteryTest),
+            0x1A => Ok(UsbData::GetBatteryTestResult),
+            0x1B => Ok(UsbData::GetEventLog {
+                start_index: <u16 as BinRead>::read_options(reader, endian, ())?,
+                count: <u8 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x1C => Ok(UsbData::GetConfig {
+                param: <u8 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x1D => Ok(UsbData::SetConfig {
+                param: <u8 as BinRead>::read_options(reader, endian, ())?,
+                value: <u32 as BinRead>::read_options(reader, endian, ())?,
+                persist: <u8 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x1E => Ok(UsbData::GetProtection),
+            0x1F => Ok(UsbData::SetProtection {
+                thresholds: read_thresholds(reader, endian)?,
+                persist: <u8 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x20 => Ok(UsbData::BeginConfigTransaction),
+            0x21 => Ok(UsbData::StageConfig {
+                param: <u8 as BinRead>::read_options(reader, endian, ())?,
+                value: <u32 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x22 => Ok(UsbData::StageProtection {
+                thresholds: read_thresholds(reader, endian)?,
+            }),
+            0x23 => Ok(UsbData::CommitConfig {
+                confirm_timeout_s: <u16 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x24 => Ok(UsbData::ConfirmConfig {
+                persist: <u8 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x25 => Ok(UsbData::AbortConfigTransaction),
+            0x26 => Ok(UsbData::GetTransactionStatus),
+            0x27 => Ok(UsbData::GetCalibration {
+                channel: <u8 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x28 => Ok(UsbData::CaptureCalibrationPoint {
+                channel: <u8 as BinRead>::read_options(reader, endian, ())?,
+                reference: <i32 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x29 => Ok(UsbData::ApplyCalibration {
+                channel: <u8 as BinRead>::read_options(reader, endian, ())?,
+                persist: <u8 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x2A => Ok(UsbData::ResetCalibration {
+                channel: <u8 as BinRead>::read_options(reader, endian, ())?,
+                persist: <u8 as BinRead>::read_options(reader, endian, ())?,
+            }),
+            0x2B => Ok(UsbData::GetHardwareInventory),
+            0x2C => Ok(UsbData::GetWatchdogReport),
+            0x2D => Ok(UsbData::GetCrashReport),
+            0x2E => Ok(UsbData::ResetEnergyCounters),
+            0x2F => Ok(UsbData::GetPowerQuality),
+            // We don't expect to READ responses or StatusPush from the host
+            0x80..=0x8C | 0xC0 => {
                 defmt::error!(
                     "[UsbData] Received unexpected magic byte for StatusResponse/StatusPush: {:#02x}",
                     magic
@@ -57,13 +801,393 @@ impl BinRead for UsbData {
     }
 }
 
+fn read_thresholds<R: Read + Seek>(
+    reader: &mut R,
+    endian: Endian,
+) -> BinResult<ProtectionThresholds> {
+    Ok(ProtectionThresholds {
+        overvoltage_mv: <u16 as BinRead>::read_options(reader, endian, ())?,
+        undervoltage_mv: <u16 as BinRead>::read_options(reader, endian, ())?,
+        ocd_limit_ma: <u16 as BinRead>::read_options(reader, endian, ())?,
+        ocd_delay_ms: <u16 as BinRead>::read_options(reader, endian, ())?,
+        scd_limit_ma: <u16 as BinRead>::read_options(reader, endian, ())?,
+        scd_delay_us: <u16 as BinRead>::read_options(reader, endian, ())?,
+        ov_delay_s: <u8 as BinRead>::read_options(reader, endian, ())?,
+        uv_delay_s: <u8 as BinRead>::read_options(reader, endian, ())?,
+    })
+}
+
+/// Records a configuration change made over USB in the power event log.
+fn log_config_change(ctx: &CommandContext<'_>, command_code: u8) {
+    log_config_change_detail(ctx, command_code as u16);
+}
+
+fn log_config_change_detail(ctx: &CommandContext<'_>, detail: u16) {
+    let event = PowerEvent::new(PowerEventCode::ConfigChange, detail);
+    if ctx.power_event_sender.try_send(event).is_err() {
+        defmt::warn!("process_command: Power event queue full, config change not logged");
+    }
+}
+
+/// Makes `policy` the runtime restore policy, publishes it to `bq76920_task` and writes it
+/// to flash, so that it is still in effect after a reset.
+async fn set_restore_policy(ctx: &mut CommandContext<'_>, policy: RestorePolicy) -> CommandStatus {
+    if ctx.transaction.state() != TransactionState::Idle {
+        return CommandStatus::Busy;
+    }
+    defmt::info!("process_command: Restore policy set to {:?}", policy);
+    ctx.config.restore_policy = policy;
+    ctx.config_publisher.publish_immediate(ctx.config);
+    log_config_change(ctx, 0x17);
+    let persisted = match ctx.config_store {
+        Some(store) => save_record(&mut *store.lock().await, &ctx.config.restore_policy).await,
+        None => false,
+    };
+    if persisted {
+        CommandStatus::Ok
+    } else {
+        CommandStatus::PersistFailed
+    }
+}
+
+/// Clears the energy counters and writes the cleared state to flash straight away, so that
+/// the old totals are not restored after a reset.
+async fn reset_energy_counters(ctx: &CommandContext<'_>) -> CommandStatus {
+    let checkpoint = ctx.energy_meter.reset();
+    log_config_change(ctx, 0x2E);
+    let persisted = match ctx.config_store {
+        Some(store) => save_record(&mut *store.lock().await, &checkpoint).await,
+        None => false,
+    };
+    if persisted {
+        CommandStatus::Ok
+    } else {
+        defmt::warn!("process_command: Failed to persist the energy counter reset");
+        CommandStatus::PersistFailed
+    }
+}
+
+/// Reads up to `count` event log entries starting `start_index` entries back from the newest.
+async fn read_event_log_page(
+    event_log: &SharedEventLog,
+    start_index: u16,
+    count: u8,
+) -> EventLogPagePayload {
+    let mut log = event_log.lock().await;
+    let mut entries = [EventLogEntry::default(); EVENT_LOG_PAGE_ENTRIES];
+    let mut read = 0u8;
+    for slot in entries
+        .iter_mut()
+        .take((count as usize).min(EVENT_LOG_PAGE_ENTRIES))
+    {
+        match log.read_newest(start_index as u32 + read as u32).await {
+            Some(entry) => *slot = entry,
+            None => break,
+        }
+        read += 1;
+    }
+    EventLogPagePayload {
+        total: log.len().min(u16::MAX as u32) as u16,
+        start_index,
+        count: read,
+        entries,
+    }
+}
+
+fn config_response(
+    param: ConfigParam,
+    status: ConfigStatus,
+    config: &SystemConfig,
+) -> ConfigResponse {
+    let (min, max) = param.bounds();
+    ConfigResponse {
+        param: param as u8,
+        status,
+        value: config.get(param),
+        min,
+        max,
+    }
+}
+
+fn unknown_config_param(param: u8) -> ConfigResponse {
+    defmt::warn!("process_command: Unknown config parameter {:#02x}", param);
+    ConfigResponse {
+        param,
+        status: ConfigStatus::UnknownParameter,
+        value: 0,
+        min: 0,
+        max: 0,
+    }
+}
+
+/// Validates and applies a `SetConfig` request, publishing the new configuration to the
+/// device tasks and optionally writing the parameter's group to flash.
+async fn apply_config(
+    ctx: &mut CommandContext<'_>,
+    param: ConfigParam,
+    value: u32,
+    persist: bool,
+) -> ConfigResponse {
+    if ctx.transaction.state() != TransactionState::Idle {
+        return config_response(param, ConfigStatus::Busy, &ctx.config);
+    }
+    if let Err(e) = ctx.config.set(param, value) {
+        defmt::warn!("process_command: Rejected {:?} = {}: {:?}", param, value, e);
+        return config_response(param, e.into(), &ctx.config);
+    }
+    defmt::info!("process_command: Config {:?} set to {}", param, value);
+    ctx.config_publisher.publish_immediate(ctx.config);
+    log_config_change_detail(ctx, ((param as u16) << 8) | 0x1D);
+
+    let mut status = ConfigStatus::Ok;
+    if persist {
+        let persisted = match ctx.config_store {
+            Some(store) => ctx.config.persist(&mut *store.lock().await, param).await,
+            None => false,
+        };
+        if !persisted {
+            status = ConfigStatus::PersistFailed;
+        }
+    }
+    config_response(param, status, &ctx.config)
+}
+
+fn protection_response(status: ConfigStatus, protection: &ProtectionStatus) -> ProtectionResponse {
+    ProtectionResponse {
+        status,
+        last_result: protection.last_result as u8,
+        active: protection.active.into(),
+    }
+}
+
+/// Validates a `SetProtection` request and hands the thresholds to `bq76920_task`, which
+/// verifies them with `try_apply_config`. The outcome is picked up by `update_protection`.
+async fn request_protection(
+    ctx: &mut CommandContext<'_>,
+    requested: CellProtectionConfig,
+    persist: bool,
+) -> ConfigStatus {
+    if ctx.pending_protection.is_some() || ctx.transaction.state() != TransactionState::Idle {
+        return ConfigStatus::Busy;
+    }
+    let mut config = ctx.config;
+    if let Err(e) = config.set_cell_protection(requested) {
+        defmt::warn!(
+            "process_command: Rejected protection {:?}: {:?}",
+            requested,
+            e
+        );
+        return e.into();
+    }
+
+    if requested == ctx.protection_status.active {
+        // Already in effect, bq76920_task has nothing to apply
+        ctx.config = config;
+        if persist && !ctx.persist_protection().await {
+            return ConfigStatus::PersistFailed;
+        }
+        return ConfigStatus::Ok;
+    }
+
+    ctx.protection_persist_failed = false;
+    ctx.deploy_config(config, Some(0x1F));
+    if let Some(pending) = ctx.pending_protection.as_mut() {
+        pending.persist = persist;
+    }
+    ConfigStatus::Ok
+}
+
+fn transaction_response(
+    command: u8,
+    status: ConfigStatus,
+    transaction: &ConfigTransaction,
+) -> TransactionResponse {
+    TransactionResponse {
+        command,
+        status,
+        state: transaction.state() as u8,
+        last_outcome: transaction.last_outcome() as u8,
+        remaining_s: transaction.remaining_s(Instant::now()),
+    }
+}
+
+/// Commits the staged configuration transaction, deploying all staged settings at once.
+fn commit_transaction(ctx: &mut CommandContext<'_>, confirm_timeout_s: u16) -> ConfigStatus {
+    let confirm_timeout_s = match confirm_timeout_s {
+        0 => DEFAULT_CONFIRM_TIMEOUT_S,
+        t if (MIN_CONFIRM_TIMEOUT_S..=MAX_CONFIRM_TIMEOUT_S).contains(&t) => t,
+        _ => return ConfigStatus::OutOfRange,
+    };
+    if ctx.pending_protection.is_some() {
+        return ConfigStatus::Busy;
+    }
+    match ctx
+        .transaction
+        .commit(ctx.config, confirm_timeout_s, Instant::now())
+    {
+        Ok(staged) => {
+            defmt::info!(
+                "Config transaction committed, confirm within {}s: {:?}",
+                confirm_timeout_s,
+                staged
+            );
+            ctx.deploy_config(staged, None);
+            log_config_change(ctx, 0x23);
+            ConfigStatus::Ok
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Confirms the applied configuration transaction, optionally writing the result to flash.
+async fn confirm_transaction(ctx: &mut CommandContext<'_>, persist: bool) -> ConfigStatus {
+    if let Err(e) = ctx.transaction.confirm() {
+        return e.into();
+    }
+    defmt::info!("Config transaction confirmed");
+    log_config_change(ctx, 0x24);
+    if persist {
+        let persisted = match ctx.config_store {
+            Some(store) => ctx.config.save(&mut *store.lock().await).await,
+            None => false,
+        };
+        if !persisted {
+            return ConfigStatus::PersistFailed;
+        }
+    }
+    ConfigStatus::Ok
+}
+
+/// Aborts the configuration transaction, rolling back settings that were already deployed.
+fn abort_transaction(ctx: &mut CommandContext<'_>) -> ConfigStatus {
+    // A rollback has to wait until the deployed protection thresholds have been verified
+    if ctx.transaction.is_committed() && ctx.pending_protection.is_some() {
+        return ConfigStatus::Busy;
+    }
+    if let Some(previous) = ctx.transaction.abort() {
+        defmt::info!("Config transaction aborted, rolling back");
+        ctx.deploy_config(previous, None);
+        log_config_change(ctx, 0x25);
+    }
+    ConfigStatus::Ok
+}
+
+/// Reading of a calibration channel with the calibration currently in effect.
+fn calibration_reading(current: &AllMeasurements<5>, channel: CalibrationChannel) -> i32 {
+    use CalibrationChannel::*;
+    let core = &current.bq76920.core_measurements;
+    let adc = &current.bq25730.adc_measurements;
+    match channel {
+        Ina226Voltage => current.ina226.voltage as i32,
+        Ina226Current => current.ina226.current as i32,
+        Bq76920Current => core.current_ma,
+        Bq76920Cell1 => core.cell_voltages.voltages[0],
+        Bq76920Cell2 => core.cell_voltages.voltages[1],
+        Bq76920Cell3 => core.cell_voltages.voltages[2],
+        Bq76920Cell4 => core.cell_voltages.voltages[3],
+        Bq76920Cell5 => core.cell_voltages.voltages[4],
+        Bq25730Ichg => adc.ichg.milliamps as i32,
+        Bq25730Idchg => adc.idchg.milliamps as i32,
+        Bq25730Iin => adc.iin.milliamps as i32,
+    }
+}
+
+fn calibration_response(
+    channel: u8,
+    status: ConfigStatus,
+    ctx: &CommandContext<'_>,
+    current: &AllMeasurements<5>,
+) -> CalibrationResponse {
+    let Some(ch) = CalibrationChannel::from_u8(channel) else {
+        defmt::warn!(
+            "process_command: Unknown calibration channel {:#02x}",
+            channel
+        );
+        return CalibrationResponse {
+            channel,
+            status: ConfigStatus::UnknownParameter,
+            points: 0,
+            measured: 0,
+            gain_trim: 0,
+            offset: 0,
+        };
+    };
+    let calibration = ctx.config.calibration.channel(ch);
+    CalibrationResponse {
+        channel,
+        status,
+        points: ctx.calibration_session.point_count(ch),
+        measured: calibration_reading(current, ch),
+        gain_trim: calibration.gain_trim,
+        offset: calibration.offset,
+    }
+}
+
+/// Computes the correction for `channel` from the captured points and publishes it to the
+/// device tasks, optionally writing the channel's group to flash.
+async fn apply_calibration(
+    ctx: &mut CommandContext<'_>,
+    channel: CalibrationChannel,
+    reset: bool,
+    persist: bool,
+) -> ConfigStatus {
+    if ctx.transaction.state() != TransactionState::Idle {
+        return ConfigStatus::Busy;
+    }
+    let calibration = if reset {
+        Default::default()
+    } else {
+        let current = ctx.config.calibration.channel(channel);
+        match ctx.calibration_session.solve(channel, current) {
+            Ok(calibration) => calibration,
+            Err(e) => {
+                defmt::warn!(
+                    "process_command: Calibration of {:?} failed: {:?}",
+                    channel,
+                    e
+                );
+                return e.into();
+            }
+        }
+    };
+    defmt::info!(
+        "process_command: Calibration of {:?} set to {:?}",
+        channel,
+        calibration
+    );
+    // Points captured under the old correction no longer match the published readings
+    ctx.calibration_session.clear();
+    *ctx.config.calibration.channel_mut(channel) = calibration;
+    ctx.config_publisher.publish_immediate(ctx.config);
+    let command_code = if reset { 0x2A } else { 0x29 };
+    log_config_change_detail(ctx, ((channel as u16) << 8) | command_code);
+
+    if persist {
+        let persisted = match ctx.config_store {
+            Some(store) => {
+                ctx.config
+                    .calibration
+                    .persist(&mut *store.lock().await, channel)
+                    .await
+            }
+            None => false,
+        };
+        if !persisted {
+            return ConfigStatus::PersistFailed;
+        }
+    }
+    ConfigStatus::Ok
+}
+
 pub struct UsbEndpoints<'d, D: Driver<'d>> {
     pub command_read_ep: D::EndpointOut,
     pub response_write_ep: D::EndpointIn,
     pub push_write_ep: D::EndpointIn,
     read_buffer: [u8; 128],
-    write_buffer: [u8; 128],
+    write_buffer: [u8; 256],
     pub status_subscription_active: bool,
+    /// Whether the host has armed the heartbeat watchdog over this connection
+    pub heartbeat_registered: bool,
 }
 
 impl<'d, D: Driver<'d>> UsbEndpoints<'d, D> {
@@ -81,8 +1205,9 @@ impl<'d, D: Driver<'d>> UsbEndpoints<'d, D> {
             response_write_ep,
             push_write_ep,
             read_buffer: [0; 128],
-            write_buffer: [0; 128],
+            write_buffer: [0; 256],
             status_subscription_active: false,
+            heartbeat_registered: false,
         }
     }
 
@@ -137,19 +1262,99 @@ impl<'d, D: Driver<'d>> UsbEndpoints<'d, D> {
         let max_packet = 64; // Assuming max packet size for interrupt endpoint
         while cur < len {
             let size = core::cmp::min(len - cur, max_packet);
-            self.response_write_ep
-                .write(&self.write_buffer[cur..(cur + size)])
-                .await?;
+            with_timeout(
+                PACKET_WRITE_TIMEOUT,
+                self.response_write_ep
+                    .write(&self.write_buffer[cur..(cur + size)]),
+            )
+            .await
+            .map_err(|_| EndpointError::Disabled)??;
             cur += size;
         }
         Ok(())
     }
 
+    async fn send_transaction_response(
+        &mut self,
+        command: u8,
+        status: ConfigStatus,
+        ctx: &CommandContext<'_>,
+    ) -> Result<(), EndpointError> {
+        let response = transaction_response(command, status, &ctx.transaction);
+        self.send_response(UsbData::TransactionResponse(response))
+            .await
+    }
+
+    /// Forwards a load control command to `bq76920_task` and acknowledges it, returning the
+    /// acknowledged status.
+    async fn forward_load_command(
+        &mut self,
+        command_code: u8,
+        command: LoadCommand,
+        ctx: &CommandContext<'_>,
+    ) -> Result<CommandStatus, EndpointError> {
+        let status = match ctx.load_command_sender.try_send(command) {
+            Ok(()) => {
+                defmt::info!("process_command: Forwarded load command: {:?}", command);
+                if matches!(
+                    command,
+                    LoadCommand::Watchdog(
+                        HostWatchdogCommand::Configure(_) | HostWatchdogCommand::Disable
+                    )
+                ) {
+                    log_config_change(ctx, command_code);
+                }
+                CommandStatus::Ok
+            }
+            Err(_) => {
+                defmt::warn!(
+                    "process_command: Load command queue full, dropping {:?}",
+                    command
+                );
+                CommandStatus::Busy
+            }
+        };
+        self.send_ack(command_code, status).await?;
+        Ok(status)
+    }
+
+    /// Forwards a battery self-test command to `bq25730_task` and acknowledges it.
+    async fn forward_battery_test_command(
+        &mut self,
+        command_code: u8,
+        command: BatteryTestCommand,
+        ctx: &CommandContext<'_>,
+    ) -> Result<(), EndpointError> {
+        let status = match ctx.battery_test_command_sender.try_send(command) {
+            Ok(()) => {
+                defmt::info!(
+                    "process_command: Forwarded battery test command: {:?}",
+                    command
+                );
+                CommandStatus::Ok
+            }
+            Err(_) => {
+                defmt::warn!(
+                    "process_command: Battery test command queue full, dropping {:?}",
+                    command
+                );
+                CommandStatus::Busy
+            }
+        };
+        self.send_ack(command_code, status).await
+    }
+
+    async fn send_ack(&mut self, command: u8, status: CommandStatus) -> Result<(), EndpointError> {
+        self.send_response(UsbData::CommandAck(CommandAck { command, status }))
+            .await
+    }
+
     #[allow(dead_code)]
     pub async fn process_command(
         &mut self,
         command: UsbData,
-        current_payload: &AllMeasurementsUsbPayload, // Changed parameter type and name
+        current: &AllMeasurements<5>,
+        ctx: &mut CommandContext<'_>,
     ) -> Result<(), EndpointError> {
         defmt::info!(
             "process_command: Received command: {:?}, current_subscription_status: {}",
@@ -168,12 +1373,12 @@ impl<'d, D: Driver<'d>> UsbEndpoints<'d, D> {
                     self.status_subscription_active
                 );
                 // Send a response to confirm subscription with current data
-                // current_payload is a reference to a Copy type, so dereference it.
+                let current_payload = current.to_usb_payload();
                 defmt::debug!(
                     "process_command: Preparing StatusResponse with data: {:?}",
-                    *current_payload
+                    current_payload
                 );
-                let response = UsbData::StatusResponse(*current_payload); // Use dereferenced current_payload
+                let response = UsbData::StatusResponse(current_payload);
                 match self.send_response(response).await {
                     Ok(_) => defmt::info!(
                         "process_command: Successfully sent subscription confirmation response."
@@ -194,10 +1399,302 @@ impl<'d, D: Driver<'d>> UsbEndpoints<'d, D> {
                     "process_command: Status subscription DEACTIVATED. New status_subscription_active: {}",
                     self.status_subscription_active
                 );
+                // A host that unsubscribes cleanly is shutting down on purpose, so its
+                // heartbeats are expected to stop: disarm the watchdog instead of power-cycling it.
+                if self.heartbeat_registered {
+                    self.heartbeat_registered = false;
+                    let disable = LoadCommand::Watchdog(HostWatchdogCommand::Disable);
+                    if ctx.load_command_sender.try_send(disable).is_err() {
+                        defmt::warn!(
+                            "process_command: Load command queue full, heartbeat watchdog still armed"
+                        );
+                    }
+                }
                 // Optionally send a response to confirm unsubscription
                 // We could send a simple ACK here if needed, but for now, just logging is sufficient.
                 defmt::debug!("process_command: UnsubscribeStatus processed.");
             }
+            UsbData::ScheduleShutdown { delay_s } => {
+                self.forward_load_command(0x10, LoadCommand::ShutdownAfter { delay_s }, ctx)
+                    .await?;
+            }
+            UsbData::ScheduleShutdownRestore {
+                delay_s,
+                off_time_s,
+            } => {
+                self.forward_load_command(
+                    0x11,
+                    LoadCommand::ShutdownAndRestore {
+                        delay_s,
+                        off_time_s,
+                    },
+                    ctx,
+                )
+                .await?;
+            }
+            UsbData::CancelLoadAction => {
+                self.forward_load_command(0x12, LoadCommand::Cancel, ctx)
+                    .await?;
+            }
+            UsbData::ConfigureHeartbeat {
+                interval_s,
+                grace_s,
+                off_time_s,
+                max_retries,
+                backoff_s,
+            } => {
+                let config = HostWatchdogConfig {
+                    interval_s,
+                    grace_s,
+                    off_time_s,
+                    max_retries,
+                    backoff_s,
+                };
+                if config.is_valid() {
+                    let status = self
+                        .forward_load_command(
+                            0x13,
+                            LoadCommand::Watchdog(HostWatchdogCommand::Configure(config)),
+                            ctx,
+                        )
+                        .await?;
+                    // Only an accepted configuration arms the watchdog that `UnsubscribeStatus`
+                    // disarms
+                    if status == CommandStatus::Ok {
+                        self.heartbeat_registered = true;
+                    }
+                } else {
+                    defmt::warn!("process_command: Invalid heartbeat config: {:?}", config);
+                    self.send_ack(0x13, CommandStatus::InvalidArgument).await?;
+                }
+            }
+            UsbData::Heartbeat => {
+                self.forward_load_command(
+                    0x14,
+                    LoadCommand::Watchdog(HostWatchdogCommand::Heartbeat),
+                    ctx,
+                )
+                .await?;
+            }
+            UsbData::RestoreLoad => {
+                self.forward_load_command(0x16, LoadCommand::RestoreNow, ctx)
+                    .await?;
+            }
+            UsbData::SetRestorePolicy {
+                mode,
+                min_soc_pct,
+                delay_s,
+            } => {
+                let mode = match mode {
+                    0 => Some(RestoreMode::Manual),
+                    1 => Some(RestoreMode::Auto),
+                    _ => None,
+                };
+                match mode {
+                    Some(mode) if min_soc_pct <= 100 => {
+                        let policy = RestorePolicy {
+                            mode,
+                            min_soc_pct,
+                            delay_s,
+                        };
+                        let status = set_restore_policy(ctx, policy).await;
+                        self.send_ack(0x17, status).await?;
+                    }
+                    _ => {
+                        defmt::warn!("process_command: Invalid restore policy: {:?}", command);
+                        self.send_ack(0x17, CommandStatus::InvalidArgument).await?;
+                    }
+                }
+            }
+            UsbData::StartBatteryTest {
+                duration_s,
+                max_depth_pct,
+            } => {
+                if duration_s == 0 || max_depth_pct == 0 || max_depth_pct > 100 {
+                    defmt::warn!(
+                        "process_command: Invalid battery test parameters: {:?}",
+                        command
+                    );
+                    self.send_ack(0x18, CommandStatus::InvalidArgument).await?;
+                } else {
+                    let params = BatteryTestParams {
+                        duration_s,
+                        max_depth_pct,
+                    };
+                    self.forward_battery_test_command(0x18, BatteryTestCommand::Start(params), ctx)
+                        .await?;
+                }
+            }
+            UsbData::AbortBatteryTest => {
+                self.forward_battery_test_command(0x19, BatteryTestCommand::Abort, ctx)
+                    .await?;
+            }
+            UsbData::GetBatteryTestResult => {
+                let result = current.bq25730.battery_test.last_result;
+                self.send_response(UsbData::BatteryTestReport(result.into()))
+                    .await?;
+            }
+            UsbData::GetEventLog { start_index, count } => {
+                let page = read_event_log_page(ctx.event_log, start_index, count).await;
+                self.send_response(UsbData::EventLogPage(page)).await?;
+            }
+            UsbData::GetConfig { param } => {
+                let response = match ConfigParam::from_u8(param) {
+                    Some(p) => config_response(p, ConfigStatus::Ok, &ctx.config),
+                    None => unknown_config_param(param),
+                };
+                self.send_response(UsbData::ConfigResponse(response))
+                    .await?;
+            }
+            UsbData::SetConfig {
+                param,
+                value,
+                persist,
+            } => {
+                let response = match ConfigParam::from_u8(param) {
+                    Some(p) => apply_config(ctx, p, value, persist != 0).await,
+                    None => unknown_config_param(param),
+                };
+                self.send_response(UsbData::ConfigResponse(response))
+                    .await?;
+            }
+            UsbData::GetProtection => {
+                let status = if ctx.pending_protection.is_some() {
+                    ConfigStatus::Busy
+                } else if ctx.protection_persist_failed {
+                    ConfigStatus::PersistFailed
+                } else {
+                    ConfigStatus::Ok
+                };
+                let response = protection_response(status, &current.bq76920.protection);
+                self.send_response(UsbData::ProtectionResponse(response))
+                    .await?;
+            }
+            UsbData::SetProtection {
+                thresholds,
+                persist,
+            } => {
+                let status = request_protection(ctx, thresholds.into(), persist != 0).await;
+                let response = protection_response(status, &current.bq76920.protection);
+                self.send_response(UsbData::ProtectionResponse(response))
+                    .await?;
+            }
+            UsbData::BeginConfigTransaction => {
+                let status = if ctx.pending_protection.is_some() {
+                    ConfigStatus::Busy
+                } else {
+                    match ctx.transaction.begin(ctx.config) {
+                        Ok(()) => ConfigStatus::Ok,
+                        Err(e) => e.into(),
+                    }
+                };
+                self.send_transaction_response(0x20, status, ctx).await?;
+            }
+            UsbData::StageConfig { param, value } => {
+                let response = match ConfigParam::from_u8(param) {
+                    Some(p) => {
+                        let status = match ctx.transaction.stage(p, value) {
+                            Ok(()) => ConfigStatus::Ok,
+                            Err(e) => e.into(),
+                        };
+                        config_response(p, status, ctx.transaction.staged())
+                    }
+                    None => unknown_config_param(param),
+                };
+                self.send_response(UsbData::ConfigResponse(response))
+                    .await?;
+            }
+            UsbData::StageProtection { thresholds } => {
+                let status = match ctx.transaction.stage_protection(thresholds.into()) {
+                    Ok(()) => ConfigStatus::Ok,
+                    Err(e) => e.into(),
+                };
+                self.send_transaction_response(0x22, status, ctx).await?;
+            }
+            UsbData::CommitConfig { confirm_timeout_s } => {
+                let status = commit_transaction(ctx, confirm_timeout_s);
+                self.send_transaction_response(0x23, status, ctx).await?;
+            }
+            UsbData::ConfirmConfig { persist } => {
+                let status = confirm_transaction(ctx, persist != 0).await;
+                self.send_transaction_response(0x24, status, ctx).await?;
+            }
+            UsbData::AbortConfigTransaction => {
+                let status = abort_transaction(ctx);
+                self.send_transaction_response(0x25, status, ctx).await?;
+            }
+            UsbData::GetTransactionStatus => {
+                self.send_transaction_response(0x26, ConfigStatus::Ok, ctx)
+                    .await?;
+            }
+            UsbData::GetCalibration { channel } => {
+                let response = calibration_response(channel, ConfigStatus::Ok, ctx, current);
+                self.send_response(UsbData::CalibrationResponse(response))
+                    .await?;
+            }
+            UsbData::CaptureCalibrationPoint { channel, reference } => {
+                if let Some(ch) = CalibrationChannel::from_u8(channel) {
+                    let measured = calibration_reading(current, ch);
+                    let points = ctx.calibration_session.capture(ch, measured, reference);
+                    defmt::info!(
+                        "process_command: Calibration point {} for {:?}: measured {}, reference {}",
+                        points,
+                        ch,
+                        measured,
+                        reference
+                    );
+                }
+                let response = calibration_response(channel, ConfigStatus::Ok, ctx, current);
+                self.send_response(UsbData::CalibrationResponse(response))
+                    .await?;
+            }
+            UsbData::ApplyCalibration { channel, persist }
+            | UsbData::ResetCalibration { channel, persist } => {
+                let reset = matches!(command, UsbData::ResetCalibration { .. });
+                let status = match CalibrationChannel::from_u8(channel) {
+                    Some(ch) => apply_calibration(ctx, ch, reset, persist != 0).await,
+                    None => ConfigStatus::UnknownParameter,
+                };
+                let response = calibration_response(channel, status, ctx, current);
+                self.send_response(UsbData::CalibrationResponse(response))
+                    .await?;
+            }
+            UsbData::GetHardwareInventory => {
+                let response = UsbData::HardwareInventoryResponse(ctx.inventory.into());
+                self.send_response(response).await?;
+            }
+            UsbData::GetWatchdogReport => {
+                let response = UsbData::WatchdogReportResponse(ctx.watchdog_report.into());
+                self.send_response(response).await?;
+            }
+            UsbData::GetCrashReport => {
+                // The snapshot does not fit into the same response
+                let response = UsbData::CrashReportResponse(ctx.last_crash.into());
+                self.send_response(response).await?;
+                let snapshot = UsbData::CrashSnapshot(ctx.last_crash.into());
+                self.send_response(snapshot).await?;
+            }
+            UsbData::ResetEnergyCounters => {
+                let status = reset_energy_counters(ctx).await;
+                self.send_ack(0x2E, status).await?;
+            }
+            UsbData::GetPowerQuality => {
+                let response = PowerQualityResponse::new(
+                    &current.bq25730.power_quality,
+                    &ctx.config.power_quality,
+                );
+                self.send_response(UsbData::PowerQualityResponse(response))
+                    .await?;
+            }
+            UsbData::DisableHeartbeat => {
+                self.heartbeat_registered = false;
+                self.forward_load_command(
+                    0x15,
+                    LoadCommand::Watchdog(HostWatchdogCommand::Disable),
+                    ctx,
+                )
+                .await?;
+            }
             _ => {
                 defmt::warn!(
                     "process_command: Received unhandled command type: {:?}",
@@ -236,9 +1733,13 @@ impl<'d, D: Driver<'d>> UsbEndpoints<'d, D> {
         let max_packet = 64; // Assuming max packet size for interrupt endpoint
         while cur < len {
             let size = core::cmp::min(len - cur, max_packet);
-            self.push_write_ep
-                .write(&self.write_buffer[cur..(cur + size)])
-                .await?;
+            with_timeout(
+                PACKET_WRITE_TIMEOUT,
+                self.push_write_ep
+                    .write(&self.write_buffer[cur..(cur + size)]),
+            )
+            .await
+            .map_err(|_| EndpointError::Disabled)??;
             cur += size;
         }
         Ok(())
@@ -0,0 +1,45 @@
+//! USB HID Power Device Class (PDC) interface.
+//!
+//! Serves the reports of `hid_report`, so that the battery/UPS services built into the OS
+//! (the Windows battery driver, macOS, NUT `usbhid-ups`, upower) recognise the device without
+//! extra software.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_usb::class::hid::{ReportId, RequestHandler};
+use embassy_usb::control::OutResponse;
+
+pub use crate::hid_report::{INPUT_REPORT_IDS, MAX_REPORT_SIZE, REPORT_DESCRIPTOR, encode_report};
+use crate::ups_state::UpsStatus;
+
+/// Latest UPS status, served to GET_REPORT requests on the control endpoint
+static LATEST_STATUS: Mutex<CriticalSectionRawMutex, Cell<Option<UpsStatus>>> =
+    Mutex::new(Cell::new(None));
+
+/// Updates the snapshot served by GET_REPORT.
+pub fn update_status(status: UpsStatus) {
+    LATEST_STATUS.lock(|cell| cell.set(Some(status)));
+}
+
+/// Handles HID class requests from the host on the control endpoint
+pub struct PowerDeviceRequestHandler {}
+
+impl RequestHandler for PowerDeviceRequestHandler {
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        let report_id = match id {
+            ReportId::In(id) | ReportId::Feature(id) => id,
+            ReportId::Out(_) => return None,
+        };
+        let status = LATEST_STATUS.lock(|cell| cell.get());
+        let len = encode_report(report_id, status.as_ref(), buf);
+        defmt::trace!("hid_power: GET_REPORT id={} len={:?}", report_id, len);
+        len
+    }
+
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        // All reports are read-only
+        defmt::warn!("hid_power: Rejected SET_REPORT {:?}: {:x}", id, data);
+        OutResponse::Rejected
+    }
+}
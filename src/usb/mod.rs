@@ -1,25 +1,55 @@
 use embassy_futures::select::{Either, select};
 use embassy_stm32::uid;
 use embassy_stm32::{peripherals, usb};
+use embassy_time::{Duration, Instant, with_timeout};
 use embassy_usb::{
-    Builder,
+    Builder, Handler,
+    class::hid::{self, HidWriter},
     class::web_usb::{self, Url, WebUsb},
     driver::EndpointError,
 };
 use static_cell::StaticCell;
 
+use crate::calibration::CalibrationSession;
+use crate::config::SystemConfig;
+use crate::config_transaction::ConfigTransaction;
+use crate::crash_report::{self, CrashReport};
 use crate::data_types::{
     AllMeasurements, Bq25730Alerts, Bq25730Measurements, Bq76920Alerts, Bq76920Measurements,
     Ina226Measurements,
 };
+use crate::energy_meter::EnergyMeter;
+use crate::event_log::{PowerEvent, PowerEventCode, SharedEventLog};
+use crate::flash_layout::SharedConfigStore;
+use crate::hardware_probe::HardwareInventory;
+use crate::i2c_supervisor::I2cSupervisor;
+use crate::protection::ProtectionStatus;
+use crate::sampling::SamplingControl;
+use crate::sensor_consistency::ConsistencyMonitor;
 use crate::shared::{
-    Bq25730AlertsSubscriber, Bq25730MeasurementsSubscriber, Bq76920AlertsSubscriber,
-    Bq76920MeasurementsSubscriber, Ina226MeasurementsSubscriber, MeasurementsPublisher,
+    BatteryTestCommandSender, Bq25730AlertsSubscriber, Bq25730MeasurementsSubscriber,
+    Bq76920AlertsSubscriber, Bq76920MeasurementsSubscriber, Ina226MeasurementsSubscriber,
+    LoadCommandSender, MeasurementsPublisher, PowerEventSender, SystemConfigPublisher,
 };
+use crate::task_watchdog::{CHECK_IN_INTERVAL, SupervisedTask, TaskWatchdog, WatchdogReport};
+use crate::ups_state;
 
 pub mod endpoints;
+pub mod hid_power;
 
-use crate::usb::endpoints::UsbEndpoints;
+use crate::usb::endpoints::{CommandContext, UsbEndpoints};
+use crate::usb::hid_power::PowerDeviceRequestHandler;
+
+/// Polling interval of the HID interrupt endpoint (ms)
+const HID_POLL_MS: u8 = 10;
+
+/// Timeout for writing an HID input report, so a host that stops polling the interrupt endpoint
+/// cannot block the main loop
+const HID_WRITE_TIMEOUT: Duration = Duration::from_millis(2 * HID_POLL_MS as u64);
+
+/// How often the confirmation deadline of a config transaction is checked while the host is
+/// disconnected
+const TRANSACTION_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 // Define statics for USB builder buffers
 static CONFIG_DESCRIPTOR_CELL: StaticCell<[u8; 256]> = StaticCell::new();
@@ -31,6 +61,13 @@ static CONTROL_BUF_CELL: StaticCell<[u8; 64]> = StaticCell::new();
 static WEB_USB_STATE_CELL: StaticCell<web_usb::State> = StaticCell::new();
 static WEBUSB_CONFIG_CELL: StaticCell<web_usb::Config> = StaticCell::new();
 
+// HID Power Device state and request handler
+static HID_STATE_CELL: StaticCell<hid::State> = StaticCell::new();
+static HID_REQUEST_HANDLER_CELL: StaticCell<PowerDeviceRequestHandler> = StaticCell::new();
+
+// Bus suspend and resume notifications for low-power operation
+static USB_STATE_HANDLER_CELL: StaticCell<UsbStateHandler> = StaticCell::new();
+
 #[embassy_executor::task]
 pub async fn usb_task(
     driver: usb::Driver<'static, peripherals::USB>,
@@ -40,6 +77,20 @@ pub async fn usb_task(
     mut bq76920_measurements_subscriber: Bq76920MeasurementsSubscriber<'static, 5>, // BQ76920 subscriber - Added generic parameter
     mut bq25730_alerts_subscriber: Bq25730AlertsSubscriber<'static>, // BQ25730 alerts subscriber
     mut bq76920_alerts_subscriber: Bq76920AlertsSubscriber<'static>, // BQ76920 alerts subscriber
+    load_command_sender: LoadCommandSender<'static>, // Load control commands to bq76920_task
+    battery_test_command_sender: BatteryTestCommandSender<'static>, // Self-test commands to bq25730_task
+    power_event_sender: PowerEventSender<'static>, // Config changes to event_log_task
+    event_log: &'static SharedEventLog,            // Power event log, paged out on request
+    config: SystemConfig,                          // Runtime configuration loaded at boot
+    config_publisher: SystemConfigPublisher<'static>, // Live configuration updates to device tasks
+    config_store: Option<&'static SharedConfigStore>, // Persists SetConfig changes
+    energy_meter: &'static EnergyMeter,            // Energy counters for telemetry
+    sampling: &'static SamplingControl, // Sampling mode for telemetry; no idle sampling while subscribed
+    i2c_supervisor: &'static I2cSupervisor, // I2C device health for telemetry
+    inventory: HardwareInventory,       // Chips found by the boot probe
+    task_watchdog: &'static TaskWatchdog, // Checked in every loop iteration
+    watchdog_report: WatchdogReport,    // Why the previous run ended
+    last_crash: Option<CrashReport>,    // Panic in the previous run, if any
 ) {
     let vid: u16 =
         u16::from_str_radix(env!("USB_VID").trim_start_matches("0x"), 16).expect("Invalid USB_VID");
@@ -79,6 +130,43 @@ pub async fn usb_task(
 
     let mut usb_endpoints = UsbEndpoints::new(&mut builder);
 
+    // HID Power Device interface, created after the vendor interface so that
+    // the vendor interface keeps interface number 0 for existing host software.
+    let hid_state = HID_STATE_CELL.init(hid::State::new());
+    let hid_request_handler = HID_REQUEST_HANDLER_CELL.init(PowerDeviceRequestHandler {});
+    let hid_config = hid::Config {
+        report_descriptor: hid_power::REPORT_DESCRIPTOR,
+        request_handler: Some(hid_request_handler),
+        poll_ms: HID_POLL_MS,
+        max_packet_size: hid_power::MAX_REPORT_SIZE as u16,
+    };
+    let mut hid_writer =
+        HidWriter::<_, { hid_power::MAX_REPORT_SIZE }>::new(&mut builder, hid_state, hid_config);
+
+    builder.handler(USB_STATE_HANDLER_CELL.init(UsbStateHandler { sampling }));
+
+    let mut command_context = CommandContext {
+        load_command_sender,
+        battery_test_command_sender,
+        power_event_sender,
+        event_log,
+        config,
+        config_publisher,
+        config_store,
+        protection_status: ProtectionStatus {
+            active: config.cell_protection,
+            ..Default::default()
+        },
+        pending_protection: None,
+        protection_persist_failed: false,
+        transaction: ConfigTransaction::default(),
+        calibration_session: CalibrationSession::default(),
+        inventory,
+        watchdog_report,
+        last_crash,
+        energy_meter,
+    };
+
     let main_usb_processing_fut = async {
         // Variables to hold the latest measurements from each task
         let mut latest_bq25730_measurements: Option<Bq25730Measurements> = None;
@@ -86,32 +174,73 @@ pub async fn usb_task(
         let mut latest_bq76920_measurements: Option<Bq76920Measurements<5>> = None;
         let mut latest_bq25730_alerts: Option<Bq25730Alerts> = None;
         let mut latest_bq76920_alerts: Option<Bq76920Alerts> = None;
+        // Last input report delivered for each of `INPUT_REPORT_IDS`. The zeroed initial value
+        // never matches an encoded report, whose first byte is its non-zero report ID.
+        let mut last_hid_reports =
+            [[0u8; hid_power::MAX_REPORT_SIZE]; hid_power::INPUT_REPORT_IDS.len()];
+        let mut last_status_push: Option<Instant> = None;
+        let mut consistency_monitor = ConsistencyMonitor::default();
         #[allow(unused_assignments)]
         let mut usb_command_to_process: Option<endpoints::UsbData> = None; // Variable to store command from select
 
         loop {
-            usb_endpoints.wait_connected().await;
+            task_watchdog.check_in(SupervisedTask::Usb);
+
+            if command_context.transaction.is_committed() {
+                // A committed configuration transaction has to roll back on time even while the
+                // host is disconnected, so keep following the device tasks until it reconnects.
+                if with_timeout(TRANSACTION_POLL_INTERVAL, usb_endpoints.wait_connected())
+                    .await
+                    .is_err()
+                {
+                    while let Some(msg) = bq76920_measurements_subscriber.try_next_message_pure() {
+                        command_context.update_protection(&msg.protection).await;
+                        latest_bq76920_measurements = Some(msg);
+                    }
+                    while let Some(msg) = bq25730_measurements_subscriber.try_next_message_pure() {
+                        latest_bq25730_measurements = Some(msg);
+                    }
+                    command_context.update_transaction(
+                        latest_bq25730_measurements.and_then(|m| m.applied_charger),
+                    );
+                    sampling.set_host_subscribed(false);
+                    continue;
+                }
+            } else if with_timeout(CHECK_IN_INTERVAL, usb_endpoints.wait_connected())
+                .await
+                .is_err()
+            {
+                sampling.set_host_subscribed(false);
+                continue;
+            }
             usb_command_to_process = None; // Clear previous command at the start of each loop iteration
 
-            // Use select to prioritize handling USB commands and new data
-            match select(
-                bq25730_measurements_subscriber.next_message(),
+            // Use select to prioritize handling USB commands and new data. Without a device task
+            // publishing (missing chips) nothing may arrive, so wake up to check in regardless.
+            let Ok(event) = with_timeout(
+                CHECK_IN_INTERVAL,
                 select(
-                    ina226_measurements_subscriber.next_message(),
+                    bq25730_measurements_subscriber.next_message(),
                     select(
-                        bq76920_measurements_subscriber.next_message(),
+                        ina226_measurements_subscriber.next_message(),
                         select(
-                            bq25730_alerts_subscriber.next_message(),
+                            bq76920_measurements_subscriber.next_message(),
                             select(
-                                bq76920_alerts_subscriber.next_message(),
-                                usb_endpoints.parse_command(),
+                                bq25730_alerts_subscriber.next_message(),
+                                select(
+                                    bq76920_alerts_subscriber.next_message(),
+                                    usb_endpoints.parse_command(),
+                                ),
                             ),
                         ),
                     ),
                 ),
             )
             .await
-            {
+            else {
+                continue;
+            };
+            match event {
                 Either::First(bq25730_meas_res) => {
                     // BQ25730 Measurements
                     match bq25730_meas_res {
@@ -142,6 +271,9 @@ pub async fn usb_task(
                                     // BQ76920 Measurements
                                     match bq76920_meas_res {
                                         embassy_sync::pubsub::WaitResult::Message(msg) => {
+                                            command_context
+                                                .update_protection(&msg.protection)
+                                                .await;
                                             latest_bq76920_measurements = Some(msg)
                                         }
                                         embassy_sync::pubsub::WaitResult::Lagged(c) => {
@@ -205,6 +337,27 @@ pub async fn usb_task(
                 }
             }
 
+            // Only current readings take part in the comparison
+            let now = Instant::now();
+            let new_sensor_faults = consistency_monitor.update(
+                latest_bq76920_measurements
+                    .as_ref()
+                    .filter(|m| m.sample.is_fresh(now)),
+                latest_bq25730_measurements
+                    .as_ref()
+                    .filter(|m| m.sample.is_fresh(now)),
+                latest_ina226_measurements
+                    .as_ref()
+                    .filter(|m| m.sample.is_fresh(now)),
+                now,
+            );
+            if new_sensor_faults != 0 {
+                let event = PowerEvent::new(PowerEventCode::SensorFault, new_sensor_faults as u16);
+                if command_context.power_event_sender.try_send(event).is_err() {
+                    defmt::warn!("usb_task: Power event queue full, sensor fault not logged");
+                }
+            }
+
             // Unified aggregation of all latest data (measurements and alerts)
             let aggregated_data = AllMeasurements {
                 bq25730: latest_bq25730_measurements.unwrap_or_default(),
@@ -212,12 +365,20 @@ pub async fn usb_task(
                 bq76920: latest_bq76920_measurements.unwrap_or_default(),
                 bq25730_alerts: latest_bq25730_alerts.unwrap_or_default(),
                 bq76920_alerts: latest_bq76920_alerts.unwrap_or_default(),
+                consistency: consistency_monitor.status(),
+                i2c: i2c_supervisor.health(),
+                energy: energy_meter.status(),
+                sampling: sampling.status(),
             };
+            // Kept in retained RAM so that a panic report shows the last known state
+            crash_report::update_snapshot(&aggregated_data, &command_context.config.low_battery);
             // Process USB command if one was stored from select!
             if let Some(cmd) = usb_command_to_process.take() {
                 defmt::info!("usb_task: Processing stored USB command: {:?}", cmd);
-                let command_payload = aggregated_data.to_usb_payload();
-                if let Err(e) = usb_endpoints.process_command(cmd, &command_payload).await {
+                if let Err(e) = usb_endpoints
+                    .process_command(cmd, &aggregated_data, &mut command_context)
+                    .await
+                {
                     defmt::error!("usb_task: Error processing USB command: {:?}", e);
                 }
                 defmt::debug!(
@@ -225,12 +386,46 @@ pub async fn usb_task(
                     usb_endpoints.status_subscription_active
                 );
             }
+            command_context.update_transaction(aggregated_data.bq25730.applied_charger);
+            sampling.set_host_subscribed(usb_endpoints.status_subscription_active);
 
             defmt::trace!(
                 "usb_task: Aggregated data for publishing/sending: {:?}",
                 aggregated_data
             );
 
+            // Update the HID Power Device reports. GET_REPORT requests are served from the
+            // snapshot; an input report is only sent when its content differs from the one last
+            // delivered, so a report that could not be written is retried on the next cycle.
+            let ups_status =
+                ups_state::from_measurements(&aggregated_data, &command_context.config.low_battery);
+            hid_power::update_status(ups_status);
+            for (report_id, last_sent) in hid_power::INPUT_REPORT_IDS
+                .into_iter()
+                .zip(last_hid_reports.iter_mut())
+            {
+                let mut report_buf = [0u8; hid_power::MAX_REPORT_SIZE];
+                let Some(len) =
+                    hid_power::encode_report(report_id, Some(&ups_status), &mut report_buf)
+                else {
+                    continue;
+                };
+                if report_buf == *last_sent {
+                    continue;
+                }
+                match with_timeout(HID_WRITE_TIMEOUT, hid_writer.write(&report_buf[..len])).await {
+                    Ok(Ok(())) => *last_sent = report_buf,
+                    Ok(Err(e)) => {
+                        defmt::warn!("usb_task: HID report {} write failed: {:?}", report_id, e)
+                    }
+                    Err(_) => {
+                        // The remaining reports would time out as well
+                        defmt::trace!("usb_task: HID report {} not polled by host", report_id);
+                        break;
+                    }
+                }
+            }
+
             // Publish the aggregated data
             measurements_publisher.publish_immediate(aggregated_data);
             defmt::debug!("usb_task: Published aggregated data.");
@@ -240,7 +435,12 @@ pub async fn usb_task(
                 "usb_task: Checking if status subscription is active for sending update. status_subscription_active: {}",
                 usb_endpoints.status_subscription_active
             );
-            if usb_endpoints.status_subscription_active {
+            let push_interval =
+                Duration::from_millis(command_context.config.usb.push_interval_ms as u64);
+            let push_due = last_status_push.is_none_or(|t| t.elapsed() >= push_interval);
+            if usb_endpoints.status_subscription_active && !push_due {
+                defmt::trace!("usb_task: Status push rate limited by push interval.");
+            } else if usb_endpoints.status_subscription_active {
                 defmt::info!(
                     "usb_task: Subscription active, attempting to send status update via USB."
                 );
@@ -254,6 +454,7 @@ pub async fn usb_task(
                 } else {
                     defmt::debug!("usb_task: Successfully sent status update via USB.");
                 }
+                last_status_push = Some(Instant::now());
             } else {
                 defmt::debug!(
                     "usb_task: Subscription not active, not sending status update via USB."
@@ -273,6 +474,23 @@ pub async fn usb_task(
     embassy_futures::join::join(usb_fut, main_usb_processing_fut).await;
 }
 
+/// Reports bus suspend and resume to the sampling control. STOP mode is only allowed while the
+/// bus is suspended; a resume is handled once the MCU next wakes from STOP.
+struct UsbStateHandler {
+    sampling: &'static SamplingControl,
+}
+
+impl Handler for UsbStateHandler {
+    fn enabled(&mut self, enabled: bool) {
+        self.sampling.set_usb_suspended(!enabled);
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        defmt::info!("usb_task: bus suspended: {}", suspended);
+        self.sampling.set_usb_suspended(suspended);
+    }
+}
+
 // The convert_to_payload function has been moved to an impl block for AllMeasurements in data_types.rs
 struct Disconnected {}
 